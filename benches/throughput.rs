@@ -0,0 +1,52 @@
+//! Throughput of processing CSV input, for deposit-heavy, dispute-heavy and mixed workloads, and
+//! of the fast path for reading rows against deserializing them with serde.
+//!
+//! Run with `cargo bench`. The input is generated in memory before measuring, so only reading
+//! and applying the rows is timed.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use toy_transaction_engine::generate::{generate, Workload};
+use toy_transaction_engine::{process_reader, process_reader_with_serde};
+
+/// The number of rows of every workload
+const ROWS: u64 = 100_000;
+
+fn throughput(c: &mut Criterion) {
+    let mut group = c.benchmark_group("throughput");
+    group.throughput(Throughput::Elements(ROWS));
+    group.sample_size(20);
+    for (name, workload) in [
+        ("deposit_heavy", Workload::deposit_heavy(ROWS)),
+        ("dispute_heavy", Workload::dispute_heavy(ROWS)),
+        ("mixed", Workload::mixed(ROWS)),
+    ] {
+        let mut input = Vec::new();
+        generate(&mut input, &workload).unwrap();
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || input.as_slice(),
+                |input| process_reader(input).unwrap(),
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+fn parsing(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parsing");
+    group.throughput(Throughput::Elements(ROWS));
+    group.sample_size(20);
+    let mut input = Vec::new();
+    generate(&mut input, &Workload::mixed(ROWS)).unwrap();
+    group.bench_function("byte_records", |b| {
+        b.iter(|| process_reader(input.as_slice()).unwrap())
+    });
+    group.bench_function("serde", |b| {
+        b.iter(|| process_reader_with_serde(input.as_slice()).unwrap())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, throughput, parsing);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    // the gRPC code is only generated with the grpc feature, using a vendored protoc so that
+    // none has to be installed
+    #[cfg(feature = "grpc")]
+    {
+        println!("cargo:rerun-if-changed=proto/transaction_engine.proto");
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("protoc is vendored");
+        std::env::set_var("PROTOC", protoc);
+        tonic_build::compile_protos("proto/transaction_engine.proto")
+            .expect("the proto definition compiles");
+    }
+}
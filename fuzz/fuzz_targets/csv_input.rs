@@ -0,0 +1,13 @@
+//! Feeds arbitrary bytes into the CSV reading and deserializing path. Any input may be
+//! rejected, but none may panic or leave the ledger out of balance.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use toy_transaction_engine::process_reader_with_rejections;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok((transaction_engine, _)) = process_reader_with_rejections(data) {
+        transaction_engine.check_ledger().unwrap();
+    }
+});
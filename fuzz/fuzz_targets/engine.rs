@@ -0,0 +1,65 @@
+//! Feeds arbitrary sequences of transactions into the engine, checking the invariants of every
+//! account after each one: the total is the sum of available and held funds, and held funds
+//! are never negative. Few clients and transaction ids are used, so that disputes, resolves
+//! and chargebacks mostly reference transactions which exist.
+
+#![no_main]
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use toy_transaction_engine::{Amount, TransactionEngine, TransactionInput};
+
+#[derive(Debug, Arbitrary)]
+enum Operation {
+    Deposit { client: u8, tx: u8, amount: i32 },
+    Withdrawal { client: u8, tx: u8, amount: i32 },
+    Dispute { client: u8, tx: u8 },
+    Resolve { client: u8, tx: u8 },
+    Chargeback { client: u8, tx: u8 },
+}
+
+impl Operation {
+    fn transaction(&self) -> TransactionInput {
+        let client = |client: &u8| (client % 4).into();
+        let tx = |tx: &u8| (tx % 16).into();
+        let amount = |amount: &i32| Amount::from_minor_units((*amount).into());
+        match self {
+            Operation::Deposit {
+                client: c,
+                tx: t,
+                amount: a,
+            } => TransactionInput::deposit(client(c), tx(t), amount(a)),
+            Operation::Withdrawal {
+                client: c,
+                tx: t,
+                amount: a,
+            } => TransactionInput::withdrawal(client(c), tx(t), amount(a)),
+            Operation::Dispute { client: c, tx: t } => TransactionInput::dispute(client(c), tx(t)),
+            Operation::Resolve { client: c, tx: t } => TransactionInput::resolve(client(c), tx(t)),
+            Operation::Chargeback { client: c, tx: t } => {
+                TransactionInput::chargeback(client(c), tx(t))
+            }
+        }
+    }
+}
+
+fuzz_target!(|operations: Vec<Operation>| {
+    let mut transaction_engine = TransactionEngine::new();
+    transaction_engine.set_verify_invariants(true);
+    for operation in &operations {
+        let transaction = operation.transaction();
+        let _ = transaction_engine.process_transaction(transaction);
+        for (client, account) in transaction_engine.accounts() {
+            assert_eq!(
+                account.total,
+                account.available + account.held,
+                "client {client} after {operation:?}"
+            );
+            assert!(
+                !account.held.is_negative(),
+                "client {client} holds negative funds after {operation:?}"
+            );
+        }
+    }
+    transaction_engine.check_ledger().unwrap();
+});
@@ -0,0 +1,94 @@
+#ifndef TOY_TRANSACTION_ENGINE_H
+#define TOY_TRANSACTION_ENGINE_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+/**
+ * The call succeeded
+ */
+#define TTE_OK 0
+
+/**
+ * The engine rejected the transaction, see `tte_last_error`
+ */
+#define TTE_REJECTED 1
+
+/**
+ * An argument was null, not valid UTF-8 or out of range, see `tte_last_error`
+ */
+#define TTE_INVALID_ARGUMENT 2
+
+/**
+ * The client has no account
+ */
+#define TTE_NOT_FOUND 3
+
+/**
+ * The engine panicked, which is a bug, and shouldn't be used any more
+ */
+#define TTE_PANICKED 4
+
+/**
+ * An engine with the message of its latest failure. Only ever handled through a pointer.
+ */
+typedef struct TteEngine TteEngine;
+
+/**
+ * The state of an account. Amounts are whole numbers of ten-thousandths, e.g. 15000 for 1.5.
+ */
+typedef struct TteAccount {
+  int64_t available;
+  int64_t held;
+  int64_t total;
+  bool locked;
+} TteAccount;
+
+/**
+ * Creates an engine with the default settings. Free it with `tte_free`.
+ */
+struct TteEngine *tte_engine_new(void);
+
+/**
+ * Frees an engine created with `tte_engine_new`. Null is ignored.
+ *
+ * # Safety
+ *
+ * `engine` must be null or a pointer returned by `tte_engine_new` which wasn't freed yet.
+ */
+void tte_free(struct TteEngine *engine);
+
+/**
+ * Applies a transaction. `kind` is the type as in CSV input, e.g. `deposit` or `dispute`, and
+ * `amount` a decimal string like `1.5`, or null for transactions without one.
+ *
+ * # Safety
+ *
+ * `engine` must be a live engine, and `kind` and `amount` null or nul-terminated strings.
+ */
+int32_t tte_submit(struct TteEngine *engine,
+                   const char *kind,
+                   uint64_t client,
+                   uint64_t tx,
+                   const char *amount);
+
+/**
+ * Writes the state of the client's account to `account`.
+ *
+ * # Safety
+ *
+ * `engine` must be a live engine and `account` point to a `TteAccount`.
+ */
+int32_t tte_get_account(struct TteEngine *engine, uint64_t client, struct TteAccount *account);
+
+/**
+ * The message of the latest failure of the engine, empty if there was none. The string is
+ * owned by the engine and valid until its next call.
+ *
+ * # Safety
+ *
+ * `engine` must be a live engine.
+ */
+const char *tte_last_error(const struct TteEngine *engine);
+
+#endif  /* TOY_TRANSACTION_ENGINE_H */
@@ -0,0 +1,108 @@
+//! Settings of single accounts, like the credit limit which lets withdrawals take the available
+//! funds negative.
+//!
+//! The settings are read from a TOML file with an entry per client such as
+//!
+//! ```toml
+//! [[account]]
+//! client = 1
+//! credit_limit = "100"
+//! ```
+//!
+//! Clients which aren't listed get the default settings, without any credit.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::{Amount, ClientId};
+
+/// The settings of an account
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct AccountConfig {
+    /// How far withdrawals may take the available funds below zero
+    pub credit_limit: Amount,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccountConfigEntry {
+    client: ClientId,
+    #[serde(default)]
+    credit_limit: Amount,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AccountConfigFile {
+    #[serde(default)]
+    account: Vec<AccountConfigEntry>,
+}
+
+/// Reads the settings of every client listed in a TOML file.
+pub fn read_account_configs(
+    path: &str,
+) -> Result<HashMap<ClientId, AccountConfig>, Box<dyn Error>> {
+    let file: AccountConfigFile = toml::from_str(&fs::read_to_string(path)?)?;
+    let mut account_configs = HashMap::new();
+    for entry in file.account {
+        if entry.credit_limit.is_negative() {
+            return Err(format!("the credit limit of client {} is negative", entry.client).into());
+        }
+        let account_config = AccountConfig {
+            credit_limit: entry.credit_limit,
+        };
+        if account_configs
+            .insert(entry.client, account_config)
+            .is_some()
+        {
+            return Err(format!("client {} is listed more than once", entry.client).into());
+        }
+    }
+    Ok(account_configs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AccountConfig;
+    use crate::{Amount, TransactionEngine, TransactionInput, TransactionProcessingError};
+
+    fn money(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_withdrawals_may_use_the_credit_limit() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_account_config(
+            1,
+            AccountConfig {
+                credit_limit: money("50"),
+            },
+        );
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("10")),
+            TransactionInput::withdrawal(1, 2, money("45")),
+            TransactionInput::withdrawal(1, 3, money("15")),
+            TransactionInput::withdrawal(1, 4, money("15.0001")),
+            TransactionInput::deposit(2, 5, money("10")),
+            TransactionInput::withdrawal(2, 6, money("11")),
+        ]);
+        assert!(results[0].is_ok() && results[1].is_ok() && results[2].is_ok());
+        assert!(matches!(
+            results[3],
+            Err(TransactionProcessingError::InsufficientFunds)
+        ));
+        assert!(matches!(
+            results[5],
+            Err(TransactionProcessingError::InsufficientFunds)
+        ));
+
+        let account = transaction_engine.get_account(1).unwrap();
+        assert_eq!(account.available.to_string(), "-50.0000");
+        assert_eq!(account.total.to_string(), "-50.0000");
+        assert!(transaction_engine.check_ledger().is_ok());
+    }
+}
@@ -0,0 +1,183 @@
+//! An async façade over the engine for services which receive transactions over the network.
+//!
+//! The engine runs on its own thread as an actor fed by a bounded channel, so applying a
+//! transaction, including writing it to the write-ahead log, never blocks the async runtime.
+//! `AsyncTransactionEngine` is a cheap handle to that thread which can be cloned into every
+//! task that submits transactions. Requests are handled one at a time in the order they were
+//! received, so a snapshot always reflects every transaction submitted before it.
+
+use std::thread;
+
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{
+    AccountDetails, ClientId, SnapshotError, TransactionEngine, TransactionInput,
+    TransactionProcessingError,
+};
+
+/// How many requests may be waiting for the engine before submitting waits
+pub const DEFAULT_QUEUE_DEPTH: usize = 1024;
+
+/// All errors which can happen when using the async engine
+#[derive(Error, Debug)]
+pub enum AsyncEngineError {
+    #[error(transparent)]
+    Processing(#[from] TransactionProcessingError),
+
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+
+    #[error("the engine was shut down")]
+    Stopped,
+}
+
+enum Request {
+    Submit(
+        TransactionInput,
+        oneshot::Sender<Result<(), TransactionProcessingError>>,
+    ),
+    Accounts(oneshot::Sender<Vec<(ClientId, AccountDetails)>>),
+    Snapshot(oneshot::Sender<Result<Vec<u8>, SnapshotError>>),
+    Shutdown(oneshot::Sender<TransactionEngine>),
+}
+
+/// A handle to an engine running on its own thread
+#[derive(Clone)]
+pub struct AsyncTransactionEngine {
+    sender: mpsc::Sender<Request>,
+}
+
+impl AsyncTransactionEngine {
+    /// Starts the engine on its own thread with room for `DEFAULT_QUEUE_DEPTH` waiting
+    /// requests. Doesn't need to be called from within a runtime.
+    pub fn new(transaction_engine: TransactionEngine) -> AsyncTransactionEngine {
+        AsyncTransactionEngine::with_queue_depth(transaction_engine, DEFAULT_QUEUE_DEPTH)
+    }
+
+    /// Starts the engine on its own thread with room for `queue_depth` waiting requests.
+    pub fn with_queue_depth(
+        transaction_engine: TransactionEngine,
+        queue_depth: usize,
+    ) -> AsyncTransactionEngine {
+        let (sender, receiver) = mpsc::channel(queue_depth.max(1));
+        thread::spawn(move || run_engine(transaction_engine, receiver));
+        AsyncTransactionEngine { sender }
+    }
+
+    /// Applies a transaction, waiting while the engine is too far behind.
+    pub async fn submit(&self, transaction: TransactionInput) -> Result<(), AsyncEngineError> {
+        let (reply, response) = oneshot::channel();
+        self.request(Request::Submit(transaction, reply)).await?;
+        Ok(response.await.map_err(|_| AsyncEngineError::Stopped)??)
+    }
+
+    /// A copy of all accounts, sorted by client id, once every transaction submitted before was
+    /// applied
+    pub async fn accounts(&self) -> Result<Vec<(ClientId, AccountDetails)>, AsyncEngineError> {
+        let (reply, response) = oneshot::channel();
+        self.request(Request::Accounts(reply)).await?;
+        response.await.map_err(|_| AsyncEngineError::Stopped)
+    }
+
+    /// A snapshot of the engine, as written by `TransactionEngine::snapshot`, once every
+    /// transaction submitted before was applied
+    pub async fn snapshot(&self) -> Result<Vec<u8>, AsyncEngineError> {
+        let (reply, response) = oneshot::channel();
+        self.request(Request::Snapshot(reply)).await?;
+        Ok(response.await.map_err(|_| AsyncEngineError::Stopped)??)
+    }
+
+    /// Stops the engine once every request sent before was handled and returns it. Requests
+    /// through other handles fail with `Stopped` afterwards.
+    pub async fn shutdown(self) -> Result<TransactionEngine, AsyncEngineError> {
+        let (reply, response) = oneshot::channel();
+        self.request(Request::Shutdown(reply)).await?;
+        response.await.map_err(|_| AsyncEngineError::Stopped)
+    }
+
+    async fn request(&self, request: Request) -> Result<(), AsyncEngineError> {
+        self.sender
+            .send(request)
+            .await
+            .map_err(|_| AsyncEngineError::Stopped)
+    }
+}
+
+/// Handles requests until all handles are dropped or the engine is shut down.
+fn run_engine(mut transaction_engine: TransactionEngine, mut receiver: mpsc::Receiver<Request>) {
+    while let Some(request) = receiver.blocking_recv() {
+        // a requester which stopped waiting doesn't need the response
+        match request {
+            Request::Submit(transaction, reply) => {
+                let _ = reply.send(transaction_engine.process_transaction(transaction));
+            }
+            Request::Accounts(reply) => {
+                let _ = reply.send(
+                    transaction_engine
+                        .sorted_accounts()
+                        .into_iter()
+                        .map(|(client_id, account)| (client_id, account.clone()))
+                        .collect(),
+                );
+            }
+            Request::Snapshot(reply) => {
+                let mut snapshot = Vec::new();
+                let result = transaction_engine.snapshot(&mut snapshot);
+                let _ = reply.send(result.map(|()| snapshot));
+            }
+            Request::Shutdown(reply) => {
+                let _ = reply.send(transaction_engine);
+                return;
+            }
+        }
+    }
+    // the engine is dropped without a shutdown, so make the write-ahead log durable
+    let _ = transaction_engine.sync_wal();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AsyncEngineError, AsyncTransactionEngine};
+    use crate::{TransactionEngine, TransactionInput, TransactionProcessingError};
+
+    #[tokio::test]
+    async fn test_submit_and_snapshot() {
+        let engine = AsyncTransactionEngine::new(TransactionEngine::new());
+        let tasks: Vec<_> = (1..=3)
+            .zip(1..=3)
+            .map(|(client, tx)| {
+                let engine = engine.clone();
+                async move {
+                    engine
+                        .submit(TransactionInput::deposit(client, tx, "2".parse().unwrap()))
+                        .await
+                }
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert!(matches!(
+            engine
+                .submit(TransactionInput::withdrawal(1, 4, "5".parse().unwrap()))
+                .await,
+            Err(AsyncEngineError::Processing(
+                TransactionProcessingError::InsufficientFunds
+            ))
+        ));
+
+        let accounts = engine.accounts().await.unwrap();
+        assert_eq!(accounts.len(), 3);
+        assert_eq!(accounts[0].1.available.to_string(), "2.0000");
+        let restored = TransactionEngine::restore(&engine.snapshot().await.unwrap()[..]).unwrap();
+        assert_eq!(restored.transaction_count(), 3);
+
+        let handle = engine.clone();
+        assert_eq!(engine.shutdown().await.unwrap().transaction_count(), 3);
+        assert!(matches!(
+            handle.accounts().await,
+            Err(AsyncEngineError::Stopped)
+        ));
+    }
+}
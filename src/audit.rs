@@ -0,0 +1,300 @@
+//! An append-only audit trail of every balance mutation, for reconciliation.
+//!
+//! Every applied transaction which changes an account is recorded with the state of the
+//! account before and after it. A new account starts from zero balances. Transactions which
+//! leave the account as it was, like a skipped zero amount, aren't recorded. A transaction
+//! charging a fee is recorded with its fee, once for its client and once for the account
+//! collecting the fee. Transactions with a timestamp are recorded with it.
+
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::{
+    AccountDetails, AccountStatus, Amount, ClientId, Timestamp, TransactionId, TransactionType,
+};
+
+/// The formats the audit trail can be written in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AuditFormat {
+    /// A row per mutation with the `_before` and `_after` values in their own columns
+    #[default]
+    Csv,
+    /// A JSON object per mutation with `before` and `after` objects
+    JsonLines,
+}
+
+impl AuditFormat {
+    /// Parses the format from its command line name.
+    pub fn from_name(name: &str) -> Option<AuditFormat> {
+        match name {
+            "csv" => Some(AuditFormat::Csv),
+            "jsonl" | "ndjson" => Some(AuditFormat::JsonLines),
+            _ => None,
+        }
+    }
+
+    /// Detects the format from the extension of a path, falling back to CSV.
+    pub fn detect(path: &str) -> AuditFormat {
+        if path.ends_with(".jsonl") || path.ends_with(".ndjson") {
+            AuditFormat::JsonLines
+        } else {
+            AuditFormat::Csv
+        }
+    }
+}
+
+/// A change of an account by a transaction
+#[derive(Debug, Clone, Copy)]
+pub struct AuditRecord<'a> {
+    /// The id of the transaction. Interest payments are numbered in an id space of their own,
+    /// so the sinks leave it out for them.
+    pub tx: TransactionId,
+    pub client: ClientId,
+    pub kind: TransactionType,
+    /// The fee charged by the transaction, zero if none
+    pub fee: Amount,
+    /// When the transaction happened, if the input says so
+    pub timestamp: Option<Timestamp>,
+    pub before: &'a AccountDetails,
+    pub after: &'a AccountDetails,
+}
+
+impl AuditRecord<'_> {
+    /// The id of the transaction, None for an interest payment as its id isn't one of the input
+    fn input_tx(&self) -> Option<TransactionId> {
+        (self.kind != TransactionType::Interest).then_some(self.tx)
+    }
+}
+
+/// Receives every balance mutation of an engine, in the order they were applied
+pub trait AuditSink {
+    /// Appends the record to the trail.
+    fn record(&mut self, record: &AuditRecord) -> io::Result<()>;
+
+    /// Writes out whatever was buffered.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+impl<S: AuditSink + ?Sized> AuditSink for Box<S> {
+    fn record(&mut self, record: &AuditRecord) -> io::Result<()> {
+        (**self).record(record)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        (**self).flush()
+    }
+}
+
+/// Creates a sink writing the trail in the format to the writer. With `continued`, the writer
+/// appends to a trail which was started before, so no CSV header is written.
+pub fn sink_for<W: Write + Send + 'static>(
+    writer: W,
+    format: AuditFormat,
+    continued: bool,
+) -> Box<dyn AuditSink + Send> {
+    match format {
+        AuditFormat::Csv if continued => Box::new(CsvAuditSink::continuing(writer)),
+        AuditFormat::Csv => Box::new(CsvAuditSink::new(writer)),
+        AuditFormat::JsonLines => Box::new(JsonLinesAuditSink::new(writer)),
+    }
+}
+
+/// A row of the CSV audit trail
+#[derive(Serialize)]
+struct AuditRow {
+    tx: Option<TransactionId>,
+    client: ClientId,
+    #[serde(rename = "type")]
+    kind: TransactionType,
+    fee: Amount,
+    timestamp: Option<Timestamp>,
+    available_before: Amount,
+    held_before: Amount,
+    total_before: Amount,
+    status_before: AccountStatus,
+    available_after: Amount,
+    held_after: Amount,
+    total_after: Amount,
+    status_after: AccountStatus,
+}
+
+/// Writes the trail as CSV with a header row
+pub struct CsvAuditSink<W: Write> {
+    writer: csv::Writer<W>,
+}
+
+impl<W: Write> CsvAuditSink<W> {
+    pub fn new(writer: W) -> CsvAuditSink<W> {
+        CsvAuditSink {
+            writer: csv::Writer::from_writer(writer),
+        }
+    }
+
+    /// Creates a sink which doesn't write a header, to append to an existing trail.
+    pub fn continuing(writer: W) -> CsvAuditSink<W> {
+        CsvAuditSink {
+            writer: csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(writer),
+        }
+    }
+}
+
+impl<W: Write> AuditSink for CsvAuditSink<W> {
+    fn record(&mut self, record: &AuditRecord) -> io::Result<()> {
+        self.writer
+            .serialize(AuditRow {
+                tx: record.input_tx(),
+                client: record.client,
+                kind: record.kind,
+                fee: record.fee,
+                timestamp: record.timestamp,
+                available_before: record.before.available,
+                held_before: record.before.held,
+                total_before: record.before.total,
+                status_before: record.before.status,
+                available_after: record.after.available,
+                held_after: record.after.held,
+                total_after: record.after.total,
+                status_after: record.after.status,
+            })
+            .map_err(io::Error::from)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// The state of an account in the JSON Lines audit trail
+#[derive(Serialize)]
+struct AccountState {
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    status: AccountStatus,
+}
+
+impl From<&AccountDetails> for AccountState {
+    fn from(account: &AccountDetails) -> AccountState {
+        AccountState {
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            status: account.status,
+        }
+    }
+}
+
+/// A line of the JSON Lines audit trail
+#[derive(Serialize)]
+struct AuditLine {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx: Option<TransactionId>,
+    client: ClientId,
+    #[serde(rename = "type")]
+    kind: TransactionType,
+    fee: Amount,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+    before: AccountState,
+    after: AccountState,
+}
+
+/// Writes the trail as a JSON object per line
+pub struct JsonLinesAuditSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonLinesAuditSink<W> {
+    pub fn new(writer: W) -> JsonLinesAuditSink<W> {
+        JsonLinesAuditSink { writer }
+    }
+}
+
+impl<W: Write> AuditSink for JsonLinesAuditSink<W> {
+    fn record(&mut self, record: &AuditRecord) -> io::Result<()> {
+        let line = AuditLine {
+            tx: record.input_tx(),
+            client: record.client,
+            kind: record.kind,
+            fee: record.fee,
+            timestamp: record.timestamp,
+            before: record.before.into(),
+            after: record.after.into(),
+        };
+        serde_json::to_writer(&mut self.writer, &line)?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use super::{AuditFormat, CsvAuditSink};
+    use crate::transaction_engine::InterestPolicy;
+    use crate::{TransactionEngine, TransactionInput};
+
+    /// A writer whose content stays readable after it was moved into the sink
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_mutations_are_recorded_with_before_and_after() {
+        let buffer = SharedBuffer::default();
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_audit_sink(CsvAuditSink::new(buffer.clone()));
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "2".parse().unwrap()),
+            TransactionInput::withdrawal(1, 2, "5".parse().unwrap()),
+            TransactionInput::dispute(1, 1),
+        ]);
+        transaction_engine.flush_audit().unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap(),
+            "tx,client,type,fee,timestamp,available_before,held_before,total_before,status_before,\
+             available_after,held_after,total_after,status_after\n\
+             1,1,deposit,0.0000,,0.0000,0.0000,0.0000,active,2.0000,0.0000,2.0000,active\n\
+             1,1,dispute,0.0000,,2.0000,0.0000,2.0000,active,0.0000,2.0000,2.0000,active\n"
+        );
+        assert_eq!(AuditFormat::detect("audit.jsonl"), AuditFormat::JsonLines);
+    }
+
+    #[test]
+    fn test_interest_is_recorded_without_a_transaction_id() {
+        let buffer = SharedBuffer::default();
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_audit_sink(CsvAuditSink::new(buffer.clone()));
+        transaction_engine.set_interest_policy(Some(InterestPolicy {
+            rate_bps: 365 * 100,
+            period: 86_400,
+        }));
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "100".parse().unwrap()).with_timestamp(0),
+            TransactionInput::deposit(1, 2, "1".parse().unwrap()).with_timestamp(86_400),
+        ]);
+        transaction_engine.flush_audit().unwrap();
+        let trail = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let rows: Vec<&str> = trail.lines().skip(1).collect();
+        assert!(rows[0].starts_with("1,1,deposit,"));
+        assert!(rows[1].starts_with(",1,interest,"));
+        assert!(rows[2].starts_with("2,1,deposit,"));
+    }
+}
@@ -0,0 +1,76 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use clap::Parser;
+use tokio::net::TcpListener;
+use toy_transaction_engine::{grpc, logging, TransactionEngine};
+use tracing::info;
+
+/// Serves the transaction engine over gRPC until SIGINT or SIGTERM is received.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// The address to listen on
+    #[arg(long, default_value = "127.0.0.1:50051")]
+    listen: String,
+
+    /// Start from the state saved in this snapshot instead of empty accounts
+    #[arg(long)]
+    load_snapshot: Option<String>,
+
+    /// Save the state to this snapshot when shutting down
+    #[arg(long)]
+    save_snapshot: Option<String>,
+}
+
+#[tokio::main]
+async fn main() {
+    logging::init(false, 0);
+    if let Err(e) = run(Args::parse()).await {
+        eprintln!("An error occurred in the gRPC server: {e}");
+        process::exit(1);
+    }
+}
+
+async fn run(args: Args) -> Result<(), Box<dyn Error>> {
+    let transaction_engine = match &args.load_snapshot {
+        Some(snapshot_path) => {
+            TransactionEngine::restore(BufReader::new(File::open(snapshot_path)?))?
+        }
+        None => TransactionEngine::new(),
+    };
+    let transaction_engine = Arc::new(Mutex::new(transaction_engine));
+
+    let listener = TcpListener::bind(&args.listen).await?;
+    info!("Listening on {}", listener.local_addr()?);
+    grpc::serve(listener, Arc::clone(&transaction_engine), shutdown_signal()).await?;
+    info!("Shut down.");
+
+    if let Some(snapshot_path) = &args.save_snapshot {
+        let transaction_engine = transaction_engine.lock().unwrap_or_else(|e| e.into_inner());
+        transaction_engine.snapshot(BufWriter::new(File::create(snapshot_path)?))?;
+    }
+    Ok(())
+}
+
+/// Completes on SIGINT, or SIGTERM on Unix.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let Ok(mut terminate) = signal(SignalKind::terminate()) else {
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        };
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => (),
+            _ = terminate.recv() => (),
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
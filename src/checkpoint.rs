@@ -0,0 +1,322 @@
+//! Checkpoints of runs over large CSV input files, so that a run which failed or was stopped
+//! can be resumed from its last checkpoint instead of from the start.
+//!
+//! A checkpoint holds the state of the engine together with the position in the input file
+//! right after the last row applied to it, the control totals read up to there and the number
+//! of rows processed and rejected before it, so that `--max-reject-rate` and `--stats` of a
+//! resumed run also cover those rows. It is
+//! written to a temporary file first and then renamed over the previous checkpoint, so a crash
+//! while writing never leaves a broken checkpoint behind.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::control_totals::{ControlTotals, ControlTotalsPolicy};
+use crate::dialect::CsvDialect;
+use crate::rejects::Rejections;
+use crate::stats::ProcessingStats;
+use crate::transaction_engine::Snapshot;
+use crate::{
+    process_row, read_row, stats_with_rejections, verify_control_totals, SnapshotError,
+    TransactionEngine,
+};
+
+/// The version of the checkpoint format written by this version of the engine
+pub const CHECKPOINT_VERSION: u32 = 2;
+
+/// How many rows are processed between two checkpoints by default
+pub const DEFAULT_CHECKPOINT_INTERVAL: u64 = 100_000;
+
+/// All errors which can happen when writing or resuming from a checkpoint
+#[derive(Error, Debug)]
+pub enum CheckpointError {
+    #[error("couldn't read or write the checkpoint: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("the checkpoint is malformed: {0}")]
+    Malformed(#[from] serde_json::Error),
+
+    #[error("checkpoint version {0} is not supported, expected version {CHECKPOINT_VERSION}")]
+    UnsupportedVersion(u32),
+
+    #[error(transparent)]
+    Snapshot(#[from] SnapshotError),
+
+    #[error("the checkpoint was taken for {expected} but the input is {actual}")]
+    InputMismatch { expected: String, actual: String },
+}
+
+/// Where checkpoints are written and how often
+#[derive(Debug, Clone)]
+pub struct CheckpointConfig {
+    pub path: String,
+    /// The number of rows between two checkpoints
+    pub interval: u64,
+}
+
+/// The position in an input file a run can be resumed from
+#[derive(Serialize, Deserialize)]
+struct InputPosition {
+    byte: u64,
+    line: u64,
+    record: u64,
+}
+
+/// The transactions processed and the rows rejected before a checkpoint, by type and by error
+/// kind, including the rows which never reached the engine
+#[derive(Serialize, Deserialize, Default)]
+struct Counts {
+    transactions: BTreeMap<Cow<'static, str>, u64>,
+    rejections: BTreeMap<Cow<'static, str>, u64>,
+}
+
+/// The state of a run at a checkpoint
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+    version: u32,
+    input_path: String,
+    position: InputPosition,
+    control_totals: ControlTotals,
+    counts: Counts,
+    engine: Snapshot,
+}
+
+/// Only the version, read first so that a checkpoint of another version gets a proper error
+#[derive(Deserialize)]
+struct CheckpointVersion {
+    version: u32,
+}
+
+impl Checkpoint {
+    /// Reads a checkpoint from a file.
+    pub fn read_from(path: &str) -> Result<Checkpoint, CheckpointError> {
+        let value: serde_json::Value = serde_json::from_reader(BufReader::new(File::open(path)?))?;
+        let CheckpointVersion { version } = serde_json::from_value(value.clone())?;
+        if version != CHECKPOINT_VERSION {
+            return Err(CheckpointError::UnsupportedVersion(version));
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+
+    /// The input file the checkpoint was taken for
+    pub fn input_path(&self) -> &str {
+        &self.input_path
+    }
+
+    /// Replaces the checkpoint at the path with this one.
+    fn write_to(&self, path: &str) -> Result<(), CheckpointError> {
+        let temporary_path = format!("{}.tmp", path);
+        let mut writer = BufWriter::new(File::create(&temporary_path)?);
+        serde_json::to_writer(&mut writer, self)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        fs::rename(&temporary_path, path)?;
+        Ok(())
+    }
+}
+
+/// Processes a CSV input file, writing a checkpoint every `checkpoints.interval` rows. With a
+/// checkpoint to resume from, the state of the engine is replaced by the one of the checkpoint
+/// and reading starts right after the last row applied to it.
+pub(crate) fn process_csv_file_with_checkpoints(
+    transaction_engine: &mut TransactionEngine,
+    input_path: &str,
+    dialect: &CsvDialect,
+    control_totals_policy: ControlTotalsPolicy,
+    rejections: &mut Rejections,
+    checkpoints: &CheckpointConfig,
+    resume_from: Option<Checkpoint>,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = dialect.reader(File::open(input_path)?);
+    let headers = dialect.headers(&mut reader)?;
+    let mut control_totals = ControlTotals::new();
+    if let Some(checkpoint) = resume_from {
+        if checkpoint.input_path != input_path {
+            return Err(CheckpointError::InputMismatch {
+                expected: checkpoint.input_path,
+                actual: input_path.to_string(),
+            }
+            .into());
+        }
+        transaction_engine.replace_state(checkpoint.engine)?;
+        transaction_engine.add_stats(&ProcessingStats {
+            transactions: checkpoint.counts.transactions,
+            rejections: checkpoint.counts.rejections,
+            ..ProcessingStats::default()
+        });
+        control_totals = checkpoint.control_totals;
+        let mut position = csv::Position::new();
+        position
+            .set_byte(checkpoint.position.byte)
+            .set_line(checkpoint.position.line)
+            .set_record(checkpoint.position.record);
+        reader.seek(position)?;
+    }
+
+    let mut rows_since_checkpoint = 0;
+    let mut record = csv::StringRecord::new();
+    while reader.read_record(&mut record)? {
+        if let Some(row) = read_row(
+            record.clone(),
+            &headers,
+            dialect.precision,
+            &mut control_totals,
+        )? {
+            process_row(transaction_engine, row, rejections)?;
+        }
+        rows_since_checkpoint += 1;
+        if rows_since_checkpoint >= checkpoints.interval {
+            let position = reader.position();
+            let stats = stats_with_rejections(transaction_engine.stats(), rejections.rejected());
+            let checkpoint = Checkpoint {
+                version: CHECKPOINT_VERSION,
+                input_path: input_path.to_string(),
+                position: InputPosition {
+                    byte: position.byte(),
+                    line: position.line(),
+                    record: position.record(),
+                },
+                control_totals,
+                counts: Counts {
+                    transactions: stats.transactions,
+                    rejections: stats.rejections,
+                },
+                engine: transaction_engine.to_snapshot()?,
+            };
+            checkpoint.write_to(&checkpoints.path)?;
+            control_totals = checkpoint.control_totals;
+            rows_since_checkpoint = 0;
+        }
+    }
+    verify_control_totals(&control_totals, control_totals_policy)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+
+    use super::{process_csv_file_with_checkpoints, Checkpoint, CheckpointConfig};
+    use crate::control_totals::ControlTotalsPolicy;
+    use crate::dialect::CsvDialect;
+    use crate::rejects::{ProcessingPolicy, Rejections};
+    use crate::TransactionEngine;
+
+    #[test]
+    fn test_resume_from_checkpoint() {
+        let directory = env::temp_dir().join(format!("tte-checkpoint-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let input_path = directory.join("input.csv").to_string_lossy().to_string();
+        let checkpoint_path = directory
+            .join("checkpoint.json")
+            .to_string_lossy()
+            .to_string();
+        fs::write(
+            &input_path,
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 5\n\
+             deposit, 1, 2, 3\n\
+             withdrawal, 1, 3, 20\n\
+             deposit, 1, 4, 1\n\
+             trailer, 4, 9\n",
+        )
+        .unwrap();
+        let checkpoints = CheckpointConfig {
+            path: checkpoint_path.clone(),
+            interval: 2,
+        };
+
+        // the strict run stops at the withdrawal, after the checkpoint of the first two rows
+        let mut transaction_engine = TransactionEngine::new();
+        assert!(process_csv_file_with_checkpoints(
+            &mut transaction_engine,
+            &input_path,
+            &CsvDialect::default(),
+            ControlTotalsPolicy::Fail,
+            &mut Rejections::new(ProcessingPolicy::Strict, false),
+            &checkpoints,
+            None,
+        )
+        .is_err());
+
+        // resuming applies the remaining rows on top of the first two only
+        let checkpoint = Checkpoint::read_from(&checkpoint_path).unwrap();
+        let mut transaction_engine = TransactionEngine::new();
+        process_csv_file_with_checkpoints(
+            &mut transaction_engine,
+            &input_path,
+            &CsvDialect::default(),
+            ControlTotalsPolicy::Fail,
+            &mut Rejections::new(ProcessingPolicy::Skip, false),
+            &checkpoints,
+            Some(checkpoint),
+        )
+        .unwrap();
+        assert_eq!(
+            transaction_engine.get_account(1).unwrap().total,
+            "9".parse().unwrap()
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_resume_counts_rows_before_checkpoint() {
+        let directory =
+            env::temp_dir().join(format!("tte-checkpoint-counts-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let input_path = directory.join("input.csv").to_string_lossy().to_string();
+        let checkpoint_path = directory
+            .join("checkpoint.json")
+            .to_string_lossy()
+            .to_string();
+        fs::write(
+            &input_path,
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 5\n\
+             deposit, 1, x, 3\n\
+             withdrawal, 1, 3, 20\n\
+             deposit, 1, 4, 1\n\
+             deposit, 1, 5, 1\n",
+        )
+        .unwrap();
+        let checkpoints = CheckpointConfig {
+            path: checkpoint_path.clone(),
+            interval: 2,
+        };
+        process_csv_file_with_checkpoints(
+            &mut TransactionEngine::new(),
+            &input_path,
+            &CsvDialect::default(),
+            ControlTotalsPolicy::Ignore,
+            &mut Rejections::new(ProcessingPolicy::Skip, false),
+            &checkpoints,
+            None,
+        )
+        .unwrap();
+
+        // the last checkpoint was taken after the fourth row, with two of them rejected
+        let checkpoint = Checkpoint::read_from(&checkpoint_path).unwrap();
+        let mut transaction_engine = TransactionEngine::new();
+        process_csv_file_with_checkpoints(
+            &mut transaction_engine,
+            &input_path,
+            &CsvDialect::default(),
+            ControlTotalsPolicy::Ignore,
+            &mut Rejections::new(ProcessingPolicy::Skip, false),
+            &checkpoints,
+            Some(checkpoint),
+        )
+        .unwrap();
+        let stats = transaction_engine.stats();
+        assert_eq!((stats.rejection_count(), stats.row_count()), (2, 5));
+        assert_eq!(stats.rejections["parse_error"], 1);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
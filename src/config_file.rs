@@ -0,0 +1,148 @@
+//! The settings of a run read from a TOML file given with `--config`, such as
+//!
+//! ```toml
+//! input = "transactions.csv"
+//! output = "accounts.json"
+//! format = "json"
+//! strict = true
+//!
+//! [engine]
+//! zero-amounts = "skip"
+//! locked-accounts = "block"
+//! dispute-window = 86400
+//! ```
+//!
+//! The keys are named like the flags. A flag given on the command line overrides the value of
+//! the file, and a setting which is in neither keeps the default of the flag.
+
+use std::error::Error;
+use std::fs;
+
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use serde::Deserialize;
+
+use crate::{parse_input_format, parse_output_format, Config, EngineConfig};
+
+/// The contents of a config file
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    /// The input file, or - to read from stdin
+    pub input: Option<String>,
+    pub input_format: Option<String>,
+    /// Where the account state is written instead of stdout
+    pub output: Option<String>,
+    /// The format of the account state
+    pub format: Option<String>,
+    pub strict: Option<bool>,
+    pub sorted: Option<bool>,
+    pub engine: EngineConfig,
+}
+
+impl ConfigFile {
+    /// Reads the config file at the path.
+    pub fn read(path: &str) -> Result<ConfigFile, Box<dyn Error>> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Applies the settings of the file to the config, except for those the matches have from
+    /// the command line.
+    pub(crate) fn apply(self, config: &mut Config, matches: &ArgMatches) -> Result<(), String> {
+        let from_file = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+        if let Some(input) = self.input.filter(|_| from_file("input_path")) {
+            config.input_path = input;
+        }
+        if let Some(name) = self.input_format.filter(|_| from_file("input_format")) {
+            config.input_format = Some(parse_input_format(&name)?);
+        }
+        if let Some(output) = self.output.filter(|_| from_file("output")) {
+            config.output = Some(output);
+        }
+        if let Some(name) = self.format.filter(|_| from_file("format")) {
+            config.format = parse_output_format(&name).map_err(|e| format!("format {e}"))?;
+        }
+        if let Some(strict) = self.strict.filter(|_| from_file("strict")) {
+            config.strict = strict;
+        }
+        if let Some(sorted) = self.sorted.filter(|_| from_file("sorted")) {
+            config.sorted = sorted;
+        }
+
+        let engine = self.engine;
+        if from_file("zero_amount_policy") {
+            config.zero_amount_policy = engine.zero_amount_policy;
+        }
+        if from_file("duplicate_id_policy") {
+            config.duplicate_id_policy = engine.duplicate_id_policy;
+        }
+        if from_file("precision_policy") {
+            config.precision_policy = engine.precision_policy;
+        }
+        if from_file("locked_account_policy") {
+            config.locked_account_policy = engine.locked_account_policy;
+        }
+        if from_file("dispute_window") {
+            config.dispute_window = engine.dispute_window;
+        }
+        if from_file("retain_policy") {
+            config.retain_policy = engine.retain_policy;
+        }
+        if from_file("allow_admin_ops") {
+            config.allow_admin_ops = engine.allow_admin_ops;
+        }
+        if from_file("verify_invariants") {
+            config.verify_invariants = engine.verify_invariants;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use crate::{Config, LockedAccountPolicy, ZeroAmountPolicy};
+
+    #[test]
+    fn test_flags_override_the_config_file() {
+        let path = env::temp_dir().join(format!("tte-config-{}.toml", process::id()));
+        fs::write(
+            &path,
+            "input = \"transactions.csv\"\n\
+             strict = true\n\
+             [engine]\n\
+             zero-amounts = \"skip\"\n\
+             locked-accounts = \"block\"\n\
+             dispute-window = 60\n",
+        )
+        .unwrap();
+        let args = |extra: &[&str]| {
+            ["tte", "--config", path.to_str().unwrap()]
+                .iter()
+                .chain(extra)
+                .map(|arg| arg.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let config = Config::new(&args(&[])).unwrap();
+        assert_eq!(config.input_path, "transactions.csv");
+        assert!(config.strict);
+        let engine_config = config.engine_config();
+        assert_eq!(engine_config.zero_amount_policy, ZeroAmountPolicy::Skip);
+        assert_eq!(
+            engine_config.locked_account_policy,
+            LockedAccountPolicy::BlockAll
+        );
+        assert_eq!(engine_config.dispute_window, Some(60));
+
+        let config = Config::new(&args(&["other.csv", "--zero-amounts", "reject"])).unwrap();
+        assert_eq!(config.input_path, "other.csv");
+        assert_eq!(config.zero_amount_policy, ZeroAmountPolicy::Reject);
+        assert_eq!(config.dispute_window, Some(60));
+
+        fs::write(&path, "[engine]\nzero-amounts = \"maybe\"\n").unwrap();
+        assert!(Config::new(&args(&[])).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
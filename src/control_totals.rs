@@ -0,0 +1,248 @@
+use csv::StringRecord;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::{Amount, TransactionInput, TransactionType};
+
+/// What to do when the control totals carried by a batch file don't match what was read from it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ControlTotalsPolicy {
+    Ignore,
+    Warn,
+    Fail,
+}
+
+impl ControlTotalsPolicy {
+    /// Parses the policy from its command line name.
+    pub fn from_name(name: &str) -> Option<ControlTotalsPolicy> {
+        match name {
+            "ignore" => Some(ControlTotalsPolicy::Ignore),
+            "warn" => Some(ControlTotalsPolicy::Warn),
+            "fail" => Some(ControlTotalsPolicy::Fail),
+            _ => None,
+        }
+    }
+}
+
+/// All errors which can happen when verifying the control totals of a batch file
+#[derive(Error, Debug, Clone)]
+pub enum ControlTotalsError {
+    #[error("invalid control record: {0}")]
+    InvalidControlRecord(&'static str),
+
+    #[error("more than one {0} control record found")]
+    DuplicateControlRecord(&'static str),
+
+    #[error("a header control record was found but the trailer control record is missing")]
+    MissingTrailer,
+
+    #[error("{record} control record expects {expected} rows but {actual} were read")]
+    RowCountMismatch {
+        record: &'static str,
+        expected: u64,
+        actual: u64,
+    },
+
+    #[error("{record} control record expects a deposit total of {expected} but {actual} was read")]
+    DepositTotalMismatch {
+        record: &'static str,
+        expected: Amount,
+        actual: Amount,
+    },
+
+    #[error("{0} control record can't be verified as the deposits add up to more than the largest amount")]
+    DepositTotalOverflow(&'static str),
+}
+
+/// The totals announced by a header or trailer control record
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ControlRecord {
+    pub row_count: u64,
+    pub deposit_total: Amount,
+}
+
+/// Accumulates the totals of a batch file while it is read and verifies them against the
+/// header/trailer control records found in the file.
+///
+/// Control records are rows of the form `header,<row count>,<deposit total>` and
+/// `trailer,<row count>,<deposit total>`. The row count covers transaction rows only.
+#[derive(Default, Serialize, Deserialize)]
+pub struct ControlTotals {
+    header: Option<ControlRecord>,
+    trailer: Option<ControlRecord>,
+    row_count: u64,
+    deposit_total: Amount,
+    /// Whether the deposits added up to more than the largest amount
+    #[serde(default)]
+    deposit_total_overflowed: bool,
+}
+
+impl ControlTotals {
+    /// Create a new, empty control totals accumulator
+    pub fn new() -> ControlTotals {
+        ControlTotals::default()
+    }
+
+    /// Checks whether the record is a header or trailer control record and if so, stores it.
+    /// Returns false for any other record so that it can be processed as a transaction.
+    pub fn try_record_control_record(
+        &mut self,
+        record: &StringRecord,
+    ) -> Result<bool, ControlTotalsError> {
+        let (name, slot) = match record.get(0) {
+            Some("header") => ("header", &mut self.header),
+            Some("trailer") => ("trailer", &mut self.trailer),
+            _ => return Ok(false),
+        };
+        if slot.is_some() {
+            return Err(ControlTotalsError::DuplicateControlRecord(name));
+        }
+
+        let row_count = record.get(1).and_then(|v| v.parse::<u64>().ok()).ok_or(
+            ControlTotalsError::InvalidControlRecord("row count must be a non negative integer"),
+        )?;
+        let deposit_total = record.get(2).and_then(|v| v.parse::<Amount>().ok()).ok_or(
+            ControlTotalsError::InvalidControlRecord("deposit total must be a number"),
+        )?;
+        *slot = Some(ControlRecord {
+            row_count,
+            deposit_total,
+        });
+        Ok(true)
+    }
+
+    /// Adds a transaction read from the file to the running totals.
+    pub fn record_transaction(&mut self, transaction: &TransactionInput) {
+        self.row_count += 1;
+        if let (TransactionType::Deposit, Some(amount)) = (transaction.kind, transaction.amount) {
+            match self.deposit_total.checked_add(amount) {
+                Some(deposit_total) => self.deposit_total = deposit_total,
+                None => self.deposit_total_overflowed = true,
+            }
+        }
+    }
+
+    /// Verifies the running totals against the control records. Files without any control
+    /// records always pass.
+    pub fn verify(&self) -> Result<(), ControlTotalsError> {
+        if self.header.is_some() && self.trailer.is_none() {
+            return Err(ControlTotalsError::MissingTrailer);
+        }
+        if let Some(header) = &self.header {
+            self.verify_against("header", header)?;
+        }
+        if let Some(trailer) = &self.trailer {
+            self.verify_against("trailer", trailer)?;
+        }
+        Ok(())
+    }
+
+    fn verify_against(
+        &self,
+        record: &'static str,
+        control_record: &ControlRecord,
+    ) -> Result<(), ControlTotalsError> {
+        if control_record.row_count != self.row_count {
+            return Err(ControlTotalsError::RowCountMismatch {
+                record,
+                expected: control_record.row_count,
+                actual: self.row_count,
+            });
+        }
+        if self.deposit_total_overflowed {
+            return Err(ControlTotalsError::DepositTotalOverflow(record));
+        }
+        if control_record.deposit_total != self.deposit_total {
+            return Err(ControlTotalsError::DepositTotalMismatch {
+                record,
+                expected: control_record.deposit_total,
+                actual: self.deposit_total,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use csv::StringRecord;
+
+    use super::{ControlTotals, ControlTotalsError};
+    use crate::{Amount, MoneyOps, TransactionId, TransactionInput, TransactionType};
+
+    fn deposit(tx: TransactionId, amount: &str) -> TransactionInput {
+        TransactionInput::new(TransactionType::Deposit, 1, tx, amount.parse().ok())
+    }
+
+    #[test]
+    fn test_matching_trailer_passes() {
+        let mut control_totals = ControlTotals::new();
+        assert!(!control_totals
+            .try_record_control_record(&StringRecord::from(vec!["deposit", "1", "1", "1.5"]))
+            .unwrap());
+        control_totals.record_transaction(&deposit(1, "1.5"));
+        control_totals.record_transaction(&deposit(2, "2.25"));
+        control_totals.record_transaction(&TransactionInput::new(
+            TransactionType::Withdrawal,
+            1,
+            3,
+            "1".parse().ok(),
+        ));
+        assert!(control_totals
+            .try_record_control_record(&StringRecord::from(vec!["trailer", "3", "3.75"]))
+            .unwrap());
+        assert!(control_totals.verify().is_ok());
+    }
+
+    #[test]
+    fn test_mismatching_totals_fail() {
+        let mut control_totals = ControlTotals::new();
+        control_totals
+            .try_record_control_record(&StringRecord::from(vec!["header", "2", "3.0"]))
+            .unwrap();
+        control_totals.record_transaction(&deposit(1, "1.0"));
+        match control_totals.verify() {
+            Err(ControlTotalsError::MissingTrailer) => (),
+            _ => panic!("Expected a missing trailer error"),
+        }
+        control_totals
+            .try_record_control_record(&StringRecord::from(vec!["trailer", "1", "3.0"]))
+            .unwrap();
+        match control_totals.verify() {
+            Err(ControlTotalsError::RowCountMismatch {
+                expected: 2,
+                actual: 1,
+                ..
+            }) => (),
+            _ => panic!("Expected a row count mismatch error"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_control_record() {
+        let mut control_totals = ControlTotals::new();
+        match control_totals
+            .try_record_control_record(&StringRecord::from(vec!["trailer", "abc", "1.0"]))
+        {
+            Err(ControlTotalsError::InvalidControlRecord(_)) => (),
+            _ => panic!("Expected an invalid control record error"),
+        }
+    }
+
+    #[test]
+    fn test_deposit_total_beyond_the_largest_amount_fails() {
+        let mut control_totals = ControlTotals::new();
+        control_totals.record_transaction(&TransactionInput::deposit(1, 1, Amount::MAX));
+        control_totals.record_transaction(&TransactionInput::deposit(1, 2, Amount::MAX));
+        // without control records the total doesn't matter
+        assert!(control_totals.verify().is_ok());
+        control_totals
+            .try_record_control_record(&StringRecord::from(vec!["trailer", "2", "1.0"]))
+            .unwrap();
+        match control_totals.verify() {
+            Err(ControlTotalsError::DepositTotalOverflow("trailer")) => (),
+            _ => panic!("Expected a deposit total overflow error"),
+        }
+    }
+}
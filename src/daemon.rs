@@ -0,0 +1,627 @@
+//! A long lived daemon mode which keeps a single engine warm between batches.
+//!
+//! The daemon listens on a Unix socket, or on the socket passed by systemd when started through
+//! socket activation (which may also be a TCP socket). Every connection is one request: the
+//! client writes a CSV batch in the same format as the input file and shuts down its writing
+//! side, the daemon applies the batch to its engine and answers with the state of all accounts
+//! once every transaction of the batch was applied. A connection which doesn't send any rows is
+//! a plain query of the current state. If the batch can't be read, nothing of it is applied and
+//! the answer is a single `error: <message>` line instead.
+//!
+//! Connections are served by a fixed number of worker threads, `--workers`, and connections
+//! beyond those wait to be accepted. A batch larger than `--max-batch-bytes` is refused, as is a
+//! client which stops sending for longer than the read timeout. The transactions of all batches
+//! being processed are applied by a single engine thread in the order given by a
+//! [`FairScheduler`]: connections take turns one transaction at a time, while the transactions
+//! of every connection keep the order in which they were sent. This way a producer sending a
+//! huge burst, for however many clients, doesn't hold up everyone else's batches until its
+//! burst is through. At most `--max-queued` transactions wait to be applied, and at most
+//! `--max-queued-per-connection` of a single connection; a batch which doesn't fit is refused
+//! without applying any of it.
+//!
+//! The policies applied to batches, i.e. control totals, locked accounts, fees and rules, can be
+//! kept in a TOML file passed with `--config`, see [`crate::policy`]. The file is read again
+//! when the daemon receives SIGHUP or a `reload` request. The new control totals policy is used
+//! for the batches read from then on, the others are applied to the engine once no batch is
+//! partially applied, so a batch being processed is never affected. If the file can't be read
+//! or parsed, the previous policies stay in place.
+//!
+//! The state can be refreshed without downtime by sending `load <path>`, which builds a new engine
+//! from the given CSV input file on a background thread while the current engine keeps serving.
+//! Loading is only enabled with `--load-dir`, and only files within that directory can be loaded,
+//! as any client of the socket may send the request. Once the new engine is ready it is swapped in
+//! at a point where no batch is partially applied, and the engine it replaced is kept so that
+//! `rollback` can swap it back in.
+
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use std::{env, process};
+
+use clap::Parser;
+use signal_hook::consts::SIGHUP;
+use tracing::{error, info, warn};
+
+use crate::control_totals::ControlTotalsPolicy;
+use crate::policy::{Policies, ReloadablePolicies};
+use crate::rejects::{ProcessingPolicy, Rejections};
+use crate::scheduler::FairScheduler;
+use crate::transaction_engine::TransactionEngine;
+use crate::{parse_control_totals_policy, process_csv, read_csv, TransactionInput};
+
+/// The first file descriptor passed by systemd socket activation
+const SD_LISTEN_FDS_START: RawFd = 3;
+
+/// How often the idle engine thread checks whether a state loaded in the background is ready
+const LOAD_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a client may stay silent while sending its request
+const READ_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The number of connections served at once if not configured otherwise
+pub const DEFAULT_WORKERS: usize = 16;
+
+/// The largest batch accepted if not configured otherwise
+pub const DEFAULT_MAX_BATCH_BYTES: u64 = 64 * 1024 * 1024;
+
+/// The largest number of transactions waiting to be applied if not configured otherwise
+pub const DEFAULT_MAX_QUEUED: usize = 1_000_000;
+
+/// The largest number of transactions of a single connection waiting to be applied if not
+/// configured otherwise
+pub const DEFAULT_MAX_QUEUED_PER_CONNECTION: usize = 100_000;
+
+/// Keeps one engine in memory across the batches sent to a Unix socket.
+#[derive(Parser, Debug)]
+#[command(name = "daemon", bin_name = "toy-transaction-engine daemon")]
+pub struct DaemonConfig {
+    /// The Unix socket to listen on, unless systemd passes one
+    #[arg(long = "socket", value_name = "PATH")]
+    pub socket_path: Option<String>,
+
+    /// Read the reloadable policies from this TOML file
+    #[arg(long = "config", value_name = "PATH")]
+    pub config_path: Option<String>,
+
+    /// The directory `load` requests may read from, loading is refused without it
+    #[arg(long, value_name = "PATH")]
+    pub load_dir: Option<String>,
+
+    /// What to do when the control totals of a batch don't match: ignore, warn or fail
+    #[arg(long = "control-totals", default_value = "warn", value_parser = parse_control_totals_policy)]
+    pub control_totals_policy: ControlTotalsPolicy,
+
+    /// The number of connections served at once
+    #[arg(long, default_value_t = DEFAULT_WORKERS, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub workers: usize,
+
+    /// The largest batch accepted in bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_BATCH_BYTES)]
+    pub max_batch_bytes: u64,
+
+    /// The largest number of transactions waiting to be applied
+    #[arg(long, default_value_t = DEFAULT_MAX_QUEUED)]
+    pub max_queued: usize,
+
+    /// The largest number of transactions of a single connection waiting to be applied
+    #[arg(long, default_value_t = DEFAULT_MAX_QUEUED_PER_CONNECTION)]
+    pub max_queued_per_connection: usize,
+}
+
+impl DaemonConfig {
+    /// Parses the arguments starting with the `daemon` subcommand.
+    pub fn new(args: &[String]) -> Result<DaemonConfig, clap::Error> {
+        DaemonConfig::try_parse_from(args)
+    }
+}
+
+/// The engines of the daemon: the one serving requests, the one it replaced and the one being
+/// loaded in the background, with the policies applied to them and the reloaded ones waiting to
+/// be applied
+struct State {
+    active: TransactionEngine,
+    previous: Option<TransactionEngine>,
+    loading: Option<JoinHandle<Result<TransactionEngine, String>>>,
+    policies: Policies,
+    reloaded_policies: Option<Policies>,
+}
+
+impl State {
+    /// Starts building a new engine from the input file on a background thread.
+    fn start_loading(
+        &mut self,
+        path: PathBuf,
+        control_totals_policy: ControlTotalsPolicy,
+    ) -> Result<(), String> {
+        if self.loading.is_some() {
+            return Err("another state is already being loaded".to_string());
+        }
+        self.loading = Some(thread::spawn(move || {
+            let mut transaction_engine = TransactionEngine::new();
+            let file = fs::File::open(&path).map_err(|e| e.to_string())?;
+            process_csv(
+                &mut transaction_engine,
+                file,
+                control_totals_policy,
+                &mut Rejections::new(ProcessingPolicy::Skip, true),
+            )
+            .map_err(|e| e.to_string())?;
+            Ok(transaction_engine)
+        }));
+        Ok(())
+    }
+
+    /// Swaps in the engine loaded in the background once it is ready, keeping the replaced one
+    /// for rollback.
+    fn swap_in_loaded(&mut self) {
+        if !self.loading.as_ref().is_some_and(JoinHandle::is_finished) {
+            return;
+        }
+        let result = self.loading.take().map(|loading| {
+            loading
+                .join()
+                .unwrap_or_else(|_| Err("loading panicked".into()))
+        });
+        match result {
+            Some(Ok(mut transaction_engine)) => {
+                self.policies.apply_to(&mut transaction_engine, None);
+                self.previous = Some(std::mem::replace(&mut self.active, transaction_engine));
+                info!("The loaded state was swapped in.");
+            }
+            Some(Err(e)) => error!("The state couldn't be loaded. Error: {}", e),
+            None => (),
+        }
+    }
+
+    /// Applies the reloaded policies to the engines, if there are any.
+    fn apply_reloaded_policies(&mut self) {
+        let Some(policies) = self.reloaded_policies.take() else {
+            return;
+        };
+        for transaction_engine in [Some(&mut self.active), self.previous.as_mut()]
+            .into_iter()
+            .flatten()
+        {
+            policies.apply_to(transaction_engine, Some(&self.policies));
+        }
+        self.policies = policies;
+    }
+
+    /// Swaps the previous engine back in, keeping the current one so that the rollback itself
+    /// can be undone.
+    fn rollback(&mut self) -> Result<(), String> {
+        let previous = self
+            .previous
+            .as_mut()
+            .ok_or("there is no previous state to roll back to")?;
+        std::mem::swap(&mut self.active, previous);
+        Ok(())
+    }
+}
+
+/// Tracks how many transactions of a batch are still waiting to be applied
+struct Batch {
+    remaining: Mutex<usize>,
+    applied: Condvar,
+}
+
+impl Batch {
+    fn finish_one(&self) {
+        let mut remaining = lock(&self.remaining);
+        *remaining -= 1;
+        if *remaining == 0 {
+            self.applied.notify_all();
+        }
+    }
+
+    fn wait_until_applied(&self) {
+        let mut remaining = lock(&self.remaining);
+        while *remaining > 0 {
+            remaining = self
+                .applied
+                .wait(remaining)
+                .unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+/// Marks a transaction of the batch as done when dropped, so that the batch is finished even if
+/// applying the transaction panics
+struct FinishOnDrop(Arc<Batch>);
+
+impl Drop for FinishOnDrop {
+    fn drop(&mut self) {
+        self.0.finish_one();
+    }
+}
+
+/// Everything shared between the connection threads and the engine thread. Whenever both the
+/// queue and the state are locked, the queue is locked first.
+struct Shared {
+    config: DaemonConfig,
+    policies: Mutex<ReloadablePolicies>,
+    state: Mutex<State>,
+    /// The queued transactions, taking turns by the connection which sent them
+    queue: Mutex<FairScheduler<u64, (TransactionInput, Arc<Batch>)>>,
+    queued: Condvar,
+    next_connection: AtomicU64,
+}
+
+/// The socket the daemon accepts connections on
+enum Listener {
+    Unix(UnixListener),
+    Tcp(TcpListener),
+}
+
+/// A connection accepted on the listener
+enum Connection {
+    Unix(UnixStream),
+    Tcp(TcpStream),
+}
+
+/// Runs the daemon until the process is stopped.
+pub fn run(config: DaemonConfig) -> Result<(), Box<dyn Error>> {
+    let listener = match activated_listener() {
+        Some(listener) => listener,
+        None => {
+            let socket_path = config
+                .socket_path
+                .as_ref()
+                .ok_or("no socket was passed by systemd and --socket wasn't given")?;
+            remove_stale_socket(socket_path)?;
+            Listener::Unix(UnixListener::bind(socket_path)?)
+        }
+    };
+
+    let policies =
+        ReloadablePolicies::from_path(config.config_path.clone(), config.control_totals_policy)?;
+    let mut transaction_engine = TransactionEngine::new();
+    policies.current().apply_to(&mut transaction_engine, None);
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(SIGHUP, Arc::clone(&reload_requested))?;
+
+    let queue = FairScheduler::bounded(config.max_queued, config.max_queued_per_connection);
+    let shared = Arc::new(Shared {
+        state: Mutex::new(State {
+            active: transaction_engine,
+            previous: None,
+            loading: None,
+            policies: policies.current().clone(),
+            reloaded_policies: None,
+        }),
+        policies: Mutex::new(policies),
+        config,
+        queue: Mutex::new(queue),
+        queued: Condvar::new(),
+        next_connection: AtomicU64::new(0),
+    });
+    let engine_shared = Arc::clone(&shared);
+    thread::spawn(move || apply_queued_transactions(&engine_shared));
+
+    // accepting blocks while all workers are busy and one more connection is waiting
+    let (sender, receiver) = mpsc::sync_channel(1);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..shared.config.workers {
+        let shared = Arc::clone(&shared);
+        let receiver = Arc::clone(&receiver);
+        thread::spawn(move || serve_connections(&shared, &receiver));
+    }
+
+    loop {
+        let connection = match &listener {
+            Listener::Unix(listener) => listener.accept().map(|(s, _)| Connection::Unix(s)),
+            Listener::Tcp(listener) => listener.accept().map(|(s, _)| Connection::Tcp(s)),
+        };
+        // a SIGHUP received since the previous connection is applied before serving this one
+        if reload_requested.swap(false, Ordering::SeqCst) {
+            if let Err(e) = reload(&shared) {
+                error!(
+                    "The daemon configuration couldn't be reloaded and the previous one is kept. Error: {}",
+                    e
+                );
+            }
+        }
+        match connection {
+            Ok(connection) => sender.send(connection)?,
+            Err(e) => error!(
+                "An error occurred when accepting a connection. Error: {}",
+                e
+            ),
+        }
+    }
+}
+
+/// A worker thread, serving one connection after another.
+fn serve_connections(shared: &Shared, receiver: &Mutex<Receiver<Connection>>) {
+    loop {
+        // the lock is released before serving so that the other workers can take connections
+        let Ok(connection) = lock(receiver).recv() else {
+            return;
+        };
+        if let Err(e) = handle_connection(shared, connection) {
+            error!("An error occurred when serving a connection. Error: {}", e);
+        }
+    }
+}
+
+/// Locks the mutex, carrying on with the data of a thread which panicked while holding it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// The engine thread, applying the queued transactions in the order given by the scheduler.
+/// While it is idle no batch is partially applied, which is when reloaded policies are applied
+/// and a loaded state is swapped in.
+fn apply_queued_transactions(shared: &Shared) {
+    loop {
+        let mut queue = lock(&shared.queue);
+        let Some((_, (transaction, batch))) = queue.pop() else {
+            let mut state = lock(&shared.state);
+            state.apply_reloaded_policies();
+            state.swap_in_loaded();
+            drop(state);
+            let _ = shared.queued.wait_timeout(queue, LOAD_POLL_INTERVAL);
+            continue;
+        };
+        // the state is locked before the queue is released so that nobody can see an empty
+        // queue while the last transaction of a batch is still being applied
+        let _finished = FinishOnDrop(batch);
+        let mut state = lock(&shared.state);
+        drop(queue);
+        apply_transaction(&mut state.active, transaction);
+    }
+}
+
+/// Processes a single transaction, logging and skipping it if it can't be applied or applying
+/// it panics, so that the engine thread keeps serving the other batches.
+fn apply_transaction(transaction_engine: &mut TransactionEngine, transaction: TransactionInput) {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        transaction_engine.process_transaction(transaction)
+    }));
+    match result {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => warn!("An error occurred when processing a transaction and it was skipped. We'll continue with next transactions. Error: {}", e),
+        Err(_) => error!("Processing a transaction panicked and it was skipped. We'll continue with next transactions."),
+    }
+}
+
+/// Takes over the listening socket passed by systemd socket activation, if there is one.
+fn activated_listener() -> Option<Listener> {
+    let listen_pid: u32 = env::var("LISTEN_PID").ok()?.parse().ok()?;
+    let listen_fds: u32 = env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    if listen_pid != process::id() || listen_fds < 1 {
+        return None;
+    }
+
+    // the descriptor is owned by this process from here on and only taken over once
+    let listener = unsafe { UnixListener::from_raw_fd(SD_LISTEN_FDS_START) };
+    if listener.local_addr().is_ok() {
+        return Some(Listener::Unix(listener));
+    }
+    // not a Unix socket, so it must be a TCP one
+    let fd = std::os::fd::IntoRawFd::into_raw_fd(listener);
+    Some(Listener::Tcp(unsafe { TcpListener::from_raw_fd(fd) }))
+}
+
+/// Removes a socket left behind by a previous run, refusing to touch any other kind of file.
+fn remove_stale_socket(socket_path: &str) -> Result<(), Box<dyn Error>> {
+    match fs::symlink_metadata(socket_path) {
+        Ok(metadata) if metadata.file_type().is_socket() => Ok(fs::remove_file(socket_path)?),
+        Ok(_) => Err(format!("{} exists and is not a socket", socket_path).into()),
+        Err(_) => Ok(()),
+    }
+}
+
+/// Reloads the policies. The engine thread applies them to the engines once it is idle.
+fn reload(shared: &Shared) -> Result<(), Box<dyn Error>> {
+    let mut policies = lock(&shared.policies);
+    policies.reload()?;
+    lock(&shared.state).reloaded_policies = Some(policies.current().clone());
+    info!("The daemon configuration was reloaded.");
+    Ok(())
+}
+
+fn handle_connection(shared: &Shared, connection: Connection) -> Result<(), Box<dyn Error>> {
+    match connection {
+        Connection::Unix(mut stream) => {
+            stream.set_read_timeout(Some(READ_TIMEOUT))?;
+            handle_request(shared, &mut stream)?;
+            stream.shutdown(Shutdown::Write)?;
+        }
+        Connection::Tcp(mut stream) => {
+            stream.set_read_timeout(Some(READ_TIMEOUT))?;
+            handle_request(shared, &mut stream)?;
+            stream.shutdown(Shutdown::Write)?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies the batch sent on the connection and answers with the resulting account state, or
+/// handles one of the `reload`, `load <path>` and `rollback` admin requests.
+fn handle_request<S: Read + Write>(shared: &Shared, stream: &mut S) -> Result<(), Box<dyn Error>> {
+    let max_batch_bytes = shared.config.max_batch_bytes;
+    let mut batch = Vec::new();
+    // one byte more than allowed is read to tell a batch of exactly the limit from a larger one
+    Read::by_ref(stream)
+        .take(max_batch_bytes.saturating_add(1))
+        .read_to_end(&mut batch)?;
+    if batch.len() as u64 > max_batch_bytes {
+        writeln!(
+            stream,
+            "error: the batch is larger than {} bytes",
+            max_batch_bytes
+        )?;
+        return Ok(());
+    }
+    let request = String::from_utf8_lossy(batch.trim_ascii()).into_owned();
+    let admin_result = match request.split_once(' ') {
+        _ if request == "reload" => Some(reload(shared).map_err(|e| e.to_string())),
+        // holding the queue lock makes sure no batch is partially applied during the rollback
+        _ if request == "rollback" => {
+            let queue = lock(&shared.queue);
+            if queue.is_empty() {
+                Some(lock(&shared.state).rollback())
+            } else {
+                Some(Err("batches are still being applied, try again".to_string()))
+            }
+        }
+        Some(("load", path)) => Some(
+            resolve_load_path(shared.config.load_dir.as_deref(), path.trim()).and_then(|path| {
+                let control_totals_policy = lock(&shared.policies).current().control_totals_policy;
+                lock(&shared.state).start_loading(path, control_totals_policy)
+            }),
+        ),
+        _ => None,
+    };
+    if let Some(admin_result) = admin_result {
+        match admin_result {
+            Ok(()) => writeln!(stream, "ok")?,
+            Err(e) => writeln!(stream, "error: {}", e)?,
+        }
+        return Ok(());
+    }
+
+    if !batch.iter().all(u8::is_ascii_whitespace) {
+        // the whole batch is read before queueing so that a batch with an error in it or with
+        // wrong control totals isn't applied at all
+        let control_totals_policy = lock(&shared.policies).current().control_totals_policy;
+        let mut transactions = Vec::new();
+        let result = read_csv(batch.as_slice(), control_totals_policy, |row| {
+            transactions.push(row.transaction?);
+            Ok(())
+        });
+        if let Err(e) = result {
+            writeln!(stream, "error: {}", e)?;
+            return Ok(());
+        }
+        match schedule(shared, transactions) {
+            Some(batch) => batch.wait_until_applied(),
+            None => {
+                writeln!(
+                    stream,
+                    "error: the batch doesn't fit into the queue, try again later or send smaller batches"
+                )?;
+                return Ok(());
+            }
+        }
+    }
+
+    // rendered before writing so that a slow reader doesn't keep the state locked
+    let mut accounts_state = Vec::new();
+    lock(&shared.state)
+        .active
+        .write_accounts_state(&mut accounts_state)?;
+    stream.write_all(&accounts_state)?;
+    Ok(())
+}
+
+/// Resolves the path of a `load` request within the load directory, refusing paths which lead
+/// out of it, e.g. through `..` or a symbolic link.
+fn resolve_load_path(load_dir: Option<&str>, path: &str) -> Result<PathBuf, String> {
+    let load_dir = load_dir.ok_or("loading is disabled, start the daemon with --load-dir")?;
+    let load_dir = fs::canonicalize(load_dir).map_err(|e| e.to_string())?;
+    let resolved = fs::canonicalize(load_dir.join(Path::new(path)))
+        .map_err(|e| format!("{} can't be loaded: {}", path, e))?;
+    if !resolved.starts_with(&load_dir) {
+        return Err(format!("{} is outside of the load directory", path));
+    }
+    Ok(resolved)
+}
+
+/// Queues all transactions of a batch at once, returning the batch to wait on, or None if the
+/// batch doesn't fit into the queue and none of it was queued.
+fn schedule(shared: &Shared, transactions: Vec<TransactionInput>) -> Option<Arc<Batch>> {
+    let batch = Arc::new(Batch {
+        remaining: Mutex::new(transactions.len()),
+        applied: Condvar::new(),
+    });
+    if transactions.is_empty() {
+        return Some(batch);
+    }
+    let connection = shared.next_connection.fetch_add(1, Ordering::Relaxed);
+    let mut queue = lock(&shared.queue);
+    if !queue.has_room(connection, transactions.len()) {
+        return None;
+    }
+    for transaction in transactions {
+        // can't fail as there was room for the whole batch
+        let _ = queue.push(connection, (transaction, Arc::clone(&batch)));
+    }
+    shared.queued.notify_one();
+    Some(batch)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Condvar, Mutex};
+    use std::{env, fs, panic, process};
+
+    use super::{resolve_load_path, Batch, DaemonConfig, FinishOnDrop, DEFAULT_MAX_QUEUED};
+    use crate::control_totals::ControlTotalsPolicy;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn test_config_from_args() {
+        let config = DaemonConfig::new(&args(&[
+            "daemon",
+            "--socket",
+            "/run/tte.sock",
+            "--control-totals",
+            "fail",
+            "--workers",
+            "4",
+        ]))
+        .unwrap();
+        assert_eq!(config.socket_path.as_deref(), Some("/run/tte.sock"));
+        assert_eq!(config.control_totals_policy, ControlTotalsPolicy::Fail);
+        assert_eq!((config.workers, config.max_queued), (4, DEFAULT_MAX_QUEUED));
+        assert!(DaemonConfig::new(&args(&["daemon", "--workers", "0"])).is_err());
+        assert!(DaemonConfig::new(&args(&["daemon", "--unknown"])).is_err());
+    }
+
+    #[test]
+    fn test_batch_is_finished_when_applying_panics() {
+        let batch = Arc::new(Batch {
+            remaining: Mutex::new(1),
+            applied: Condvar::new(),
+        });
+        let finished = FinishOnDrop(Arc::clone(&batch));
+        let _ = panic::catch_unwind(move || {
+            let _finished = finished;
+            panic!("applying failed");
+        });
+        batch.wait_until_applied();
+    }
+
+    #[test]
+    fn test_only_files_in_the_load_dir_can_be_loaded() {
+        let load_dir = env::temp_dir().join(format!("tte-daemon-load-{}", process::id()));
+        fs::create_dir_all(&load_dir).unwrap();
+        fs::write(load_dir.join("state.csv"), "type,client,tx,amount\n").unwrap();
+        let load_dir_path = load_dir.to_str().unwrap();
+
+        assert!(resolve_load_path(None, "state.csv").is_err());
+        assert_eq!(
+            resolve_load_path(Some(load_dir_path), "state.csv").unwrap(),
+            fs::canonicalize(load_dir.join("state.csv")).unwrap()
+        );
+        assert!(resolve_load_path(Some(load_dir_path), "/etc/passwd").is_err());
+        assert!(resolve_load_path(Some(load_dir_path), "../../../../etc/passwd").is_err());
+        fs::remove_dir_all(load_dir).unwrap();
+    }
+}
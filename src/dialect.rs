@@ -0,0 +1,158 @@
+//! The dialect of CSV input: its delimiter and quote character, whether it starts with a header
+//! row, and what its columns are called.
+//!
+//! Columns named differently from the engine's are mapped with a TOML file naming the column of
+//! the input for every column of the engine, such as
+//!
+//! ```toml
+//! type = "txn_type"
+//! client = "acct"
+//! tx = "txn_id"
+//! amount = "amt"
+//! ```
+//!
+//! Input without a header row has its columns in the order `type`, `client`, `tx`, `amount` and
+//! `timestamp`. Lines starting with the comment character, `#` by default, are skipped anywhere
+//! in the input, and so are rows without any values.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::Read;
+
+use csv::StringRecord;
+
+use crate::PrecisionPolicy;
+
+/// The columns of the engine, in the order of input without a header row
+const COLUMNS: [&str; 5] = ["type", "client", "tx", "amount", "timestamp"];
+
+/// How CSV input is written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsvDialect {
+    pub delimiter: u8,
+    pub quote: u8,
+    pub has_headers: bool,
+    /// Lines starting with this character are skipped, if there is one
+    pub comment: Option<u8>,
+    /// What to do with amounts which have more than four decimal places
+    pub precision: PrecisionPolicy,
+    /// The column of the input for every column of the engine which is named differently
+    pub columns: HashMap<String, String>,
+}
+
+impl Default for CsvDialect {
+    fn default() -> Self {
+        CsvDialect {
+            delimiter: b',',
+            quote: b'"',
+            has_headers: true,
+            comment: Some(b'#'),
+            precision: PrecisionPolicy::Reject,
+            columns: HashMap::new(),
+        }
+    }
+}
+
+impl CsvDialect {
+    /// Reads the column mapping from a TOML file.
+    pub fn read_columns(path: &str) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        let columns: HashMap<String, String> = toml::from_str(&fs::read_to_string(path)?)?;
+        if let Some(column) = columns
+            .keys()
+            .find(|column| !COLUMNS.contains(&column.as_str()))
+        {
+            return Err(format!("{column} is not a column of the engine").into());
+        }
+        Ok(columns)
+    }
+
+    /// Creates a reader for CSV input in this dialect.
+    pub(crate) fn reader<R: Read>(&self, input: R) -> csv::Reader<R> {
+        csv::ReaderBuilder::new()
+            .trim(csv::Trim::All)
+            .flexible(true)
+            .delimiter(self.delimiter)
+            .quote(self.quote)
+            .has_headers(self.has_headers)
+            .comment(self.comment)
+            .from_reader(input)
+    }
+
+    /// The header row of the input with its columns named like the engine's.
+    pub(crate) fn headers<R: Read>(
+        &self,
+        reader: &mut csv::Reader<R>,
+    ) -> csv::Result<StringRecord> {
+        if !self.has_headers {
+            return Ok(StringRecord::from(COLUMNS.to_vec()));
+        }
+        let headers = reader.headers()?;
+        if self.columns.is_empty() {
+            return Ok(headers.clone());
+        }
+        Ok(headers
+            .iter()
+            .map(|header| {
+                self.columns
+                    .iter()
+                    .find(|(_, input_column)| input_column.as_str() == header)
+                    .map_or(header, |(column, _)| column.as_str())
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::CsvDialect;
+    use crate::control_totals::ControlTotalsPolicy;
+    use crate::{read_csv_with_dialect, TransactionInput, TransactionType};
+
+    fn read(input: &str, dialect: &CsvDialect) -> Vec<TransactionInput> {
+        let mut transactions = Vec::new();
+        read_csv_with_dialect(
+            input.as_bytes(),
+            dialect,
+            ControlTotalsPolicy::Ignore,
+            |row| {
+                transactions.push(row.transaction?);
+                Ok(())
+            },
+        )
+        .unwrap();
+        transactions
+    }
+
+    #[test]
+    fn test_partner_dialect_is_mapped() {
+        let dialect = CsvDialect {
+            delimiter: b';',
+            quote: b'\'',
+            columns: HashMap::from([
+                ("type".to_string(), "txn_type".to_string()),
+                ("client".to_string(), "acct".to_string()),
+                ("tx".to_string(), "txn_id".to_string()),
+                ("amount".to_string(), "amt".to_string()),
+            ]),
+            ..CsvDialect::default()
+        };
+        let transactions = read(
+            "amt; acct; txn_type; txn_id\n'2.5'; 1; deposit; 7\n",
+            &dialect,
+        );
+        assert_eq!(transactions[0].kind(), TransactionType::Deposit);
+        assert_eq!((transactions[0].client(), transactions[0].tx()), (1, 7));
+        assert_eq!(transactions[0].amount(), "2.5".parse().ok());
+
+        let dialect = CsvDialect {
+            has_headers: false,
+            ..CsvDialect::default()
+        };
+        let transactions = read("deposit, 2, 1, 3, 100\n", &dialect);
+        assert_eq!(transactions[0].client(), 2);
+        assert_eq!(transactions[0].timestamp(), Some(100));
+    }
+}
@@ -0,0 +1,177 @@
+//! The `diff` subcommand, showing what changed between two accounts CSVs written by the
+//! engine, e.g. by runs on consecutive days.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read};
+
+use clap::Parser;
+use serde::Serialize;
+
+use crate::money::format_minor_units;
+use crate::reconcile::{read_accounts, AccountRecord};
+use crate::{ClientId, MoneyOps};
+
+/// Compares two accounts CSVs, exiting with a nonzero code if they differ.
+#[derive(Parser, Debug)]
+#[command(name = "diff", bin_name = "toy-transaction-engine diff")]
+pub struct DiffConfig {
+    /// The earlier accounts CSV
+    pub old_path: String,
+
+    /// The later accounts CSV
+    pub new_path: String,
+}
+
+impl DiffConfig {
+    /// Parses the arguments starting with the `diff` subcommand.
+    pub fn new(args: &[String]) -> Result<DiffConfig, clap::Error> {
+        DiffConfig::try_parse_from(args)
+    }
+}
+
+/// How an account changed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+/// A change of an account between two accounts CSVs
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AccountChange {
+    pub client: ClientId,
+    pub change: ChangeKind,
+    /// The field which changed, empty for an account which was added or removed
+    pub field: &'static str,
+    pub old: String,
+    pub new: String,
+    /// How much an amount changed, empty for other fields
+    pub delta: String,
+}
+
+/// The changes between the accounts of two accounts CSVs, sorted by client.
+pub fn diff_accounts<R: Read, S: Read>(old: R, new: S) -> Result<Vec<AccountChange>, csv::Error> {
+    let old_accounts = read_accounts(old)?;
+    let new_accounts = read_accounts(new)?;
+    Ok(changes(&old_accounts, &new_accounts))
+}
+
+fn changes(
+    old_accounts: &BTreeMap<ClientId, AccountRecord>,
+    new_accounts: &BTreeMap<ClientId, AccountRecord>,
+) -> Vec<AccountChange> {
+    let mut changes = Vec::new();
+    for (client, old) in old_accounts {
+        let Some(new) = new_accounts.get(client) else {
+            changes.push(AccountChange {
+                client: *client,
+                change: ChangeKind::Removed,
+                field: "",
+                old: old.total.to_string(),
+                new: String::new(),
+                delta: (-old.total).to_string(),
+            });
+            continue;
+        };
+        let amounts = [
+            ("available", old.available, new.available),
+            ("held", old.held, new.held),
+            ("total", old.total, new.total),
+        ];
+        for (field, old_amount, new_amount) in amounts {
+            if old_amount != new_amount {
+                changes.push(AccountChange {
+                    client: *client,
+                    change: ChangeKind::Changed,
+                    field,
+                    old: old_amount.to_string(),
+                    new: new_amount.to_string(),
+                    delta: format_minor_units(
+                        new_amount.to_minor_units() - old_amount.to_minor_units(),
+                    ),
+                });
+            }
+        }
+        if old.locked != new.locked {
+            changes.push(AccountChange {
+                client: *client,
+                change: ChangeKind::Changed,
+                field: "locked",
+                old: old.locked.to_string(),
+                new: new.locked.to_string(),
+                delta: String::new(),
+            });
+        }
+    }
+    for (client, new) in new_accounts {
+        if !old_accounts.contains_key(client) {
+            changes.push(AccountChange {
+                client: *client,
+                change: ChangeKind::Added,
+                field: "",
+                old: String::new(),
+                new: new.total.to_string(),
+                delta: new.total.to_string(),
+            });
+        }
+    }
+    changes.sort_by_key(|change| change.client);
+    changes
+}
+
+/// Writes the changes as CSV.
+pub fn write_changes<W: io::Write>(writer: W, changes: &[AccountChange]) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    writer.write_record(["client", "change", "field", "old", "new", "delta"])?;
+    for change in changes {
+        writer.serialize(change)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Compares the files and writes the changes to stdout, returning how many there are.
+pub fn run(config: DiffConfig) -> Result<usize, Box<dyn Error>> {
+    let changes = diff_accounts(
+        BufReader::new(File::open(&config.old_path)?),
+        BufReader::new(File::open(&config.new_path)?),
+    )?;
+    write_changes(BufWriter::new(io::stdout().lock()), &changes)?;
+    Ok(changes.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_accounts, write_changes};
+
+    #[test]
+    fn test_changes_between_two_runs() {
+        let old = b"client,available,held,total,locked\n\
+                    1,10.0000,0.0000,10.0000,false\n\
+                    2,5.0000,0.0000,5.0000,false\n\
+                    3,1.0000,0.0000,1.0000,false\n";
+        let new = b"client,available,held,total,locked\n\
+                    1,10.0000,0.0000,10.0000,false\n\
+                    2,2.5000,0.0000,2.5000,true\n\
+                    4,7.0000,0.0000,7.0000,false\n";
+        let changes = diff_accounts(&old[..], &new[..]).unwrap();
+
+        let mut output = Vec::new();
+        write_changes(&mut output, &changes).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,change,field,old,new,delta\n\
+             2,changed,available,5.0000,2.5000,-2.5000\n\
+             2,changed,total,5.0000,2.5000,-2.5000\n\
+             2,changed,locked,false,true,\n\
+             3,removed,,1.0000,,-1.0000\n\
+             4,added,,,7.0000,7.0000\n"
+        );
+    }
+}
@@ -0,0 +1,361 @@
+//! Observing the engine while it applies transactions, e.g. to stream account changes to a
+//! monitoring system instead of only looking at the final state.
+
+use std::io::{self, Write};
+use std::sync::{Arc, Mutex};
+
+use serde::Serialize;
+use tracing::error;
+
+use crate::rejects::{Rejection, RejectionError};
+use crate::transaction_engine::TransactionProcessingError;
+use crate::{
+    AccountDetails, AccountStatus, Amount, ClientId, Timestamp, TransactionId, TransactionInput,
+    TransactionType,
+};
+
+/// Is told about every transaction an engine processes, at the steps of its lifecycle. Every
+/// hook does nothing unless it is implemented, so an observer only implements what it needs,
+/// e.g. `on_account_locked` to alert on lockouts.
+pub trait EngineObserver {
+    /// Called before a transaction is processed.
+    fn on_transaction_start(&mut self, _transaction: &TransactionInput) {}
+
+    /// Called after a transaction was applied, with the state of the account of its client.
+    fn on_applied(&mut self, _transaction: &TransactionInput, _account: &AccountDetails) {}
+
+    /// Called after a transaction was rejected.
+    fn on_rejected(
+        &mut self,
+        _transaction: &TransactionInput,
+        _error: &TransactionProcessingError,
+    ) {
+    }
+
+    /// Called after `on_applied` when the transaction locked the account of its client, e.g. a
+    /// chargeback.
+    fn on_account_locked(&mut self, _transaction: &TransactionInput, _account: &AccountDetails) {}
+
+    /// Writes out whatever was buffered.
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A line of the event stream
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    AccountUpdated {
+        tx: TransactionId,
+        #[serde(rename = "type")]
+        kind: TransactionType,
+        client: ClientId,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        timestamp: Option<Timestamp>,
+        available: Amount,
+        held: Amount,
+        total: Amount,
+        locked: bool,
+        status: AccountStatus,
+    },
+    TransactionRejected {
+        tx: TransactionId,
+        #[serde(rename = "type")]
+        kind: TransactionType,
+        client: ClientId,
+        error_kind: &'static str,
+        error: &'a str,
+    },
+}
+
+/// Writes every event as a JSON object on its own line. Wrap the writer in a `LineWriter` to
+/// have every event written out as soon as it happens. Once writing fails, the error is logged
+/// and no further events are written.
+pub struct NdjsonObserver<W: Write> {
+    writer: W,
+    failed: bool,
+}
+
+impl<W: Write> NdjsonObserver<W> {
+    pub fn new(writer: W) -> NdjsonObserver<W> {
+        NdjsonObserver {
+            writer,
+            failed: false,
+        }
+    }
+
+    fn write(&mut self, event: Event) {
+        if self.failed {
+            return;
+        }
+        let result = serde_json::to_writer(&mut self.writer, &event)
+            .map_err(io::Error::from)
+            .and_then(|()| self.writer.write_all(b"\n"));
+        if let Err(e) = result {
+            error!("The event stream couldn't be written and no further events will be written. Error: {}", e);
+            self.failed = true;
+        }
+    }
+}
+
+/// A row of the results file. The transaction of a row which couldn't be read isn't known.
+#[derive(Serialize)]
+struct ResultRow {
+    tx: Option<TransactionId>,
+    client: Option<ClientId>,
+    #[serde(rename = "type")]
+    kind: Option<TransactionType>,
+    status: &'static str,
+    error_kind: Option<&'static str>,
+}
+
+/// Writes the outcome of every transaction as CSV with a `tx,client,type,status,error_kind`
+/// header, where the status is `applied` or `rejected`. Interest payments have no `tx`, as
+/// their ids aren't the ones of the input. Clones write to the same file, so that the rows
+/// which never reached the engine can be added in input order.
+pub struct CsvResultsObserver<W: Write> {
+    writer: Arc<Mutex<ResultsWriter<W>>>,
+}
+
+struct ResultsWriter<W: Write> {
+    writer: csv::Writer<W>,
+    failed: bool,
+}
+
+impl<W: Write> Clone for CsvResultsObserver<W> {
+    fn clone(&self) -> CsvResultsObserver<W> {
+        CsvResultsObserver {
+            writer: Arc::clone(&self.writer),
+        }
+    }
+}
+
+impl<W: Write> CsvResultsObserver<W> {
+    pub fn new(writer: W) -> CsvResultsObserver<W> {
+        CsvResultsObserver {
+            writer: Arc::new(Mutex::new(ResultsWriter {
+                writer: csv::Writer::from_writer(writer),
+                failed: false,
+            })),
+        }
+    }
+
+    /// Writes a rejected row for a rejection which never reached the engine, i.e. a row which
+    /// couldn't be read or came out of order, with the transaction it was read as, if it could
+    /// be. Rows the engine rejected are already written through `on_rejected`.
+    pub(crate) fn on_unread(
+        &mut self,
+        rejection: &Rejection,
+        transaction: Option<&TransactionInput>,
+    ) {
+        if let RejectionError::Processing(_) = rejection.error {
+            return;
+        }
+        self.write(ResultRow {
+            tx: transaction.map(TransactionInput::tx),
+            client: transaction.map(TransactionInput::client),
+            kind: transaction.map(TransactionInput::kind),
+            status: "rejected",
+            error_kind: Some(rejection.error.kind()),
+        });
+    }
+
+    fn write(&mut self, row: ResultRow) {
+        let mut results = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        if results.failed {
+            return;
+        }
+        if let Err(e) = results.writer.serialize(row) {
+            error!("The results file couldn't be written and no further results will be written. Error: {}", e);
+            results.failed = true;
+        }
+    }
+}
+
+impl<W: Write> EngineObserver for CsvResultsObserver<W> {
+    fn on_applied(&mut self, transaction: &TransactionInput, _account: &AccountDetails) {
+        // interest is numbered in an id space of its own, which isn't the one of the input
+        let interest = transaction.kind() == TransactionType::Interest;
+        self.write(ResultRow {
+            tx: (!interest).then_some(transaction.tx()),
+            client: Some(transaction.client()),
+            kind: Some(transaction.kind()),
+            status: "applied",
+            error_kind: None,
+        });
+    }
+
+    fn on_rejected(&mut self, transaction: &TransactionInput, error: &TransactionProcessingError) {
+        self.write(ResultRow {
+            tx: Some(transaction.tx()),
+            client: Some(transaction.client()),
+            kind: Some(transaction.kind()),
+            status: "rejected",
+            error_kind: Some(error.kind()),
+        });
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .writer
+            .flush()
+    }
+}
+
+impl<W: Write> EngineObserver for NdjsonObserver<W> {
+    fn on_applied(&mut self, transaction: &TransactionInput, account: &AccountDetails) {
+        self.write(Event::AccountUpdated {
+            tx: transaction.tx(),
+            kind: transaction.kind(),
+            client: transaction.client(),
+            timestamp: transaction.timestamp(),
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.is_locked(),
+            status: account.status,
+        });
+    }
+
+    fn on_rejected(&mut self, transaction: &TransactionInput, error: &TransactionProcessingError) {
+        self.write(Event::TransactionRejected {
+            tx: transaction.tx(),
+            kind: transaction.kind(),
+            client: transaction.client(),
+            error_kind: error.kind(),
+            error: &error.to_string(),
+        });
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::sync::{Arc, Mutex};
+
+    use super::{CsvResultsObserver, EngineObserver, NdjsonObserver};
+    use crate::transaction_engine::TransactionProcessingError;
+    use crate::{AccountDetails, TransactionEngine, TransactionInput};
+
+    /// A writer whose output can still be read once the observer was handed to the engine
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Records which hooks were called for which transactions
+    #[derive(Clone, Default)]
+    struct HookLog(Arc<Mutex<Vec<String>>>);
+
+    impl EngineObserver for HookLog {
+        fn on_transaction_start(&mut self, transaction: &TransactionInput) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("start {}", transaction.tx()));
+        }
+
+        fn on_applied(&mut self, transaction: &TransactionInput, _account: &AccountDetails) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("applied {}", transaction.tx()));
+        }
+
+        fn on_rejected(
+            &mut self,
+            transaction: &TransactionInput,
+            error: &TransactionProcessingError,
+        ) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("rejected {} {}", transaction.tx(), error.kind()));
+        }
+
+        fn on_account_locked(&mut self, _transaction: &TransactionInput, account: &AccountDetails) {
+            self.0
+                .lock()
+                .unwrap()
+                .push(format!("locked {}", account.total));
+        }
+    }
+
+    #[test]
+    fn test_lifecycle_hooks_are_called_in_order() {
+        let hook_log = HookLog::default();
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.add_observer(hook_log.clone());
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "2".parse().unwrap()),
+            TransactionInput::dispute(1, 1),
+            TransactionInput::chargeback(1, 1),
+            TransactionInput::deposit(1, 2, "1".parse().unwrap()),
+        ]);
+        assert_eq!(
+            *hook_log.0.lock().unwrap(),
+            [
+                "start 1",
+                "applied 1",
+                "start 1",
+                "applied 1",
+                "start 1",
+                "applied 1",
+                "locked 0.0000",
+                "start 2",
+                "rejected 2 account_locked",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_events_are_written_as_ndjson() {
+        let buffer = SharedBuffer::default();
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.add_observer(NdjsonObserver::new(buffer.clone()));
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "2".parse().unwrap()),
+            TransactionInput::withdrawal(1, 2, "3".parse().unwrap()),
+            TransactionInput::dispute(1, 1),
+        ]);
+        assert_eq!(
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap(),
+            "{\"event\":\"account_updated\",\"tx\":1,\"type\":\"deposit\",\"client\":1,\"available\":\"2.0000\",\"held\":\"0.0000\",\"total\":\"2.0000\",\"locked\":false,\"status\":\"active\"}\n\
+             {\"event\":\"transaction_rejected\",\"tx\":2,\"type\":\"withdrawal\",\"client\":1,\"error_kind\":\"insufficient_funds\",\"error\":\"transaction cannot be completed due to insufficient funds\"}\n\
+             {\"event\":\"account_updated\",\"tx\":1,\"type\":\"dispute\",\"client\":1,\"available\":\"0.0000\",\"held\":\"2.0000\",\"total\":\"2.0000\",\"locked\":false,\"status\":\"active\"}\n"
+        );
+    }
+
+    #[test]
+    fn test_results_are_written_as_csv() {
+        let buffer = SharedBuffer::default();
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.add_observer(CsvResultsObserver::new(buffer.clone()));
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "2".parse().unwrap()),
+            TransactionInput::resolve(1, 1),
+        ]);
+        transaction_engine.flush_observers().unwrap();
+        assert_eq!(
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap(),
+            "tx,client,type,status,error_kind\n\
+             1,1,deposit,applied,\n\
+             1,1,resolve,rejected,transaction_not_disputed\n"
+        );
+    }
+}
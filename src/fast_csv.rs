@@ -0,0 +1,211 @@
+//! A fast path for reading CSV input in the engine's own columns.
+//!
+//! Deserializing every row with serde allocates a `StringRecord` per row. When the header row
+//! names only the engine's columns, in any order, rows are instead read into one reused
+//! `ByteRecord` and deposits, withdrawals, disputes, resolves and chargebacks are parsed by hand
+//! and handed straight to the engine. Any row the fast path can't parse, such as a control
+//! record, another kind of transaction or a malformed row, takes the serde path, so that what is
+//! accepted and the rejection messages are the same either way.
+
+use std::error::Error;
+use std::io::Read;
+use std::str;
+
+use csv::{ByteRecord, StringRecord};
+use tracing::debug_span;
+
+use crate::control_totals::ControlTotals;
+use crate::dialect::CsvDialect;
+use crate::rejects::{Rejection, RejectionError, Rejections};
+use crate::{
+    process_row, read_row, verify_control_totals, Amount, ClientId, ControlTotalsPolicy,
+    PrecisionPolicy, TransactionEngine, TransactionId, TransactionInput, TransactionType,
+};
+
+/// Where the engine's columns are in the input
+struct Columns {
+    kind: usize,
+    client: usize,
+    tx: usize,
+    amount: usize,
+    timestamp: Option<usize>,
+}
+
+impl Columns {
+    /// The positions of the columns, None if the header row names anything but the engine's
+    /// columns or names one twice
+    fn find(headers: &StringRecord) -> Option<Columns> {
+        let position = |names: &[&str]| {
+            let mut matching = headers
+                .iter()
+                .enumerate()
+                .filter(|(_, header)| names.contains(header));
+            let (index, _) = matching.next()?;
+            matching.next().is_none().then_some(index)
+        };
+        let columns = Columns {
+            kind: position(&["type"])?,
+            client: position(&["client"])?,
+            tx: position(&["tx"])?,
+            amount: position(&["amount"])?,
+            timestamp: position(&["timestamp", "ts"]),
+        };
+        let expected = if columns.timestamp.is_some() { 5 } else { 4 };
+        (headers.len() == expected).then_some(columns)
+    }
+
+    /// Parses a row, None if it has to take the serde path.
+    fn parse(
+        &self,
+        record: &ByteRecord,
+        headers: usize,
+        precision_policy: PrecisionPolicy,
+    ) -> Option<TransactionInput> {
+        if record.len() != headers {
+            return None;
+        }
+        let field = |index: usize| str::from_utf8(&record[index]).ok();
+        let kind = match &record[self.kind] {
+            b"deposit" => TransactionType::Deposit,
+            b"withdrawal" => TransactionType::Withdrawal,
+            b"dispute" => TransactionType::Dispute,
+            b"resolve" => TransactionType::Resolve,
+            b"chargeback" => TransactionType::Chargeback,
+            _ => return None,
+        };
+        let client = field(self.client)?.parse::<ClientId>().ok()?;
+        let tx = field(self.tx)?.parse::<TransactionId>().ok()?;
+        let amount = match field(self.amount)? {
+            "" => None,
+            amount => match amount.parse::<Amount>() {
+                Ok(amount) => Some(amount),
+                Err(_) if precision_policy != PrecisionPolicy::Reject => {
+                    Some(Amount::parse_with_precision(amount, precision_policy).ok()?)
+                }
+                Err(_) => return None,
+            },
+        };
+        let transaction = TransactionInput::new(kind, client, tx, amount);
+        match self.timestamp.map(field) {
+            None | Some(Some("")) => Some(transaction),
+            Some(timestamp) => Some(transaction.with_timestamp(timestamp?.parse().ok()?)),
+        }
+    }
+}
+
+/// Reads and processes CSV input in the dialect like `process_csv`, parsing the rows by hand
+/// where it can.
+pub(crate) fn process_csv_fast<R: Read>(
+    transaction_engine: &mut TransactionEngine,
+    input: R,
+    dialect: &CsvDialect,
+    control_totals_policy: ControlTotalsPolicy,
+    rejections: &mut Rejections,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = dialect.reader(input);
+    let headers = dialect.headers(&mut reader)?;
+    let columns = Columns::find(&headers);
+    let mut control_totals = ControlTotals::new();
+    let mut record = ByteRecord::new();
+    while reader.read_byte_record(&mut record)? {
+        let transaction = columns
+            .as_ref()
+            .and_then(|columns| columns.parse(&record, headers.len(), dialect.precision));
+        let Some(transaction) = transaction else {
+            let record = StringRecord::from_byte_record(record.clone())
+                .map_err(|e| e.utf8_error().to_string())?;
+            if let Some(row) = read_row(record, &headers, dialect.precision, &mut control_totals)? {
+                process_row(transaction_engine, row, rejections)?;
+            }
+            continue;
+        };
+        control_totals.record_transaction(&transaction);
+        let line = record.position().map_or(0, |position| position.line());
+        let _row = debug_span!("row", line).entered();
+        if let Err(e) = transaction_engine.process_transaction(transaction) {
+            let record = StringRecord::from_byte_record_lossy(record.clone());
+            rejections.reject(Rejection::from_record(
+                line,
+                &record,
+                RejectionError::from(e),
+            ))?;
+        }
+    }
+    verify_control_totals(&control_totals, control_totals_policy)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::process_csv_fast;
+    use crate::dialect::CsvDialect;
+    use crate::rejects::{ProcessingPolicy, Rejections};
+    use crate::TransactionEngine;
+    use crate::{process_row, read_csv_with_dialect, ControlTotalsPolicy, PrecisionPolicy};
+
+    #[test]
+    fn test_fast_path_matches_the_serde_path() {
+        let input = "\
+tx, client, type, amount, ts
+1, 1, deposit, 10.5, 3
+2, 1, withdrawal, 20, 4
+header, 5, 30
+3, 2, deposit, 2.123456, 5
+1, 1, dispute, ,
+4, 1, unlock, ,
+5, 1, deposit, abc, 6
+6, 1, deposit, 1
+1, 1, chargeback, , 9
+7, 1, deposit, 1, 10
+";
+        let dialect = CsvDialect {
+            precision: PrecisionPolicy::Round,
+            ..CsvDialect::default()
+        };
+        let process = |fast: bool| {
+            let mut transaction_engine = TransactionEngine::new();
+            let mut rejections = Rejections::new(ProcessingPolicy::Skip, false);
+            if fast {
+                process_csv_fast(
+                    &mut transaction_engine,
+                    input.as_bytes(),
+                    &dialect,
+                    ControlTotalsPolicy::Ignore,
+                    &mut rejections,
+                )
+                .unwrap();
+            } else {
+                read_csv_with_dialect(
+                    input.as_bytes(),
+                    &dialect,
+                    ControlTotalsPolicy::Ignore,
+                    |row| Ok(process_row(&mut transaction_engine, row, &mut rejections)?),
+                )
+                .unwrap();
+            }
+            let accounts: Vec<_> = transaction_engine
+                .sorted_accounts()
+                .into_iter()
+                .map(|(client, account)| (client, account.available, account.held, account.status))
+                .collect();
+            let rejections: Vec<_> = rejections
+                .into_vec()
+                .into_iter()
+                .map(|rejection| {
+                    (
+                        rejection.line,
+                        rejection.record,
+                        rejection.error.to_string(),
+                    )
+                })
+                .collect();
+            (accounts, rejections)
+        };
+
+        let (accounts, rejections) = process(true);
+        assert_eq!(accounts.len(), 2);
+        assert_eq!(accounts[1].1, "2.1235".parse().unwrap());
+        assert_eq!(rejections.len(), 4);
+        assert_eq!((accounts, rejections), process(false));
+    }
+}
@@ -0,0 +1,187 @@
+//! Fees charged on deposits and withdrawals.
+//!
+//! A [`FeeSchedule`] is read from a TOML file such as
+//!
+//! ```toml
+//! collection_account = 65535
+//!
+//! [deposit]
+//! bps = 25
+//!
+//! [withdrawal]
+//! flat = "0.5"
+//! ```
+//!
+//! Every fee is a flat amount plus a rate in basis points of the amount of the transaction,
+//! rounded to four decimal places. A deposit fee is taken from the deposited amount and a
+//! withdrawal fee is taken from the available funds on top of the withdrawn amount, in the same
+//! posting as the transaction, and credited to the collection account. Disputes of a deposit
+//! hold what was credited, without the fee.
+
+use std::error::Error;
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::{Amount, ClientId, MoneyOps, TransactionType};
+
+/// The largest rate, which takes the whole amount
+const MAX_BPS: u32 = 10_000;
+
+/// The fee charged on one type of transaction
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Fee {
+    /// Charged on every transaction
+    pub flat: Amount,
+    /// Charged in hundredths of a percent of the amount
+    pub bps: u32,
+}
+
+impl Fee {
+    /// The fee on a transaction of the amount
+    pub fn on(&self, amount: Amount) -> Amount {
+        let rate = Amount::from_minor_units(i64::from(self.bps.min(MAX_BPS)));
+        // the product can't overflow as the rate is at most one, the sum saturates so a fee too
+        // large to be kept is still refused as more than any amount
+        self.flat
+            .checked_add(amount.checked_mul(rate).unwrap_or(amount))
+            .unwrap_or(Amount::MAX)
+    }
+}
+
+/// The fees charged by an engine and the account they are credited to
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FeeSchedule {
+    /// The client whose account collects the fees
+    pub collection_account: ClientId,
+    #[serde(default)]
+    pub deposit: Fee,
+    #[serde(default)]
+    pub withdrawal: Fee,
+}
+
+impl FeeSchedule {
+    /// Reads the schedule from a TOML file.
+    pub fn from_path(path: &str) -> Result<FeeSchedule, Box<dyn Error>> {
+        let fee_schedule: FeeSchedule = toml::from_str(&fs::read_to_string(path)?)?;
+        for fee in [fee_schedule.deposit, fee_schedule.withdrawal] {
+            if fee.flat.is_negative() {
+                return Err("fees can't be negative".into());
+            }
+            if fee.bps > MAX_BPS {
+                return Err(format!("fee rates can't be above {} bps", MAX_BPS).into());
+            }
+        }
+        Ok(fee_schedule)
+    }
+
+    /// The fee on a transaction of the kind and amount, zero for kinds without fees
+    pub fn fee(&self, kind: TransactionType, amount: Amount) -> Amount {
+        match kind {
+            TransactionType::Deposit => self.deposit.on(amount),
+            TransactionType::Withdrawal => self.withdrawal.on(amount),
+            _ => Amount::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Fee, FeeSchedule};
+    use crate::transaction_engine::TransactionProcessingError;
+    use crate::{Amount, TransactionEngine, TransactionInput, TransactionType};
+
+    fn money(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_fees_are_collected_with_the_transaction() {
+        let fee_schedule = FeeSchedule {
+            collection_account: 9,
+            deposit: Fee {
+                flat: Amount::ZERO,
+                bps: 25,
+            },
+            withdrawal: Fee {
+                flat: money("0.5"),
+                bps: 0,
+            },
+        };
+        assert_eq!(
+            fee_schedule.fee(TransactionType::Deposit, money("0.0003")),
+            money("0")
+        );
+
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_fee_schedule(Some(fee_schedule));
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("100")),
+            TransactionInput::withdrawal(1, 2, money("10")),
+            TransactionInput::withdrawal(1, 3, money("89.5")),
+        ]);
+        assert!(results[0].is_ok() && results[1].is_ok() && results[2].is_err());
+        assert_eq!(
+            transaction_engine.get_account(1).unwrap().total,
+            money("89.25")
+        );
+        assert_eq!(
+            transaction_engine.get_account(9).unwrap().total,
+            money("0.75")
+        );
+        assert!(transaction_engine.check_ledger().is_ok());
+    }
+
+    #[test]
+    fn test_disputes_hold_the_credited_amount() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_fee_schedule(Some(FeeSchedule {
+            collection_account: 9,
+            deposit: Fee {
+                flat: money("1"),
+                bps: 0,
+            },
+            withdrawal: Fee::default(),
+        }));
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("10")),
+            TransactionInput::dispute(1, 1),
+        ]);
+        assert!(results.iter().all(Result::is_ok));
+        let account = transaction_engine.get_account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (Amount::ZERO, money("9"), money("9"))
+        );
+    }
+
+    #[test]
+    fn test_fees_are_not_collected_by_a_closed_account() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_allow_admin_ops(true);
+        transaction_engine.set_fee_schedule(Some(FeeSchedule {
+            collection_account: 9,
+            deposit: Fee {
+                flat: money("1"),
+                bps: 0,
+            },
+            withdrawal: Fee::default(),
+        }));
+        let results = transaction_engine.process_transactions([
+            TransactionInput::status_change(TransactionType::Open, 9, 1),
+            TransactionInput::status_change(TransactionType::Close, 9, 2),
+            TransactionInput::deposit(1, 3, money("10")),
+        ]);
+        assert!(matches!(
+            results[2],
+            Err(TransactionProcessingError::CollectionAccountUnavailable)
+        ));
+        assert!(transaction_engine.get_account(1).is_none());
+        assert_eq!(
+            transaction_engine.get_account(9).unwrap().total,
+            Amount::ZERO
+        );
+    }
+}
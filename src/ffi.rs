@@ -0,0 +1,301 @@
+//! A C interface to the engine, with the `ffi` feature, for embedding it in services written in
+//! other languages.
+//!
+//! The shared and static libraries are built with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib,staticlib`. The header
+//! `include/toy_transaction_engine.h` is generated from this module with cbindgen and checked
+//! in. A test fails when it is out of date, and
+//! `TTE_UPDATE_HEADER=1 cargo test --features ffi` writes it again. An engine is created with
+//! `tte_engine_new`, fed with `tte_submit`, queried with `tte_get_account` and freed with
+//! `tte_free`. Functions returning a status return `TTE_OK` on success, and the message of the
+//! latest failure can be read with `tte_last_error`. A panic never unwinds into the caller: the
+//! call returns `TTE_PANICKED`, or null for pointers. An engine must not be used from two
+//! threads at the same time.
+
+use std::ffi::{c_char, CStr, CString};
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+
+use crate::{
+    Amount, ClientId, MoneyOps, TransactionEngine, TransactionId, TransactionInput, TransactionType,
+};
+
+/// The call succeeded
+pub const TTE_OK: i32 = 0;
+/// The engine rejected the transaction, see `tte_last_error`
+pub const TTE_REJECTED: i32 = 1;
+/// An argument was null, not valid UTF-8 or out of range, see `tte_last_error`
+pub const TTE_INVALID_ARGUMENT: i32 = 2;
+/// The client has no account
+pub const TTE_NOT_FOUND: i32 = 3;
+/// The engine panicked, which is a bug, and shouldn't be used any more
+pub const TTE_PANICKED: i32 = 4;
+
+/// An engine with the message of its latest failure. Only ever handled through a pointer.
+pub struct TteEngine {
+    engine: TransactionEngine,
+    last_error: CString,
+}
+
+impl TteEngine {
+    fn fail(&mut self, status: i32, message: impl ToString) -> i32 {
+        // a message can't contain a nul byte, so it is cut at the first one
+        let mut message = message.to_string().into_bytes();
+        message.truncate(
+            message
+                .iter()
+                .position(|b| *b == 0)
+                .unwrap_or(message.len()),
+        );
+        self.last_error = CString::new(message).expect("the nul bytes were removed");
+        status
+    }
+}
+
+/// The state of an account. Amounts are whole numbers of ten-thousandths, e.g. 15000 for 1.5.
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TteAccount {
+    pub available: i64,
+    pub held: i64,
+    pub total: i64,
+    pub locked: bool,
+}
+
+/// Creates an engine with the default settings. Free it with `tte_free`.
+#[no_mangle]
+pub extern "C" fn tte_engine_new() -> *mut TteEngine {
+    panic::catch_unwind(|| {
+        Box::into_raw(Box::new(TteEngine {
+            engine: TransactionEngine::new(),
+            last_error: CString::default(),
+        }))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Frees an engine created with `tte_engine_new`. Null is ignored.
+///
+/// # Safety
+///
+/// `engine` must be null or a pointer returned by `tte_engine_new` which wasn't freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn tte_free(engine: *mut TteEngine) {
+    if !engine.is_null() {
+        // nothing can be reported from here, but the panic mustn't unwind into C
+        let _ = panic::catch_unwind(AssertUnwindSafe(|| drop(Box::from_raw(engine))));
+    }
+}
+
+/// Applies a transaction. `kind` is the type as in CSV input, e.g. `deposit` or `dispute`, and
+/// `amount` a decimal string like `1.5`, or null for transactions without one.
+///
+/// # Safety
+///
+/// `engine` must be a live engine, and `kind` and `amount` null or nul-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn tte_submit(
+    engine: *mut TteEngine,
+    kind: *const c_char,
+    client: u64,
+    tx: u64,
+    amount: *const c_char,
+) -> i32 {
+    guard(engine, || submit(engine, kind, client, tx, amount))
+}
+
+unsafe fn submit(
+    engine: *mut TteEngine,
+    kind: *const c_char,
+    client: u64,
+    tx: u64,
+    amount: *const c_char,
+) -> i32 {
+    let Some(engine) = engine.as_mut() else {
+        return TTE_INVALID_ARGUMENT;
+    };
+    let Some(kind) = string(kind).and_then(TransactionType::from_name) else {
+        return engine.fail(TTE_INVALID_ARGUMENT, "the type is missing or unknown");
+    };
+    // the conversions can't fail with 64-bit client and transaction ids
+    #[allow(irrefutable_let_patterns)]
+    let (Ok(client), Ok(tx)) = (ClientId::try_from(client), TransactionId::try_from(tx)) else {
+        return engine.fail(TTE_INVALID_ARGUMENT, "the client or tx is out of range");
+    };
+    let amount = match (amount.is_null(), string(amount).map(str::parse::<Amount>)) {
+        (true, _) => None,
+        (false, Some(Ok(amount))) => Some(amount),
+        (false, Some(Err(e))) => return engine.fail(TTE_INVALID_ARGUMENT, e),
+        (false, None) => return engine.fail(TTE_INVALID_ARGUMENT, "the amount isn't UTF-8"),
+    };
+    let transaction = TransactionInput::new(kind, client, tx, amount);
+    match engine.engine.process_transaction(transaction) {
+        Ok(()) => TTE_OK,
+        Err(e) => engine.fail(TTE_REJECTED, e),
+    }
+}
+
+/// Writes the state of the client's account to `account`.
+///
+/// # Safety
+///
+/// `engine` must be a live engine and `account` point to a `TteAccount`.
+#[no_mangle]
+pub unsafe extern "C" fn tte_get_account(
+    engine: *mut TteEngine,
+    client: u64,
+    account: *mut TteAccount,
+) -> i32 {
+    guard(engine, || get_account(engine, client, account))
+}
+
+unsafe fn get_account(engine: *mut TteEngine, client: u64, account: *mut TteAccount) -> i32 {
+    let Some(engine) = engine.as_mut() else {
+        return TTE_INVALID_ARGUMENT;
+    };
+    if account.is_null() {
+        return engine.fail(TTE_INVALID_ARGUMENT, "the account is null");
+    }
+    let Some(details) = ClientId::try_from(client)
+        .ok()
+        .and_then(|client| engine.engine.get_account(client))
+    else {
+        return engine.fail(TTE_NOT_FOUND, format!("client {client} has no account"));
+    };
+    let minor_units = |amount: Amount| i64::try_from(amount.to_minor_units()).ok();
+    let (Some(available), Some(held), Some(total)) = (
+        minor_units(details.available),
+        minor_units(details.held),
+        minor_units(details.total),
+    ) else {
+        return engine.fail(
+            TTE_INVALID_ARGUMENT,
+            "the balance doesn't fit in an int64_t",
+        );
+    };
+    *account = TteAccount {
+        available,
+        held,
+        total,
+        locked: details.is_locked(),
+    };
+    TTE_OK
+}
+
+/// The message of the latest failure of the engine, empty if there was none. The string is
+/// owned by the engine and valid until its next call.
+///
+/// # Safety
+///
+/// `engine` must be a live engine.
+#[no_mangle]
+pub unsafe extern "C" fn tte_last_error(engine: *const TteEngine) -> *const c_char {
+    panic::catch_unwind(AssertUnwindSafe(|| match engine.as_ref() {
+        Some(engine) => engine.last_error.as_ptr(),
+        None => ptr::null(),
+    }))
+    .unwrap_or(ptr::null())
+}
+
+/// Runs the body of a call, returning `TTE_PANICKED` instead of unwinding into C if it panics.
+unsafe fn guard(engine: *mut TteEngine, body: impl FnOnce() -> i32) -> i32 {
+    panic::catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|_| match engine.as_mut() {
+        Some(engine) => engine.fail(TTE_PANICKED, "the engine panicked"),
+        None => TTE_PANICKED,
+    })
+}
+
+/// The string at the pointer, None if it is null or not UTF-8
+unsafe fn string<'a>(value: *const c_char) -> Option<&'a str> {
+    if value.is_null() {
+        return None;
+    }
+    CStr::from_ptr(value).to_str().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+    use std::{env, fs, ptr};
+
+    use super::{
+        guard, tte_engine_new, tte_free, tte_get_account, tte_last_error, tte_submit, TteAccount,
+        TTE_INVALID_ARGUMENT, TTE_NOT_FOUND, TTE_OK, TTE_PANICKED, TTE_REJECTED,
+    };
+
+    #[test]
+    fn test_engine_is_used_through_the_c_interface() {
+        unsafe {
+            let engine = tte_engine_new();
+            assert_eq!(
+                tte_submit(engine, c"deposit".as_ptr(), 1, 1, c"2.5".as_ptr()),
+                TTE_OK
+            );
+            assert_eq!(
+                tte_submit(engine, c"withdrawal".as_ptr(), 1, 2, c"3".as_ptr()),
+                TTE_REJECTED
+            );
+            let error = CStr::from_ptr(tte_last_error(engine)).to_str().unwrap();
+            assert!(error.contains("insufficient funds"), "{error}");
+            assert_eq!(
+                tte_submit(engine, c"dispute".as_ptr(), 1, 1, ptr::null()),
+                TTE_OK
+            );
+            assert_eq!(
+                tte_submit(engine, c"deposit".as_ptr(), 1, 3, c"abc".as_ptr()),
+                TTE_INVALID_ARGUMENT
+            );
+            assert_eq!(
+                tte_submit(engine, c"bogus".as_ptr(), 1, 4, ptr::null()),
+                TTE_INVALID_ARGUMENT
+            );
+
+            let mut account = TteAccount::default();
+            assert_eq!(tte_get_account(engine, 1, &mut account), TTE_OK);
+            assert_eq!(
+                account,
+                TteAccount {
+                    available: 0,
+                    held: 25_000,
+                    total: 25_000,
+                    locked: false,
+                }
+            );
+            assert_eq!(tte_get_account(engine, 2, &mut account), TTE_NOT_FOUND);
+
+            assert_eq!(guard(engine, || panic!("a bug")), TTE_PANICKED);
+            let error = CStr::from_ptr(tte_last_error(engine)).to_str().unwrap();
+            assert_eq!(error, "the engine panicked");
+            tte_free(engine);
+        }
+    }
+
+    #[test]
+    fn test_header_is_up_to_date() {
+        let mut header = Vec::new();
+        cbindgen::Builder::new()
+            .with_src(concat!(env!("CARGO_MANIFEST_DIR"), "/src/ffi.rs"))
+            .with_language(cbindgen::Language::C)
+            .with_include_guard("TOY_TRANSACTION_ENGINE_H")
+            .with_sys_include("stdbool.h")
+            .with_sys_include("stdint.h")
+            .with_no_includes()
+            .with_documentation(true)
+            .generate()
+            .unwrap()
+            .write(&mut header);
+        let header = String::from_utf8(header).unwrap();
+        let path = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/include/toy_transaction_engine.h"
+        );
+        if env::var_os("TTE_UPDATE_HEADER").is_some() {
+            fs::write(path, &header).unwrap();
+        }
+        assert!(
+            fs::read_to_string(path).unwrap() == header,
+            "{} is out of date, write it again with TTE_UPDATE_HEADER=1 cargo test --features ffi",
+            path
+        );
+    }
+}
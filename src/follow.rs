@@ -0,0 +1,150 @@
+//! Follows a CSV input file which keeps growing, like `tail -f`, applying new rows as they are
+//! appended and writing the state of all accounts every now and then.
+//!
+//! Following ends on SIGINT or SIGTERM, after which the final state is written as usual.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tracing::error;
+
+use crate::control_totals::ControlTotalsPolicy;
+use crate::dialect::CsvDialect;
+use crate::rejects::Rejections;
+use crate::{process_row, read_csv_with_dialect, TransactionEngine};
+
+/// How often the file is checked for new rows once everything was read
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// The default number of seconds between two writes of the state
+pub const DEFAULT_EMIT_EVERY: u64 = 10;
+
+/// Reads a file, waiting for more to be appended at its end instead of ending there. Only
+/// ends once `stop` is set.
+pub struct FollowReader {
+    file: File,
+    position: u64,
+    stop: Arc<AtomicBool>,
+}
+
+impl FollowReader {
+    pub fn new(file: File, stop: Arc<AtomicBool>) -> FollowReader {
+        FollowReader {
+            file,
+            position: 0,
+            stop,
+        }
+    }
+}
+
+impl Read for FollowReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.file.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                self.position += read as u64;
+                return Ok(read);
+            }
+            if self.stop.load(Ordering::SeqCst) {
+                return Ok(0);
+            }
+            if self.file.metadata()?.len() < self.position {
+                return Err(io::Error::other("the input file was truncated"));
+            }
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+/// Processes a CSV input file until the process is stopped, calling `emit` with the state every
+/// `emit_every` if anything changed since the last call.
+pub(crate) fn process_csv_following(
+    transaction_engine: &mut TransactionEngine,
+    input_path: &str,
+    dialect: &CsvDialect,
+    control_totals_policy: ControlTotalsPolicy,
+    rejections: &mut Rejections,
+    emit_every: Duration,
+    emit: impl Fn(&TransactionEngine) -> Result<(), Box<dyn Error>> + Sync,
+) -> Result<(), Box<dyn Error>> {
+    let stop = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&stop))?;
+    }
+    let reader = FollowReader::new(File::open(input_path)?, Arc::clone(&stop));
+
+    let transaction_engine = Mutex::new(transaction_engine);
+    let changed = AtomicBool::new(false);
+    let finished = AtomicBool::new(false);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let mut last_emit = Instant::now();
+            while !finished.load(Ordering::SeqCst) {
+                thread::sleep(POLL_INTERVAL);
+                if last_emit.elapsed() < emit_every || !changed.swap(false, Ordering::SeqCst) {
+                    continue;
+                }
+                last_emit = Instant::now();
+                if let Err(e) = emit(&lock(&transaction_engine)) {
+                    error!("The state couldn't be written. Error: {}", e);
+                }
+            }
+        });
+        let result = read_csv_with_dialect(reader, dialect, control_totals_policy, |row| {
+            process_row(&mut lock(&transaction_engine), row, rejections)?;
+            changed.store(true, Ordering::SeqCst);
+            Ok(())
+        });
+        finished.store(true, Ordering::SeqCst);
+        result
+    })
+}
+
+fn lock<'a, 'b>(
+    transaction_engine: &'a Mutex<&'b mut TransactionEngine>,
+) -> MutexGuard<'a, &'b mut TransactionEngine> {
+    transaction_engine.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Read, Write};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::FollowReader;
+
+    #[test]
+    fn test_follow_reader_waits_for_appended_rows() {
+        let path = env::temp_dir().join(format!("tte-follow-{}.csv", std::process::id()));
+        fs::write(&path, "type,client,tx,amount\n").unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut reader = FollowReader::new(File::open(&path).unwrap(), Arc::clone(&stop));
+
+        let writer = {
+            let path = path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(300));
+                let mut file = OpenOptions::new().append(true).open(path).unwrap();
+                file.write_all(b"deposit,1,1,2\n").unwrap();
+                thread::sleep(Duration::from_millis(300));
+                stop.store(true, Ordering::SeqCst);
+            })
+        };
+        let mut content = String::new();
+        reader.read_to_string(&mut content).unwrap();
+        writer.join().unwrap();
+        assert_eq!(content, "type,client,tx,amount\ndeposit,1,1,2\n");
+        fs::remove_file(path).unwrap();
+    }
+}
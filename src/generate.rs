@@ -0,0 +1,207 @@
+//! Synthetic CSV input for measuring throughput.
+//!
+//! The `generate` subcommand writes a file of deposits, withdrawals and disputes over a given
+//! number of clients. Transaction ids are sequential and amounts are random with four decimal
+//! places. Of the rows given to disputes, about half open a dispute of an earlier deposit and
+//! the others settle an open dispute, mostly with a resolve and sometimes with a chargeback.
+//! The same seed always gives the same file.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+
+use clap::Parser;
+
+use crate::ClientId;
+
+/// The share of settled disputes which are charged back
+const CHARGEBACK_RATIO: f64 = 0.1;
+
+/// Writes a synthetic CSV file of transactions, e.g. to benchmark the engine.
+#[derive(Parser, Debug)]
+#[command(name = "generate", bin_name = "toy-transaction-engine generate")]
+pub struct GenerateConfig {
+    /// The number of rows to write
+    #[arg(long, default_value_t = 1_000_000)]
+    pub rows: u64,
+
+    /// The number of clients the rows are spread over
+    #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+    pub clients: u64,
+
+    /// The share of rows which are withdrawals
+    #[arg(long, default_value_t = 0.2)]
+    pub withdrawal_ratio: f64,
+
+    /// The share of rows which are disputes, resolves or chargebacks
+    #[arg(long, default_value_t = 0.05)]
+    pub dispute_ratio: f64,
+
+    /// The seed of the random numbers
+    #[arg(long, default_value_t = 1)]
+    pub seed: u64,
+
+    /// Where the rows are written instead of stdout
+    #[arg(long)]
+    pub output: Option<String>,
+}
+
+impl GenerateConfig {
+    /// Parses the arguments starting with the `generate` subcommand.
+    pub fn new(args: &[String]) -> Result<GenerateConfig, clap::Error> {
+        GenerateConfig::try_parse_from(args)
+    }
+}
+
+/// What to generate
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Workload {
+    pub rows: u64,
+    pub clients: u64,
+    pub withdrawal_ratio: f64,
+    pub dispute_ratio: f64,
+    pub seed: u64,
+}
+
+impl Workload {
+    /// Mostly deposits with a few withdrawals and no disputes
+    pub fn deposit_heavy(rows: u64) -> Workload {
+        Workload {
+            rows,
+            clients: 1000,
+            withdrawal_ratio: 0.1,
+            dispute_ratio: 0.0,
+            seed: 1,
+        }
+    }
+
+    /// A third of the rows are disputes, resolves and chargebacks
+    pub fn dispute_heavy(rows: u64) -> Workload {
+        Workload {
+            dispute_ratio: 0.3,
+            ..Workload::deposit_heavy(rows)
+        }
+    }
+
+    /// Deposits, withdrawals and some disputes, like the defaults of the subcommand
+    pub fn mixed(rows: u64) -> Workload {
+        Workload {
+            withdrawal_ratio: 0.3,
+            dispute_ratio: 0.05,
+            ..Workload::deposit_heavy(rows)
+        }
+    }
+}
+
+/// A small, fast generator of pseudo-random numbers (SplitMix64)
+struct Random(u64);
+
+impl Random {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A number in 0..bound
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next_u64() % bound
+    }
+
+    /// A number in [0, 1)
+    fn fraction(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Writes the rows of the workload as CSV with a header row.
+pub fn generate<W: Write>(writer: W, workload: &Workload) -> io::Result<()> {
+    let mut writer = BufWriter::new(writer);
+    let mut random = Random(workload.seed);
+    // the deposits which can still be disputed and the open disputes, by client and tx
+    let mut deposits: Vec<(u64, u64)> = Vec::new();
+    let mut disputes: Vec<(u64, u64)> = Vec::new();
+    writeln!(writer, "type,client,tx,amount")?;
+    let mut tx = 0;
+    for _ in 0..workload.rows {
+        let choice = random.fraction();
+        if choice < workload.dispute_ratio && !(deposits.is_empty() && disputes.is_empty()) {
+            if disputes.is_empty() || (!deposits.is_empty() && random.below(2) == 0) {
+                let (client, tx) =
+                    deposits.swap_remove(random.below(deposits.len() as u64) as usize);
+                writeln!(writer, "dispute,{client},{tx},")?;
+                disputes.push((client, tx));
+            } else {
+                let (client, tx) =
+                    disputes.swap_remove(random.below(disputes.len() as u64) as usize);
+                let kind = if random.fraction() < CHARGEBACK_RATIO {
+                    "chargeback"
+                } else {
+                    "resolve"
+                };
+                writeln!(writer, "{kind},{client},{tx},")?;
+            }
+            continue;
+        }
+        tx += 1;
+        let client = random.below(workload.clients) + 1;
+        if choice < workload.dispute_ratio + workload.withdrawal_ratio {
+            let amount = random.below(100_000);
+            writeln!(writer, "withdrawal,{client},{tx},{}", format_amount(amount))?;
+        } else {
+            let amount = random.below(10_000_000) + 1;
+            writeln!(writer, "deposit,{client},{tx},{}", format_amount(amount))?;
+            deposits.push((client, tx));
+        }
+    }
+    writer.flush()
+}
+
+/// Formats ten-thousandths as an amount with four decimal places.
+fn format_amount(minor_units: u64) -> String {
+    format!("{}.{:04}", minor_units / 10_000, minor_units % 10_000)
+}
+
+/// Writes the file the config asks for.
+pub fn run(config: GenerateConfig) -> Result<(), Box<dyn Error>> {
+    if ClientId::try_from(config.clients).is_err() {
+        return Err(format!("there can be at most {} clients", ClientId::MAX).into());
+    }
+    let workload = Workload {
+        rows: config.rows,
+        clients: config.clients,
+        withdrawal_ratio: config.withdrawal_ratio,
+        dispute_ratio: config.dispute_ratio,
+        seed: config.seed,
+    };
+    match &config.output {
+        Some(output_path) => generate(File::create(output_path)?, &workload)?,
+        None => generate(io::stdout().lock(), &workload)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, Workload};
+    use crate::process_reader;
+
+    #[test]
+    fn test_generated_rows_can_be_processed() {
+        let workload = Workload::dispute_heavy(10_000);
+        let mut first = Vec::new();
+        generate(&mut first, &workload).unwrap();
+        let mut second = Vec::new();
+        generate(&mut second, &workload).unwrap();
+        assert_eq!(first, second);
+
+        let text = String::from_utf8(first).unwrap();
+        assert_eq!(text.lines().count(), 10_001);
+        assert!(text.lines().any(|line| line.starts_with("dispute,")));
+        assert!(text.lines().any(|line| line.starts_with("resolve,")));
+        let transaction_engine = process_reader(text.as_bytes()).unwrap();
+        assert!(transaction_engine.accounts().count() <= 1000);
+    }
+}
@@ -0,0 +1,360 @@
+//! A gRPC service over the engine, as defined in `proto/transaction_engine.proto`, and a thin
+//! client for it.
+//!
+//! Transactions which can't be applied are answered with the canonical status code closest to
+//! the reason, see [`status_for`]. Amounts travel as decimal strings so that no precision is
+//! lost.
+
+// the helpers return tonic's `Status` like the service methods they are used in
+#![allow(clippy::result_large_err)]
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::{Channel, Server};
+use tonic::{Code, Request, Response, Status};
+
+use crate::{
+    AccountDetails, AccountStatus, Amount, ClientId, TransactionEngine, TransactionId,
+    TransactionInput, TransactionProcessingError, TransactionType,
+};
+
+/// The code generated from the proto definition
+pub mod proto {
+    tonic::include_proto!("transaction_engine.v1");
+}
+
+use proto::transaction_engine_client::TransactionEngineClient;
+use proto::transaction_engine_server::{self, TransactionEngineServer};
+
+/// The service applying the submitted transactions to a shared engine
+pub struct GrpcService {
+    transaction_engine: Arc<Mutex<TransactionEngine>>,
+}
+
+impl GrpcService {
+    /// Creates a service over the engine. The engine stays accessible through the other
+    /// clones of the `Arc`, e.g. to save a snapshot once the server is shut down.
+    pub fn new(transaction_engine: Arc<Mutex<TransactionEngine>>) -> GrpcService {
+        GrpcService { transaction_engine }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TransactionEngine> {
+        self.transaction_engine
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// The status code a transaction which can't be applied is answered with
+pub fn status_for(error: &TransactionProcessingError) -> Status {
+    let code = match error {
+        TransactionProcessingError::AccountNotFound
+        | TransactionProcessingError::TransactionNotFound => Code::NotFound,
+        TransactionProcessingError::AmountValueNotFound
+        | TransactionProcessingError::InvalidAmount(_)
+        | TransactionProcessingError::FeeExceedsAmount(_)
+        | TransactionProcessingError::InterestNotAllowed
+        | TransactionProcessingError::TimestampTooFarAhead(_) => Code::InvalidArgument,
+        TransactionProcessingError::DuplicateTransactionId
+        | TransactionProcessingError::AlreadyProcessed => Code::AlreadyExists,
+        TransactionProcessingError::ClientMismatch
+        | TransactionProcessingError::AdminOperationsNotAllowed
+        | TransactionProcessingError::RiskDenied(_) => Code::PermissionDenied,
+        TransactionProcessingError::AccountLocked
+        | TransactionProcessingError::InsufficientFunds
+        | TransactionProcessingError::AmountNotFoundOnTransactionToDispute
+        | TransactionProcessingError::CannotResolveNonDisputedTransaction
+        | TransactionProcessingError::CannotDisputeAnAlreadyDisputedTransaction
+        | TransactionProcessingError::CannotDisputeAChargedBackTransaction
+        | TransactionProcessingError::AccountNotLocked
+        | TransactionProcessingError::AccountFrozen
+        | TransactionProcessingError::AccountNotFrozen
+        | TransactionProcessingError::AccountClosed
+        | TransactionProcessingError::AccountAlreadyOpen
+        | TransactionProcessingError::AccountNotEmpty
+        | TransactionProcessingError::CollectionAccountUnavailable
+        | TransactionProcessingError::DisputeWindowExpired
+        | TransactionProcessingError::DisputeAmountTooLarge(_)
+        | TransactionProcessingError::RuleViolated(_)
+        | TransactionProcessingError::Quarantined(_) => Code::FailedPrecondition,
+        TransactionProcessingError::BalanceOverflow => Code::OutOfRange,
+        // the transaction was rolled back, so it can be retried
+        TransactionProcessingError::WalWriteFailed(_) => Code::Unavailable,
+        TransactionProcessingError::StorageFailed(_)
+        | TransactionProcessingError::InvariantViolated { .. } => Code::Internal,
+    };
+    Status::new(code, error.to_string())
+}
+
+#[tonic::async_trait]
+impl transaction_engine_server::TransactionEngine for GrpcService {
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::SubmitTransactionRequest>,
+    ) -> Result<Response<proto::SubmitTransactionResponse>, Status> {
+        let transaction = transaction_from_request(request.into_inner())?;
+        self.lock()
+            .process_transaction(transaction)
+            .map_err(|e| status_for(&e))?;
+        Ok(Response::new(proto::SubmitTransactionResponse {}))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::GetAccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let client = client_id(request.into_inner().client)?;
+        match self.lock().get_account(client) {
+            Some(account) => Ok(Response::new(to_proto_account(client, account))),
+            None => Err(Status::not_found(format!(
+                "client {} has no account",
+                client
+            ))),
+        }
+    }
+
+    type StreamAccountsStream = Pin<Box<dyn Stream<Item = Result<proto::Account, Status>> + Send>>;
+
+    async fn stream_accounts(
+        &self,
+        _request: Request<proto::StreamAccountsRequest>,
+    ) -> Result<Response<Self::StreamAccountsStream>, Status> {
+        // copied so that the engine isn't locked while the client reads the stream
+        let accounts: Vec<Result<proto::Account, Status>> = self
+            .lock()
+            .sorted_accounts()
+            .into_iter()
+            .map(|(client, account)| Ok(to_proto_account(client, account)))
+            .collect();
+        Ok(Response::new(Box::pin(tokio_stream::iter(accounts))))
+    }
+}
+
+/// Serves the engine on the listener until `shutdown` completes.
+pub async fn serve(
+    listener: TcpListener,
+    transaction_engine: Arc<Mutex<TransactionEngine>>,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), tonic::transport::Error> {
+    Server::builder()
+        .add_service(TransactionEngineServer::new(GrpcService::new(
+            transaction_engine,
+        )))
+        .serve_with_incoming_shutdown(TcpListenerStream::new(listener), shutdown)
+        .await
+}
+
+/// A thin client working with the types of this crate
+pub struct GrpcClient {
+    inner: TransactionEngineClient<Channel>,
+}
+
+impl GrpcClient {
+    /// Connects to a server, e.g. `http://127.0.0.1:50051`.
+    pub async fn connect(address: String) -> Result<GrpcClient, tonic::transport::Error> {
+        Ok(GrpcClient {
+            inner: TransactionEngineClient::connect(address).await?,
+        })
+    }
+
+    /// Applies a transaction.
+    pub async fn submit(&mut self, transaction: TransactionInput) -> Result<(), Status> {
+        let kind = match transaction.kind() {
+            TransactionType::Deposit => proto::TransactionType::Deposit,
+            TransactionType::Withdrawal => proto::TransactionType::Withdrawal,
+            TransactionType::Dispute => proto::TransactionType::Dispute,
+            TransactionType::Resolve => proto::TransactionType::Resolve,
+            TransactionType::Chargeback => proto::TransactionType::Chargeback,
+            TransactionType::Unlock => proto::TransactionType::Unlock,
+            TransactionType::Open => proto::TransactionType::Open,
+            TransactionType::Close => proto::TransactionType::Close,
+            TransactionType::Freeze => proto::TransactionType::Freeze,
+            TransactionType::Unfreeze => proto::TransactionType::Unfreeze,
+            TransactionType::Interest => proto::TransactionType::Interest,
+        };
+        self.inner
+            .submit_transaction(proto::SubmitTransactionRequest {
+                r#type: kind.into(),
+                client: proto_id(transaction.client()),
+                tx: proto_id(transaction.tx()),
+                amount: transaction.amount().map(|amount| amount.to_string()),
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// The state of the client's account
+    pub async fn get_account(&mut self, client: ClientId) -> Result<AccountDetails, Status> {
+        let account = self
+            .inner
+            .get_account(proto::GetAccountRequest {
+                client: proto_id(client),
+            })
+            .await?
+            .into_inner();
+        Ok(from_proto_account(&account)?.1)
+    }
+
+    /// The state of all accounts, sorted by client id
+    pub async fn accounts(&mut self) -> Result<Vec<(ClientId, AccountDetails)>, Status> {
+        let mut stream = self
+            .inner
+            .stream_accounts(proto::StreamAccountsRequest {})
+            .await?
+            .into_inner();
+        let mut accounts = Vec::new();
+        while let Some(account) = stream.next().await {
+            accounts.push(from_proto_account(&account?)?);
+        }
+        Ok(accounts)
+    }
+}
+
+/// Widens an id to the `uint64` of the protocol, whatever width ids have in this build.
+fn proto_id(id: impl Into<u64>) -> u64 {
+    id.into()
+}
+
+fn client_id(client: u64) -> Result<ClientId, Status> {
+    ClientId::try_from(client)
+        .map_err(|_| Status::invalid_argument(format!("{} is not a valid client id", client)))
+}
+
+fn transaction_id(tx: u64) -> Result<TransactionId, Status> {
+    TransactionId::try_from(tx)
+        .map_err(|_| Status::invalid_argument(format!("{} is not a valid transaction id", tx)))
+}
+
+fn transaction_from_request(
+    request: proto::SubmitTransactionRequest,
+) -> Result<TransactionInput, Status> {
+    let kind = match request.r#type() {
+        proto::TransactionType::Deposit => TransactionType::Deposit,
+        proto::TransactionType::Withdrawal => TransactionType::Withdrawal,
+        proto::TransactionType::Dispute => TransactionType::Dispute,
+        proto::TransactionType::Resolve => TransactionType::Resolve,
+        proto::TransactionType::Chargeback => TransactionType::Chargeback,
+        proto::TransactionType::Unlock => TransactionType::Unlock,
+        proto::TransactionType::Open => TransactionType::Open,
+        proto::TransactionType::Close => TransactionType::Close,
+        proto::TransactionType::Freeze => TransactionType::Freeze,
+        proto::TransactionType::Unfreeze => TransactionType::Unfreeze,
+        proto::TransactionType::Interest => TransactionType::Interest,
+        proto::TransactionType::Unspecified => {
+            return Err(Status::invalid_argument("the transaction type must be set"))
+        }
+    };
+    let amount = request
+        .amount
+        .map(|amount| amount.parse::<Amount>())
+        .transpose()
+        .map_err(|e| Status::invalid_argument(e.to_string()))?;
+    Ok(TransactionInput::new(
+        kind,
+        client_id(request.client)?,
+        transaction_id(request.tx)?,
+        amount,
+    ))
+}
+
+fn to_proto_account(client: ClientId, account: &AccountDetails) -> proto::Account {
+    proto::Account {
+        client: proto_id(client),
+        available: account.available.to_string(),
+        held: account.held.to_string(),
+        total: account.total.to_string(),
+        locked: account.is_locked(),
+        status: account.status.name().to_string(),
+    }
+}
+
+fn from_proto_account(account: &proto::Account) -> Result<(ClientId, AccountDetails), Status> {
+    let amount = |value: &str| {
+        value
+            .parse::<Amount>()
+            .map_err(|e| Status::internal(e.to_string()))
+    };
+    Ok((
+        client_id(account.client)?,
+        AccountDetails {
+            available: amount(&account.available)?,
+            held: amount(&account.held)?,
+            total: amount(&account.total)?,
+            // servers which don't send the status yet only know whether it is locked
+            status: match AccountStatus::from_name(&account.status) {
+                Some(status) => status,
+                None if account.locked => AccountStatus::Locked,
+                None => AccountStatus::Active,
+            },
+        },
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::net::TcpListener;
+    use tokio::sync::oneshot;
+    use tonic::Code;
+
+    use super::{serve, GrpcClient};
+    use crate::{TransactionEngine, TransactionInput};
+
+    #[tokio::test]
+    async fn test_submit_and_query_over_grpc() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let transaction_engine = Arc::new(Mutex::new(TransactionEngine::new()));
+        let (stop, stopped) = oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, Arc::clone(&transaction_engine), async {
+            let _ = stopped.await;
+        }));
+
+        let mut client = GrpcClient::connect(address).await.unwrap();
+        client
+            .submit(TransactionInput::deposit(2, 1, "1.5".parse().unwrap()))
+            .await
+            .unwrap();
+        client
+            .submit(TransactionInput::deposit(1, 2, "3".parse().unwrap()))
+            .await
+            .unwrap();
+        let status = client
+            .submit(TransactionInput::withdrawal(1, 3, "4".parse().unwrap()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        let status = client
+            .submit(TransactionInput::deposit(1, 2, "3".parse().unwrap()))
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::AlreadyExists);
+
+        assert_eq!(
+            client.get_account(1).await.unwrap().available.to_string(),
+            "3.0000"
+        );
+        assert_eq!(
+            client.get_account(3).await.unwrap_err().code(),
+            Code::NotFound
+        );
+        let accounts = client.accounts().await.unwrap();
+        assert_eq!(
+            accounts
+                .iter()
+                .map(|(client, _)| *client)
+                .collect::<Vec<_>>(),
+            vec![1, 2]
+        );
+
+        stop.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert_eq!(transaction_engine.lock().unwrap().transaction_count(), 2);
+    }
+}
@@ -0,0 +1,146 @@
+//! A persistent set of the deposits and withdrawals applied by earlier runs, so that a file
+//! which is submitted twice, or files which overlap, don't apply the same transaction again.
+//!
+//! The ids are kept in a file as fixed size little-endian records and read into memory when it
+//! is opened. The ids applied by a run are only appended once it commits them, e.g. after the
+//! snapshot holding their effect was saved, so a run which fails halfway doesn't mark
+//! transactions as processed which aren't in any saved state. A partial last record, as left by
+//! a crash while writing, is dropped.
+
+use std::collections::HashSet;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem::size_of;
+
+use crate::TransactionId;
+
+/// The size of a record of the file
+const ID_SIZE: usize = size_of::<TransactionId>();
+
+/// An open idempotency store
+pub struct IdempotencyStore {
+    file: File,
+    /// The ids applied by earlier runs and this one
+    seen: HashSet<TransactionId>,
+    /// The ids applied by this run which aren't in the file yet
+    pending: Vec<TransactionId>,
+}
+
+impl IdempotencyStore {
+    /// Opens the store at the path, creating it if it doesn't exist.
+    pub fn open(path: &str) -> io::Result<IdempotencyStore> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .read(true)
+            .append(true)
+            .open(path)?;
+        let mut bytes = Vec::new();
+        file.read_to_end(&mut bytes)?;
+        let records = bytes.chunks_exact(ID_SIZE);
+        if !records.remainder().is_empty() {
+            file.set_len((bytes.len() - records.remainder().len()) as u64)?;
+        }
+        let seen = records
+            .map(|record| {
+                let mut id = [0; ID_SIZE];
+                id.copy_from_slice(record);
+                TransactionId::from_le_bytes(id)
+            })
+            .collect();
+        Ok(IdempotencyStore {
+            file,
+            seen,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Whether a transaction with the id was applied, by an earlier run or this one
+    pub fn contains(&self, tx: TransactionId) -> bool {
+        self.seen.contains(&tx)
+    }
+
+    /// Marks the id as applied. It is written to the file by the next `commit`.
+    pub fn insert(&mut self, tx: TransactionId) {
+        if self.seen.insert(tx) {
+            self.pending.push(tx);
+        }
+    }
+
+    /// Appends the ids applied since the last commit to the file and flushes it to disk.
+    pub fn commit(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let bytes: Vec<u8> = self
+            .pending
+            .iter()
+            .flat_map(|tx| tx.to_le_bytes())
+            .collect();
+        self.file.write_all(&bytes)?;
+        self.file.sync_all()?;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// The number of ids applied, by earlier runs and this one
+    pub fn len(&self) -> usize {
+        self.seen.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.seen.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::{env, process};
+
+    use super::IdempotencyStore;
+    use crate::{TransactionEngine, TransactionInput, TransactionProcessingError};
+
+    #[test]
+    fn test_transactions_of_earlier_runs_are_rejected() {
+        let path = env::temp_dir().join(format!("tte-idempotency-{}", process::id()));
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_file(path);
+
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_idempotency_store(IdempotencyStore::open(path).unwrap());
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "10".parse().unwrap()),
+            TransactionInput::withdrawal(1, 2, "50".parse().unwrap()),
+        ]);
+        transaction_engine.commit_idempotency().unwrap();
+        // a crash while appending leaves a partial record
+        OpenOptions::new()
+            .append(true)
+            .open(path)
+            .unwrap()
+            .write_all(&[7])
+            .unwrap();
+
+        let store = IdempotencyStore::open(path).unwrap();
+        assert_eq!(store.len(), 1);
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_idempotency_store(store);
+        assert!(matches!(
+            transaction_engine.process_transaction(TransactionInput::deposit(
+                1,
+                1,
+                "10".parse().unwrap()
+            )),
+            Err(TransactionProcessingError::AlreadyProcessed)
+        ));
+        // the withdrawal was rejected, so its id is free
+        transaction_engine.process_transactions([TransactionInput::deposit(
+            1,
+            2,
+            "10".parse().unwrap(),
+        )]);
+        assert_eq!(transaction_engine.len(), 1);
+        fs::remove_file(path).unwrap();
+    }
+}
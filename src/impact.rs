@@ -0,0 +1,149 @@
+//! The impact of a run on the accounts, summarized for `--dry-run` instead of the state of all
+//! accounts.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::money::format_minor_units;
+use crate::{AccountDetails, ClientId, MoneyOps, ProcessingStats, TransactionEngine};
+
+/// How an account changed
+#[derive(Debug, Clone, PartialEq)]
+pub struct AccountImpact {
+    pub client: ClientId,
+    /// The account before the run, None for an account the run created
+    pub before: Option<AccountDetails>,
+    pub after: AccountDetails,
+}
+
+impl AccountImpact {
+    /// Whether the run locked the account
+    pub fn locked(&self) -> bool {
+        self.after.is_locked() && !self.before.as_ref().is_some_and(AccountDetails::is_locked)
+    }
+}
+
+/// What a run would change
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImpactSummary {
+    pub stats: ProcessingStats,
+    /// The accounts which changed, sorted by client
+    pub accounts: Vec<AccountImpact>,
+}
+
+impl ImpactSummary {
+    /// Compares the accounts of the engine with the ones it had before the run.
+    pub fn new(
+        before: &HashMap<ClientId, AccountDetails>,
+        transaction_engine: &TransactionEngine,
+        stats: ProcessingStats,
+    ) -> ImpactSummary {
+        let accounts = transaction_engine
+            .sorted_accounts()
+            .into_iter()
+            .filter(|(client, account)| before.get(client) != Some(*account))
+            .map(|(client, account)| AccountImpact {
+                client,
+                before: before.get(&client).cloned(),
+                after: account.clone(),
+            })
+            .collect();
+        ImpactSummary { stats, accounts }
+    }
+}
+
+impl fmt::Display for ImpactSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let created = self
+            .accounts
+            .iter()
+            .filter(|account| account.before.is_none())
+            .count();
+        let locked = self
+            .accounts
+            .iter()
+            .filter(|account| account.locked())
+            .count();
+        writeln!(f, "dry run, nothing was written")?;
+        writeln!(f, "{}", self.stats)?;
+        write!(
+            f,
+            "affected accounts: {} ({} new, {} locked)",
+            self.accounts.len(),
+            created,
+            locked
+        )?;
+        for account in &self.accounts {
+            let before = account.before.clone().unwrap_or_default();
+            let change = account.after.total.to_minor_units() - before.total.to_minor_units();
+            write!(
+                f,
+                "\n  client {}{}: total {} -> {} ({}{}), held {} -> {}",
+                account.client,
+                if account.before.is_none() {
+                    " (new)"
+                } else {
+                    ""
+                },
+                before.total,
+                account.after.total,
+                if change < 0 { "" } else { "+" },
+                format_minor_units(change),
+                before.held,
+                account.after.held,
+            )?;
+            if before.status != account.after.status {
+                write!(
+                    f,
+                    ", {} -> {}",
+                    before.status.name(),
+                    account.after.status.name()
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ImpactSummary;
+    use crate::{Amount, TransactionEngine, TransactionInput};
+
+    fn money(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_only_changed_accounts_are_listed() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("10")),
+            TransactionInput::deposit(2, 2, money("5")),
+        ]);
+        let before = transaction_engine
+            .accounts()
+            .map(|(client, account)| (client, account.clone()))
+            .collect();
+        transaction_engine.process_transactions([
+            TransactionInput::dispute(2, 2),
+            TransactionInput::chargeback(2, 2),
+            TransactionInput::deposit(3, 3, money("1.5")),
+        ]);
+        let summary = ImpactSummary::new(&before, &transaction_engine, transaction_engine.stats());
+        assert_eq!(
+            summary
+                .accounts
+                .iter()
+                .map(|account| account.client)
+                .collect::<Vec<_>>(),
+            vec![2, 3]
+        );
+        let text = summary.to_string();
+        assert!(text.contains("affected accounts: 2 (1 new, 1 locked)"));
+        assert!(text.contains(
+            "client 2: total 5.0000 -> 0.0000 (-5.0000), held 0.0000 -> 0.0000, active -> locked"
+        ));
+        assert!(text.contains("client 3 (new): total 0.0000 -> 1.5000 (+1.5000)"));
+    }
+}
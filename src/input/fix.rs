@@ -0,0 +1,246 @@
+//! Reads transactions from a stream of FIX execution reports.
+//!
+//! Messages may be delimited by SOH as on the wire or by `|` as commonly found in logs, and may
+//! optionally be separated by newlines. Only the messages themselves are consumed, so a socket
+//! must carry a plain message stream (e.g. a drop copy feed) without the FIX session layer.
+//!
+//! Every execution report (`35=8`) that reports a fill (`150=F`, or `150=1`/`150=2` for FIX 4.2)
+//! becomes a transaction on the client given by `Account` (1), keyed by `ExecID` (17), for the
+//! cash value of the fill (`LastQty` (32) times `LastPx` (31)). Buys withdraw cash from the
+//! account and sells deposit it. All other messages are ignored.
+
+use std::io::BufRead;
+
+use thiserror::Error;
+
+use crate::{Amount, TransactionInput, TransactionType};
+
+const BEGIN_STRING: u32 = 8;
+const CHECKSUM: u32 = 10;
+const ACCOUNT: u32 = 1;
+const LAST_PX: u32 = 31;
+const LAST_QTY: u32 = 32;
+const MSG_TYPE: u32 = 35;
+const EXEC_ID: u32 = 17;
+const SIDE: u32 = 54;
+const EXEC_TYPE: u32 = 150;
+
+/// All errors which can happen when reading FIX messages
+#[derive(Error, Debug)]
+pub enum FixError {
+    #[error("couldn't read FIX messages: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("malformed FIX field: {0}")]
+    MalformedField(String),
+
+    #[error("required tag {0} not found on a fill")]
+    MissingTag(u32),
+
+    #[error("tag {0} has an invalid value {1}")]
+    InvalidValue(u32, String),
+}
+
+/// An iterator over the fills found in a FIX message stream, read lazily so that it can be used
+/// on a socket.
+pub struct FixMessages<R: BufRead> {
+    reader: R,
+    message: Vec<(u32, String)>,
+}
+
+impl<R: BufRead> FixMessages<R> {
+    /// Create a new iterator reading messages from the reader
+    pub fn new(reader: R) -> FixMessages<R> {
+        FixMessages {
+            reader,
+            message: Vec::new(),
+        }
+    }
+
+    /// Reads the next non empty field from the stream, returning None at the end of the stream.
+    fn next_field(&mut self) -> Result<Option<(u32, String)>, FixError> {
+        let mut field = Vec::new();
+        loop {
+            let available = self.reader.fill_buf()?;
+            if available.is_empty() {
+                if field.is_empty() {
+                    return Ok(None);
+                }
+                break;
+            }
+            match available
+                .iter()
+                .position(|b| matches!(b, 0x01 | b'|' | b'\n' | b'\r'))
+            {
+                Some(i) => {
+                    field.extend_from_slice(&available[..i]);
+                    self.reader.consume(i + 1);
+                    if !field.is_empty() {
+                        break;
+                    }
+                }
+                None => {
+                    let length = available.len();
+                    field.extend_from_slice(available);
+                    self.reader.consume(length);
+                }
+            }
+        }
+
+        let field = String::from_utf8_lossy(&field).into_owned();
+        let (tag, value) = field
+            .split_once('=')
+            .ok_or_else(|| FixError::MalformedField(field.clone()))?;
+        let tag = tag
+            .trim()
+            .parse::<u32>()
+            .map_err(|_| FixError::MalformedField(field.clone()))?;
+        Ok(Some((tag, value.to_string())))
+    }
+
+    /// Reads fields until a complete message is available. Messages end with their checksum, or
+    /// when the next message begins or the stream ends for logs which don't carry checksums. A
+    /// message with a malformed field is read to its end and then fails as a whole, so that
+    /// reading can go on with the next one.
+    fn next_message(&mut self) -> Result<Option<Vec<(u32, String)>>, FixError> {
+        let mut malformed = None;
+        let message = loop {
+            let (tag, value) = match self.next_field() {
+                Ok(Some(field)) => field,
+                Ok(None) => break std::mem::take(&mut self.message),
+                Err(FixError::MalformedField(field)) => {
+                    malformed.get_or_insert(field);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            if tag == BEGIN_STRING && !self.message.is_empty() {
+                let message = std::mem::take(&mut self.message);
+                self.message.push((tag, value));
+                break message;
+            }
+            self.message.push((tag, value));
+            if tag == CHECKSUM {
+                break std::mem::take(&mut self.message);
+            }
+        };
+        match malformed {
+            Some(field) => Err(FixError::MalformedField(field)),
+            None if message.is_empty() => Ok(None),
+            None => Ok(Some(message)),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for FixMessages<R> {
+    type Item = Result<TransactionInput, FixError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            match self.next_message() {
+                Ok(Some(message)) => match to_transaction(&message) {
+                    Ok(Some(transaction)) => return Some(Ok(transaction)),
+                    Ok(None) => continue,
+                    Err(e) => return Some(Err(e)),
+                },
+                Ok(None) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Maps a message to a transaction if it is an execution report for a fill.
+fn to_transaction(message: &[(u32, String)]) -> Result<Option<TransactionInput>, FixError> {
+    let get = |tag: u32| {
+        message
+            .iter()
+            .find(|(t, _)| *t == tag)
+            .map(|(_, value)| value.as_str())
+    };
+    if get(MSG_TYPE) != Some("8") || !matches!(get(EXEC_TYPE), Some("F" | "1" | "2")) {
+        return Ok(None);
+    }
+
+    let require = |tag: u32| get(tag).ok_or(FixError::MissingTag(tag));
+    let parse = |tag: u32| -> Result<Amount, FixError> {
+        let value = require(tag)?;
+        value
+            .parse::<Amount>()
+            .map_err(|_| FixError::InvalidValue(tag, value.to_string()))
+    };
+
+    let kind = match require(SIDE)? {
+        "1" => TransactionType::Withdrawal,
+        "2" | "5" | "6" => TransactionType::Deposit,
+        other => return Err(FixError::InvalidValue(SIDE, other.to_string())),
+    };
+    let client = require(ACCOUNT)?;
+    let client = client
+        .parse()
+        .map_err(|_| FixError::InvalidValue(ACCOUNT, client.to_string()))?;
+    let price = parse(LAST_PX)?;
+    let amount = parse(LAST_QTY)?
+        .checked_mul(price)
+        .ok_or_else(|| FixError::InvalidValue(LAST_PX, price.to_string()))?;
+    let tx = require(EXEC_ID)?;
+    let tx = tx
+        .parse()
+        .map_err(|_| FixError::InvalidValue(EXEC_ID, tx.to_string()))?;
+    Ok(Some(TransactionInput::new(kind, client, tx, Some(amount))))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FixError, FixMessages};
+    use crate::TransactionType;
+
+    #[test]
+    fn test_fills_are_mapped() {
+        let stream = "8=FIX.4.4\x019=100\x0135=8\x011=5\x0117=100\x01150=F\x0154=2\x0132=10\x0131=1.5\x0110=000\x01\
+8=FIX.4.4\x019=50\x0135=0\x0110=000\x01\
+8=FIX.4.4\x019=100\x0135=8\x011=5\x0117=101\x01150=0\x0154=1\x0110=000\x01\
+8=FIX.4.4\x019=100\x0135=8\x011=5\x0117=102\x01150=F\x0154=1\x0132=2\x0131=3\x0110=000\x01";
+        let transactions: Vec<_> = FixMessages::new(stream.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(transactions.len(), 2);
+        assert!(matches!(transactions[0].kind, TransactionType::Deposit));
+        assert_eq!(transactions[0].client, 5);
+        assert_eq!(transactions[0].tx, 100);
+        assert_eq!(transactions[0].amount, "15".parse().ok());
+        assert!(matches!(transactions[1].kind, TransactionType::Withdrawal));
+        assert_eq!(transactions[1].tx, 102);
+        assert_eq!(transactions[1].amount, "6".parse().ok());
+    }
+
+    #[test]
+    fn test_pipe_delimited_log_without_checksums() {
+        let log = "8=FIX.4.2|35=8|1=3|17=7|150=2|54=1|32=1|31=4.25|\n\
+8=FIX.4.2|35=8|1=3|17=8|150=1|54=2|32=1|31=1|\n";
+        let transactions: Vec<_> = FixMessages::new(log.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(transactions.len(), 2);
+        assert_eq!(transactions[0].tx, 7);
+        assert_eq!(transactions[0].amount, "4.25".parse().ok());
+        assert_eq!(transactions[1].tx, 8);
+    }
+
+    #[test]
+    fn test_fill_without_account_is_rejected() {
+        let log = "8=FIX.4.4|35=8|17=1|150=F|54=1|32=1|31=1|10=000|";
+        let mut messages = FixMessages::new(log.as_bytes());
+        assert!(matches!(messages.next(), Some(Err(_))));
+    }
+
+    #[test]
+    fn test_reading_goes_on_after_a_malformed_message() {
+        let log = "8=FIX.4.2|35=8|1=3|17=7|150=2|oops|54=1|32=1|31=4.25|10=000|\n\
+8=FIX.4.2|35=8|1=3|17=8|150=2|54=1|32=1|31=1|10=000|\n";
+        let results: Vec<_> = FixMessages::new(log.as_bytes()).collect();
+        assert_eq!(results.len(), 2);
+        assert!(matches!(&results[0], Err(FixError::MalformedField(field)) if field == "oops"));
+        assert_eq!(results[1].as_ref().unwrap().tx, 8);
+    }
+}
@@ -0,0 +1,272 @@
+//! Reads transactions from ISO 20022 XML messages.
+//!
+//! Two message families are understood:
+//!
+//! * `camt.053`/`camt.054` statements and notifications. Every `Ntry` becomes a deposit
+//!   (`CRDT`) or a withdrawal (`DBIT`) on the account of the enclosing statement. Entries which
+//!   are reversals (`RvslInd` set) or carry return information (`RtrInf`) are returns of an
+//!   earlier entry and become a dispute followed by a chargeback of that entry.
+//! * `pain.001` credit transfer initiations. Every `CdtTrfTxInf` becomes a withdrawal from the
+//!   debtor account of the enclosing payment information block.
+//!
+//! Client ids are taken from the `Othr/Id` of the account and transaction ids from the
+//! `EndToEndId` (falling back to `NtryRef` for camt entries), both of which must be numeric.
+
+use std::io::BufRead;
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use thiserror::Error;
+
+use crate::{Amount, ClientId, TransactionInput, TransactionType};
+
+/// All errors which can happen when reading an ISO 20022 message
+#[derive(Error, Debug)]
+pub enum Iso20022Error {
+    #[error("malformed XML: {0}")]
+    Xml(#[from] quick_xml::Error),
+
+    #[error("required element {0} not found")]
+    MissingElement(&'static str),
+
+    #[error("identifier {0} is not a valid numeric id")]
+    InvalidIdentifier(String),
+
+    #[error("amount {0} is not a valid amount")]
+    InvalidAmount(String),
+
+    #[error("credit/debit indicator {0} is not valid")]
+    InvalidCreditDebitIndicator(String),
+}
+
+/// The fields collected for a single camt entry or pain credit transfer
+#[derive(Default)]
+struct Entry {
+    reference: Option<String>,
+    end_to_end_id: Option<String>,
+    amount: Option<String>,
+    credit_debit_indicator: Option<String>,
+    is_return: bool,
+}
+
+/// Reads all transactions contained in an ISO 20022 message.
+pub fn read_transactions<R: BufRead>(reader: R) -> Result<Vec<TransactionInput>, Iso20022Error> {
+    let mut reader = Reader::from_reader(reader);
+    reader.config_mut().trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut path: Vec<String> = Vec::new();
+    let mut account: Option<ClientId> = None;
+    let mut entry = Entry::default();
+    let mut transactions = Vec::new();
+    loop {
+        match reader.read_event_into(&mut buf)? {
+            Event::Start(e) => {
+                let name = e.local_name().into_inner().to_string();
+                match name.as_str() {
+                    "Ntry" | "CdtTrfTxInf" => entry = Entry::default(),
+                    "RtrInf" => entry.is_return = true,
+                    _ => (),
+                }
+                path.push(name);
+            }
+            Event::End(_) => match path.pop().as_deref() {
+                Some("Ntry") => transactions.extend(camt_entry_to_transactions(account, &entry)?),
+                Some("CdtTrfTxInf") => {
+                    transactions.push(pain_entry_to_transaction(account, &entry)?)
+                }
+                Some("Stmt" | "Ntfctn" | "Rpt" | "PmtInf") => account = None,
+                _ => (),
+            },
+            Event::Text(text) => {
+                let text = text.xml10_content().into_owned();
+                record_text(&path, text, &mut account, &mut entry)?;
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+        buf.clear();
+    }
+    Ok(transactions)
+}
+
+/// Stores the text of an element if it is one of the elements the reader cares about.
+fn record_text(
+    path: &[String],
+    text: String,
+    account: &mut Option<ClientId>,
+    entry: &mut Entry,
+) -> Result<(), Iso20022Error> {
+    let ends_with = |suffix: &[&str]| {
+        path.len() >= suffix.len() && path[path.len() - suffix.len()..].iter().eq(suffix.iter())
+    };
+
+    if ends_with(&["Stmt", "Acct", "Id", "Othr", "Id"])
+        || ends_with(&["Ntfctn", "Acct", "Id", "Othr", "Id"])
+        || ends_with(&["Rpt", "Acct", "Id", "Othr", "Id"])
+        || ends_with(&["PmtInf", "DbtrAcct", "Id", "Othr", "Id"])
+    {
+        *account = Some(parse_id(&text)?);
+    } else if ends_with(&["Ntry", "Amt"]) || ends_with(&["CdtTrfTxInf", "Amt", "InstdAmt"]) {
+        entry.amount = Some(text);
+    } else if ends_with(&["Ntry", "CdtDbtInd"]) {
+        entry.credit_debit_indicator = Some(text);
+    } else if ends_with(&["Ntry", "RvslInd"]) {
+        entry.is_return |= text == "true";
+    } else if ends_with(&["Ntry", "NtryRef"]) {
+        entry.reference = Some(text);
+    } else if ends_with(&["Refs", "EndToEndId"]) || ends_with(&["PmtId", "EndToEndId"]) {
+        entry.end_to_end_id = Some(text);
+    }
+    Ok(())
+}
+
+/// Maps a camt entry to the engine transactions it represents.
+fn camt_entry_to_transactions(
+    account: Option<ClientId>,
+    entry: &Entry,
+) -> Result<Vec<TransactionInput>, Iso20022Error> {
+    let client = account.ok_or(Iso20022Error::MissingElement("Acct/Id/Othr/Id"))?;
+    let tx = entry
+        .end_to_end_id
+        .as_ref()
+        .or(entry.reference.as_ref())
+        .ok_or(Iso20022Error::MissingElement("EndToEndId or NtryRef"))?;
+    let tx = parse_id(tx)?;
+
+    if entry.is_return {
+        return Ok(vec![
+            TransactionInput::new(TransactionType::Dispute, client, tx, None),
+            TransactionInput::new(TransactionType::Chargeback, client, tx, None),
+        ]);
+    }
+
+    let kind = match entry.credit_debit_indicator.as_deref() {
+        Some("CRDT") => TransactionType::Deposit,
+        Some("DBIT") => TransactionType::Withdrawal,
+        Some(other) => {
+            return Err(Iso20022Error::InvalidCreditDebitIndicator(
+                other.to_string(),
+            ))
+        }
+        None => return Err(Iso20022Error::MissingElement("CdtDbtInd")),
+    };
+    Ok(vec![TransactionInput::new(
+        kind,
+        client,
+        tx,
+        Some(parse_amount(entry)?),
+    )])
+}
+
+/// Maps a pain credit transfer to a withdrawal from the debtor account.
+fn pain_entry_to_transaction(
+    account: Option<ClientId>,
+    entry: &Entry,
+) -> Result<TransactionInput, Iso20022Error> {
+    let client = account.ok_or(Iso20022Error::MissingElement("DbtrAcct/Id/Othr/Id"))?;
+    let tx = entry
+        .end_to_end_id
+        .as_ref()
+        .ok_or(Iso20022Error::MissingElement("PmtId/EndToEndId"))?;
+    Ok(TransactionInput::new(
+        TransactionType::Withdrawal,
+        client,
+        parse_id(tx)?,
+        Some(parse_amount(entry)?),
+    ))
+}
+
+fn parse_id<T: std::str::FromStr>(value: &str) -> Result<T, Iso20022Error> {
+    value
+        .parse::<T>()
+        .map_err(|_| Iso20022Error::InvalidIdentifier(value.to_string()))
+}
+
+fn parse_amount(entry: &Entry) -> Result<Amount, Iso20022Error> {
+    let amount = entry
+        .amount
+        .as_ref()
+        .ok_or(Iso20022Error::MissingElement("Amt"))?;
+    amount
+        .parse::<Amount>()
+        .map_err(|_| Iso20022Error::InvalidAmount(amount.clone()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_transactions;
+    use crate::TransactionType;
+
+    #[test]
+    fn test_camt054_entries() {
+        let message = r#"<?xml version="1.0" encoding="UTF-8"?>
+<Document xmlns="urn:iso:std:iso:20022:tech:xsd:camt.054.001.08">
+  <BkToCstmrDbtCdtNtfctn>
+    <Ntfctn>
+      <Acct><Id><Othr><Id>7</Id></Othr></Id></Acct>
+      <Ntry>
+        <NtryRef>1</NtryRef>
+        <Amt Ccy="EUR">10.50</Amt>
+        <CdtDbtInd>CRDT</CdtDbtInd>
+      </Ntry>
+      <Ntry>
+        <NtryRef>900</NtryRef>
+        <Amt Ccy="EUR">2.00</Amt>
+        <CdtDbtInd>DBIT</CdtDbtInd>
+        <NtryDtls><TxDtls><Refs><EndToEndId>2</EndToEndId></Refs></TxDtls></NtryDtls>
+      </Ntry>
+      <Ntry>
+        <NtryRef>901</NtryRef>
+        <Amt Ccy="EUR">10.50</Amt>
+        <CdtDbtInd>DBIT</CdtDbtInd>
+        <RvslInd>true</RvslInd>
+        <NtryDtls><TxDtls><Refs><EndToEndId>1</EndToEndId></Refs></TxDtls></NtryDtls>
+      </Ntry>
+    </Ntfctn>
+  </BkToCstmrDbtCdtNtfctn>
+</Document>"#;
+        let transactions = read_transactions(message.as_bytes()).unwrap();
+        assert_eq!(transactions.len(), 4);
+        assert!(matches!(transactions[0].kind, TransactionType::Deposit));
+        assert_eq!(transactions[0].client, 7);
+        assert_eq!(transactions[0].tx, 1);
+        assert_eq!(transactions[0].amount, "10.5".parse().ok());
+        assert!(matches!(transactions[1].kind, TransactionType::Withdrawal));
+        assert_eq!(transactions[1].tx, 2);
+        assert!(matches!(transactions[2].kind, TransactionType::Dispute));
+        assert_eq!(transactions[2].tx, 1);
+        assert!(matches!(transactions[3].kind, TransactionType::Chargeback));
+        assert_eq!(transactions[3].tx, 1);
+    }
+
+    #[test]
+    fn test_pain001_credit_transfers() {
+        let message = r#"<Document xmlns="urn:iso:std:iso:20022:tech:xsd:pain.001.001.09">
+  <CstmrCdtTrfInitn>
+    <PmtInf>
+      <DbtrAcct><Id><Othr><Id>3</Id></Othr></Id></DbtrAcct>
+      <CdtTrfTxInf>
+        <PmtId><EndToEndId>11</EndToEndId></PmtId>
+        <Amt><InstdAmt Ccy="EUR">1.25</InstdAmt></Amt>
+        <CdtrAcct><Id><Othr><Id>99</Id></Othr></Id></CdtrAcct>
+      </CdtTrfTxInf>
+    </PmtInf>
+  </CstmrCdtTrfInitn>
+</Document>"#;
+        let transactions = read_transactions(message.as_bytes()).unwrap();
+        assert_eq!(transactions.len(), 1);
+        assert!(matches!(transactions[0].kind, TransactionType::Withdrawal));
+        assert_eq!(transactions[0].client, 3);
+        assert_eq!(transactions[0].tx, 11);
+        assert_eq!(transactions[0].amount, "1.25".parse().ok());
+    }
+
+    #[test]
+    fn test_non_numeric_account_is_rejected() {
+        let message = r#"<Document><Ntfctn>
+  <Acct><Id><Othr><Id>ABC</Id></Othr></Id></Acct>
+</Ntfctn></Document>"#;
+        assert!(read_transactions(message.as_bytes()).is_err());
+    }
+}
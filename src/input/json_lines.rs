@@ -0,0 +1,116 @@
+//! Reads transactions from JSON Lines (newline-delimited JSON) input.
+//!
+//! Every non blank line is an object with the same fields as a CSV row, e.g.
+//! `{"type": "deposit", "client": 1, "tx": 1, "amount": "1.5"}`. Amounts may be strings or
+//! numbers; numbers are taken as written, so `1.5` is read exactly like `"1.5"`.
+
+use std::io::{self, BufRead};
+
+use serde_json::Value;
+
+use crate::TransactionInput;
+
+/// A line of the input together with the transaction read from it
+pub struct JsonLine {
+    /// The number of the line, starting at 1
+    pub line: u64,
+    pub text: String,
+    pub transaction: Result<TransactionInput, serde_json::Error>,
+}
+
+/// An iterator over the non blank lines of JSON Lines input, read lazily so that a line which
+/// can't be deserialized can be skipped on its own.
+pub struct JsonLines<R: BufRead> {
+    lines: io::Lines<R>,
+    line: u64,
+}
+
+impl<R: BufRead> JsonLines<R> {
+    /// Create a new iterator reading lines from the reader
+    pub fn new(reader: R) -> JsonLines<R> {
+        JsonLines {
+            lines: reader.lines(),
+            line: 0,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for JsonLines<R> {
+    type Item = io::Result<JsonLine>;
+
+    fn next(&mut self) -> Option<io::Result<JsonLine>> {
+        loop {
+            let text = match self.lines.next()? {
+                Ok(text) => text,
+                Err(e) => return Some(Err(e)),
+            };
+            self.line += 1;
+            if text.trim().is_empty() {
+                continue;
+            }
+            return Some(Ok(JsonLine {
+                line: self.line,
+                transaction: parse_transaction(&text),
+                text,
+            }));
+        }
+    }
+}
+
+/// Deserializes a transaction from a single line.
+pub fn parse_transaction(text: &str) -> Result<TransactionInput, serde_json::Error> {
+    transaction_from_value(serde_json::from_str(text)?)
+}
+
+/// Deserializes a transaction from a JSON object.
+pub(crate) fn transaction_from_value(
+    mut value: Value,
+) -> Result<TransactionInput, serde_json::Error> {
+    // amounts are parsed from their text, which arbitrary_precision keeps for numbers as well
+    if let Some(amount @ Value::Number(_)) = value.get_mut("amount") {
+        *amount = Value::String(amount.to_string());
+    }
+    serde_json::from_value(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::JsonLines;
+    use crate::{TransactionInput, TransactionType};
+
+    #[test]
+    fn test_read_json_lines() {
+        let input = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 1.1234}\n\
+                     \n\
+                     {\"type\": \"dispute\", \"client\": 1, \"tx\": 1}\n\
+                     {\"type\": \"withdrawal\", \"client\": 1, \"tx\": 2, \"amount\": \"0.5\"}\n\
+                     {\"type\": \"deposit\", \"client\": \"x\"}\n";
+        let lines: Vec<_> = JsonLines::new(input.as_bytes())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(
+            lines.iter().map(|line| line.line).collect::<Vec<_>>(),
+            vec![1, 3, 4, 5]
+        );
+        match lines[0].transaction {
+            Ok(TransactionInput {
+                kind: TransactionType::Deposit,
+                client: 1,
+                tx: 1,
+                amount: Some(amount),
+                ..
+            }) => assert_eq!(amount, "1.1234".parse().unwrap()),
+            _ => panic!("Expected a deposit"),
+        }
+        assert!(matches!(
+            lines[1].transaction,
+            Ok(TransactionInput {
+                kind: TransactionType::Dispute,
+                amount: None,
+                ..
+            })
+        ));
+        assert!(lines[2].transaction.is_ok());
+        assert!(lines[3].transaction.is_err());
+    }
+}
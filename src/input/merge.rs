@@ -0,0 +1,323 @@
+//! CSV input spread over several files, e.g. hourly exports, processed as one input.
+//!
+//! The files are read side by side and the next row applied is always the one with the earliest
+//! timestamp, so files with a timestamp column are interleaved in the order of their
+//! timestamps. Rows with the same timestamp, and rows without one, are taken from the files in
+//! the order they were given, so files without a timestamp column are processed one after the
+//! other. The control totals of every file are verified on their own.
+//!
+//! Files which are partitioned by client can instead be processed in parallel, each on its own
+//! thread with its own engine. As clients are independent, merging the accounts of the engines
+//! gives the same state as processing the files one after the other.
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::thread;
+
+use csv::StringRecord;
+
+use crate::control_totals::ControlTotals;
+use crate::dialect::CsvDialect;
+use crate::ordering::{OrderingConfig, Reorderer};
+use crate::rejects::{ProcessingPolicy, Rejection, Rejections};
+use crate::sharded::{ShardedEngineError, ShardedOutcome};
+use crate::{
+    process_held_rows, process_row, process_row_in_order, read_csv_with_dialect, read_row,
+    verify_control_totals, ClientId, ControlTotalsPolicy, CsvRow, TransactionEngine,
+};
+
+/// The characters which make an input path a glob pattern
+const GLOB_CHARACTERS: [char; 3] = ['*', '?', '['];
+
+/// Expands the glob patterns among the input paths to the files they match, sorted by path.
+/// Other paths are kept as they are.
+pub fn expand_input_paths(input_paths: &[String]) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut expanded = Vec::new();
+    for input_path in input_paths {
+        if !input_path.contains(GLOB_CHARACTERS) {
+            expanded.push(input_path.clone());
+            continue;
+        }
+        let matched = glob::glob(input_path)?
+            .map(|path| Ok(path?.to_string_lossy().to_string()))
+            .collect::<Result<Vec<String>, glob::GlobError>>()?;
+        if matched.is_empty() {
+            return Err(format!("no input file matches {input_path}").into());
+        }
+        expanded.extend(matched);
+    }
+    Ok(expanded)
+}
+
+/// An input file with the row read next
+struct CsvFile {
+    reader: csv::Reader<File>,
+    headers: StringRecord,
+    control_totals: ControlTotals,
+    next: Option<CsvRow>,
+}
+
+impl CsvFile {
+    fn open(path: &str, dialect: &CsvDialect) -> Result<CsvFile, Box<dyn Error>> {
+        let mut reader = dialect.reader(File::open(path)?);
+        let headers = dialect.headers(&mut reader)?;
+        let mut file = CsvFile {
+            reader,
+            headers,
+            control_totals: ControlTotals::new(),
+            next: None,
+        };
+        file.advance(dialect)?;
+        Ok(file)
+    }
+
+    /// Reads the next transaction row, skipping control records.
+    fn advance(&mut self, dialect: &CsvDialect) -> Result<(), Box<dyn Error>> {
+        self.next = None;
+        let mut record = StringRecord::new();
+        while self.next.is_none() && self.reader.read_record(&mut record)? {
+            self.next = read_row(
+                record.clone(),
+                &self.headers,
+                dialect.precision,
+                &mut self.control_totals,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads and processes the CSV input files as one input, putting the rows in the order of
+/// their timestamps as configured like `process_csv_in_order`. The line of a rejected row is
+/// the line within its file.
+pub(crate) fn process_csv_files(
+    transaction_engine: &mut TransactionEngine,
+    input_paths: &[String],
+    dialect: &CsvDialect,
+    control_totals_policy: ControlTotalsPolicy,
+    rejections: &mut Rejections,
+    ordering_config: OrderingConfig,
+) -> Result<(), Box<dyn Error>> {
+    let mut files = input_paths
+        .iter()
+        .map(|input_path| CsvFile::open(input_path, dialect))
+        .collect::<Result<Vec<CsvFile>, Box<dyn Error>>>()?;
+    let mut reorderer = Reorderer::new(ordering_config);
+    // None comes first, so a row without a timestamp is applied as soon as it is reached
+    while let Some((_, index)) = files
+        .iter()
+        .enumerate()
+        .filter_map(|(index, file)| {
+            let row = file.next.as_ref()?;
+            let timestamp = row.transaction.as_ref().ok().and_then(|t| t.timestamp());
+            Some((timestamp, index))
+        })
+        .min()
+    {
+        let file = &mut files[index];
+        let row = file.next.take().expect("the file has a next row");
+        file.advance(dialect)?;
+        process_row_in_order(transaction_engine, &mut reorderer, row, rejections)?;
+    }
+    process_held_rows(transaction_engine, &mut reorderer, rejections)?;
+    for file in &files {
+        verify_control_totals(&file.control_totals, control_totals_policy)?;
+    }
+    Ok(())
+}
+
+/// Processes every CSV input file with one of the engines on its own thread. The rejections
+/// of the files are in the order of the files, and the lines of the rows within their file.
+/// Fails if a client has rows in more than one file.
+pub(crate) fn process_partitioned_files(
+    engines: Vec<TransactionEngine>,
+    input_paths: &[String],
+    dialect: &CsvDialect,
+    control_totals_policy: ControlTotalsPolicy,
+) -> Result<ShardedOutcome, Box<dyn Error>> {
+    let results = thread::scope(|scope| {
+        let workers: Vec<_> = engines
+            .into_iter()
+            .zip(input_paths)
+            .map(|(engine, input_path)| {
+                scope.spawn(move || {
+                    process_partition(engine, input_path, dialect, control_totals_policy)
+                        .map_err(|e| format!("{input_path}: {e}"))
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().map_err(|_| ShardedEngineError::ShardStopped))
+            .collect::<Result<Vec<_>, ShardedEngineError>>()
+    })?;
+
+    let mut partition_of: HashMap<ClientId, usize> = HashMap::new();
+    let mut engines = Vec::with_capacity(results.len());
+    let mut rejections = Vec::new();
+    for (index, result) in results.into_iter().enumerate() {
+        let (engine, clients, partition_rejections) = result?;
+        for client in clients {
+            if let Some(first) = partition_of.insert(client, index) {
+                return Err(ShardedEngineError::ClientInSeveralPartitions {
+                    client,
+                    first: input_paths[first].clone(),
+                    second: input_paths[index].clone(),
+                }
+                .into());
+            }
+        }
+        engines.push(engine);
+        rejections.extend(partition_rejections);
+    }
+    Ok(ShardedOutcome::new(engines, rejections))
+}
+
+/// What a partition hands back: its engine, the clients with rows in it and its rejections
+type PartitionResult = (TransactionEngine, HashSet<ClientId>, Vec<Rejection>);
+
+/// Processes a CSV input file, noting the clients it has rows of.
+fn process_partition(
+    mut transaction_engine: TransactionEngine,
+    input_path: &str,
+    dialect: &CsvDialect,
+    control_totals_policy: ControlTotalsPolicy,
+) -> Result<PartitionResult, Box<dyn Error>> {
+    let mut clients = HashSet::new();
+    // rejections are logged and checked against the processing policy once all are merged
+    let mut rejections = Rejections::new(ProcessingPolicy::Skip, false);
+    read_csv_with_dialect(
+        File::open(input_path)?,
+        dialect,
+        control_totals_policy,
+        |row| {
+            if let Ok(transaction) = &row.transaction {
+                clients.insert(transaction.client());
+            }
+            Ok(process_row(&mut transaction_engine, row, &mut rejections)?)
+        },
+    )?;
+    Ok((transaction_engine, clients, rejections.into_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::{expand_input_paths, process_csv_files, process_partitioned_files};
+    use crate::dialect::CsvDialect;
+    use crate::rejects::{ProcessingPolicy, Rejections};
+    use crate::{ControlTotalsPolicy, TransactionEngine};
+
+    #[test]
+    fn test_files_are_interleaved_by_timestamp() {
+        let directory = env::temp_dir().join(format!("tte-merge-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let write = |name: &str, contents: &str| {
+            fs::write(directory.join(name), contents).unwrap();
+        };
+        write(
+            "hour-1.csv",
+            "type,client,tx,amount,timestamp\ndeposit,1,1,5,10\nwithdrawal,1,2,4,30\n",
+        );
+        write(
+            "hour-2.csv",
+            "type,client,tx,amount,timestamp\nwithdrawal,1,3,4,20\n",
+        );
+        write("plain-1.csv", "type,client,tx,amount\ndeposit,2,4,5\n");
+        write("plain-2.csv", "type,client,tx,amount\nwithdrawal,2,5,4\n");
+        let input_paths = |pattern: &str| {
+            expand_input_paths(&[directory.join(pattern).to_string_lossy().to_string()]).unwrap()
+        };
+        let process = |input_paths: &[String]| {
+            let mut transaction_engine = TransactionEngine::new();
+            let mut rejections = Rejections::new(ProcessingPolicy::Skip, false);
+            process_csv_files(
+                &mut transaction_engine,
+                input_paths,
+                &CsvDialect::default(),
+                ControlTotalsPolicy::Fail,
+                &mut rejections,
+                Default::default(),
+            )
+            .unwrap();
+            (transaction_engine, rejections.into_vec())
+        };
+
+        let (transaction_engine, rejections) = process(&input_paths("hour-*.csv"));
+        assert_eq!(
+            transaction_engine.get_account(1).unwrap().total,
+            "1".parse().unwrap()
+        );
+        // the withdrawal of the first file came after the one of the second
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].record, "withdrawal,1,2,4,30");
+
+        // in the given order without timestamps
+        let mut plain = input_paths("plain-*.csv");
+        plain.reverse();
+        let (transaction_engine, rejections) = process(&plain);
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(
+            transaction_engine.get_account(2).unwrap().total,
+            "5".parse().unwrap()
+        );
+
+        assert!(expand_input_paths(&[directory
+            .join("missing-*.csv")
+            .to_string_lossy()
+            .to_string()])
+        .is_err());
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_partitioned_files_must_not_share_clients() {
+        let directory = env::temp_dir().join(format!("tte-partitioned-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let input_paths: Vec<String> = [
+            (
+                "shard-1.csv",
+                "type,client,tx,amount\ndeposit,1,1,5\nwithdrawal,1,2,9\n",
+            ),
+            ("shard-2.csv", "type,client,tx,amount\ndeposit,2,3,2\n"),
+            ("shard-3.csv", "type,client,tx,amount\ndeposit,2,4,1\n"),
+        ]
+        .iter()
+        .map(|(name, contents)| {
+            let path = directory.join(name);
+            fs::write(&path, contents).unwrap();
+            path.to_string_lossy().to_string()
+        })
+        .collect();
+        let process = |input_paths: &[String]| {
+            let engines = input_paths
+                .iter()
+                .map(|_| TransactionEngine::new())
+                .collect();
+            process_partitioned_files(
+                engines,
+                input_paths,
+                &CsvDialect::default(),
+                ControlTotalsPolicy::Fail,
+            )
+        };
+
+        let outcome = process(&input_paths[..2]).unwrap();
+        let totals: Vec<_> = outcome
+            .sorted_accounts()
+            .iter()
+            .map(|(client, account)| (*client, account.total))
+            .collect();
+        assert_eq!(
+            totals,
+            [(1, "5".parse().unwrap()), (2, "2".parse().unwrap())]
+        );
+        assert_eq!(outcome.rejections.len(), 1);
+
+        let error = process(&input_paths).err().unwrap();
+        assert!(error.to_string().starts_with("client 2 appears in both"));
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
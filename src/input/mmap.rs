@@ -0,0 +1,100 @@
+//! Reading input files through a memory map instead of buffered reads.
+//!
+//! Mapping a large file saves the copies and system calls of reading it in small pieces, and
+//! lets the kernel read ahead as far as it likes. It is built with the opt-in `mmap` feature.
+//!
+//! # Safety
+//!
+//! A mapped file must not be changed while it is processed. If another process truncates it,
+//! reading the pages past its new end kills the engine with SIGBUS, and if it is rewritten in
+//! place the rows read are undefined behavior as far as Rust is concerned. So files are only
+//! mapped when asked to, and the auto policy leaves small files, whose reads are cheap anyway,
+//! to buffered reads. Files which may still be written to, like the input of `--follow`, must
+//! never be mapped.
+
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+
+use memmap2::Mmap;
+
+/// The size from which input files are mapped with the auto policy
+pub const AUTO_MMAP_SIZE: u64 = 64 * 1024 * 1024;
+
+/// When input files are memory mapped
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MmapPolicy {
+    /// Map files of at least `AUTO_MMAP_SIZE` bytes
+    Auto,
+    /// Map every input file
+    Always,
+    /// Always use buffered reads
+    #[default]
+    Never,
+}
+
+impl MmapPolicy {
+    /// Parses the policy from its command line name.
+    pub fn from_name(name: &str) -> Option<MmapPolicy> {
+        match name {
+            "auto" => Some(MmapPolicy::Auto),
+            "always" => Some(MmapPolicy::Always),
+            "never" => Some(MmapPolicy::Never),
+            _ => None,
+        }
+    }
+}
+
+/// Opens the input file for reading, mapping it into memory if the policy says so. Only regular
+/// files which aren't empty are mapped.
+pub(crate) fn open_file(path: &str, mmap_policy: MmapPolicy) -> io::Result<Box<dyn Read + Send>> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let map = match mmap_policy {
+        MmapPolicy::Auto => metadata.len() >= AUTO_MMAP_SIZE,
+        MmapPolicy::Always => metadata.len() > 0,
+        MmapPolicy::Never => false,
+    };
+    if !map || !metadata.is_file() {
+        return Ok(Box::new(file));
+    }
+    // SAFETY: the file is only read, and the input must not be changed while it is processed
+    let mmap = unsafe { Mmap::map(&file)? };
+    #[cfg(unix)]
+    mmap.advise(memmap2::Advice::Sequential)?;
+    Ok(Box::new(Cursor::new(mmap)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+    use std::{env, fs, process};
+
+    use super::{open_file, MmapPolicy};
+
+    #[test]
+    fn test_mapped_file_reads_like_the_file() {
+        let path = env::temp_dir().join(format!("tte-mmap-{}.csv", process::id()));
+        let contents = "type,client,tx,amount\ndeposit,1,1,5\n".repeat(1000);
+        fs::write(&path, &contents).unwrap();
+        let empty = env::temp_dir().join(format!("tte-mmap-empty-{}.csv", process::id()));
+        fs::write(&empty, "").unwrap();
+
+        for policy in [MmapPolicy::Auto, MmapPolicy::Always, MmapPolicy::Never] {
+            let mut read = String::new();
+            open_file(path.to_str().unwrap(), policy)
+                .unwrap()
+                .read_to_string(&mut read)
+                .unwrap();
+            assert_eq!(read, contents);
+        }
+        // an empty file can't be mapped
+        let mut read = String::new();
+        open_file(empty.to_str().unwrap(), MmapPolicy::Always)
+            .unwrap()
+            .read_to_string(&mut read)
+            .unwrap();
+        assert!(read.is_empty());
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&empty).unwrap();
+    }
+}
@@ -0,0 +1,65 @@
+pub mod fix;
+#[cfg(feature = "iso20022")]
+pub mod iso20022;
+pub mod json_lines;
+pub mod merge;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+pub mod nacha;
+pub mod ofx;
+#[cfg(feature = "parquet")]
+pub mod parquet;
+
+/// The formats transactions can be read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InputFormat {
+    Csv,
+    JsonLines,
+    #[cfg(feature = "iso20022")]
+    Iso20022,
+    Ofx,
+    Fix,
+    Nacha,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl InputFormat {
+    /// Parses the format from its command line name.
+    pub fn from_name(name: &str) -> Option<InputFormat> {
+        match name {
+            "csv" => Some(InputFormat::Csv),
+            "jsonl" | "ndjson" => Some(InputFormat::JsonLines),
+            #[cfg(feature = "iso20022")]
+            "iso20022" => Some(InputFormat::Iso20022),
+            "ofx" | "qfx" => Some(InputFormat::Ofx),
+            "fix" => Some(InputFormat::Fix),
+            "nacha" | "ach" => Some(InputFormat::Nacha),
+            #[cfg(feature = "parquet")]
+            "parquet" => Some(InputFormat::Parquet),
+            _ => None,
+        }
+    }
+
+    /// Guesses the format from the extension of the input path, falling back to CSV. Sockets
+    /// can only carry FIX messages.
+    pub fn detect(path: &str) -> InputFormat {
+        if path.starts_with("tcp://") {
+            return InputFormat::Fix;
+        }
+        let extension = path
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("jsonl" | "ndjson") => InputFormat::JsonLines,
+            #[cfg(feature = "iso20022")]
+            Some("xml") => InputFormat::Iso20022,
+            Some("ofx" | "qfx") => InputFormat::Ofx,
+            Some("fix") => InputFormat::Fix,
+            Some("ach") => InputFormat::Nacha,
+            #[cfg(feature = "parquet")]
+            Some("parquet") => InputFormat::Parquet,
+            _ => InputFormat::Csv,
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! Reads transactions from NACHA ACH files.
+//!
+//! Records are 94 characters wide and may either be separated by newlines or blocked together
+//! without any separator. Only entry detail (`6`) records and their addenda (`7`) records are
+//! used, the file and batch header/control records are skipped.
+//!
+//! Entries are mapped by the last digit of their transaction code:
+//!
+//! * `2` (credits, e.g. 22 checking credit) become deposits.
+//! * `7` (debits, e.g. 27 checking debit) and the loan debit code 55 become withdrawals.
+//! * `1`/`6` (automated returns and notifications of change) carry a `99` return addenda or a
+//!   `98` NOC addenda. Returns become a dispute followed by a chargeback of the original entry,
+//!   NOCs carry no money and are skipped.
+//! * `3`/`8` (prenotes) and `4`/`9` (zero dollar entries) are skipped.
+//!
+//! The client id is the DFI account number of the entry and the transaction id is the 7 digit
+//! sequence number at the end of the trace number, which returns reference through the original
+//! entry trace number in their addenda.
+
+use std::io::Read;
+
+use thiserror::Error;
+
+use crate::{Amount, ClientId, TransactionId, TransactionInput, TransactionType};
+
+const RECORD_LENGTH: usize = 94;
+
+/// All errors which can happen when reading a NACHA file
+#[derive(Error, Debug)]
+pub enum NachaError {
+    #[error("couldn't read the NACHA file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("record {0} is not {RECORD_LENGTH} characters long")]
+    InvalidRecordLength(usize),
+
+    #[error("record {record} has an invalid {field}: {value}")]
+    InvalidField {
+        record: usize,
+        field: &'static str,
+        value: String,
+    },
+
+    #[error("return entry in record {0} doesn't have a return addenda record")]
+    MissingReturnAddenda(usize),
+}
+
+/// An entry detail record together with the addenda records following it
+struct Entry<'a> {
+    record_number: usize,
+    record: &'a str,
+    addenda: Vec<(usize, &'a str)>,
+}
+
+/// Reads all transactions contained in a NACHA file.
+pub fn read_transactions<R: Read>(mut reader: R) -> Result<Vec<TransactionInput>, NachaError> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+    let records = split_records(&content)?;
+
+    let mut transactions = Vec::new();
+    let mut entry: Option<Entry> = None;
+    for (index, record) in records.into_iter().enumerate() {
+        let record_number = index + 1;
+        if record.starts_with('7') {
+            if let Some(e) = entry.as_mut() {
+                e.addenda.push((record_number, record));
+            }
+            continue;
+        }
+        if let Some(e) = entry.take() {
+            transactions.extend(entry_to_transactions(&e)?);
+        }
+        if record.starts_with('6') {
+            entry = Some(Entry {
+                record_number,
+                record,
+                addenda: Vec::new(),
+            });
+        }
+    }
+    if let Some(e) = entry.take() {
+        transactions.extend(entry_to_transactions(&e)?);
+    }
+    Ok(transactions)
+}
+
+/// Splits the file into its records, which are either one per line or blocked without separators.
+fn split_records(content: &str) -> Result<Vec<&str>, NachaError> {
+    let records: Vec<&str> = if content.contains('\n') {
+        content
+            .lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .filter(|line| !line.trim().is_empty())
+            .collect()
+    } else {
+        let content = content.trim_end();
+        if !content.len().is_multiple_of(RECORD_LENGTH) || !content.is_ascii() {
+            return Err(NachaError::InvalidRecordLength(
+                content.len() / RECORD_LENGTH + 1,
+            ));
+        }
+        (0..content.len() / RECORD_LENGTH)
+            .map(|i| &content[i * RECORD_LENGTH..(i + 1) * RECORD_LENGTH])
+            .collect()
+    };
+
+    for (index, record) in records.iter().enumerate() {
+        // some generators trim the trailing blanks of records, so shorter records are accepted
+        if record.len() > RECORD_LENGTH || !record.is_ascii() {
+            return Err(NachaError::InvalidRecordLength(index + 1));
+        }
+    }
+    Ok(records)
+}
+
+/// Maps an entry detail record and its addenda to the engine transactions it represents.
+fn entry_to_transactions(entry: &Entry) -> Result<Vec<TransactionInput>, NachaError> {
+    let transaction_code = field(entry.record_number, entry.record, 2, 3, "transaction code")?;
+    let client: ClientId = parse_field(entry.record_number, entry.record, 13, 29, "account")?;
+
+    let kind = match transaction_code.as_bytes() {
+        b"55" => TransactionType::Withdrawal,
+        [_, b'2'] => TransactionType::Deposit,
+        [_, b'7'] => TransactionType::Withdrawal,
+        [_, b'1' | b'6'] => return return_to_transactions(entry, client),
+        [_, b'3' | b'4' | b'8' | b'9'] => return Ok(Vec::new()),
+        _ => {
+            return Err(NachaError::InvalidField {
+                record: entry.record_number,
+                field: "transaction code",
+                value: transaction_code.to_string(),
+            })
+        }
+    };
+    let cents: u64 = parse_field(entry.record_number, entry.record, 30, 39, "amount")?;
+    Ok(vec![TransactionInput::new(
+        kind,
+        client,
+        trace_sequence_number(entry.record_number, entry.record, 80)?,
+        Some(Amount::from_minor_units(cents as i64 * 100)),
+    )])
+}
+
+/// Maps a return entry to a dispute and chargeback of the original entry. NOCs are skipped.
+fn return_to_transactions(
+    entry: &Entry,
+    client: ClientId,
+) -> Result<Vec<TransactionInput>, NachaError> {
+    let (addenda_record_number, addenda) = entry
+        .addenda
+        .iter()
+        .find(|(_, a)| matches!(a.get(1..3), Some("99" | "98")))
+        .ok_or(NachaError::MissingReturnAddenda(entry.record_number))?;
+    if addenda.get(1..3) == Some("98") {
+        return Ok(Vec::new());
+    }
+
+    let tx = trace_sequence_number(*addenda_record_number, addenda, 7)?;
+    Ok(vec![
+        TransactionInput::new(TransactionType::Dispute, client, tx, None),
+        TransactionInput::new(TransactionType::Chargeback, client, tx, None),
+    ])
+}
+
+/// Reads the sequence number (the last 7 digits) of the 15 digit trace number starting at the
+/// given position.
+fn trace_sequence_number(
+    record_number: usize,
+    record: &str,
+    start: usize,
+) -> Result<TransactionId, NachaError> {
+    parse_field(record_number, record, start + 8, start + 14, "trace number")
+}
+
+/// Returns the trimmed field between the given 1-based, inclusive positions of the record.
+fn field<'a>(
+    record_number: usize,
+    record: &'a str,
+    start: usize,
+    end: usize,
+    name: &'static str,
+) -> Result<&'a str, NachaError> {
+    record
+        .get(start - 1..end)
+        .map(str::trim)
+        .ok_or(NachaError::InvalidField {
+            record: record_number,
+            field: name,
+            value: record.to_string(),
+        })
+}
+
+fn parse_field<T: std::str::FromStr>(
+    record_number: usize,
+    record: &str,
+    start: usize,
+    end: usize,
+    name: &'static str,
+) -> Result<T, NachaError> {
+    let value = field(record_number, record, start, end, name)?;
+    value.parse::<T>().map_err(|_| NachaError::InvalidField {
+        record: record_number,
+        field: name,
+        value: value.to_string(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_transactions;
+    use crate::TransactionType;
+
+    /// Builds a 94 character record from its fields, which are given as (start position, value).
+    fn record(fields: &[(usize, &str)]) -> String {
+        let mut record = vec![b' '; 94];
+        for (start, value) in fields {
+            record[start - 1..start - 1 + value.len()].copy_from_slice(value.as_bytes());
+        }
+        String::from_utf8(record).unwrap()
+    }
+
+    fn entry(code: &str, account: &str, cents: &str, trace: &str, addenda: &str) -> String {
+        record(&[
+            (1, "6"),
+            (2, code),
+            (4, "09100001"),
+            (12, "9"),
+            (13, account),
+            (30, cents),
+            (55, "JANE DOE"),
+            (79, addenda),
+            (80, trace),
+        ])
+    }
+
+    #[test]
+    fn test_entries_and_returns() {
+        let file = [
+            record(&[(1, "101 091000019 1234567891"), (24, "2209011200A094101")]),
+            record(&[(1, "5220ACME"), (51, "PPD")]),
+            entry("22", "12", "0000012550", "091000010000001", "0"),
+            entry("27", "12", "0000000500", "091000010000002", "0"),
+            entry("23", "12", "0000000000", "091000010000003", "0"),
+            entry("26", "12", "0000012550", "091000010000004", "1"),
+            record(&[(1, "799R10091000010000001"), (80, "091000010000004")]),
+            entry("21", "12", "0000000000", "091000010000005", "1"),
+            record(&[(1, "798C01091000010000002"), (80, "091000010000005")]),
+            record(&[(1, "8220")]),
+            record(&[(1, "9000001")]),
+        ]
+        .join("\n");
+        let transactions = read_transactions(file.as_bytes()).unwrap();
+        assert_eq!(transactions.len(), 4);
+        assert!(matches!(transactions[0].kind, TransactionType::Deposit));
+        assert_eq!(transactions[0].client, 12);
+        assert_eq!(transactions[0].tx, 1);
+        assert_eq!(transactions[0].amount, "125.5".parse().ok());
+        assert!(matches!(transactions[1].kind, TransactionType::Withdrawal));
+        assert_eq!(transactions[1].tx, 2);
+        assert_eq!(transactions[1].amount, "5".parse().ok());
+        assert!(matches!(transactions[2].kind, TransactionType::Dispute));
+        assert_eq!(transactions[2].tx, 1);
+        assert!(matches!(transactions[3].kind, TransactionType::Chargeback));
+        assert_eq!(transactions[3].tx, 1);
+    }
+
+    #[test]
+    fn test_blocked_records_without_newlines() {
+        let file = entry("32", "3", "0000000100", "091000010000009", "0");
+        let transactions = read_transactions(file.as_bytes()).unwrap();
+        assert_eq!(transactions.len(), 1);
+        assert_eq!(transactions[0].client, 3);
+        assert_eq!(transactions[0].tx, 9);
+        assert_eq!(transactions[0].amount, "1".parse().ok());
+    }
+
+    #[test]
+    fn test_return_without_addenda_is_rejected() {
+        let file = entry("26", "3", "0000000100", "091000010000009", "1");
+        assert!(read_transactions(file.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,177 @@
+//! Reads transactions from OFX/QFX bank and credit card statement downloads.
+//!
+//! Both the SGML based OFX 1.x files (where leaf elements aren't closed) and the XML based OFX
+//! 2.x files are understood. Every `STMTTRN` becomes a deposit when its `TRNAMT` is positive
+//! and a withdrawal otherwise, on the client given by the statement's `ACCTID`. Transactions
+//! are keyed by their `FITID`, which is used as the transaction id. Banks commonly repeat
+//! transactions in overlapping downloads, so a `FITID` that was already seen is skipped.
+
+use std::collections::HashSet;
+use std::io::Read;
+
+use thiserror::Error;
+
+use crate::{Amount, ClientId, TransactionId, TransactionInput, TransactionType};
+
+/// All errors which can happen when reading an OFX file
+#[derive(Error, Debug)]
+pub enum OfxError {
+    #[error("couldn't read the OFX file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("malformed OFX: {0}")]
+    Malformed(&'static str),
+
+    #[error("required element {0} not found")]
+    MissingElement(&'static str),
+
+    #[error("identifier {0} is not a valid numeric id")]
+    InvalidIdentifier(String),
+
+    #[error("amount {0} is not a valid amount")]
+    InvalidAmount(String),
+}
+
+/// The fields collected for a single `STMTTRN` aggregate
+#[derive(Default)]
+struct StatementTransaction {
+    fit_id: Option<String>,
+    amount: Option<String>,
+}
+
+/// Reads all transactions contained in an OFX/QFX file.
+pub fn read_transactions<R: Read>(mut reader: R) -> Result<Vec<TransactionInput>, OfxError> {
+    let mut content = String::new();
+    reader.read_to_string(&mut content)?;
+
+    let mut account: Option<ClientId> = None;
+    let mut statement_transaction: Option<StatementTransaction> = None;
+    let mut seen_fit_ids: HashSet<TransactionId> = HashSet::new();
+    let mut transactions = Vec::new();
+    // anything before the first tag is the OFX 1.x header block and is ignored
+    for token in content.split('<').skip(1) {
+        let (tag, value) = token
+            .split_once('>')
+            .ok_or(OfxError::Malformed("unterminated tag"))?;
+        let value = value.trim();
+        match tag.trim().to_ascii_uppercase().as_str() {
+            "ACCTID" => account = Some(parse_id(value)?),
+            "STMTTRN" => statement_transaction = Some(StatementTransaction::default()),
+            "/STMTTRN" => {
+                let statement_transaction = statement_transaction
+                    .take()
+                    .ok_or(OfxError::Malformed("STMTTRN closed without being opened"))?;
+                let transaction = to_transaction(account, &statement_transaction)?;
+                if seen_fit_ids.insert(transaction.tx) {
+                    transactions.push(transaction);
+                }
+            }
+            "FITID" => {
+                if let Some(t) = statement_transaction.as_mut() {
+                    t.fit_id = Some(value.to_string());
+                }
+            }
+            "TRNAMT" => {
+                if let Some(t) = statement_transaction.as_mut() {
+                    t.amount = Some(value.to_string());
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(transactions)
+}
+
+/// Maps a statement transaction to a deposit or withdrawal depending on the sign of its amount.
+fn to_transaction(
+    account: Option<ClientId>,
+    statement_transaction: &StatementTransaction,
+) -> Result<TransactionInput, OfxError> {
+    let client = account.ok_or(OfxError::MissingElement("ACCTID"))?;
+    let fit_id = statement_transaction
+        .fit_id
+        .as_ref()
+        .ok_or(OfxError::MissingElement("FITID"))?;
+    let amount = statement_transaction
+        .amount
+        .as_ref()
+        .ok_or(OfxError::MissingElement("TRNAMT"))?;
+    let signed_amount = amount
+        .parse::<Amount>()
+        .map_err(|_| OfxError::InvalidAmount(amount.clone()))?;
+
+    let kind = if signed_amount.is_negative() {
+        TransactionType::Withdrawal
+    } else {
+        TransactionType::Deposit
+    };
+    Ok(TransactionInput::new(
+        kind,
+        client,
+        parse_id(fit_id)?,
+        Some(signed_amount.abs()),
+    ))
+}
+
+fn parse_id<T: std::str::FromStr>(value: &str) -> Result<T, OfxError> {
+    value
+        .parse::<T>()
+        .map_err(|_| OfxError::InvalidIdentifier(value.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_transactions;
+    use crate::TransactionType;
+
+    #[test]
+    fn test_sgml_statement() {
+        let file = "OFXHEADER:100
+DATA:OFXSGML
+VERSION:102
+
+<OFX>
+<BANKMSGSRSV1><STMTTRNRS><STMTRS>
+<BANKACCTFROM><BANKID>121000358<ACCTID>42<ACCTTYPE>CHECKING</BANKACCTFROM>
+<BANKTRANLIST>
+<STMTTRN><TRNTYPE>CREDIT<DTPOSTED>20220901<TRNAMT>100.50<FITID>1001<NAME>Salary</STMTTRN>
+<STMTTRN><TRNTYPE>DEBIT<DTPOSTED>20220902<TRNAMT>-20.25<FITID>1002<NAME>Groceries</STMTTRN>
+<STMTTRN><TRNTYPE>CREDIT<DTPOSTED>20220901<TRNAMT>100.50<FITID>1001<NAME>Salary</STMTTRN>
+</BANKTRANLIST>
+</STMTRS></STMTTRNRS></BANKMSGSRSV1>
+</OFX>";
+        let transactions = read_transactions(file.as_bytes()).unwrap();
+        assert_eq!(transactions.len(), 2);
+        assert!(matches!(transactions[0].kind, TransactionType::Deposit));
+        assert_eq!(transactions[0].client, 42);
+        assert_eq!(transactions[0].tx, 1001);
+        assert_eq!(transactions[0].amount, "100.5".parse().ok());
+        assert!(matches!(transactions[1].kind, TransactionType::Withdrawal));
+        assert_eq!(transactions[1].tx, 1002);
+        assert_eq!(transactions[1].amount, "20.25".parse().ok());
+    }
+
+    #[test]
+    fn test_xml_statement() {
+        let file = r#"<?xml version="1.0" encoding="UTF-8"?>
+<?OFX OFXHEADER="200" VERSION="220"?>
+<OFX><CREDITCARDMSGSRSV1><CCSTMTTRNRS><CCSTMTRS>
+<CCACCTFROM><ACCTID>7</ACCTID></CCACCTFROM>
+<BANKTRANLIST>
+<STMTTRN><TRNTYPE>DEBIT</TRNTYPE><TRNAMT>-5.00</TRNAMT><FITID>3</FITID></STMTTRN>
+</BANKTRANLIST>
+</CCSTMTRS></CCSTMTTRNRS></CREDITCARDMSGSRSV1></OFX>"#;
+        let transactions = read_transactions(file.as_bytes()).unwrap();
+        assert_eq!(transactions.len(), 1);
+        assert!(matches!(transactions[0].kind, TransactionType::Withdrawal));
+        assert_eq!(transactions[0].client, 7);
+        assert_eq!(transactions[0].tx, 3);
+        assert_eq!(transactions[0].amount, "5".parse().ok());
+    }
+
+    #[test]
+    fn test_missing_fit_id_is_rejected() {
+        let file = "<OFX><ACCTID>1<STMTTRN><TRNAMT>1.00</STMTTRN></OFX>";
+        assert!(read_transactions(file.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,219 @@
+//! Reads transactions from Parquet files, with the `parquet` feature.
+//!
+//! The file needs `type`, `client`, `tx` and `amount` columns and may have a `timestamp` column,
+//! like CSV input. Types are strings, ids and timestamps any integer type, and amounts strings,
+//! decimals or floats. Other columns are ignored. The file is read a record batch at a time, so
+//! files larger than memory can be processed.
+
+use std::fs::File;
+
+use arrow_array::cast::AsArray;
+use arrow_array::types::UInt64Type;
+use arrow_array::{Array, ArrayRef, RecordBatch, StringArray, UInt64Array};
+use arrow_cast::cast;
+use arrow_schema::{ArrowError, DataType};
+use parquet::arrow::arrow_reader::{ParquetRecordBatchReader, ParquetRecordBatchReaderBuilder};
+use parquet::errors::ParquetError;
+use thiserror::Error;
+
+use crate::{Amount, ClientId, TransactionId, TransactionInput, TransactionType};
+
+/// All errors which can happen when reading transactions from Parquet
+#[derive(Error, Debug)]
+pub enum ParquetInputError {
+    #[error("couldn't read the Parquet file: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    Parquet(#[from] ParquetError),
+
+    #[error(transparent)]
+    Arrow(#[from] ArrowError),
+
+    #[error("the Parquet file has no {0} column")]
+    MissingColumn(&'static str),
+
+    #[error("row {0} has an invalid {1}")]
+    InvalidValue(u64, &'static str),
+}
+
+/// The columns of a record batch, cast to the types they are read as
+struct Columns {
+    kind: StringArray,
+    client: UInt64Array,
+    tx: UInt64Array,
+    amount: StringArray,
+    timestamp: Option<UInt64Array>,
+}
+
+impl Columns {
+    fn new(batch: &RecordBatch) -> Result<Columns, ParquetInputError> {
+        let column = |name: &'static str, data_type: &DataType| -> Result<ArrayRef, _> {
+            let column = batch
+                .column_by_name(name)
+                .ok_or(ParquetInputError::MissingColumn(name))?;
+            Ok::<_, ParquetInputError>(cast(column, data_type)?)
+        };
+        let timestamp = match batch.column_by_name("timestamp") {
+            Some(_) => Some(column("timestamp", &DataType::UInt64)?),
+            None => None,
+        };
+        Ok(Columns {
+            kind: column("type", &DataType::Utf8)?.as_string().clone(),
+            client: column("client", &DataType::UInt64)?
+                .as_primitive::<UInt64Type>()
+                .clone(),
+            tx: column("tx", &DataType::UInt64)?
+                .as_primitive::<UInt64Type>()
+                .clone(),
+            amount: column("amount", &DataType::Utf8)?.as_string().clone(),
+            timestamp: timestamp.map(|t| t.as_primitive::<UInt64Type>().clone()),
+        })
+    }
+
+    /// The transaction of a row, numbered from 1 across the file for errors
+    fn transaction(&self, index: usize, row: u64) -> Result<TransactionInput, ParquetInputError> {
+        let invalid = |column| ParquetInputError::InvalidValue(row, column);
+        let kind = self
+            .kind
+            .is_valid(index)
+            .then(|| self.kind.value(index))
+            .and_then(TransactionType::from_name)
+            .ok_or(invalid("type"))?;
+        let client = self
+            .client
+            .is_valid(index)
+            .then(|| ClientId::try_from(self.client.value(index)).ok())
+            .flatten()
+            .ok_or(invalid("client"))?;
+        let tx = self
+            .tx
+            .is_valid(index)
+            .then(|| TransactionId::try_from(self.tx.value(index)).ok())
+            .flatten()
+            .ok_or(invalid("tx"))?;
+        let amount = match self.amount.is_valid(index) {
+            true => Some(
+                self.amount
+                    .value(index)
+                    .parse::<Amount>()
+                    .map_err(|_| invalid("amount"))?,
+            ),
+            false => None,
+        };
+        let transaction = TransactionInput::new(kind, client, tx, amount);
+        match &self.timestamp {
+            Some(timestamp) if !timestamp.is_null(index) => {
+                Ok(transaction.with_timestamp(timestamp.value(index)))
+            }
+            _ => Ok(transaction),
+        }
+    }
+}
+
+/// An iterator over the transactions of a Parquet file, read a record batch at a time
+pub struct ParquetTransactions {
+    batches: ParquetRecordBatchReader,
+    columns: Option<Columns>,
+    index: usize,
+    row: u64,
+}
+
+impl ParquetTransactions {
+    /// Opens the file, reading its metadata.
+    pub fn open(path: &str) -> Result<ParquetTransactions, ParquetInputError> {
+        let batches = ParquetRecordBatchReaderBuilder::try_new(File::open(path)?)?.build()?;
+        Ok(ParquetTransactions {
+            batches,
+            columns: None,
+            index: 0,
+            row: 0,
+        })
+    }
+}
+
+impl Iterator for ParquetTransactions {
+    type Item = Result<TransactionInput, ParquetInputError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(columns) = &self.columns {
+                if self.index < columns.kind.len() {
+                    self.index += 1;
+                    self.row += 1;
+                    return Some(columns.transaction(self.index - 1, self.row));
+                }
+            }
+            let batch = match self.batches.next()? {
+                Ok(batch) => batch,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match Columns::new(&batch) {
+                Ok(columns) => self.columns = Some(columns),
+                Err(e) => return Some(Err(e)),
+            }
+            self.index = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::sync::Arc;
+    use std::{env, fs, process};
+
+    use arrow_array::{ArrayRef, Decimal128Array, Int64Array, RecordBatch, StringArray};
+    use parquet::arrow::ArrowWriter;
+
+    use super::{ParquetInputError, ParquetTransactions};
+    use crate::{TransactionInput, TransactionType};
+
+    #[test]
+    fn test_transactions_are_read_from_record_batches() {
+        let path = env::temp_dir().join(format!("tte-parquet-input-{}.parquet", process::id()));
+        let columns: [(&str, ArrayRef); 4] = [
+            (
+                "type",
+                Arc::new(StringArray::from(vec![
+                    "deposit",
+                    "withdrawal",
+                    "dispute",
+                    "bogus",
+                ])),
+            ),
+            ("client", Arc::new(Int64Array::from(vec![1, 1, 1, 2]))),
+            ("tx", Arc::new(Int64Array::from(vec![1, 2, 1, 3]))),
+            (
+                "amount",
+                Arc::new(
+                    Decimal128Array::from(vec![Some(250), Some(125), None, Some(1)])
+                        .with_precision_and_scale(10, 2)
+                        .unwrap(),
+                ),
+            ),
+        ];
+        let batch = RecordBatch::try_from_iter(columns).unwrap();
+        let mut writer =
+            ArrowWriter::try_new(File::create(&path).unwrap(), batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let transactions: Vec<Result<TransactionInput, ParquetInputError>> =
+            ParquetTransactions::open(path.to_str().unwrap())
+                .unwrap()
+                .collect();
+        assert_eq!(transactions.len(), 4);
+        let deposit = transactions[0].as_ref().unwrap();
+        assert_eq!(deposit.kind(), TransactionType::Deposit);
+        assert_eq!(deposit.amount(), "2.5".parse().ok());
+        let withdrawal = transactions[1].as_ref().unwrap();
+        assert_eq!((withdrawal.client(), withdrawal.tx()), (1, 2));
+        assert_eq!(transactions[2].as_ref().unwrap().amount(), None);
+        assert!(matches!(
+            transactions[3],
+            Err(ParquetInputError::InvalidValue(4, "type"))
+        ));
+        fs::remove_file(&path).unwrap();
+    }
+}
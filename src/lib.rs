@@ -1,63 +1,2043 @@
+//! A toy transaction engine which applies deposits, withdrawals and disputes to client accounts.
+//!
+//! The stable API is what is exported from the crate root, i.e. `TransactionEngine` with its
+//! `AccountDetails`, errors and policies, `TransactionInput`, `Money` and `MoneyOps`, and the
+//! `process_reader*` functions, together with the `input`, `output`, `rejects`, `control_totals`,
+//! `events`, `audit`, `stats` and `source` modules and, with the `async`, `ffi`, `grpc`,
+//! `postgres`, `sqlite`, `test-util` and `wasm-bindgen` features, the `async_engine`, `ffi`,
+//! `grpc`, `pg`, `sqlite`, `test_util` and `wasm` modules. The `config_file`, `daemon`, `dialect`,
+//! `diff`, `generate`, `impact`, `logging`, `policy`, `reconcile`, `scheduler`, `server`,
+//! `statement` and `validate` modules and `Config`/`run` back the command line tool and may change
+//! with it. The `server` module is built with the `server` feature, which is off by default, the
+//! `metrics` module with the `metrics` feature and `input::mmap` with the `mmap` feature.
+//!
+//! ```
+//! use toy_transaction_engine::{TransactionEngine, TransactionInput};
+//!
+//! let mut engine = TransactionEngine::new();
+//! engine
+//!     .process_transaction(TransactionInput::deposit(1, 1, "2.5".parse().unwrap()))
+//!     .unwrap();
+//! assert_eq!(engine.get_account(1).unwrap().available.to_string(), "2.5000");
+//! ```
+
+use std::collections::HashMap;
 use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, LineWriter, Read, Write};
+use std::mem;
+use std::net::TcpStream;
+use std::time::Duration;
 
+use clap::{ArgGroup, CommandFactory, FromArgMatches, Parser};
 use serde::{Deserialize, Serialize};
+use tracing::{debug_span, warn};
+
+use account_config::read_account_configs;
+use audit::AuditFormat;
+use checkpoint::{Checkpoint, CheckpointConfig, DEFAULT_CHECKPOINT_INTERVAL};
+use config_file::ConfigFile;
+use control_totals::{ControlTotals, ControlTotalsError, ControlTotalsPolicy};
+use csv::StringRecord;
+use dialect::CsvDialect;
+pub use events::EngineObserver;
+use events::{CsvResultsObserver, NdjsonObserver};
+use fees::FeeSchedule;
+use follow::DEFAULT_EMIT_EVERY;
+use idempotency::IdempotencyStore;
+use impact::ImpactSummary;
+#[cfg(feature = "mmap")]
+use input::mmap::MmapPolicy;
+use input::InputFormat;
+#[cfg(feature = "decimal")]
+pub use money::DecimalMoney;
+pub use money::{Money, MoneyOps, PrecisionPolicy};
+use ordering::{OrderingConfig, OrderingPolicy, Reorderer};
+use output::OutputFormat;
+use pipeline::{PipelineConfig, DEFAULT_BATCH_SIZE, DEFAULT_PIPELINE_DEPTH};
+use rejects::{
+    ProcessingPolicy, RejectThreshold, Rejection, RejectionError, Rejections, TooManyRejections,
+};
+use risk::HeuristicRiskAssessor;
+use rules::RulesConfig;
+use sharded::{ShardedEngineError, ShardedOutcome, ShardedTransactionEngine};
+pub use stats::ProcessingStats;
+use stats::StatsFormat;
+use transaction_engine::DEFAULT_INTEREST_PERIOD;
+pub use transaction_engine::{
+    AccountDetails, AccountStatus, AsOf, Dispute, DisputeId, DisputeState, DuplicateIdPolicy,
+    EngineConfig, EventSink, HistoryRetention, InterestPolicy, JsonLinesEventSink, Ledger,
+    LedgerAccount, LedgerError, LockedAccountPolicy, RetainPolicy, RollbackError, SnapshotError,
+    StateEvent, StatementLine, StoredTransaction, TransactionEngine, TransactionFilter,
+    TransactionProcessingError, TransactionState, ZeroAmountPolicy, SNAPSHOT_VERSION,
+};
+use wal::{FsyncPolicy, Wal};
 
+pub mod account_config;
+#[cfg(feature = "async")]
+pub mod async_engine;
+pub mod audit;
+pub mod checkpoint;
+pub mod config_file;
+pub mod control_totals;
+#[cfg(unix)]
+pub mod daemon;
+pub mod dialect;
+pub mod diff;
+pub mod events;
+mod fast_csv;
+pub mod fees;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod follow;
+pub mod generate;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod idempotency;
+pub mod impact;
+pub mod input;
+pub mod logging;
+#[cfg(feature = "metrics")]
+pub mod metrics;
+pub mod money;
+pub mod mt940;
+pub mod ordering;
+pub mod output;
+#[cfg(feature = "postgres")]
+pub mod pg;
+pub mod pipeline;
+pub mod policy;
+pub mod reconcile;
+pub mod rejects;
+pub mod risk;
+pub mod rules;
+pub mod scheduler;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod sharded;
+pub mod source;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod statement;
+pub mod stats;
+#[cfg(any(test, feature = "test-util"))]
+pub mod test_util;
 mod transaction_engine;
+pub mod validate;
+pub mod wal;
+#[cfg(feature = "wasm-bindgen")]
+pub mod wasm;
+
+/// The input path standing for stdin
+pub const STDIN_PATH: &str = "-";
 
+/// Processes a file of transactions and prints the resulting state of all accounts.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+#[command(group(ArgGroup::new("sharding").args(["shards", "partitioned"])))]
 pub struct Config {
+    /// The input file, or - to read from stdin. Glob patterns like `hourly/*.csv` are expanded
+    #[arg(default_value = STDIN_PATH)]
     pub input_path: String,
+
+    /// More CSV input files or glob patterns, processed into the same engine as the first
+    pub more_input_paths: Vec<String>,
+
+    /// The format of the input, detected from the extension of the input path if not given
+    #[arg(long, value_parser = parse_input_format)]
+    pub input_format: Option<InputFormat>,
+
+    /// Read settings from this TOML file, flags given on the command line override them
+    #[arg(long = "config", value_name = "PATH")]
+    pub config_path: Option<String>,
+
+    /// What to do when the control totals of the input don't match: ignore, warn or fail
+    #[arg(long = "control-totals", default_value = "warn", value_parser = parse_control_totals_policy)]
+    pub control_totals_policy: ControlTotalsPolicy,
+
+    /// The delimiter of CSV input
+    #[arg(long, value_name = "CHAR", default_value = ",", value_parser = parse_csv_byte)]
+    pub delimiter: u8,
+
+    /// The quote character of CSV input
+    #[arg(long, value_name = "CHAR", default_value = "\"", value_parser = parse_csv_byte)]
+    pub quote: u8,
+
+    /// CSV input has no header row, its columns are type, client, tx, amount and timestamp
+    #[arg(long, conflicts_with_all = ["column_map", "rejects_raw"])]
+    pub no_headers: bool,
+
+    /// Skip the lines of CSV input starting with this character
+    #[arg(long, value_name = "CHAR", default_value = "#", value_parser = parse_csv_byte)]
+    pub comment: u8,
+
+    /// Don't skip any lines of CSV input as comments
+    #[arg(long)]
+    pub no_comments: bool,
+
+    /// Read the names of the columns of CSV input from this TOML file
+    #[arg(long, value_name = "PATH")]
+    pub column_map: Option<String>,
+
+    /// What to do with amounts of CSV input with more than four decimal places: reject, round
+    /// or truncate
+    #[arg(long = "precision", default_value = "reject", value_parser = parse_precision_policy)]
+    pub precision_policy: PrecisionPolicy,
+
+    /// What to do with deposits and withdrawals of a zero amount: skip or reject
+    #[arg(long = "zero-amounts", default_value = "reject", value_parser = parse_zero_amount_policy)]
+    pub zero_amount_policy: ZeroAmountPolicy,
+
+    /// What to do with deposits and withdrawals reusing the id of a transaction kept for
+    /// disputes: reject, or ignore to skip them without an error
+    #[arg(long = "duplicate-ids", default_value = "reject", value_parser = parse_duplicate_id_policy)]
+    pub duplicate_id_policy: DuplicateIdPolicy,
+
+    /// Write every dispute with its state to this path, in the format of the account state
+    #[arg(long, conflicts_with = "sharding")]
+    pub disputes_report: Option<String>,
+
+    /// Write the skipped rows as CSV to this path
+    #[arg(long)]
+    pub rejects_path: Option<String>,
+
+    /// Copy the skipped rows of a CSV input file verbatim to this path, with the line they
+    /// start on in a first column, so that they can be fixed and submitted again
+    #[arg(long, value_name = "PATH")]
+    pub rejects_raw: Option<String>,
+
+    /// Start from the state saved in this snapshot instead of empty accounts
+    #[arg(long)]
+    pub load_snapshot: Option<String>,
+
+    /// Save the state after processing the input to this snapshot
+    #[arg(long)]
+    pub save_snapshot: Option<String>,
+
+    /// Export the accounts, transactions and disputes to this SQLite database at the end
+    #[cfg(feature = "sqlite")]
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["sharding", "dry_run"])]
+    pub sqlite: Option<String>,
+
+    /// Upsert the final state of accounts into the PostgreSQL database at this URL
+    #[cfg(feature = "postgres")]
+    #[arg(long, value_name = "URL", conflicts_with_all = ["sharding", "dry_run"])]
+    pub pg_url: Option<String>,
+
+    /// The table --pg-url upserts the accounts into, optionally qualified by its schema
+    #[cfg(feature = "postgres")]
+    #[arg(long, value_name = "TABLE", default_value = pg::DEFAULT_PG_TABLE, value_parser = pg::parse_pg_table)]
+    pub pg_table: String,
+
+    /// Write a checkpoint to this path while processing a CSV input file
+    #[arg(long)]
+    pub checkpoint: Option<String>,
+
+    /// The number of rows between two checkpoints
+    #[arg(long, default_value_t = DEFAULT_CHECKPOINT_INTERVAL, value_parser = clap::value_parser!(u64).range(1..))]
+    pub checkpoint_every: u64,
+
+    /// Resume the run from this checkpoint, which is also where the next checkpoints are
+    /// written unless --checkpoint is given. The input path defaults to the one of the
+    /// checkpoint.
+    #[arg(long, conflicts_with = "load_snapshot")]
+    pub resume: Option<String>,
+
+    /// Which transactions are kept for disputes: all, or deposits to save memory
+    #[arg(long = "retain", default_value = "all", value_parser = parse_retain_policy)]
+    pub retain_policy: RetainPolicy,
+
+    /// Keep at most this many deposits and withdrawals in memory, spilling older ones to disk
+    #[arg(long, value_name = "ENTRIES")]
+    pub transaction_cache_size: Option<usize>,
+
+    /// The number of rows the input is expected to have, to size the maps of the engine
+    /// up front instead of growing them while processing
+    #[arg(long, value_name = "ROWS")]
+    pub expected_rows: Option<usize>,
+
+    /// Whether input files are memory mapped instead of read in pieces: auto, always or never.
+    /// Auto maps files of 64 MiB or more, and `--mmap` alone means always. A mapped file must
+    /// not be truncated or rewritten while it is processed, which kills the run with SIGBUS
+    #[cfg(feature = "mmap")]
+    #[arg(long, value_name = "POLICY", default_value = "never", num_args = 0..=1, default_missing_value = "always", value_parser = parse_mmap_policy)]
+    pub mmap: MmapPolicy,
+
+    /// Append every applied transaction to this write-ahead log
+    #[arg(long)]
+    pub wal: Option<String>,
+
+    /// When the write-ahead log is flushed to disk: always, batch or never
+    #[arg(long, default_value = "batch", value_parser = parse_fsync_policy)]
+    pub wal_fsync: FsyncPolicy,
+
+    /// Rotate the write-ahead log once it is larger than this many bytes
+    #[arg(long)]
+    pub wal_max_bytes: Option<u64>,
+
+    /// Start from the state rebuilt from this write-ahead log, e.g. after a crash
+    #[arg(long, conflicts_with = "resume")]
+    pub replay_wal: Option<String>,
+
+    /// Roll back the transactions replayed from the write-ahead log after the one with this
+    /// sequence number, i.e. keep the first SEQ
+    #[arg(long, value_name = "SEQ", requires = "replay_wal")]
+    pub rollback_to: Option<u64>,
+
+    /// Parse CSV input on a separate thread while the transactions are applied
+    #[arg(long, conflicts_with_all = ["checkpoint", "resume"])]
+    pub pipeline: bool,
+
+    /// The number of rows the reader thread passes on at once with --pipeline
+    #[arg(long, default_value_t = DEFAULT_BATCH_SIZE)]
+    pub batch_size: usize,
+
+    /// How many batches the reader thread may be ahead with --pipeline before it waits
+    #[arg(long, default_value_t = DEFAULT_PIPELINE_DEPTH)]
+    pub pipeline_depth: usize,
+
+    /// Spread the clients of CSV input over this many engines on their own threads
+    #[arg(long, conflicts_with_all = ["checkpoint", "resume", "pipeline", "load_snapshot", "save_snapshot", "wal", "replay_wal"])]
+    pub shards: Option<usize>,
+
+    /// Process every CSV input file on its own thread with its own engine, as the files are
+    /// partitioned by client. Fails if a client appears in more than one file
+    #[arg(long, conflicts_with_all = ["checkpoint", "resume", "pipeline", "load_snapshot", "save_snapshot", "wal", "replay_wal"])]
+    pub partitioned: bool,
+
+    /// What to do with CSV and JSON Lines rows whose timestamp is before one seen earlier:
+    /// ignore, reject or sort
+    #[arg(long = "out-of-order", default_value = "ignore", value_parser = parse_ordering_policy, conflicts_with_all = ["checkpoint", "resume", "sharding"])]
+    pub ordering_policy: OrderingPolicy,
+
+    /// The seconds rows may be late and still be sorted with --out-of-order sort
+    #[arg(long, value_name = "SECONDS", default_value_t = 0)]
+    pub reorder_window: u64,
+
+    /// Keep reading a CSV input file as it grows, writing the account state every now and then
+    #[arg(long, conflicts_with_all = ["checkpoint", "resume", "pipeline", "sharding", "ordering_policy"])]
+    pub follow: bool,
+
+    /// The number of seconds between two writes of the account state with --follow
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_EMIT_EVERY)]
+    pub emit_every: u64,
+
+    /// Stream every account change and rejected transaction as NDJSON to this path
+    #[arg(long, conflicts_with = "sharding")]
+    pub events: Option<String>,
+
+    /// Append the state before and after every balance mutation to this audit log
+    #[arg(long, conflicts_with = "sharding")]
+    pub audit_log: Option<String>,
+
+    /// The format of the audit log: csv or jsonl, detected from its extension if not given
+    #[arg(long, value_parser = parse_audit_format)]
+    pub audit_format: Option<AuditFormat>,
+
+    /// Reject deposits and withdrawals whose id was applied by an earlier run with the same
+    /// store, adding the ids applied by this one once the run succeeded
+    #[arg(long, value_name = "PATH", conflicts_with = "sharding")]
+    pub idempotency_db: Option<String>,
+
+    /// Append every state change as a typed JSON event to this path, from which the state can
+    /// be rebuilt
+    #[arg(long, conflicts_with = "sharding")]
+    pub event_log: Option<String>,
+
+    /// Write the outcome of every transaction as CSV to this path
+    #[arg(long, conflicts_with = "sharding")]
+    pub results: Option<String>,
+
+    /// Charge the fees of this TOML fee schedule on deposits and withdrawals
+    #[arg(long, value_name = "PATH", conflicts_with = "sharding")]
+    pub fees: Option<String>,
+
+    /// Reject transactions breaking the limits of this TOML or JSON rules file. What counts
+    /// towards the limits isn't saved in snapshots or checkpoints, so it can't be combined with
+    /// resuming from either.
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["sharding", "resume", "load_snapshot"])]
+    pub rules: Option<String>,
+
+    /// Hold disputes of clients with many disputes and rapid withdrawals for review
+    #[arg(long, conflicts_with = "sharding")]
+    pub risk_review: bool,
+
+    /// Write the transactions held for review as CSV to this path
+    #[arg(long, value_name = "PATH", requires = "risk_review")]
+    pub risk_quarantine: Option<String>,
+
+    /// Apply the approved transactions of this CSV file, e.g. an edited quarantine export,
+    /// without assessing their risk before the input
+    #[arg(long, value_name = "PATH", conflicts_with = "sharding")]
+    pub approved: Option<String>,
+
+    /// Read the credit limits of accounts from this TOML file
+    #[arg(long, value_name = "PATH", conflicts_with = "sharding")]
+    pub accounts_config: Option<String>,
+
+    /// Pay interest on available funds at this yearly rate in basis points, following the
+    /// timestamps of the input
+    #[arg(long, value_name = "BPS", conflicts_with = "sharding")]
+    pub interest_rate: Option<u32>,
+
+    /// The seconds between two payments of interest
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_INTEREST_PERIOD, value_parser = clap::value_parser!(u64).range(1..))]
+    pub interest_period: u64,
+
+    /// What is still applied to accounts locked by a chargeback besides unlocks: settle to
+    /// apply resolves and chargebacks of the disputes still open, deposits, or block
+    #[arg(long = "locked-accounts", default_value = "settle", value_parser = parse_locked_account_policy)]
+    pub locked_account_policy: LockedAccountPolicy,
+
+    /// Reject disputes filed more than this many seconds after their transaction, by the
+    /// timestamps of the input
+    #[arg(long, value_name = "SECONDS")]
+    pub dispute_window: Option<u64>,
+
+    /// Apply administrative operations, i.e. unlock, open, close, freeze and unfreeze rows,
+    /// instead of rejecting them
+    #[arg(long)]
+    pub allow_admin_ops: bool,
+
+    /// Check after every applied transaction that its account is consistent, stopping at the
+    /// first transaction which isn't
+    #[arg(long)]
+    pub verify_invariants: bool,
+
+    /// Print a SHA-256 hash of the final state of all accounts to stderr, to check that two
+    /// runs ended with the same accounts
+    #[arg(long)]
+    pub print_state_hash: bool,
+
+    /// Print summary statistics of the run to stderr, as text or json
+    #[arg(long, value_name = "FORMAT", num_args = 0..=1, default_missing_value = "text", value_parser = parse_stats_format)]
+    pub stats: Option<StatsFormat>,
+
+    /// Write the account state to this path instead of stdout
+    #[arg(long)]
+    pub output: Option<String>,
+
+    /// The format of the account state: csv, json or jsonl
+    #[arg(long, default_value = "csv", value_parser = parse_output_format)]
+    pub format: OutputFormat,
+
+    /// Stop at the first row which can't be read or processed instead of skipping it
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Exit with a nonzero code if any row was rejected, after writing the output
+    #[arg(long, conflicts_with = "max_reject_rate")]
+    pub fail_on_rejects: bool,
+
+    /// Exit with a nonzero code if more than this share of the rows was rejected, e.g. 0.01,
+    /// after writing the output
+    #[arg(long, value_name = "RATE", value_parser = parse_reject_rate)]
+    pub max_reject_rate: Option<f64>,
+
+    /// Only log errors to stderr, not skipped rows and control total mismatches
+    #[arg(long, short, conflicts_with = "verbose")]
+    pub quiet: bool,
+
+    /// Log more to stderr: -v also logs every applied transaction, -vv everything
+    #[arg(long, short, action = clap::ArgAction::Count)]
+    pub verbose: u8,
+
+    /// Print the accounts sorted by client id
+    #[arg(long)]
+    pub sorted: bool,
+
+    /// Process the input but print a summary of the accounts it would change instead of the
+    /// state of all accounts, writing no snapshot, write-ahead log or checkpoint
+    #[arg(long, conflicts_with_all = ["save_snapshot", "wal", "checkpoint", "resume", "follow", "sharding", "idempotency_db"])]
+    pub dry_run: bool,
 }
 
 impl Config {
-    pub fn new(args: &[String]) -> Result<Config, &'static str> {
-        if args.len() < 2 {
-            return Err(
-                "Required arguments not passed. You must pass the input path as an argument",
-            );
+    /// Parses the command line arguments, including the program name, and the config file
+    /// if one is given.
+    pub fn new(args: &[String]) -> Result<Config, clap::Error> {
+        let matches = Config::command().try_get_matches_from(args)?;
+        let mut config = Config::from_arg_matches(&matches)?;
+        if let Some(config_path) = config.config_path.clone() {
+            ConfigFile::read(&config_path)
+                .map_err(|e| e.to_string())
+                .and_then(|config_file| config_file.apply(&mut config, &matches))
+                .map_err(|e| {
+                    Config::command().error(
+                        clap::error::ErrorKind::InvalidValue,
+                        format!("the config file {config_path} is invalid: {e}"),
+                    )
+                })?;
+        }
+        Ok(config)
+    }
+
+    /// The dialect of CSV input, reading the column mapping if there is one
+    pub fn csv_dialect(&self) -> Result<CsvDialect, Box<dyn Error>> {
+        let columns = match &self.column_map {
+            Some(column_map_path) => CsvDialect::read_columns(column_map_path)?,
+            None => HashMap::new(),
+        };
+        Ok(CsvDialect {
+            delimiter: self.delimiter,
+            quote: self.quote,
+            has_headers: !self.no_headers,
+            comment: (!self.no_comments).then_some(self.comment),
+            precision: self.precision_policy,
+            columns,
+        })
+    }
+
+    /// The settings the engine treats transactions with
+    pub fn engine_config(&self) -> EngineConfig {
+        EngineConfig::default()
+            .with_zero_amount_policy(self.zero_amount_policy)
+            .with_duplicate_id_policy(self.duplicate_id_policy)
+            .with_precision_policy(self.precision_policy)
+            .with_locked_account_policy(self.locked_account_policy)
+            .with_dispute_window(self.dispute_window)
+            .with_retain_policy(self.retain_policy)
+            .with_allow_admin_ops(self.allow_admin_ops)
+            .with_verify_invariants(self.verify_invariants)
+    }
+
+    /// The share of rejected rows which fails the run, if any
+    pub fn reject_threshold(&self) -> Option<RejectThreshold> {
+        if self.fail_on_rejects {
+            return Some(RejectThreshold { max_rate: 0.0 });
         }
+        self.max_reject_rate
+            .map(|max_rate| RejectThreshold { max_rate })
+    }
+
+    /// The input files with glob patterns expanded, in the order they were given
+    pub fn input_paths(&self) -> Result<Vec<String>, Box<dyn Error>> {
+        let mut input_paths = vec![self.input_path.clone()];
+        input_paths.extend(self.more_input_paths.iter().cloned());
+        input::merge::expand_input_paths(&input_paths)
+    }
 
-        let input_path = args[1].clone();
-        Ok(Config { input_path })
+    /// The format of the input, as given or detected from the input path
+    pub fn input_format(&self) -> InputFormat {
+        self.input_format
+            .unwrap_or_else(|| InputFormat::detect(&self.input_path))
+    }
+
+    /// Where and how often checkpoints are written, if they are
+    pub fn checkpoint_config(&self) -> Option<CheckpointConfig> {
+        let path = self.checkpoint.as_ref().or(self.resume.as_ref())?;
+        Some(CheckpointConfig {
+            path: path.clone(),
+            interval: self.checkpoint_every,
+        })
+    }
+
+    /// How the rows of the input are ordered
+    pub fn ordering_config(&self) -> OrderingConfig {
+        OrderingConfig {
+            policy: self.ordering_policy,
+            window: self.reorder_window,
+        }
     }
+
+    pub fn processing_policy(&self) -> ProcessingPolicy {
+        if self.strict {
+            ProcessingPolicy::Strict
+        } else {
+            ProcessingPolicy::Skip
+        }
+    }
+}
+
+pub(crate) fn parse_input_format(name: &str) -> Result<InputFormat, String> {
+    InputFormat::from_name(name).ok_or_else(|| format!("{} is not a supported input format", name))
+}
+
+pub(crate) fn parse_control_totals_policy(name: &str) -> Result<ControlTotalsPolicy, &'static str> {
+    ControlTotalsPolicy::from_name(name).ok_or("must be one of ignore, warn or fail")
+}
+
+pub(crate) fn parse_zero_amount_policy(name: &str) -> Result<ZeroAmountPolicy, &'static str> {
+    ZeroAmountPolicy::from_name(name).ok_or("must be one of skip or reject")
+}
+
+fn parse_duplicate_id_policy(name: &str) -> Result<DuplicateIdPolicy, &'static str> {
+    DuplicateIdPolicy::from_name(name).ok_or("must be one of reject or ignore")
+}
+
+fn parse_precision_policy(name: &str) -> Result<PrecisionPolicy, &'static str> {
+    PrecisionPolicy::from_name(name).ok_or("must be one of reject, round or truncate")
+}
+
+fn parse_ordering_policy(name: &str) -> Result<OrderingPolicy, &'static str> {
+    OrderingPolicy::from_name(name).ok_or("must be one of ignore, reject or sort")
+}
+
+fn parse_locked_account_policy(name: &str) -> Result<LockedAccountPolicy, &'static str> {
+    LockedAccountPolicy::from_name(name).ok_or("must be one of block, deposits or settle")
+}
+
+fn parse_retain_policy(name: &str) -> Result<RetainPolicy, &'static str> {
+    RetainPolicy::from_name(name).ok_or("must be one of all or deposits")
+}
+
+fn parse_fsync_policy(name: &str) -> Result<FsyncPolicy, &'static str> {
+    FsyncPolicy::from_name(name).ok_or("must be one of always, batch or never")
+}
+
+#[cfg(feature = "mmap")]
+fn parse_mmap_policy(name: &str) -> Result<MmapPolicy, &'static str> {
+    MmapPolicy::from_name(name).ok_or("must be one of auto, always or never")
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+fn parse_audit_format(name: &str) -> Result<AuditFormat, &'static str> {
+    AuditFormat::from_name(name).ok_or("must be one of csv or jsonl")
+}
+
+fn parse_stats_format(name: &str) -> Result<StatsFormat, &'static str> {
+    StatsFormat::from_name(name).ok_or("must be one of text or json")
+}
+
+fn parse_csv_byte(value: &str) -> Result<u8, &'static str> {
+    match value.as_bytes() {
+        [byte] => Ok(*byte),
+        _ => Err("expected a single ASCII character"),
+    }
+}
+
+fn parse_reject_rate(rate: &str) -> Result<f64, String> {
+    match rate.parse::<f64>() {
+        Ok(rate) if (0.0..=1.0).contains(&rate) => Ok(rate),
+        _ => Err(format!("{rate} is not a rate between 0 and 1")),
+    }
+}
+
+pub(crate) fn parse_output_format(name: &str) -> Result<OutputFormat, &'static str> {
+    #[cfg(not(feature = "parquet"))]
+    let formats = "must be one of csv, json or jsonl";
+    #[cfg(feature = "parquet")]
+    let formats = "must be one of csv, json, jsonl or parquet";
+    OutputFormat::from_name(name).ok_or(formats)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "lowercase")]
+#[non_exhaustive]
 pub enum TransactionType {
     Deposit,
     Withdrawal,
     Dispute,
     Resolve,
     Chargeback,
+    /// An administrative operation lifting the lock of an account
+    Unlock,
+    /// An administrative operation opening an account, or reopening a closed one
+    Open,
+    /// An administrative operation closing an account without funds
+    Close,
+    /// An administrative operation stopping funds from leaving an account
+    Freeze,
+    /// An administrative operation lifting the freeze of an account
+    Unfreeze,
+    /// Interest paid by the engine, never read from the input
+    Interest,
 }
 
+impl TransactionType {
+    /// The name of the type as used in the input
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Unlock => "unlock",
+            TransactionType::Open => "open",
+            TransactionType::Close => "close",
+            TransactionType::Freeze => "freeze",
+            TransactionType::Unfreeze => "unfreeze",
+            TransactionType::Interest => "interest",
+        }
+    }
+
+    /// Parses the type from its name in the input.
+    pub fn from_name(name: &str) -> Option<TransactionType> {
+        match name {
+            "deposit" => Some(TransactionType::Deposit),
+            "withdrawal" => Some(TransactionType::Withdrawal),
+            "dispute" => Some(TransactionType::Dispute),
+            "resolve" => Some(TransactionType::Resolve),
+            "chargeback" => Some(TransactionType::Chargeback),
+            "unlock" => Some(TransactionType::Unlock),
+            "open" => Some(TransactionType::Open),
+            "close" => Some(TransactionType::Close),
+            "freeze" => Some(TransactionType::Freeze),
+            "unfreeze" => Some(TransactionType::Unfreeze),
+            "interest" => Some(TransactionType::Interest),
+            _ => None,
+        }
+    }
+}
+
+/// The id of a client, a u16 unless the `client-id-u32` or `client-id-u64` feature widens it
+#[cfg(not(any(feature = "client-id-u32", feature = "client-id-u64")))]
 pub type ClientId = u16;
+/// The id of a client, a u16 unless the `client-id-u32` or `client-id-u64` feature widens it
+#[cfg(all(feature = "client-id-u32", not(feature = "client-id-u64")))]
+pub type ClientId = u32;
+/// The id of a client, a u16 unless the `client-id-u32` or `client-id-u64` feature widens it
+#[cfg(feature = "client-id-u64")]
+pub type ClientId = u64;
+/// The id of a transaction, a u32 unless the `tx-id-u64` feature widens it
+#[cfg(not(feature = "tx-id-u64"))]
 pub type TransactionId = u32;
-pub type Amount = f32;
+/// The id of a transaction, a u32 unless the `tx-id-u64` feature widens it
+#[cfg(feature = "tx-id-u64")]
+pub type TransactionId = u64;
+/// The amount the engine keeps balances in, `DecimalMoney` with the `decimal` feature
+#[cfg(not(feature = "decimal"))]
+pub type Amount = Money;
+/// The amount the engine keeps balances in, `DecimalMoney` with the `decimal` feature
+#[cfg(feature = "decimal")]
+pub type Amount = DecimalMoney;
+/// Seconds since the Unix epoch
+pub type Timestamp = u64;
 
 /// Type for a deserialized transaction input read from the input file
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
 pub struct TransactionInput {
     #[serde(rename = "type")]
     kind: TransactionType,
     client: ClientId,
     tx: TransactionId,
     amount: Option<Amount>,
+    /// When the transaction happened, if the input says so
+    #[serde(default, alias = "ts", skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+}
+
+impl TransactionInput {
+    /// A deposit of the amount into the account of the client
+    pub fn deposit(client: ClientId, tx: TransactionId, amount: Amount) -> TransactionInput {
+        TransactionInput::new(TransactionType::Deposit, client, tx, Some(amount))
+    }
+
+    /// A withdrawal of the amount from the account of the client
+    pub fn withdrawal(client: ClientId, tx: TransactionId, amount: Amount) -> TransactionInput {
+        TransactionInput::new(TransactionType::Withdrawal, client, tx, Some(amount))
+    }
+
+    /// A dispute of the deposit or withdrawal with the id `tx`
+    pub fn dispute(client: ClientId, tx: TransactionId) -> TransactionInput {
+        TransactionInput::new(TransactionType::Dispute, client, tx, None)
+    }
+
+    /// A resolve of the dispute of the transaction with the id `tx`
+    pub fn resolve(client: ClientId, tx: TransactionId) -> TransactionInput {
+        TransactionInput::new(TransactionType::Resolve, client, tx, None)
+    }
+
+    /// A chargeback of the disputed transaction with the id `tx`
+    pub fn chargeback(client: ClientId, tx: TransactionId) -> TransactionInput {
+        TransactionInput::new(TransactionType::Chargeback, client, tx, None)
+    }
+
+    /// An administrative unlock of the account of the client, recorded with the id `tx`
+    pub fn unlock(client: ClientId, tx: TransactionId) -> TransactionInput {
+        TransactionInput::new(TransactionType::Unlock, client, tx, None)
+    }
+
+    /// A change of the lifecycle status of the account of the client, recorded with the id
+    /// `tx`. `kind` is one of `Open`, `Close`, `Freeze` and `Unfreeze`.
+    pub fn status_change(
+        kind: TransactionType,
+        client: ClientId,
+        tx: TransactionId,
+    ) -> TransactionInput {
+        TransactionInput::new(kind, client, tx, None)
+    }
+
+    /// Creates a transaction of any kind. Deposits and withdrawals without an amount are
+    /// rejected by the engine, the amount of any other kind is ignored.
+    pub fn new(
+        kind: TransactionType,
+        client: ClientId,
+        tx: TransactionId,
+        amount: Option<Amount>,
+    ) -> TransactionInput {
+        TransactionInput {
+            kind,
+            client,
+            tx,
+            amount,
+            timestamp: None,
+        }
+    }
+
+    /// The same transaction, happening at the timestamp
+    pub fn with_timestamp(self, timestamp: Timestamp) -> TransactionInput {
+        TransactionInput {
+            timestamp: Some(timestamp),
+            ..self
+        }
+    }
+
+    pub fn kind(&self) -> TransactionType {
+        self.kind
+    }
+
+    pub fn client(&self) -> ClientId {
+        self.client
+    }
+
+    /// The id of the transaction, or of the transaction referenced by a dispute, resolve or
+    /// chargeback
+    pub fn tx(&self) -> TransactionId {
+        self.tx
+    }
+
+    pub fn amount(&self) -> Option<Amount> {
+        self.amount
+    }
+
+    pub fn timestamp(&self) -> Option<Timestamp> {
+        self.timestamp
+    }
+}
+
+/// The main method to run the library. Returns the rows which were skipped.
+pub fn run(mut config: Config) -> Result<Vec<Rejection>, Box<dyn Error>> {
+    let resume_from = match &config.resume {
+        Some(checkpoint_path) => Some(Checkpoint::read_from(checkpoint_path)?),
+        None => None,
+    };
+    if let Some(checkpoint) = &resume_from {
+        if config.input_path == STDIN_PATH {
+            config.input_path = checkpoint.input_path().to_string();
+        }
+    }
+    let input_paths = config.input_paths()?;
+    config.input_path = input_paths[0].clone();
+    if input_paths.len() > 1 {
+        if config.input_format() != InputFormat::Csv || input_paths.contains(&STDIN_PATH.into()) {
+            return Err("multiple inputs are only supported for CSV input files".into());
+        }
+        if config.shards.is_some()
+            || config.checkpoint_config().is_some()
+            || config.follow
+            || config.pipeline
+            || config.rejects_raw.is_some()
+        {
+            return Err("multiple inputs can't be combined with --shards, checkpoints, --follow, --pipeline or --rejects-raw".into());
+        }
+    }
+    if config.rejects_raw.is_some()
+        && (config.input_format() != InputFormat::Csv || config.input_path == STDIN_PATH)
+    {
+        return Err("--rejects-raw is only supported for CSV input files".into());
+    }
+    if let (Some(wal_path), Some(replay_path), Some(_)) =
+        (&config.wal, &config.replay_wal, config.rollback_to)
+    {
+        // the rolled back transactions would stay in the log and be applied again by the
+        // next replay
+        if same_file(wal_path, replay_path) {
+            return Err(
+                "--rollback-to can't write to the write-ahead log it replays, pass another --wal"
+                    .into(),
+            );
+        }
+    }
+    let dialect = config.csv_dialect()?;
+    if let Some(shards) = config.shards {
+        return run_sharded(&config, &dialect, shards);
+    }
+    if config.partitioned {
+        return run_partitioned(&config, &dialect, &input_paths);
+    }
+    let checkpoints = config.checkpoint_config();
+    if checkpoints.is_some()
+        && (config.input_format() != InputFormat::Csv || config.input_path == STDIN_PATH)
+    {
+        return Err("checkpoints are only supported for CSV input files".into());
+    }
+    if config.follow
+        && (config.input_format() != InputFormat::Csv || config.input_path == STDIN_PATH)
+    {
+        return Err("--follow is only supported for CSV input files".into());
+    }
+
+    let mut transaction_engine = match &config.load_snapshot {
+        Some(snapshot_path) => {
+            TransactionEngine::restore(BufReader::new(File::open(snapshot_path)?))?
+        }
+        None => TransactionEngine::new(),
+    };
+    transaction_engine.set_config(config.engine_config());
+    if let Some(rate_bps) = config.interest_rate {
+        transaction_engine.set_interest_policy(Some(InterestPolicy {
+            rate_bps,
+            period: config.interest_period,
+        }));
+    }
+    if let Some(fees_path) = &config.fees {
+        transaction_engine.set_fee_schedule(Some(FeeSchedule::from_path(fees_path)?));
+    }
+    if let Some(rules_path) = &config.rules {
+        for rule in RulesConfig::from_path(rules_path)?.rules() {
+            transaction_engine.add_rule(rule);
+        }
+    }
+    if config.risk_review {
+        transaction_engine.set_risk_assessor(Some(Box::new(HeuristicRiskAssessor::default())));
+    }
+    if let Some(accounts_config_path) = &config.accounts_config {
+        for (client, account_config) in read_account_configs(accounts_config_path)? {
+            transaction_engine.set_account_config(client, account_config);
+        }
+    }
+    if let Some(cache_size) = config.transaction_cache_size {
+        transaction_engine.set_transaction_cache_size(cache_size)?;
+    }
+    if let Some(expected_rows) = config.expected_rows {
+        transaction_engine.reserve(expected_rows);
+    }
+    if let Some(wal_path) = &config.replay_wal {
+        if config.rollback_to.is_some() {
+            transaction_engine.set_undo_limit(usize::MAX);
+        }
+        transaction_engine.replay_wal(wal_path)?;
+        if let Some(seq) = config.rollback_to {
+            transaction_engine.rollback_to(seq)?;
+            transaction_engine.set_undo_limit(0);
+        }
+    }
+    if let Some(wal_path) = &config.wal {
+        transaction_engine.set_wal(Wal::open(wal_path, config.wal_fsync, config.wal_max_bytes)?);
+    }
+    if let Some(audit_path) = &config.audit_log {
+        let audit_format = config
+            .audit_format
+            .unwrap_or_else(|| AuditFormat::detect(audit_path));
+        let audit_log = File::options().create(true).append(true).open(audit_path)?;
+        let continued = audit_log.metadata()?.len() > 0;
+        transaction_engine.set_audit_sink(audit::sink_for(
+            BufWriter::new(audit_log),
+            audit_format,
+            continued,
+        ));
+    }
+    if let Some(idempotency_path) = &config.idempotency_db {
+        transaction_engine.set_idempotency_store(IdempotencyStore::open(idempotency_path)?);
+    }
+    if let Some(event_log_path) = &config.event_log {
+        let event_log = File::options()
+            .create(true)
+            .append(true)
+            .open(event_log_path)?;
+        transaction_engine.set_event_sink(JsonLinesEventSink::new(BufWriter::new(event_log)));
+    }
+    let results = match &config.results {
+        Some(results_path) => {
+            let results = CsvResultsObserver::new(File::create(results_path)?);
+            transaction_engine.add_observer(results.clone());
+            Some(results)
+        }
+        None => None,
+    };
+    if let Some(events_path) = &config.events {
+        transaction_engine.add_observer(NdjsonObserver::new(LineWriter::new(File::create(
+            events_path,
+        )?)));
+    }
+    let accounts_before: HashMap<ClientId, AccountDetails> = if config.dry_run {
+        transaction_engine
+            .accounts()
+            .map(|(client, account)| (client, account.clone()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+    let mut rejections = Rejections::new(config.processing_policy(), !config.quiet);
+    if let Some(results) = results {
+        rejections.set_results(results);
+    }
+    if let Some(approved_path) = &config.approved {
+        process_approved_transactions(&mut transaction_engine, approved_path)?;
+    }
+    match config.input_format() {
+        InputFormat::Csv => {
+            // a mismatch is only ever logged with the warn policy
+            let control_totals_policy = match config.control_totals_policy {
+                ControlTotalsPolicy::Warn if config.quiet => ControlTotalsPolicy::Ignore,
+                control_totals_policy => control_totals_policy,
+            };
+            match &checkpoints {
+                Some(checkpoints) => checkpoint::process_csv_file_with_checkpoints(
+                    &mut transaction_engine,
+                    &config.input_path,
+                    &dialect,
+                    control_totals_policy,
+                    &mut rejections,
+                    checkpoints,
+                    resume_from,
+                )?,
+                None if config.follow => follow::process_csv_following(
+                    &mut transaction_engine,
+                    &config.input_path,
+                    &dialect,
+                    control_totals_policy,
+                    &mut rejections,
+                    Duration::from_secs(config.emit_every),
+                    |transaction_engine| emit_state(&config, transaction_engine),
+                )?,
+                None if input_paths.len() > 1 => input::merge::process_csv_files(
+                    &mut transaction_engine,
+                    &input_paths,
+                    &dialect,
+                    control_totals_policy,
+                    &mut rejections,
+                    config.ordering_config(),
+                )?,
+                None if config.pipeline => pipeline::process_csv_pipelined(
+                    &mut transaction_engine,
+                    open_input(&config)?,
+                    dialect.clone(),
+                    control_totals_policy,
+                    &mut rejections,
+                    PipelineConfig {
+                        batch_size: config.batch_size,
+                        depth: config.pipeline_depth,
+                    },
+                    config.ordering_config(),
+                )?,
+                None => process_csv_in_order(
+                    &mut transaction_engine,
+                    open_input(&config)?,
+                    &dialect,
+                    control_totals_policy,
+                    &mut rejections,
+                    config.ordering_config(),
+                )?,
+            }
+        }
+        InputFormat::JsonLines => {
+            let reader = BufReader::new(open_input(&config)?);
+            let mut reorderer = Reorderer::new(config.ordering_config());
+            for line in input::json_lines::JsonLines::new(reader) {
+                let line = line?;
+                let timestamp = line.transaction.as_ref().ok().and_then(|t| t.timestamp());
+                if let Err(late) = reorderer.push(line, timestamp) {
+                    let error = RejectionError::OutOfOrder {
+                        timestamp: late.timestamp,
+                        latest: late.latest,
+                    };
+                    rejections.reject_read(
+                        Rejection::from_line(late.item.line, &late.item.text, error),
+                        late.item.transaction.as_ref().ok(),
+                    )?;
+                }
+                while let Some(line) = reorderer.pop_ready() {
+                    process_json_line(&mut transaction_engine, line, &mut rejections)?;
+                }
+            }
+            while let Some(line) = reorderer.pop() {
+                process_json_line(&mut transaction_engine, line, &mut rejections)?;
+            }
+        }
+        #[cfg(feature = "iso20022")]
+        InputFormat::Iso20022 => {
+            let reader = BufReader::new(open_input(&config)?);
+            let transactions = input::iso20022::read_transactions(reader)?;
+            apply_transactions(&mut transaction_engine, transactions, &mut rejections)?;
+        }
+        InputFormat::Ofx => {
+            let reader = BufReader::new(open_input(&config)?);
+            let transactions = input::ofx::read_transactions(reader)?;
+            apply_transactions(&mut transaction_engine, transactions, &mut rejections)?;
+        }
+        InputFormat::Fix => {
+            let reader: Box<dyn BufRead> = match config.input_path.strip_prefix("tcp://") {
+                Some(address) => Box::new(BufReader::new(TcpStream::connect(address)?)),
+                None => Box::new(BufReader::new(open_input(&config)?)),
+            };
+            // fills are applied as they arrive, as the stream may never end
+            for (index, transaction) in input::fix::FixMessages::new(reader).enumerate() {
+                let transaction = match transaction {
+                    Ok(transaction) => transaction,
+                    // the stream can't be read any further
+                    Err(e @ input::fix::FixError::Io(_)) => return Err(e.into()),
+                    Err(e) => {
+                        rejections.reject(Rejection::from_line(
+                            index as u64 + 1,
+                            "",
+                            RejectionError::Parse(e.to_string()),
+                        ))?;
+                        continue;
+                    }
+                };
+                let _row = debug_span!("row", position = index + 1).entered();
+                if let Err(e) = transaction_engine.process_transaction(transaction) {
+                    rejections.reject(Rejection::from_transaction(
+                        index as u64 + 1,
+                        &transaction,
+                        e.into(),
+                    ))?;
+                }
+            }
+        }
+        #[cfg(feature = "parquet")]
+        InputFormat::Parquet => {
+            let transactions = input::parquet::ParquetTransactions::open(&config.input_path)?;
+            for (index, transaction) in transactions.enumerate() {
+                let transaction = transaction?;
+                let _row = debug_span!("row", position = index + 1).entered();
+                if let Err(e) = transaction_engine.process_transaction(transaction) {
+                    rejections.reject(Rejection::from_transaction(
+                        index as u64 + 1,
+                        &transaction,
+                        e.into(),
+                    ))?;
+                }
+            }
+        }
+        InputFormat::Nacha => {
+            let reader = BufReader::new(open_input(&config)?);
+            let transactions = input::nacha::read_transactions(reader)?;
+            apply_transactions(&mut transaction_engine, transactions, &mut rejections)?;
+        }
+    }
+
+    transaction_engine.sync_wal()?;
+    transaction_engine.flush_observers()?;
+    transaction_engine.flush_audit()?;
+    transaction_engine.flush_events()?;
+    // a bug in how transactions are posted must not end up in the output
+    transaction_engine.check_ledger()?;
+    if let Some(snapshot_path) = &config.save_snapshot {
+        transaction_engine.snapshot(BufWriter::new(File::create(snapshot_path)?))?;
+    }
+
+    let rejections = rejections.into_vec();
+    if config.dry_run {
+        let stats = stats_with_rejections(transaction_engine.stats(), &rejections);
+        let summary = ImpactSummary::new(&accounts_before, &transaction_engine, stats);
+        let mut output: Box<dyn Write> = match &config.output {
+            Some(output_path) => Box::new(File::create(output_path)?),
+            None => Box::new(io::stdout()),
+        };
+        writeln!(output, "{}", summary)?;
+        write_rejection_files(&config, &dialect, &rejections)?;
+        check_rejections(&config, &summary.stats)?;
+        return Ok(rejections);
+    }
+    if let Some(stats_format) = config.stats {
+        print_stats(transaction_engine.stats(), &rejections, stats_format)?;
+    }
+    if config.print_state_hash {
+        eprintln!("state hash: {}", transaction_engine.state_hash());
+    }
+
+    write_disputes_report(&config, &transaction_engine)?;
+    #[cfg(feature = "sqlite")]
+    if let Some(database_path) = &config.sqlite {
+        sqlite::export(&transaction_engine, database_path)?;
+    }
+    #[cfg(feature = "postgres")]
+    if let Some(url) = &config.pg_url {
+        pg::export(transaction_engine.accounts(), url, &config.pg_table)?;
+    }
+    if let Some(quarantine_path) = &config.risk_quarantine {
+        risk::write_quarantine(
+            BufWriter::new(File::create(quarantine_path)?),
+            transaction_engine.quarantine(),
+        )?;
+    }
+    let stats = stats_with_rejections(transaction_engine.stats(), &rejections);
+    let accounts = if config.sorted {
+        transaction_engine.sorted_accounts()
+    } else {
+        transaction_engine.accounts().collect()
+    };
+    let rejections = write_results(&config, &dialect, accounts, rejections)?;
+    // only once the output and the state holding them were written, so that a run which failed
+    // to write them can be repeated
+    transaction_engine.commit_idempotency()?;
+    check_rejections(&config, &stats)?;
+    Ok(rejections)
 }
 
-/// The main method to run the library
-pub fn run(config: Config) -> Result<(), Box<dyn Error>> {
-    let mut transaction_engine = transaction_engine::TransactionEngine::new();
-    let mut reader = csv::ReaderBuilder::new()
-        .trim(csv::Trim::All)
-        .flexible(true)
-        .from_path(config.input_path)?;
-    for row_result in reader.deserialize() {
-        let transaction: TransactionInput = row_result?;
+/// Runs with the clients of CSV input spread over several engines. Rejections are collected
+/// from all engines and reported in the order of the input once every row was applied.
+fn run_sharded(
+    config: &Config,
+    dialect: &CsvDialect,
+    shards: usize,
+) -> Result<Vec<Rejection>, Box<dyn Error>> {
+    if config.input_format() != InputFormat::Csv {
+        return Err("sharding is only supported for CSV input".into());
+    }
+    let engines = sharded_engines(config, shards.max(1))?;
+    let processing_policy = config.processing_policy();
+    let mut sharded_engine =
+        ShardedTransactionEngine::with_engines_and_policy(engines, processing_policy);
+    let control_totals_policy = match config.control_totals_policy {
+        ControlTotalsPolicy::Warn if config.quiet => ControlTotalsPolicy::Ignore,
+        policy => policy,
+    };
+    let mut rejected = Vec::new();
+    let read = read_csv_with_dialect(open_input(config)?, dialect, control_totals_policy, |row| {
+        // the rest of the input isn't read once a rejection stopped the shards
+        if sharded_engine.is_stopped() {
+            return Err(ShardedEngineError::Stopped.into());
+        }
+        match row.transaction {
+            Ok(transaction) => sharded_engine.submit_record(row.line, transaction, &row.record)?,
+            Err(e) => {
+                rejected.push(Rejection::from_record(
+                    row.line,
+                    &row.record,
+                    RejectionError::Parse(e.to_string()),
+                ));
+                if processing_policy == ProcessingPolicy::Strict {
+                    sharded_engine.stop();
+                }
+            }
+        }
+        Ok(())
+    });
+    match read {
+        Err(e) if matches!(e.downcast_ref(), Some(ShardedEngineError::Stopped)) => {}
+        read => read?,
+    }
+    let mut outcome = sharded_engine.finish()?;
+    rejected.append(&mut outcome.rejections);
+    rejected.sort_by_key(|rejection| rejection.line);
+    outcome.rejections = rejected;
+    write_sharded_results(config, dialect, outcome)
+}
+
+/// Runs with every CSV input file, partitioned by client, processed by its own engine on its
+/// own thread.
+fn run_partitioned(
+    config: &Config,
+    dialect: &CsvDialect,
+    input_paths: &[String],
+) -> Result<Vec<Rejection>, Box<dyn Error>> {
+    if config.input_format() != InputFormat::Csv || input_paths.contains(&STDIN_PATH.into()) {
+        return Err("--partitioned is only supported for CSV input files".into());
+    }
+    let control_totals_policy = match config.control_totals_policy {
+        ControlTotalsPolicy::Warn if config.quiet => ControlTotalsPolicy::Ignore,
+        policy => policy,
+    };
+    let outcome = input::merge::process_partitioned_files(
+        sharded_engines(config, input_paths.len())?,
+        input_paths,
+        dialect,
+        control_totals_policy,
+    )?;
+    write_sharded_results(config, dialect, outcome)
+}
+
+/// The engines of a sharded run, with the settings of the config
+fn sharded_engines(config: &Config, count: usize) -> io::Result<Vec<TransactionEngine>> {
+    (0..count)
+        .map(|_| {
+            let mut transaction_engine = TransactionEngine::with_config(config.engine_config());
+            if let Some(cache_size) = config.transaction_cache_size {
+                transaction_engine.set_transaction_cache_size(cache_size)?;
+            }
+            // the rows are spread over the engines
+            if let Some(expected_rows) = config.expected_rows {
+                transaction_engine.reserve(expected_rows / count);
+            }
+            Ok(transaction_engine)
+        })
+        .collect()
+}
+
+/// Reports the rejections of a sharded run and writes the accounts of all its engines.
+fn write_sharded_results(
+    config: &Config,
+    dialect: &CsvDialect,
+    mut outcome: ShardedOutcome,
+) -> Result<Vec<Rejection>, Box<dyn Error>> {
+    let rejected = mem::take(&mut outcome.rejections);
+    let mut rejections = Rejections::new(config.processing_policy(), !config.quiet);
+    for rejection in rejected {
+        rejections.reject(rejection)?;
+    }
+    let rejections = rejections.into_vec();
+    let mut stats = ProcessingStats::default();
+    for transaction_engine in outcome.engines() {
+        stats.merge(&transaction_engine.stats());
+    }
+    if let Some(stats_format) = config.stats {
+        print_stats(stats.clone(), &rejections, stats_format)?;
+    }
+    if config.print_state_hash {
+        eprintln!(
+            "state hash: {}",
+            transaction_engine::state_hash(&outcome.sorted_accounts())
+        );
+    }
+    let stats = stats_with_rejections(stats, &rejections);
+    let rejections = write_results(config, dialect, outcome.sorted_accounts(), rejections)?;
+    check_rejections(config, &stats)?;
+    Ok(rejections)
+}
+
+/// Prints the statistics of the engine to stderr, counting the rows which couldn't be read
+/// as rejections too.
+fn print_stats(
+    stats: ProcessingStats,
+    rejections: &[Rejection],
+    stats_format: StatsFormat,
+) -> Result<(), Box<dyn Error>> {
+    let stats = stats_with_rejections(stats, rejections);
+    match stats_format {
+        StatsFormat::Text => eprintln!("{}", stats),
+        StatsFormat::Json => eprintln!("{}", serde_json::to_string(&stats)?),
+    }
+    Ok(())
+}
+
+/// The statistics of the engine with the rows which never reached it counted as rejections.
+fn stats_with_rejections(mut stats: ProcessingStats, rejections: &[Rejection]) -> ProcessingStats {
+    for rejection in rejections {
+        if let RejectionError::Parse(_) | RejectionError::OutOfOrder { .. } = rejection.error {
+            stats.count_rejection(rejection.error.kind());
+        }
+    }
+    stats
+}
+
+/// Logs the number of rejected rows, if there are any, and checks it against the threshold of
+/// the run, if there is one. Only rows read from the input count, not interest payments.
+fn check_rejections(config: &Config, stats: &ProcessingStats) -> Result<(), TooManyRejections> {
+    let rejected = stats.rejection_count();
+    let rows = stats.row_count();
+    if rejected > 0 {
+        warn!("{} of {} rows were rejected", rejected, rows);
+    }
+    match config.reject_threshold() {
+        Some(threshold) => threshold.check(rejected, rows),
+        None => Ok(()),
+    }
+}
+
+/// Whether the paths name the same file, also if they are spelled differently
+fn same_file(path: &str, other: &str) -> bool {
+    match (fs::canonicalize(path), fs::canonicalize(other)) {
+        (Ok(path), Ok(other)) => path == other,
+        _ => path == other,
+    }
+}
+
+/// Writes the current state while following the input. An output file is replaced at once, so
+/// that readers never see a partially written state.
+fn emit_state(
+    config: &Config,
+    transaction_engine: &TransactionEngine,
+) -> Result<(), Box<dyn Error>> {
+    let accounts = if config.sorted {
+        transaction_engine.sorted_accounts()
+    } else {
+        transaction_engine.accounts().collect()
+    };
+    match &config.output {
+        Some(output_path) => {
+            let temporary_path = format!("{}.tmp", output_path);
+            output::write_accounts(File::create(&temporary_path)?, accounts, config.format)?;
+            fs::rename(&temporary_path, output_path)?;
+        }
+        None => output::write_accounts(io::stdout().lock(), accounts, config.format)?,
+    }
+    Ok(())
+}
+
+/// Writes the accounts to the output and the rejections to the rejects file, if requested.
+fn write_results(
+    config: &Config,
+    dialect: &CsvDialect,
+    accounts: Vec<(ClientId, &AccountDetails)>,
+    rejections: Vec<Rejection>,
+) -> Result<Vec<Rejection>, Box<dyn Error>> {
+    let output: Box<dyn Write> = match &config.output {
+        Some(output_path) => Box::new(File::create(output_path)?),
+        None => Box::new(io::stdout()),
+    };
+    output::write_accounts(output, accounts, config.format)?;
+
+    write_rejection_files(config, dialect, &rejections)?;
+    Ok(rejections)
+}
+
+/// Writes the rejections to the rejects file and copies the rejected rows to the raw rejects
+/// file, if requested.
+fn write_rejection_files(
+    config: &Config,
+    dialect: &CsvDialect,
+    rejections: &[Rejection],
+) -> Result<(), Box<dyn Error>> {
+    if let Some(rejects_path) = &config.rejects_path {
+        rejects::write_rejections(File::create(rejects_path)?, rejections)?;
+    }
+    if let Some(raw_path) = &config.rejects_raw {
+        rejects::write_raw_rejections(
+            BufReader::new(File::open(&config.input_path)?),
+            dialect,
+            BufWriter::new(File::create(raw_path)?),
+            rejections,
+        )?;
+    }
+    Ok(())
+}
+
+/// Applies the transactions somebody approved after they were held for review. A transaction
+/// which can't be applied is logged like a rejected row of the input.
+fn process_approved_transactions(
+    transaction_engine: &mut TransactionEngine,
+    approved_path: &str,
+) -> Result<(), Box<dyn Error>> {
+    for transaction in csv_reader(File::open(approved_path)?).deserialize() {
+        let transaction: TransactionInput = transaction?;
+        if let Err(e) = transaction_engine.process_approved_transaction(transaction) {
+            warn!(
+                tx = transaction.tx,
+                "approved transaction couldn't be applied: {}", e
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Writes the disputes of the engine to the disputes report, if one was requested.
+fn write_disputes_report(
+    config: &Config,
+    transaction_engine: &TransactionEngine,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(report_path) = &config.disputes_report {
+        output::write_disputes(
+            BufWriter::new(File::create(report_path)?),
+            transaction_engine.disputes(),
+            config.format,
+        )?;
+    }
+    Ok(())
+}
+
+/// Processes CSV transactions read from any source and returns the resulting engine, so that
+/// its state can be queried or written by the caller. Control totals carried by the input are
+/// verified with the default `warn` policy.
+pub fn process_reader<R: Read>(reader: R) -> Result<TransactionEngine, Box<dyn Error>> {
+    let (transaction_engine, _) = process_reader_with_rejections(reader)?;
+    Ok(transaction_engine)
+}
+
+/// Like `process_reader`, but also returns the rows which were skipped.
+pub fn process_reader_with_rejections<R: Read>(
+    reader: R,
+) -> Result<(TransactionEngine, Vec<Rejection>), Box<dyn Error>> {
+    process_reader_with_policy(reader, ProcessingPolicy::Skip)
+}
+
+/// Like `process_reader_with_rejections`, handling rows which can't be read or processed with
+/// the given policy. With `ProcessingPolicy::Strict`, the first such row is returned as the error.
+pub fn process_reader_with_policy<R: Read>(
+    reader: R,
+    processing_policy: ProcessingPolicy,
+) -> Result<(TransactionEngine, Vec<Rejection>), Box<dyn Error>> {
+    let mut transaction_engine = TransactionEngine::new();
+    let mut rejections = Rejections::new(processing_policy, true);
+    process_csv(
+        &mut transaction_engine,
+        reader,
+        ControlTotalsPolicy::Warn,
+        &mut rejections,
+    )?;
+    Ok((transaction_engine, rejections.into_vec()))
+}
+
+/// Like `process_reader`, but deserializing every row with serde instead of parsing the rows
+/// in the engine's own columns by hand. The result is the same, only slower, so this is mostly
+/// useful to measure the difference.
+pub fn process_reader_with_serde<R: Read>(reader: R) -> Result<TransactionEngine, Box<dyn Error>> {
+    let mut transaction_engine = TransactionEngine::new();
+    let mut rejections = Rejections::new(ProcessingPolicy::Skip, true);
+    read_csv(reader, ControlTotalsPolicy::Warn, |row| {
+        Ok(process_row(&mut transaction_engine, row, &mut rejections)?)
+    })?;
+    Ok(transaction_engine)
+}
+
+/// Opens the input path of the run for reading, memory mapped as `--mmap` says, or stdin if
+/// the path is `-`.
+fn open_input(config: &Config) -> io::Result<Box<dyn Read + Send>> {
+    if config.input_path == STDIN_PATH {
+        return Ok(Box::new(io::stdin()));
+    }
+    #[cfg(feature = "mmap")]
+    return input::mmap::open_file(&config.input_path, config.mmap);
+    #[cfg(not(feature = "mmap"))]
+    Ok(Box::new(File::open(&config.input_path)?))
+}
+
+/// Reads and processes CSV input, verifying its control totals if it carries any. Amounts
+/// with more than four decimal places are handled with the precision policy of the engine.
+pub(crate) fn process_csv<R: Read>(
+    transaction_engine: &mut TransactionEngine,
+    input: R,
+    control_totals_policy: ControlTotalsPolicy,
+    rejections: &mut Rejections,
+) -> Result<(), Box<dyn Error>> {
+    let dialect = CsvDialect {
+        precision: transaction_engine.config().precision_policy,
+        ..CsvDialect::default()
+    };
+    fast_csv::process_csv_fast(
+        transaction_engine,
+        input,
+        &dialect,
+        control_totals_policy,
+        rejections,
+    )
+}
+
+/// Reads and processes CSV input in the dialect like `process_csv`, putting the rows in the
+/// order of their timestamps as configured.
+pub(crate) fn process_csv_in_order<R: Read>(
+    transaction_engine: &mut TransactionEngine,
+    input: R,
+    dialect: &CsvDialect,
+    control_totals_policy: ControlTotalsPolicy,
+    rejections: &mut Rejections,
+    ordering_config: OrderingConfig,
+) -> Result<(), Box<dyn Error>> {
+    let mut reorderer = Reorderer::new(ordering_config);
+    if reorderer.passes_through() {
+        return fast_csv::process_csv_fast(
+            transaction_engine,
+            input,
+            dialect,
+            control_totals_policy,
+            rejections,
+        );
+    }
+    read_csv_with_dialect(input, dialect, control_totals_policy, |row| {
+        Ok(process_row_in_order(
+            transaction_engine,
+            &mut reorderer,
+            row,
+            rejections,
+        )?)
+    })?;
+    Ok(process_held_rows(
+        transaction_engine,
+        &mut reorderer,
+        rejections,
+    )?)
+}
+
+/// Passes a row read from CSV input to the reorderer, processing every row whose turn came.
+/// A row which came too late is recorded as rejected.
+pub(crate) fn process_row_in_order(
+    transaction_engine: &mut TransactionEngine,
+    reorderer: &mut Reorderer<CsvRow>,
+    row: CsvRow,
+    rejections: &mut Rejections,
+) -> Result<(), Rejection> {
+    if reorderer.passes_through() {
+        return process_row(transaction_engine, row, rejections);
+    }
+    let timestamp = row.transaction.as_ref().ok().and_then(|t| t.timestamp());
+    if let Err(late) = reorderer.push(row, timestamp) {
+        let error = RejectionError::OutOfOrder {
+            timestamp: late.timestamp,
+            latest: late.latest,
+        };
+        rejections.reject_read(
+            Rejection::from_record(late.item.line, &late.item.record, error),
+            late.item.transaction.as_ref().ok(),
+        )?;
+    }
+    while let Some(row) = reorderer.pop_ready() {
+        process_row(transaction_engine, row, rejections)?;
+    }
+    Ok(())
+}
+
+/// Processes the rows the reorderer still holds back, at the end of the input.
+pub(crate) fn process_held_rows(
+    transaction_engine: &mut TransactionEngine,
+    reorderer: &mut Reorderer<CsvRow>,
+    rejections: &mut Rejections,
+) -> Result<(), Rejection> {
+    while let Some(row) = reorderer.pop() {
+        process_row(transaction_engine, row, rejections)?;
+    }
+    Ok(())
+}
+
+/// Processes a row read from CSV input, recording it as rejected if it can't be read or
+/// processed.
+pub(crate) fn process_row(
+    transaction_engine: &mut TransactionEngine,
+    row: CsvRow,
+    rejections: &mut Rejections,
+) -> Result<(), Rejection> {
+    let _row = debug_span!("row", line = row.line).entered();
+    let result = match row.transaction {
+        Ok(transaction) => transaction_engine
+            .process_transaction(transaction)
+            .map_err(RejectionError::from),
+        Err(e) => Err(RejectionError::Parse(e.to_string())),
+    };
+    if let Err(e) = result {
+        rejections.reject(Rejection::from_record(row.line, &row.record, e))?;
+    }
+    Ok(())
+}
+
+/// A transaction row read from CSV input
+pub(crate) struct CsvRow {
+    /// The line the row starts on
+    pub line: u64,
+    pub record: StringRecord,
+    pub transaction: Result<TransactionInput, csv::Error>,
+}
+
+/// Reads CSV input, passing every transaction row to the callback and verifying the control
+/// totals at the end if the input carries any. Reading stops at the first error returned by the
+/// callback.
+pub(crate) fn read_csv<R: Read>(
+    input: R,
+    control_totals_policy: ControlTotalsPolicy,
+    on_row: impl FnMut(CsvRow) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    read_csv_with_dialect(input, &CsvDialect::default(), control_totals_policy, on_row)
+}
+
+/// Reads CSV input in the dialect like `read_csv`.
+pub(crate) fn read_csv_with_dialect<R: Read>(
+    input: R,
+    dialect: &CsvDialect,
+    control_totals_policy: ControlTotalsPolicy,
+    mut on_row: impl FnMut(CsvRow) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut reader = dialect.reader(input);
+    let headers = dialect.headers(&mut reader)?;
+    let mut control_totals = ControlTotals::new();
+    for record_result in reader.records() {
+        if let Some(row) = read_row(
+            record_result?,
+            &headers,
+            dialect.precision,
+            &mut control_totals,
+        )? {
+            on_row(row)?;
+        }
+    }
+    verify_control_totals(&control_totals, control_totals_policy)?;
+    Ok(())
+}
+
+/// Creates a reader for CSV input in the format of the input file.
+pub(crate) fn csv_reader<R: Read>(input: R) -> csv::Reader<R> {
+    CsvDialect::default().reader(input)
+}
+
+/// Deserializes a record of CSV input, adding it to the control totals. Returns None for
+/// control records and rows without any values. An amount with more than four decimal places
+/// is rounded or truncated as the precision policy says.
+pub(crate) fn read_row(
+    record: StringRecord,
+    headers: &StringRecord,
+    precision_policy: PrecisionPolicy,
+    control_totals: &mut ControlTotals,
+) -> Result<Option<CsvRow>, ControlTotalsError> {
+    if record.iter().all(str::is_empty) || control_totals.try_record_control_record(&record)? {
+        return Ok(None);
+    }
+    let transaction = match with_precision(&record, headers, precision_policy) {
+        Some(adjusted) => adjusted.deserialize::<TransactionInput>(Some(headers)),
+        None => record.deserialize::<TransactionInput>(Some(headers)),
+    };
+    if let Ok(transaction) = &transaction {
+        control_totals.record_transaction(transaction);
+    }
+    Ok(Some(CsvRow {
+        line: record.position().map_or(0, |position| position.line()),
+        record,
+        transaction,
+    }))
+}
+
+/// The record with its amount rounded or truncated to four decimal places, None if it doesn't
+/// need to be changed
+fn with_precision(
+    record: &StringRecord,
+    headers: &StringRecord,
+    precision_policy: PrecisionPolicy,
+) -> Option<StringRecord> {
+    if precision_policy == PrecisionPolicy::Reject {
+        return None;
+    }
+    let index = headers.iter().position(|header| header == "amount")?;
+    let amount = record.get(index)?;
+    if amount.parse::<Amount>().is_ok() {
+        return None;
+    }
+    let adjusted = Amount::parse_with_precision(amount, precision_policy).ok()?;
+    let mut fields: Vec<String> = record.iter().map(str::to_string).collect();
+    fields[index] = adjusted.to_string();
+    let mut adjusted = StringRecord::from(fields);
+    adjusted.set_position(record.position().cloned());
+    Some(adjusted)
+}
+
+/// Verifies the control totals read from an input, failing only with the fail policy.
+pub(crate) fn verify_control_totals(
+    control_totals: &ControlTotals,
+    control_totals_policy: ControlTotalsPolicy,
+) -> Result<(), ControlTotalsError> {
+    match control_totals_policy {
+        ControlTotalsPolicy::Ignore => (),
+        ControlTotalsPolicy::Warn => {
+            if let Err(e) = control_totals.verify() {
+                warn!(
+                    "The control totals of the input file don't match. Error: {}",
+                    e
+                );
+            }
+        }
+        ControlTotalsPolicy::Fail => control_totals.verify()?,
+    }
+    Ok(())
+}
+
+/// Processes a line of JSON Lines input, recording it as rejected if it can't be read or
+/// processed.
+fn process_json_line(
+    transaction_engine: &mut TransactionEngine,
+    line: input::json_lines::JsonLine,
+    rejections: &mut Rejections,
+) -> Result<(), Rejection> {
+    let _row = debug_span!("row", line = line.line).entered();
+    let result = match line.transaction {
+        Ok(transaction) => transaction_engine
+            .process_transaction(transaction)
+            .map_err(RejectionError::from),
+        Err(e) => Err(RejectionError::Parse(e.to_string())),
+    };
+    if let Err(e) = result {
+        rejections.reject(Rejection::from_line(line.line, &line.text, e))?;
+    }
+    Ok(())
+}
+
+/// Processes the transactions read from an input which isn't line based, recording the
+/// rejected ones with their position in the input.
+fn apply_transactions(
+    transaction_engine: &mut TransactionEngine,
+    transactions: Vec<TransactionInput>,
+    rejections: &mut Rejections,
+) -> Result<(), Rejection> {
+    for (index, transaction) in transactions.into_iter().enumerate() {
+        let _row = debug_span!("row", position = index + 1).entered();
         if let Err(e) = transaction_engine.process_transaction(transaction) {
-            eprintln!("An error occurred when processing a transaction and it was skipped. We'll continue with next transactions. Error: {}", e);
+            rejections.reject(Rejection::from_transaction(
+                index as u64 + 1,
+                &transaction,
+                e.into(),
+            ))?;
         }
     }
-    transaction_engine.print_accounts_state();
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        process_csv_in_order, process_reader, process_reader_with_policy,
+        process_reader_with_rejections, run, Config,
+    };
+    use crate::control_totals::ControlTotalsPolicy;
+    use std::fs::{self, File};
+    use std::{env, process};
+
+    use crate::dialect::CsvDialect;
+    use crate::events::CsvResultsObserver;
+    use crate::ordering::{OrderingConfig, OrderingPolicy};
+    use crate::output::OutputFormat;
+    use crate::rejects::{ProcessingPolicy, Rejections};
+    use crate::{PrecisionPolicy, TransactionEngine, TransactionInput, TransactionType};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_transaction_constructors() {
+        let mut transaction_engine = TransactionEngine::new();
+        let deposit = TransactionInput::deposit(1, 1, "3".parse().unwrap());
+        assert_eq!(deposit.kind(), TransactionType::Deposit);
+        assert_eq!((deposit.client(), deposit.tx()), (1, 1));
+        assert_eq!(deposit.amount(), "3".parse().ok());
+        assert_eq!(TransactionInput::dispute(1, 1).amount(), None);
+        let results = transaction_engine.process_transactions([
+            deposit,
+            TransactionInput::withdrawal(1, 2, "1".parse().unwrap()),
+            TransactionInput::dispute(1, 1),
+            TransactionInput::resolve(1, 1),
+            TransactionInput::dispute(1, 1),
+            TransactionInput::chargeback(1, 1),
+        ]);
+        assert!(results.iter().all(Result::is_ok));
+        assert!(transaction_engine.get_account(1).unwrap().is_locked());
+    }
+
+    #[test]
+    fn test_config_from_args() {
+        let config = Config::new(&args(&["tte", "transactions.csv"])).unwrap();
+        assert_eq!(config.input_path, "transactions.csv");
+        assert_eq!(config.format, OutputFormat::Csv);
+        assert_eq!(config.processing_policy(), ProcessingPolicy::Skip);
+
+        let config = Config::new(&args(&[
+            "tte", "--format", "json", "--strict", "--quiet", "--output", "out.json",
+        ]))
+        .unwrap();
+        assert_eq!(config.input_path, "-");
+        assert_eq!(config.format, OutputFormat::Json);
+        assert_eq!(config.output.as_deref(), Some("out.json"));
+        assert_eq!(config.processing_policy(), ProcessingPolicy::Strict);
+        assert!(config.quiet);
+
+        assert_eq!(Config::new(&args(&["tte", "-vv"])).unwrap().verbose, 2);
+        assert!(Config::new(&args(&["tte", "--format", "xml"])).is_err());
+        assert!(Config::new(&args(&["tte", "--quiet", "--verbose"])).is_err());
+        assert!(Config::new(&args(&["tte", "--rules", "r.toml", "--resume", "c.json"])).is_err());
+        assert!(Config::new(&args(&[
+            "tte",
+            "--rules",
+            "r.toml",
+            "--load-snapshot",
+            "s.json"
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn test_process_reader() {
+        let input = "type, client, tx, amount\ndeposit, 1, 1, 2.5\nwithdrawal, 1, 2, 1.0\n";
+        let transaction_engine = process_reader(input.as_bytes()).unwrap();
+        let mut output = Vec::new();
+        transaction_engine
+            .write_accounts_state(&mut output)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_rejected_rows_are_reported_with_line_numbers() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 2.5\n\
+                     withdrawal, 1, 2, 5.0\n\
+                     deposit, x, 3, 1.0\n\
+                     deposit, 1, 4, 1.0\n";
+        let (transaction_engine, rejections) =
+            process_reader_with_rejections(input.as_bytes()).unwrap();
+        assert_eq!(
+            transaction_engine.get_account(1).unwrap().total,
+            "3.5".parse().unwrap()
+        );
+        assert_eq!(rejections.len(), 2);
+        assert_eq!(rejections[0].line, 3);
+        assert_eq!(rejections[0].error.kind(), "insufficient_funds");
+        assert_eq!(rejections[0].record, "withdrawal,1,2,5.0");
+        assert_eq!(rejections[1].line, 4);
+        assert_eq!(rejections[1].error.kind(), "parse_error");
+    }
+
+    #[test]
+    fn test_extra_decimal_places_are_rounded() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 2.00005\n\
+                     deposit, 1, 2, 1.12344\n";
+        let dialect = CsvDialect {
+            precision: PrecisionPolicy::Round,
+            ..CsvDialect::default()
+        };
+        let mut transaction_engine = TransactionEngine::new();
+        let mut rejections = Rejections::new(ProcessingPolicy::Strict, false);
+        process_csv_in_order(
+            &mut transaction_engine,
+            input.as_bytes(),
+            &dialect,
+            ControlTotalsPolicy::Ignore,
+            &mut rejections,
+            OrderingConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            transaction_engine
+                .get_account(1)
+                .unwrap()
+                .available
+                .to_string(),
+            "3.1235"
+        );
+    }
+
+    #[test]
+    fn test_blank_and_comment_lines_are_skipped() {
+        let input = "# hand written test file\n\
+                     type, client, tx, amount\n\
+                     \n\
+                     deposit, 1, 1, 2.5\n   \n\
+                     # a comment, with commas\n\
+                     , , ,\n\
+                     withdrawal, 1, 2, 5.0\n";
+        let (_, rejections) = process_reader_with_rejections(input.as_bytes()).unwrap();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].line, 8);
+    }
+
+    #[test]
+    fn test_rows_are_sorted_by_their_timestamps() {
+        let input = "type, client, tx, amount, ts\n\
+                     withdrawal, 1, 2, 1.0, 20\n\
+                     deposit, 1, 1, 2.5, 10\n\
+                     deposit, 1, 3, 1.0, 5\n";
+        let mut transaction_engine = TransactionEngine::new();
+        let mut rejections = Rejections::new(ProcessingPolicy::Skip, false);
+        process_csv_in_order(
+            &mut transaction_engine,
+            input.as_bytes(),
+            &CsvDialect::default(),
+            ControlTotalsPolicy::Ignore,
+            &mut rejections,
+            OrderingConfig {
+                policy: OrderingPolicy::Sort,
+                window: 10,
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            transaction_engine.get_account(1).unwrap().total,
+            "1.5".parse().unwrap()
+        );
+        let rejections = rejections.into_vec();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(
+            (rejections[0].line, rejections[0].error.kind()),
+            (4, "out_of_order")
+        );
+    }
+
+    #[test]
+    fn test_rows_which_never_reached_the_engine_are_in_the_results() {
+        let input = "type, client, tx, amount, ts\n\
+                     deposit, 1, 1, 2.5, 10\n\
+                     deposit, x, 2, 1.0, 20\n\
+                     deposit, 1, 3, 1.0, 5\n\
+                     withdrawal, 1, 4, 5.0, 30\n";
+        let path = env::temp_dir().join(format!("tte-unread-results-{}.csv", process::id()));
+        let results = CsvResultsObserver::new(File::create(&path).unwrap());
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.add_observer(results.clone());
+        let mut rejections = Rejections::new(ProcessingPolicy::Skip, false);
+        rejections.set_results(results);
+        process_csv_in_order(
+            &mut transaction_engine,
+            input.as_bytes(),
+            &CsvDialect::default(),
+            ControlTotalsPolicy::Ignore,
+            &mut rejections,
+            OrderingConfig {
+                policy: OrderingPolicy::Reject,
+                window: 0,
+            },
+        )
+        .unwrap();
+        transaction_engine.flush_observers().unwrap();
+        let written = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            written,
+            "tx,client,type,status,error_kind\n\
+             1,1,deposit,applied,\n\
+             ,,,rejected,parse_error\n\
+             3,1,deposit,rejected,out_of_order\n\
+             4,1,withdrawal,rejected,insufficient_funds\n"
+        );
+    }
+
+    #[test]
+    fn test_ids_are_only_kept_once_the_output_was_written() {
+        let dir = env::temp_dir().join(format!("tte-idempotency-output-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("input.csv");
+        fs::write(&input, "type,client,tx,amount\ndeposit,1,1,2.5\n").unwrap();
+        let idempotency_db = dir.join("ids");
+        let run_with_output = |output: &str| {
+            run(Config::new(&args(&[
+                "tte",
+                "--quiet",
+                "--idempotency-db",
+                idempotency_db.to_str().unwrap(),
+                "--output",
+                output,
+                input.to_str().unwrap(),
+            ]))
+            .unwrap())
+        };
+        let missing_dir = dir.join("missing").join("out.csv");
+        assert!(run_with_output(missing_dir.to_str().unwrap()).is_err());
+        let rejections = run_with_output(dir.join("out.csv").to_str().unwrap()).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(rejections.is_empty());
+    }
+
+    #[test]
+    fn test_rolled_back_wal_is_not_appended_to() {
+        let dir = env::temp_dir().join(format!("tte-rollback-wal-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let wal = dir.join("wal");
+        fs::write(&wal, "").unwrap();
+        let same_wal = dir.join(".").join("wal");
+        let result = run(Config::new(&args(&[
+            "tte",
+            "--replay-wal",
+            wal.to_str().unwrap(),
+            "--rollback-to",
+            "0",
+            "--wal",
+            same_wal.to_str().unwrap(),
+        ]))
+        .unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .starts_with("--rollback-to can't write to the write-ahead log it replays"));
+    }
+
+    #[test]
+    fn test_strict_policy_stops_at_first_rejection() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 2.5\n\
+                     withdrawal, 1, 2, 5.0\n\
+                     deposit, 1, 3, 1.0\n";
+        let error = process_reader_with_policy(input.as_bytes(), ProcessingPolicy::Strict)
+            .err()
+            .expect("Expected strict processing to fail");
+        assert!(error.to_string().starts_with("line 3: "));
+    }
+}
@@ -0,0 +1,32 @@
+//! Sets up the diagnostics the binaries write to stderr.
+//!
+//! The library logs through `tracing`: skipped rows and control total mismatches at `warn`,
+//! lifecycle messages of the long running modes at `info`, and every applied transaction with
+//! the new state of its account at `debug`, inside a `row` span carrying the line of the input.
+
+use std::io::{self, IsTerminal};
+
+use tracing_subscriber::EnvFilter;
+
+/// The filter used when neither the command line nor `RUST_LOG` asks for another one
+const DEFAULT_FILTER: &str = "warn,toy_transaction_engine=info,tte_grpc_server=info";
+
+/// Installs a subscriber writing to stderr. `--quiet` only lets errors through and every
+/// `--verbose` lowers the level of this crate by one step. Without either, `RUST_LOG` is used
+/// if it is set. Does nothing if a subscriber was installed already.
+pub fn init(quiet: bool, verbose: u8) {
+    let filter = match (quiet, verbose) {
+        (true, _) => EnvFilter::new("error"),
+        (false, 0) => {
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER))
+        }
+        (false, 1) => EnvFilter::new("warn,toy_transaction_engine=debug,tte_grpc_server=debug"),
+        (false, _) => EnvFilter::new("warn,toy_transaction_engine=trace,tte_grpc_server=trace"),
+    };
+    let _ = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal())
+        .with_target(false)
+        .try_init();
+}
@@ -1,16 +1,166 @@
 use std::{env, process};
 
-use toy_transaction_engine::Config;
+use toy_transaction_engine::rejects::TooManyRejections;
+use toy_transaction_engine::{logging, Config};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let config = Config::new(&args).unwrap_or_else(|err| {
-        eprintln!("Couldn't pass the arguments: {}", err);
-        process::exit(1)
-    });
+    #[cfg(feature = "server")]
+    if args.get(1).map(String::as_str) == Some("serve") {
+        run_server(&args[1..]);
+        return;
+    }
+    #[cfg(feature = "kafka")]
+    if args.get(1).map(String::as_str) == Some("consume") {
+        run_consumer(&args[1..]);
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("diff") {
+        run_diff(&args[1..]);
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("reconcile") {
+        run_reconcile(&args[1..]);
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("statement") {
+        run_statement(&args[1..]);
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("validate") {
+        run_validate(&args[1..]);
+        return;
+    }
+    if args.get(1).map(String::as_str) == Some("generate") {
+        run_generate(&args[1..]);
+        return;
+    }
+    #[cfg(unix)]
+    if args.get(1).map(String::as_str) == Some("daemon") {
+        run_daemon(&args[1..]);
+        return;
+    }
+
+    let config = Config::new(&args).unwrap_or_else(|err| err.exit());
+    logging::init(config.quiet, config.verbose);
 
     if let Err(e) = toy_transaction_engine::run(config) {
+        if let Some(e) = e.downcast_ref::<TooManyRejections>() {
+            eprintln!("The run failed: {e}");
+            process::exit(3);
+        }
         eprintln!("An error occurred in the application: {e}");
         process::exit(1);
     }
 }
+
+fn run_diff(args: &[String]) {
+    use toy_transaction_engine::diff::{self, DiffConfig};
+
+    let config = DiffConfig::new(args).unwrap_or_else(|err| err.exit());
+    logging::init(false, 0);
+
+    match diff::run(config) {
+        Ok(0) => (),
+        Ok(_) => process::exit(1),
+        Err(e) => {
+            eprintln!("An error occurred comparing the accounts: {e}");
+            process::exit(2);
+        }
+    }
+}
+
+fn run_validate(args: &[String]) {
+    use toy_transaction_engine::validate::{self, ValidateConfig};
+
+    let config = ValidateConfig::new(args).unwrap_or_else(|err| err.exit());
+    logging::init(false, 0);
+
+    match validate::run(config) {
+        Ok(0) => (),
+        Ok(_) => process::exit(1),
+        Err(e) => {
+            eprintln!("An error occurred validating the input: {e}");
+            process::exit(2);
+        }
+    }
+}
+
+fn run_generate(args: &[String]) {
+    use toy_transaction_engine::generate::{self, GenerateConfig};
+
+    let config = GenerateConfig::new(args).unwrap_or_else(|err| err.exit());
+    logging::init(false, 0);
+
+    if let Err(e) = generate::run(config) {
+        eprintln!("An error occurred generating the input: {e}");
+        process::exit(1);
+    }
+}
+
+fn run_reconcile(args: &[String]) {
+    use toy_transaction_engine::reconcile::{self, ReconcileConfig};
+
+    let config = ReconcileConfig::new(args).unwrap_or_else(|err| err.exit());
+    logging::init(false, 0);
+
+    match reconcile::run(config) {
+        Ok(0) => (),
+        Ok(_) => process::exit(1),
+        Err(e) => {
+            eprintln!("An error occurred reconciling the accounts: {e}");
+            process::exit(2);
+        }
+    }
+}
+
+fn run_statement(args: &[String]) {
+    use toy_transaction_engine::statement::{self, StatementConfig};
+
+    let config = StatementConfig::new(args).unwrap_or_else(|err| err.exit());
+    logging::init(false, 0);
+
+    if let Err(e) = statement::run(config) {
+        eprintln!("An error occurred writing the statement: {e}");
+        process::exit(1);
+    }
+}
+
+#[cfg(feature = "server")]
+fn run_server(args: &[String]) {
+    use toy_transaction_engine::server::{self, ServerConfig};
+
+    let config = ServerConfig::new(args).unwrap_or_else(|err| err.exit());
+    logging::init(false, 0);
+
+    if let Err(e) = server::run(config) {
+        eprintln!("An error occurred in the server: {e}");
+        process::exit(1);
+    }
+}
+
+#[cfg(feature = "kafka")]
+fn run_consumer(args: &[String]) {
+    use toy_transaction_engine::source::kafka::{self, ConsumerConfig};
+
+    let config = ConsumerConfig::new(args).unwrap_or_else(|err| err.exit());
+    logging::init(false, 0);
+
+    if let Err(e) = kafka::run(config) {
+        eprintln!("An error occurred in the consumer: {e}");
+        process::exit(1);
+    }
+}
+
+#[cfg(unix)]
+fn run_daemon(args: &[String]) {
+    use toy_transaction_engine::daemon::{self, DaemonConfig};
+
+    let config = DaemonConfig::new(args).unwrap_or_else(|err| err.exit());
+    logging::init(false, 0);
+
+    if let Err(e) = daemon::run(config) {
+        eprintln!("An error occurred in the daemon: {e}");
+        process::exit(1);
+    }
+}
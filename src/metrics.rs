@@ -0,0 +1,223 @@
+//! Prometheus metrics of an engine, for the long running modes.
+//!
+//! [`Metrics`] is fed by a [`MetricsObserver`] registered on the engine and rendered in the
+//! Prometheus text format, either on the `/metrics` route of the server or by the small
+//! exporter in [`serve`] for modes without an HTTP server of their own.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::Duration;
+
+use tracing::error;
+
+use crate::events::EngineObserver;
+use crate::money::format_minor_units;
+use crate::transaction_engine::TransactionProcessingError;
+use crate::{AccountDetails, Amount, ClientId, MoneyOps, TransactionInput};
+
+/// The content type of the rendered metrics
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// How often the exporter checks whether it should shut down
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a scraper may take to send its request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct Counts {
+    processed: BTreeMap<&'static str, u64>,
+    rejected: BTreeMap<(&'static str, &'static str), u64>,
+    /// The funds held on every account, to keep the total up to date as accounts change
+    held: HashMap<ClientId, Amount>,
+    /// In minor units, as the funds of many accounts can add up to more than any amount
+    held_total: i128,
+}
+
+/// The counters and gauges of an engine
+#[derive(Default)]
+pub struct Metrics {
+    counts: Mutex<Counts>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    /// Sets the gauges from accounts which already exist, e.g. in an engine restored from a
+    /// snapshot.
+    pub fn observe_accounts<'a>(
+        &self,
+        accounts: impl IntoIterator<Item = (ClientId, &'a AccountDetails)>,
+    ) {
+        let mut counts = self.lock();
+        for (client, account) in accounts {
+            counts.set_held(client, account.held);
+        }
+    }
+
+    /// An observer updating these metrics, to be added to the engine
+    pub fn observer(self: &Arc<Self>) -> MetricsObserver {
+        MetricsObserver(Arc::clone(self))
+    }
+
+    /// The metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        let counts = self.lock();
+        let mut text = String::new();
+        text.push_str(
+            "# HELP tte_transactions_processed_total Transactions processed, applied or not.\n\
+             # TYPE tte_transactions_processed_total counter\n",
+        );
+        for (kind, count) in &counts.processed {
+            let _ = writeln!(
+                text,
+                "tte_transactions_processed_total{{type=\"{}\"}} {}",
+                kind, count
+            );
+        }
+        text.push_str(
+            "# HELP tte_transactions_rejected_total Transactions rejected, by error kind.\n\
+             # TYPE tte_transactions_rejected_total counter\n",
+        );
+        for ((kind, error_kind), count) in &counts.rejected {
+            let _ = writeln!(
+                text,
+                "tte_transactions_rejected_total{{type=\"{}\",error_kind=\"{}\"}} {}",
+                kind, error_kind, count
+            );
+        }
+        let _ = write!(
+            text,
+            "# HELP tte_accounts Accounts in the engine.\n\
+             # TYPE tte_accounts gauge\n\
+             tte_accounts {}\n\
+             # HELP tte_held_funds Funds held by disputes over all accounts.\n\
+             # TYPE tte_held_funds gauge\n\
+             tte_held_funds {}\n",
+            counts.held.len(),
+            format_minor_units(counts.held_total)
+        );
+        text
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Counts> {
+        self.counts.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Counts {
+    fn set_held(&mut self, client: ClientId, held: Amount) {
+        let previous = self.held.insert(client, held).unwrap_or_default();
+        self.held_total += held.to_minor_units() - previous.to_minor_units();
+    }
+}
+
+/// Updates [`Metrics`] with every transaction the engine processes
+pub struct MetricsObserver(Arc<Metrics>);
+
+impl EngineObserver for MetricsObserver {
+    fn on_applied(&mut self, transaction: &TransactionInput, account: &AccountDetails) {
+        let mut counts = self.0.lock();
+        *counts
+            .processed
+            .entry(transaction.kind().name())
+            .or_default() += 1;
+        counts.set_held(transaction.client(), account.held);
+    }
+
+    fn on_rejected(&mut self, transaction: &TransactionInput, error: &TransactionProcessingError) {
+        let mut counts = self.0.lock();
+        let kind = transaction.kind().name();
+        *counts.processed.entry(kind).or_default() += 1;
+        *counts.rejected.entry((kind, error.kind())).or_default() += 1;
+    }
+}
+
+/// Answers `GET /metrics` on the listener until `stop` is set. Every connection carries one
+/// request and is answered on the accepting thread, as rendering is quick.
+pub fn serve(
+    listener: TcpListener,
+    metrics: Arc<Metrics>,
+    stop: Arc<AtomicBool>,
+) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    while !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                if let Err(e) = answer(stream, &metrics) {
+                    error!("An error occurred when serving the metrics. Error: {}", e);
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(e) => error!(
+                "An error occurred when accepting a connection. Error: {}",
+                e
+            ),
+        }
+    }
+    Ok(())
+}
+
+fn answer(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(READ_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?).take(64 * 1024);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // the headers are read so that the client isn't reset by closing an unread socket
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 0 && !header.trim_end().is_empty() {
+        header.clear();
+    }
+
+    let mut parts = request_line.split(' ');
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", CONTENT_TYPE, metrics.render()),
+        _ => ("404 Not Found", "text/plain", "not found".to_string()),
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::Metrics;
+    use crate::{TransactionEngine, TransactionInput};
+
+    #[test]
+    fn test_metrics_follow_the_engine() {
+        let metrics = Arc::new(Metrics::new());
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.add_observer(metrics.observer());
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "2".parse().unwrap()),
+            TransactionInput::deposit(2, 2, "1.5".parse().unwrap()),
+            TransactionInput::withdrawal(1, 3, "5".parse().unwrap()),
+            TransactionInput::dispute(2, 2),
+        ]);
+
+        let text = metrics.render();
+        assert!(text.contains("tte_transactions_processed_total{type=\"deposit\"} 2\n"));
+        assert!(text.contains(
+            "tte_transactions_rejected_total{type=\"withdrawal\",error_kind=\"insufficient_funds\"} 1\n"
+        ));
+        assert!(text.contains("tte_accounts 2\n"));
+        assert!(text.contains("tte_held_funds 1.5000\n"));
+    }
+}
@@ -0,0 +1,503 @@
+//! A fixed-point decimal type for amounts of money.
+//!
+//! Amounts are kept as a whole number of ten-thousandths, which gives the four decimal places
+//! required by the input format without any of the rounding issues of binary floats: sums are
+//! exact and a resolve or chargeback always releases exactly what its dispute held.
+//!
+//! [`MoneyOps`] is what the engine needs of an amount. `Money` keeps it in an `i64`, which is
+//! the fastest and is the engine's `Amount` by default. The `decimal` feature adds
+//! [`DecimalMoney`] on top of `rust_decimal`, which trades speed for balances far beyond the
+//! roughly 922 trillion an `i64` holds, and makes it the engine's `Amount` instead. Both keep
+//! four decimal places, so the input and output formats are the same either way. There is no
+//! float backend, as floats can't keep sums exact.
+
+use std::fmt;
+use std::hash::Hash;
+use std::iter::Sum;
+use std::marker::PhantomData;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+use serde::de::{self, DeserializeOwned, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use thiserror::Error;
+
+#[cfg(feature = "decimal")]
+mod decimal;
+
+#[cfg(feature = "decimal")]
+pub use decimal::DecimalMoney;
+
+/// The number of decimal places kept
+pub const DECIMAL_PLACES: u32 = 4;
+
+/// The number of minor units in one unit
+const SCALE: i64 = 10_i64.pow(DECIMAL_PLACES);
+
+/// All errors which can happen when parsing an amount
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ParseMoneyError {
+    #[error("{0} is not a decimal number")]
+    Invalid(String),
+
+    #[error("{0} has more than {DECIMAL_PLACES} decimal places")]
+    TooManyDecimalPlaces(String),
+
+    #[error("{0} is too large")]
+    OutOfRange(String),
+}
+
+/// What to do with amounts which have more than four decimal places
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PrecisionPolicy {
+    /// Reject the amount
+    #[default]
+    Reject,
+    /// Round the amount half away from zero
+    Round,
+    /// Drop the extra decimal places
+    Truncate,
+}
+
+impl PrecisionPolicy {
+    /// Parses the policy from its command line name.
+    pub fn from_name(name: &str) -> Option<PrecisionPolicy> {
+        match name {
+            "reject" => Some(PrecisionPolicy::Reject),
+            "round" => Some(PrecisionPolicy::Round),
+            "truncate" => Some(PrecisionPolicy::Truncate),
+            _ => None,
+        }
+    }
+}
+
+/// The operations the engine needs of an amount with four decimal places
+pub trait MoneyOps:
+    Copy
+    + Default
+    + Ord
+    + Hash
+    + fmt::Debug
+    + fmt::Display
+    + FromStr<Err = ParseMoneyError>
+    + Add<Output = Self>
+    + AddAssign
+    + Sub<Output = Self>
+    + SubAssign
+    + Neg<Output = Self>
+    + Sum
+    + Serialize
+    + DeserializeOwned
+    + Send
+    + Sync
+    + 'static
+{
+    const ZERO: Self;
+
+    /// The largest amount which can be kept
+    const MAX: Self;
+
+    /// The fixed-size encoding of an amount, e.g. for records on disk
+    type Bytes: AsRef<[u8]> + AsMut<[u8]> + Default;
+
+    /// Creates an amount from a whole number of ten-thousandths.
+    fn from_minor_units(minor_units: i64) -> Self;
+
+    /// Creates an amount from a whole number of ten-thousandths, None if it can't be kept.
+    fn try_from_minor_units(minor_units: i128) -> Option<Self>;
+
+    /// The amount as a whole number of ten-thousandths
+    fn to_minor_units(self) -> i128;
+
+    fn to_le_bytes(self) -> Self::Bytes;
+
+    fn from_le_bytes(bytes: Self::Bytes) -> Self;
+
+    /// Adds two amounts, returning None on overflow.
+    fn checked_add(self, other: Self) -> Option<Self>;
+
+    /// Subtracts an amount, returning None on overflow.
+    fn checked_sub(self, other: Self) -> Option<Self>;
+
+    /// Multiplies two amounts, rounding the product half away from zero to four decimal
+    /// places. Returns None on overflow.
+    fn checked_mul(self, other: Self) -> Option<Self>;
+
+    fn is_negative(self) -> bool {
+        self < Self::ZERO
+    }
+
+    fn abs(self) -> Self {
+        if self.is_negative() {
+            -self
+        } else {
+            self
+        }
+    }
+
+    /// Parses an amount like `from_str`, rounding or truncating the decimal places beyond the
+    /// fourth as the policy says.
+    fn parse_with_precision(
+        value: &str,
+        precision_policy: PrecisionPolicy,
+    ) -> Result<Self, ParseMoneyError> {
+        let places = DECIMAL_PLACES as usize;
+        let (whole, fraction) = match value.split_once('.') {
+            Some((whole, fraction))
+                if fraction.len() > places && precision_policy != PrecisionPolicy::Reject =>
+            {
+                (whole, fraction)
+            }
+            _ => return value.parse(),
+        };
+        if !fraction.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(ParseMoneyError::Invalid(value.to_string()));
+        }
+        let truncated: Self = format!("{}.{}", whole, &fraction[..places]).parse()?;
+        if precision_policy == PrecisionPolicy::Truncate || fraction.as_bytes()[places] < b'5' {
+            return Ok(truncated);
+        }
+        let unit = if whole.starts_with('-') { -1 } else { 1 };
+        truncated
+            .checked_add(Self::from_minor_units(unit))
+            .ok_or_else(|| ParseMoneyError::OutOfRange(value.to_string()))
+    }
+}
+
+/// An amount of money with four decimal places
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Money(i64);
+
+impl Money {
+    pub const ZERO: Money = Money(0);
+
+    /// Creates an amount from a whole number of ten-thousandths.
+    pub const fn from_minor_units(minor_units: i64) -> Money {
+        Money(minor_units)
+    }
+
+    /// The amount as a whole number of ten-thousandths
+    pub const fn minor_units(self) -> i64 {
+        self.0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn abs(self) -> Money {
+        Money(self.0.abs())
+    }
+
+    /// Adds two amounts, returning None on overflow.
+    pub fn checked_add(self, other: Money) -> Option<Money> {
+        self.0.checked_add(other.0).map(Money)
+    }
+
+    /// Subtracts an amount, returning None on overflow.
+    pub fn checked_sub(self, other: Money) -> Option<Money> {
+        self.0.checked_sub(other.0).map(Money)
+    }
+
+    /// Multiplies two amounts, e.g. a quantity and a price, rounding the product half away from
+    /// zero to four decimal places. Returns None on overflow.
+    pub fn checked_mul(self, other: Money) -> Option<Money> {
+        let product = i128::from(self.0) * i128::from(other.0);
+        let half = i128::from(SCALE / 2) * product.signum();
+        i64::try_from((product + half) / i128::from(SCALE))
+            .ok()
+            .map(Money)
+    }
+}
+
+impl Money {
+    /// Parses an amount like `from_str`, rounding or truncating the decimal places beyond the
+    /// fourth as the policy says.
+    pub fn parse_with_precision(
+        value: &str,
+        precision_policy: PrecisionPolicy,
+    ) -> Result<Money, ParseMoneyError> {
+        <Money as MoneyOps>::parse_with_precision(value, precision_policy)
+    }
+}
+
+impl MoneyOps for Money {
+    const ZERO: Money = Money::ZERO;
+    const MAX: Money = Money(i64::MAX);
+
+    type Bytes = [u8; 8];
+
+    fn from_minor_units(minor_units: i64) -> Money {
+        Money(minor_units)
+    }
+
+    fn try_from_minor_units(minor_units: i128) -> Option<Money> {
+        i64::try_from(minor_units).ok().map(Money)
+    }
+
+    fn to_minor_units(self) -> i128 {
+        i128::from(self.0)
+    }
+
+    fn to_le_bytes(self) -> [u8; 8] {
+        self.0.to_le_bytes()
+    }
+
+    fn from_le_bytes(bytes: [u8; 8]) -> Money {
+        Money(i64::from_le_bytes(bytes))
+    }
+
+    fn checked_add(self, other: Money) -> Option<Money> {
+        Money::checked_add(self, other)
+    }
+
+    fn checked_sub(self, other: Money) -> Option<Money> {
+        Money::checked_sub(self, other)
+    }
+
+    fn checked_mul(self, other: Money) -> Option<Money> {
+        Money::checked_mul(self, other)
+    }
+
+    fn is_negative(self) -> bool {
+        Money::is_negative(self)
+    }
+
+    fn abs(self) -> Money {
+        Money::abs(self)
+    }
+}
+
+/// Splits a plain decimal number into its sign, whole digits and decimal places, checking that
+/// there are digits and no more than four decimal places.
+pub(crate) fn split_decimal(value: &str) -> Result<(bool, &str, &str), ParseMoneyError> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value.strip_prefix('+').unwrap_or(value)),
+    };
+    let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+    if whole.is_empty() && fraction.is_empty()
+        || !whole
+            .bytes()
+            .chain(fraction.bytes())
+            .all(|b| b.is_ascii_digit())
+    {
+        return Err(ParseMoneyError::Invalid(value.to_string()));
+    }
+    if fraction.len() > DECIMAL_PLACES as usize {
+        return Err(ParseMoneyError::TooManyDecimalPlaces(value.to_string()));
+    }
+    Ok((negative, whole, fraction))
+}
+
+/// Writes a formatted amount honouring the width and alignment flags, aligned right by default.
+pub(crate) fn pad(f: &mut fmt::Formatter<'_>, formatted: &str) -> fmt::Result {
+    match f.width() {
+        Some(width) => match f.align() {
+            Some(fmt::Alignment::Left) => write!(f, "{:<width$}", formatted),
+            Some(fmt::Alignment::Center) => write!(f, "{:^width$}", formatted),
+            _ => write!(f, "{:>width$}", formatted),
+        },
+        None => f.write_str(formatted),
+    }
+}
+
+/// Formats a whole number of ten-thousandths like an amount, e.g. a sum of amounts which can be
+/// beyond the largest amount.
+pub(crate) fn format_minor_units(minor_units: i128) -> String {
+    let sign = if minor_units < 0 { "-" } else { "" };
+    let units = minor_units.unsigned_abs();
+    format!(
+        "{}{}.{:0width$}",
+        sign,
+        units / 10_000,
+        units % 10_000,
+        width = DECIMAL_PLACES as usize
+    )
+}
+
+impl FromStr for Money {
+    type Err = ParseMoneyError;
+
+    /// Parses a plain decimal number such as `2`, `-1.5` or `3.1234`.
+    fn from_str(value: &str) -> Result<Money, ParseMoneyError> {
+        let invalid = || ParseMoneyError::Invalid(value.to_string());
+        let (negative, whole, fraction) = split_decimal(value)?;
+        let out_of_range = || ParseMoneyError::OutOfRange(value.to_string());
+        let whole: i64 = match whole {
+            "" => 0,
+            _ => whole.parse().map_err(|_| out_of_range())?,
+        };
+        let fraction: i64 = format!("{:0<width$}", fraction, width = DECIMAL_PLACES as usize)
+            .parse()
+            .map_err(|_| invalid())?;
+        let minor_units = whole
+            .checked_mul(SCALE)
+            .and_then(|units| units.checked_add(fraction))
+            .ok_or_else(out_of_range)?;
+        Ok(Money(if negative { -minor_units } else { minor_units }))
+    }
+}
+
+impl fmt::Display for Money {
+    /// Formats the amount with exactly four decimal places. Width and alignment flags are
+    /// honoured, the precision is always four.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let units = self.0.unsigned_abs();
+        let scale = SCALE.unsigned_abs();
+        let formatted = format!(
+            "{}{}.{:0width$}",
+            sign,
+            units / scale,
+            units % scale,
+            width = DECIMAL_PLACES as usize
+        );
+        pad(f, &formatted)
+    }
+}
+
+impl Add for Money {
+    type Output = Money;
+
+    fn add(self, other: Money) -> Money {
+        Money(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Money {
+    fn add_assign(&mut self, other: Money) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for Money {
+    type Output = Money;
+
+    fn sub(self, other: Money) -> Money {
+        Money(self.0 - other.0)
+    }
+}
+
+impl SubAssign for Money {
+    fn sub_assign(&mut self, other: Money) {
+        self.0 -= other.0;
+    }
+}
+
+impl Neg for Money {
+    type Output = Money;
+
+    fn neg(self) -> Money {
+        Money(-self.0)
+    }
+}
+
+impl Sum for Money {
+    fn sum<I: Iterator<Item = Money>>(iter: I) -> Money {
+        iter.fold(Money::ZERO, Add::add)
+    }
+}
+
+impl Serialize for Money {
+    /// Serializes the amount as its decimal string so that no precision is lost.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Money, D::Error> {
+        deserializer.deserialize_str(MoneyVisitor(PhantomData))
+    }
+}
+
+/// Deserializes an amount from its decimal string, or from a whole number
+pub(crate) struct MoneyVisitor<T>(pub(crate) PhantomData<T>);
+
+impl<T: FromStr<Err = ParseMoneyError>> Visitor<'_> for MoneyVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "a decimal number with at most {} decimal places",
+            DECIMAL_PLACES
+        )
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<T, E> {
+        value.trim().parse().map_err(E::custom)
+    }
+
+    fn visit_i64<E: de::Error>(self, value: i64) -> Result<T, E> {
+        self.visit_str(&value.to_string())
+    }
+
+    fn visit_u64<E: de::Error>(self, value: u64) -> Result<T, E> {
+        self.visit_str(&value.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Money, ParseMoneyError, PrecisionPolicy};
+
+    #[test]
+    fn test_parse_and_format() {
+        for (input, output) in [
+            ("1.5", "1.5000"),
+            ("2", "2.0000"),
+            ("3.1234", "3.1234"),
+            ("-0.25", "-0.2500"),
+            (".5", "0.5000"),
+            ("7.", "7.0000"),
+        ] {
+            assert_eq!(input.parse::<Money>().unwrap().to_string(), output);
+        }
+        assert_eq!(
+            format!("{:>8}", Money::from_minor_units(15_000)),
+            "  1.5000"
+        );
+    }
+
+    #[test]
+    fn test_invalid_amounts_are_rejected() {
+        assert!(matches!(
+            "1.23456".parse::<Money>(),
+            Err(ParseMoneyError::TooManyDecimalPlaces(_))
+        ));
+        for input in ["", ".", "abc", "1.2.3", "1e5", "--1", "99999999999999999"] {
+            assert!(input.parse::<Money>().is_err(), "{} was accepted", input);
+        }
+    }
+
+    #[test]
+    fn test_extra_decimal_places_follow_the_policy() {
+        let parse = |value, precision_policy| {
+            Money::parse_with_precision(value, precision_policy).map(|money| money.to_string())
+        };
+        assert_eq!(parse("1.23455", PrecisionPolicy::Round).unwrap(), "1.2346");
+        assert_eq!(
+            parse("-1.23455", PrecisionPolicy::Round).unwrap(),
+            "-1.2346"
+        );
+        assert_eq!(parse("1.23449", PrecisionPolicy::Round).unwrap(), "1.2345");
+        assert_eq!(
+            parse("1.23459", PrecisionPolicy::Truncate).unwrap(),
+            "1.2345"
+        );
+        assert_eq!(parse("2.5", PrecisionPolicy::Truncate).unwrap(), "2.5000");
+        assert!(parse("1.23455", PrecisionPolicy::Reject).is_err());
+        assert!(parse("1.2345x", PrecisionPolicy::Round).is_err());
+    }
+
+    #[test]
+    fn test_sums_are_exact() {
+        let tenth: Money = "0.1".parse().unwrap();
+        let total: Money = std::iter::repeat_n(tenth, 10).sum();
+        assert_eq!(total, "1".parse().unwrap());
+        assert_eq!(total - tenth - tenth, "0.8".parse().unwrap());
+    }
+}
@@ -0,0 +1,226 @@
+//! An amount backed by `rust_decimal`, with the `decimal` feature.
+
+use std::fmt;
+use std::iter::Sum;
+use std::marker::PhantomData;
+use std::ops::{Add, AddAssign, Neg, Sub, SubAssign};
+use std::str::FromStr;
+
+use rust_decimal::{Decimal, RoundingStrategy};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+use super::{
+    pad, split_decimal, MoneyOps, MoneyVisitor, ParseMoneyError, PrecisionPolicy, DECIMAL_PLACES,
+};
+
+/// An amount of money with four decimal places, kept as a 96-bit decimal
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DecimalMoney(Decimal);
+
+impl DecimalMoney {
+    pub const ZERO: DecimalMoney = DecimalMoney(Decimal::ZERO);
+
+    /// Creates an amount from a whole number of ten-thousandths.
+    pub fn from_minor_units(minor_units: i64) -> DecimalMoney {
+        DecimalMoney(Decimal::new(minor_units, DECIMAL_PLACES))
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0.is_sign_negative() && !self.0.is_zero()
+    }
+
+    pub fn abs(self) -> DecimalMoney {
+        DecimalMoney(self.0.abs())
+    }
+
+    /// Adds two amounts, returning None on overflow.
+    pub fn checked_add(self, other: DecimalMoney) -> Option<DecimalMoney> {
+        self.0.checked_add(other.0).map(DecimalMoney)
+    }
+
+    /// Subtracts an amount, returning None on overflow.
+    pub fn checked_sub(self, other: DecimalMoney) -> Option<DecimalMoney> {
+        self.0.checked_sub(other.0).map(DecimalMoney)
+    }
+
+    /// Multiplies two amounts, rounding the product half away from zero to four decimal
+    /// places. Returns None on overflow.
+    pub fn checked_mul(self, other: DecimalMoney) -> Option<DecimalMoney> {
+        self.0.checked_mul(other.0).map(|product| {
+            DecimalMoney(
+                product
+                    .round_dp_with_strategy(DECIMAL_PLACES, RoundingStrategy::MidpointAwayFromZero),
+            )
+        })
+    }
+
+    /// Parses an amount like `from_str`, rounding or truncating the decimal places beyond the
+    /// fourth as the policy says.
+    pub fn parse_with_precision(
+        value: &str,
+        precision_policy: PrecisionPolicy,
+    ) -> Result<DecimalMoney, ParseMoneyError> {
+        <DecimalMoney as MoneyOps>::parse_with_precision(value, precision_policy)
+    }
+}
+
+impl MoneyOps for DecimalMoney {
+    const ZERO: DecimalMoney = DecimalMoney::ZERO;
+    const MAX: DecimalMoney = DecimalMoney(Decimal::MAX);
+
+    type Bytes = [u8; 16];
+
+    fn from_minor_units(minor_units: i64) -> DecimalMoney {
+        DecimalMoney::from_minor_units(minor_units)
+    }
+
+    fn try_from_minor_units(minor_units: i128) -> Option<DecimalMoney> {
+        Decimal::try_from_i128_with_scale(minor_units, DECIMAL_PLACES)
+            .ok()
+            .map(DecimalMoney)
+    }
+
+    fn to_minor_units(self) -> i128 {
+        // the mantissa has at most 96 bits, so it fits once scaled to four decimal places
+        let scale = self.0.scale();
+        if scale <= DECIMAL_PLACES {
+            self.0.mantissa() * 10_i128.pow(DECIMAL_PLACES - scale)
+        } else {
+            self.0.mantissa() / 10_i128.pow(scale - DECIMAL_PLACES)
+        }
+    }
+
+    fn to_le_bytes(self) -> [u8; 16] {
+        self.0.serialize()
+    }
+
+    fn from_le_bytes(bytes: [u8; 16]) -> DecimalMoney {
+        DecimalMoney(Decimal::deserialize(bytes))
+    }
+
+    fn checked_add(self, other: DecimalMoney) -> Option<DecimalMoney> {
+        DecimalMoney::checked_add(self, other)
+    }
+
+    fn checked_sub(self, other: DecimalMoney) -> Option<DecimalMoney> {
+        DecimalMoney::checked_sub(self, other)
+    }
+
+    fn checked_mul(self, other: DecimalMoney) -> Option<DecimalMoney> {
+        DecimalMoney::checked_mul(self, other)
+    }
+
+    fn is_negative(self) -> bool {
+        DecimalMoney::is_negative(self)
+    }
+
+    fn abs(self) -> DecimalMoney {
+        DecimalMoney::abs(self)
+    }
+}
+
+impl FromStr for DecimalMoney {
+    type Err = ParseMoneyError;
+
+    /// Parses a plain decimal number such as `2`, `-1.5` or `3.1234`.
+    fn from_str(value: &str) -> Result<DecimalMoney, ParseMoneyError> {
+        let (negative, whole, fraction) = split_decimal(value)?;
+        let digits = format!("{}{}.{}", if negative { "-" } else { "" }, whole, fraction);
+        Decimal::from_str_exact(digits.trim_end_matches('.'))
+            .map(DecimalMoney)
+            .map_err(|_| ParseMoneyError::OutOfRange(value.to_string()))
+    }
+}
+
+impl fmt::Display for DecimalMoney {
+    /// Formats the amount with exactly four decimal places. Width and alignment flags are
+    /// honoured, the precision is always four.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        pad(f, &format!("{:.4}", self.0))
+    }
+}
+
+impl Add for DecimalMoney {
+    type Output = DecimalMoney;
+
+    fn add(self, other: DecimalMoney) -> DecimalMoney {
+        DecimalMoney(self.0 + other.0)
+    }
+}
+
+impl AddAssign for DecimalMoney {
+    fn add_assign(&mut self, other: DecimalMoney) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for DecimalMoney {
+    type Output = DecimalMoney;
+
+    fn sub(self, other: DecimalMoney) -> DecimalMoney {
+        DecimalMoney(self.0 - other.0)
+    }
+}
+
+impl SubAssign for DecimalMoney {
+    fn sub_assign(&mut self, other: DecimalMoney) {
+        self.0 -= other.0;
+    }
+}
+
+impl Neg for DecimalMoney {
+    type Output = DecimalMoney;
+
+    fn neg(self) -> DecimalMoney {
+        DecimalMoney(-self.0)
+    }
+}
+
+impl Sum for DecimalMoney {
+    fn sum<I: Iterator<Item = DecimalMoney>>(iter: I) -> DecimalMoney {
+        iter.fold(DecimalMoney::ZERO, Add::add)
+    }
+}
+
+impl Serialize for DecimalMoney {
+    /// Serializes the amount as its decimal string so that no precision is lost.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for DecimalMoney {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<DecimalMoney, D::Error> {
+        deserializer.deserialize_str(MoneyVisitor(PhantomData))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DecimalMoney;
+    use crate::{Money, MoneyOps, PrecisionPolicy};
+
+    #[test]
+    fn test_amounts_match_money() {
+        for input in ["1.5", "2", "-0.25", ".5", "7.", "922337203685477.5807"] {
+            let decimal: DecimalMoney = input.parse().unwrap();
+            let money: Money = input.parse().unwrap();
+            assert_eq!(decimal.to_string(), money.to_string());
+            assert_eq!(decimal.to_minor_units(), money.to_minor_units());
+        }
+        for input in ["", "abc", "1e5", "--1", "1.23456"] {
+            assert!(
+                input.parse::<DecimalMoney>().is_err(),
+                "{} was accepted",
+                input
+            );
+        }
+        let rounded = DecimalMoney::parse_with_precision("1.23455", PrecisionPolicy::Round);
+        assert_eq!(rounded.unwrap().to_string(), "1.2346");
+
+        // beyond the range of Money
+        let large: DecimalMoney = "922337203685477.5807".parse().unwrap();
+        assert_eq!((large + large).to_string(), "1844674407370955.1614");
+        assert_eq!(DecimalMoney::from_le_bytes(large.to_le_bytes()), large);
+    }
+}
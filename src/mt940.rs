@@ -0,0 +1,320 @@
+//! Parses SWIFT MT940 (customer statement) and MT942 (interim transaction report) messages so
+//! that bank statements can be compared against the balances of the engine, which
+//! `reconcile --expected-format mt940` does.
+//!
+//! Messages may be given as plain field text or wrapped in the SWIFT `{1:}{2:}{4:...-}` block
+//! envelope. The account identification (`:25:`) is mapped to a client id by taking the part
+//! after its last `/`, so `10020030/42` and `42` both belong to client 42.
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use crate::{Amount, ClientId};
+
+/// All errors which can happen when parsing MT940/MT942 messages
+#[derive(Error, Debug, Clone)]
+pub enum Mt940Error {
+    #[error("field :{tag}: has an invalid value {value}")]
+    InvalidField { tag: String, value: String },
+
+    #[error("field :{0}: found outside of a statement")]
+    FieldOutsideOfStatement(String),
+
+    #[error("statement {0} doesn't have an account identification (:25:)")]
+    MissingAccount(String),
+
+    #[error("the balance of account {0} is beyond the largest amount")]
+    BalanceOverflow(String),
+}
+
+/// An opening or closing balance of a statement
+#[derive(Debug, Clone, PartialEq)]
+pub struct Balance {
+    /// The date of the balance as YYMMDD
+    pub date: String,
+    pub currency: String,
+    /// The balance, negative for debit balances
+    pub amount: Amount,
+}
+
+/// A single booked transaction (`:61:`) of a statement
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatementLine {
+    /// The value date as YYMMDD
+    pub value_date: String,
+    /// The amount, negative for debits
+    pub amount: Amount,
+    /// The reference for the account owner
+    pub reference: String,
+}
+
+/// A single MT940 statement or MT942 interim report
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Statement {
+    pub reference: String,
+    pub account: String,
+    /// The opening balance (`:60F:`/`:60M:`), not present on MT942 reports
+    pub opening_balance: Option<Balance>,
+    /// The closing booked balance (`:62F:`/`:62M:`), not present on MT942 reports
+    pub closing_balance: Option<Balance>,
+    /// The closing available balance (`:64:`)
+    pub closing_available_balance: Option<Balance>,
+    pub lines: Vec<StatementLine>,
+}
+
+impl Statement {
+    /// The client the statement belongs to, if its account identification ends in a client id
+    pub fn client_id(&self) -> Option<ClientId> {
+        self.account
+            .rsplit('/')
+            .next()
+            .and_then(|id| id.trim().parse().ok())
+    }
+
+    /// The sum of all booked transactions of the statement, None if it is beyond the largest
+    /// amount
+    pub fn net_movement(&self) -> Option<Amount> {
+        self.lines
+            .iter()
+            .try_fold(Amount::ZERO, |sum, line| sum.checked_add(line.amount))
+    }
+}
+
+/// Parses all statements contained in the input.
+pub fn parse_statements(input: &str) -> Result<Vec<Statement>, Mt940Error> {
+    let mut statements: Vec<Statement> = Vec::new();
+    let mut current: Option<Statement> = None;
+    for (tag, value) in fields(input) {
+        if tag == "20" {
+            if let Some(statement) = current.take() {
+                statements.push(finish(statement)?);
+            }
+            current = Some(Statement {
+                reference: value,
+                ..Statement::default()
+            });
+            continue;
+        }
+
+        let statement = current
+            .as_mut()
+            .ok_or_else(|| Mt940Error::FieldOutsideOfStatement(tag.clone()))?;
+        match tag.as_str() {
+            "25" => statement.account = value.trim().to_string(),
+            "60F" | "60M" => statement.opening_balance = Some(parse_balance(&tag, &value)?),
+            "62F" | "62M" => statement.closing_balance = Some(parse_balance(&tag, &value)?),
+            "64" => statement.closing_available_balance = Some(parse_balance(&tag, &value)?),
+            "61" => statement.lines.push(parse_statement_line(&value)?),
+            _ => (),
+        }
+    }
+    if let Some(statement) = current.take() {
+        statements.push(finish(statement)?);
+    }
+    Ok(statements)
+}
+
+/// Computes the latest known booked balance per client. Statements are applied in order, so the
+/// closing balance of an MT940 statement replaces the balance of the client while the movements
+/// of an MT942 report are added on top of the last known balance.
+pub fn latest_balances(statements: &[Statement]) -> Result<HashMap<ClientId, Amount>, Mt940Error> {
+    let mut balances = HashMap::new();
+    for statement in statements {
+        let Some(client) = statement.client_id() else {
+            continue;
+        };
+        match &statement.closing_balance {
+            Some(closing_balance) => {
+                balances.insert(client, closing_balance.amount);
+            }
+            None => {
+                if let Some(balance) = balances.get_mut(&client) {
+                    *balance = statement
+                        .net_movement()
+                        .and_then(|net_movement| balance.checked_add(net_movement))
+                        .ok_or_else(|| Mt940Error::BalanceOverflow(statement.account.clone()))?;
+                }
+            }
+        }
+    }
+    Ok(balances)
+}
+
+fn finish(statement: Statement) -> Result<Statement, Mt940Error> {
+    if statement.account.is_empty() {
+        return Err(Mt940Error::MissingAccount(statement.reference));
+    }
+    Ok(statement)
+}
+
+/// Splits the input into its `:tag:value` fields, joining continuation lines and dropping the
+/// SWIFT block envelope.
+fn fields(input: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in input.lines() {
+        let line = line.trim_end_matches('\r');
+        // the text block starts with `{4:` and ends with `-}`, the other blocks are single lines
+        let line = line.strip_prefix("{4:").unwrap_or(line);
+        if line.starts_with('{') || line.starts_with('-') {
+            continue;
+        }
+        let parsed_tag = line.strip_prefix(':').and_then(|rest| rest.split_once(':'));
+        match (parsed_tag, fields.last_mut()) {
+            (Some((tag, value)), _) => fields.push((tag.to_string(), value.to_string())),
+            (None, Some((_, value))) if !line.is_empty() => {
+                value.push('\n');
+                value.push_str(line);
+            }
+            _ => (),
+        }
+    }
+    fields
+}
+
+fn invalid(tag: &str, value: &str) -> Mt940Error {
+    Mt940Error::InvalidField {
+        tag: tag.to_string(),
+        value: value.to_string(),
+    }
+}
+
+/// Parses a balance of the form `C220901EUR1000,00`.
+fn parse_balance(tag: &str, value: &str) -> Result<Balance, Mt940Error> {
+    let value = value.trim();
+    if value.len() < 11 || !value.is_ascii() {
+        return Err(invalid(tag, value));
+    }
+    let is_debit = match &value[..1] {
+        "C" => false,
+        "D" => true,
+        _ => return Err(invalid(tag, value)),
+    };
+    let amount = parse_amount(&value[10..]).ok_or_else(|| invalid(tag, value))?;
+    Ok(Balance {
+        date: value[1..7].to_string(),
+        currency: value[7..10].to_string(),
+        amount: if is_debit { -amount } else { amount },
+    })
+}
+
+/// Parses a statement line of the form `2209010901C500,00NTRFNONREF//bank ref`.
+fn parse_statement_line(value: &str) -> Result<StatementLine, Mt940Error> {
+    let first_line = value.lines().next().unwrap_or_default().trim();
+    if !first_line.is_ascii() || first_line.len() < 6 {
+        return Err(invalid("61", value));
+    }
+    let value_date = first_line[..6].to_string();
+    let mut rest = &first_line[6..];
+    // optional entry date as MMDD
+    if rest.len() >= 4 && rest[..4].bytes().all(|b| b.is_ascii_digit()) {
+        rest = &rest[4..];
+    }
+
+    let (is_debit, length) = if rest.starts_with("RC") {
+        (true, 2)
+    } else if rest.starts_with("RD") {
+        (false, 2)
+    } else if rest.starts_with('C') {
+        (false, 1)
+    } else if rest.starts_with('D') {
+        (true, 1)
+    } else {
+        return Err(invalid("61", value));
+    };
+    rest = &rest[length..];
+    // optional funds code, the third character of the currency code
+    if rest.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        rest = &rest[1..];
+    }
+
+    let amount_length = rest
+        .find(|c: char| !c.is_ascii_digit() && c != ',')
+        .unwrap_or(rest.len());
+    let amount = parse_amount(&rest[..amount_length]).ok_or_else(|| invalid("61", value))?;
+    // skip the 4 character transaction type identification code
+    let reference = rest.get(amount_length + 4..).unwrap_or_default();
+    let reference = reference.split("//").next().unwrap_or_default();
+    Ok(StatementLine {
+        value_date,
+        amount: if is_debit { -amount } else { amount },
+        reference: reference.to_string(),
+    })
+}
+
+/// Parses an amount using a comma as the decimal separator.
+fn parse_amount(value: &str) -> Option<Amount> {
+    if value.is_empty() {
+        return None;
+    }
+    value.replace(',', ".").parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{latest_balances, parse_statements};
+    use crate::Amount;
+
+    fn money(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    const MT940: &str =
+        "{1:F01BANKDEFFAXXX0000000000}{2:O9401200220901BANKDEFFAXXX00000000002209011200N}{4:
+:20:STMT1
+:25:10020030/42
+:28C:00001/001
+:60F:C220831EUR1000,00
+:61:2209010901C500,00NTRFNONREF//B1
+:86:Salary
+ September
+:61:220902D120,5NMSCINV-7
+:62F:C220902EUR1379,50
+:64:C220902EUR1379,50
+-}";
+
+    #[test]
+    fn test_mt940_statement() {
+        let statements = parse_statements(MT940).unwrap();
+        assert_eq!(statements.len(), 1);
+        let statement = &statements[0];
+        assert_eq!(statement.reference, "STMT1");
+        assert_eq!(statement.client_id(), Some(42));
+        assert_eq!(
+            statement.opening_balance.as_ref().unwrap().amount,
+            money("1000")
+        );
+        assert_eq!(
+            statement.closing_balance.as_ref().unwrap().amount,
+            money("1379.5")
+        );
+        assert_eq!(statement.closing_balance.as_ref().unwrap().currency, "EUR");
+        assert_eq!(statement.lines.len(), 2);
+        assert_eq!(statement.lines[0].amount, money("500"));
+        assert_eq!(statement.lines[0].reference, "NONREF");
+        assert_eq!(statement.lines[1].amount, money("-120.5"));
+        assert_eq!(statement.lines[1].reference, "INV-7");
+        assert_eq!(statement.net_movement(), Some(money("379.5")));
+    }
+
+    #[test]
+    fn test_mt942_movements_are_added_to_last_balance() {
+        let mt942 = ":20:INTERIM1
+:25:42
+:28C:00002/001
+:13D:2209031200+0200
+:61:220903C20,00NTRFREF1
+:61:220903RC5,00NTRFREF2
+:90C:1EUR20,00
+";
+        let mut statements = parse_statements(MT940).unwrap();
+        statements.extend(parse_statements(mt942).unwrap());
+        let balances = latest_balances(&statements).unwrap();
+        assert_eq!(balances.get(&42), Some(&money("1394.5")));
+    }
+
+    #[test]
+    fn test_statement_without_account_is_rejected() {
+        assert!(parse_statements(":20:STMT\n:60F:C220831EUR1,00\n").is_err());
+    }
+}
@@ -0,0 +1,162 @@
+//! Rows which arrive out of the order of their timestamps.
+//!
+//! With [`OrderingPolicy::Reject`], a row with a timestamp before the latest one seen is
+//! rejected. With [`OrderingPolicy::Sort`], rows are held back until the input is a window of
+//! seconds past their timestamp and applied sorted by timestamp, rows with the same timestamp
+//! in the order they arrived. Only rows more than the window late are rejected then. Rows
+//! without a timestamp are taken to happen at the latest timestamp seen.
+
+use std::collections::BTreeMap;
+
+use crate::Timestamp;
+
+/// What to do with rows whose timestamp is before the latest one seen
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OrderingPolicy {
+    /// Apply rows in the order they arrive, whatever their timestamps
+    #[default]
+    Ignore,
+    /// Reject rows which arrive after a row with a later timestamp
+    Reject,
+    /// Sort the rows within the window, rejecting the ones which arrive later than that
+    Sort,
+}
+
+impl OrderingPolicy {
+    /// Parses the policy from its command line name.
+    pub fn from_name(name: &str) -> Option<OrderingPolicy> {
+        match name {
+            "ignore" => Some(OrderingPolicy::Ignore),
+            "reject" => Some(OrderingPolicy::Reject),
+            "sort" => Some(OrderingPolicy::Sort),
+            _ => None,
+        }
+    }
+}
+
+/// How the rows of an input are ordered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct OrderingConfig {
+    pub policy: OrderingPolicy,
+    /// The seconds rows may be late with the sort policy
+    pub window: u64,
+}
+
+/// A row which arrived too late to be applied in order
+#[derive(Debug)]
+pub struct OutOfOrder<T> {
+    pub item: T,
+    pub timestamp: Timestamp,
+    /// The latest timestamp seen before the row
+    pub latest: Timestamp,
+}
+
+/// Puts rows in the order of their timestamps
+#[derive(Debug)]
+pub struct Reorderer<T> {
+    policy: OrderingPolicy,
+    window: u64,
+    latest: Option<Timestamp>,
+    /// The rows held back, by timestamp and then by the order they arrived in
+    held: BTreeMap<(Timestamp, u64), T>,
+    received: u64,
+}
+
+impl<T> Reorderer<T> {
+    pub fn new(ordering_config: OrderingConfig) -> Reorderer<T> {
+        let window = match ordering_config.policy {
+            OrderingPolicy::Sort => ordering_config.window,
+            _ => 0,
+        };
+        Reorderer {
+            policy: ordering_config.policy,
+            window,
+            latest: None,
+            held: BTreeMap::new(),
+            received: 0,
+        }
+    }
+
+    /// Whether rows are applied as they arrive
+    pub fn passes_through(&self) -> bool {
+        self.policy == OrderingPolicy::Ignore
+    }
+
+    /// Takes a row with its timestamp, handing it back if it arrived too late.
+    pub fn push(&mut self, item: T, timestamp: Option<Timestamp>) -> Result<(), OutOfOrder<T>> {
+        let latest = self.latest.unwrap_or(0);
+        let timestamp = match self.policy {
+            OrderingPolicy::Ignore => latest,
+            _ => timestamp.unwrap_or(latest),
+        };
+        if timestamp.saturating_add(self.window) < latest {
+            return Err(OutOfOrder {
+                item,
+                timestamp,
+                latest,
+            });
+        }
+        self.latest = Some(latest.max(timestamp));
+        self.held.insert((timestamp, self.received), item);
+        self.received += 1;
+        Ok(())
+    }
+
+    /// The next row which can be applied, as no row which is still to come can be before it
+    pub fn pop_ready(&mut self) -> Option<T> {
+        let latest = self.latest?;
+        let entry = self.held.first_entry()?;
+        if entry.key().0.saturating_add(self.window) > latest {
+            return None;
+        }
+        Some(entry.remove())
+    }
+
+    /// The next row held back, once there are no more rows to come
+    pub fn pop(&mut self) -> Option<T> {
+        self.held.pop_first().map(|(_, item)| item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{OrderingConfig, OrderingPolicy, Reorderer};
+
+    fn apply(reorderer: &mut Reorderer<u32>, rows: &[(u32, u64)]) -> (Vec<u32>, Vec<u32>) {
+        let (mut applied, mut rejected) = (Vec::new(), Vec::new());
+        for (row, timestamp) in rows {
+            if let Err(late) = reorderer.push(*row, Some(*timestamp)) {
+                rejected.push(late.item);
+            }
+            while let Some(row) = reorderer.pop_ready() {
+                applied.push(row);
+            }
+        }
+        while let Some(row) = reorderer.pop() {
+            applied.push(row);
+        }
+        (applied, rejected)
+    }
+
+    #[test]
+    fn test_rows_are_sorted_within_the_window() {
+        let rows = [(1, 10), (2, 5), (3, 12), (4, 30), (5, 15), (6, 30)];
+        let mut reorderer = Reorderer::new(OrderingConfig {
+            policy: OrderingPolicy::Reject,
+            window: 60,
+        });
+        assert_eq!(apply(&mut reorderer, &rows), (vec![1, 3, 4, 6], vec![2, 5]));
+
+        let mut reorderer = Reorderer::new(OrderingConfig {
+            policy: OrderingPolicy::Sort,
+            window: 10,
+        });
+        assert_eq!(apply(&mut reorderer, &rows), (vec![2, 1, 3, 4, 6], vec![5]));
+
+        let mut reorderer = Reorderer::new(OrderingConfig::default());
+        assert_eq!(
+            apply(&mut reorderer, &rows),
+            (vec![1, 2, 3, 4, 5, 6], vec![])
+        );
+    }
+}
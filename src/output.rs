@@ -0,0 +1,390 @@
+//! Writes the state of accounts, and the disputes report, in the formats supported on the
+//! command line.
+//!
+//! With the `parquet` feature the accounts can also be written as a Parquet file, or built as
+//! an Arrow `RecordBatch` to hand to other tools, with the client as a `UInt64`, the amounts as
+//! `Decimal128(38, 4)` and whether the account is locked as a `Boolean`.
+
+use std::io;
+#[cfg(feature = "parquet")]
+use std::sync::Arc;
+
+#[cfg(feature = "parquet")]
+use arrow_array::{ArrayRef, BooleanArray, Decimal128Array, RecordBatch, UInt64Array};
+#[cfg(feature = "parquet")]
+use arrow_schema::ArrowError;
+#[cfg(feature = "parquet")]
+use parquet::arrow::ArrowWriter;
+#[cfg(feature = "parquet")]
+use parquet::errors::ParquetError;
+use serde::Serialize;
+use thiserror::Error;
+
+#[cfg(feature = "parquet")]
+use crate::MoneyOps;
+use crate::{AccountDetails, Amount, ClientId, Dispute, StatementLine};
+
+/// The formats the state of accounts can be written in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    /// A JSON array with an object for every account
+    Json,
+    /// A JSON object for every account on its own line
+    JsonLines,
+    /// A Parquet file, only for the state of accounts
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl OutputFormat {
+    /// Parses the format from its command line name.
+    pub fn from_name(name: &str) -> Option<OutputFormat> {
+        match name {
+            "csv" => Some(OutputFormat::Csv),
+            "json" => Some(OutputFormat::Json),
+            "jsonl" | "ndjson" => Some(OutputFormat::JsonLines),
+            #[cfg(feature = "parquet")]
+            "parquet" => Some(OutputFormat::Parquet),
+            _ => None,
+        }
+    }
+}
+
+/// All errors which can happen when writing the state of accounts
+#[derive(Error, Debug)]
+pub enum OutputError {
+    #[error(transparent)]
+    Csv(#[from] csv::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[cfg(feature = "parquet")]
+    #[error(transparent)]
+    Parquet(#[from] ParquetError),
+
+    #[cfg(feature = "parquet")]
+    #[error(transparent)]
+    Arrow(#[from] ArrowError),
+
+    #[error("only the state of accounts can be written as {0}")]
+    UnsupportedFormat(&'static str),
+}
+
+/// A row of the accounts state output
+#[derive(Serialize)]
+struct AccountRow {
+    client: ClientId,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+impl AccountRow {
+    fn new(client: ClientId, account: &AccountDetails) -> AccountRow {
+        AccountRow {
+            client,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            locked: account.is_locked(),
+        }
+    }
+}
+
+/// Writes the accounts in the given format.
+pub fn write_accounts<'a, W: io::Write>(
+    writer: W,
+    accounts: impl IntoIterator<Item = (ClientId, &'a AccountDetails)>,
+    format: OutputFormat,
+) -> Result<(), OutputError> {
+    match format {
+        OutputFormat::Csv => write_accounts_csv(writer, accounts)?,
+        OutputFormat::Json => write_accounts_json(writer, accounts)?,
+        OutputFormat::JsonLines => write_accounts_json_lines(writer, accounts)?,
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => write_accounts_parquet(writer, accounts)?,
+    }
+    Ok(())
+}
+
+/// Writes the header row followed by a row for every account.
+pub fn write_accounts_csv<'a, W: io::Write>(
+    writer: W,
+    accounts: impl IntoIterator<Item = (ClientId, &'a AccountDetails)>,
+) -> csv::Result<()> {
+    // the header is written by hand so that it's there even if there are no accounts
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    writer.write_record(["client", "available", "held", "total", "locked"])?;
+    for (client_id, account) in accounts {
+        writer.serialize(AccountRow::new(client_id, account))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the accounts as a JSON array. Amounts are strings so that no precision is lost.
+pub fn write_accounts_json<'a, W: io::Write>(
+    mut writer: W,
+    accounts: impl IntoIterator<Item = (ClientId, &'a AccountDetails)>,
+) -> Result<(), OutputError> {
+    let rows: Vec<AccountRow> = accounts
+        .into_iter()
+        .map(|(client_id, account)| AccountRow::new(client_id, account))
+        .collect();
+    serde_json::to_writer_pretty(&mut writer, &rows)?;
+    writeln!(writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the accounts as newline-delimited JSON objects.
+pub fn write_accounts_json_lines<'a, W: io::Write>(
+    mut writer: W,
+    accounts: impl IntoIterator<Item = (ClientId, &'a AccountDetails)>,
+) -> Result<(), OutputError> {
+    for (client_id, account) in accounts {
+        serde_json::to_writer(&mut writer, &AccountRow::new(client_id, account))?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// The accounts as an Arrow record batch.
+#[cfg(feature = "parquet")]
+// the conversion of the client is a no-op with 64-bit client ids
+#[allow(clippy::useless_conversion)]
+pub fn accounts_record_batch<'a>(
+    accounts: impl IntoIterator<Item = (ClientId, &'a AccountDetails)>,
+) -> Result<RecordBatch, ArrowError> {
+    let accounts: Vec<(ClientId, &AccountDetails)> = accounts.into_iter().collect();
+    let amounts = |amount: fn(&AccountDetails) -> Amount| -> Result<ArrayRef, ArrowError> {
+        let amounts = accounts
+            .iter()
+            .map(|(_, account)| amount(account).to_minor_units());
+        Ok(Arc::new(
+            Decimal128Array::from_iter_values(amounts).with_precision_and_scale(38, 4)?,
+        ))
+    };
+    let clients = accounts.iter().map(|(client, _)| u64::from(*client));
+    let locked = accounts
+        .iter()
+        .map(|(_, account)| Some(account.is_locked()));
+    RecordBatch::try_from_iter([
+        (
+            "client",
+            Arc::new(UInt64Array::from_iter_values(clients)) as ArrayRef,
+        ),
+        ("available", amounts(|account| account.available)?),
+        ("held", amounts(|account| account.held)?),
+        ("total", amounts(|account| account.total)?),
+        ("locked", Arc::new(BooleanArray::from_iter(locked))),
+    ])
+}
+
+/// Writes the accounts as a Parquet file with a single row group.
+#[cfg(feature = "parquet")]
+pub fn write_accounts_parquet<'a, W: io::Write>(
+    mut writer: W,
+    accounts: impl IntoIterator<Item = (ClientId, &'a AccountDetails)>,
+) -> Result<(), OutputError> {
+    let batch = accounts_record_batch(accounts)?;
+    // the writer of the file has to be Send, so the file is put together in memory first
+    let mut file = Vec::new();
+    let mut parquet_writer = ArrowWriter::try_new(&mut file, batch.schema(), None)?;
+    parquet_writer.write(&batch)?;
+    parquet_writer.close()?;
+    writer.write_all(&file)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the disputes in the given format, as a row or object for every dispute with its
+/// amount, the part of it which is still held and the part which was charged back.
+pub fn write_disputes<'a, W: io::Write>(
+    mut writer: W,
+    disputes: impl IntoIterator<Item = &'a Dispute>,
+    format: OutputFormat,
+) -> Result<(), OutputError> {
+    match format {
+        OutputFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(writer);
+            writer.write_record([
+                "dispute",
+                "tx",
+                "client",
+                "amount",
+                "remaining",
+                "charged_back",
+                "state",
+                "timestamp",
+            ])?;
+            for dispute in disputes {
+                writer.serialize(dispute)?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Json => {
+            let disputes: Vec<&Dispute> = disputes.into_iter().collect();
+            serde_json::to_writer_pretty(&mut writer, &disputes)?;
+            writeln!(writer)?;
+            writer.flush()?;
+        }
+        OutputFormat::JsonLines => {
+            for dispute in disputes {
+                serde_json::to_writer(&mut writer, dispute)?;
+                writeln!(writer)?;
+            }
+            writer.flush()?;
+        }
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => return Err(OutputError::UnsupportedFormat("Parquet")),
+    }
+    Ok(())
+}
+
+/// Writes the lines of a statement in the given format, as a row or object for every
+/// transaction with the balances after it.
+pub fn write_statement<W: io::Write>(
+    mut writer: W,
+    statement: &[StatementLine],
+    format: OutputFormat,
+) -> Result<(), OutputError> {
+    match format {
+        OutputFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(writer);
+            writer.write_record([
+                "seq",
+                "tx",
+                "type",
+                "amount",
+                "fee",
+                "timestamp",
+                "available",
+                "held",
+                "total",
+                "status",
+            ])?;
+            for line in statement {
+                writer.serialize(line)?;
+            }
+            writer.flush()?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, statement)?;
+            writeln!(writer)?;
+            writer.flush()?;
+        }
+        OutputFormat::JsonLines => {
+            for line in statement {
+                serde_json::to_writer(&mut writer, line)?;
+                writeln!(writer)?;
+            }
+            writer.flush()?;
+        }
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => return Err(OutputError::UnsupportedFormat("Parquet")),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_accounts, OutputFormat};
+    use crate::{AccountDetails, AccountStatus};
+
+    fn account(available: &str) -> AccountDetails {
+        let available = available.parse().unwrap();
+        AccountDetails {
+            available,
+            held: Default::default(),
+            total: available,
+            status: AccountStatus::Active,
+        }
+    }
+
+    #[test]
+    fn test_write_accounts_json() {
+        let account = AccountDetails {
+            available: "1.5".parse().unwrap(),
+            held: "0.25".parse().unwrap(),
+            total: "1.75".parse().unwrap(),
+            status: AccountStatus::Active,
+        };
+        let mut output = Vec::new();
+        write_accounts(&mut output, [(1, &account)], OutputFormat::Json).unwrap();
+        let accounts: serde_json::Value = serde_json::from_slice(&output).unwrap();
+        assert_eq!(
+            accounts,
+            serde_json::json!([{
+                "client": 1,
+                "available": "1.5000",
+                "held": "0.2500",
+                "total": "1.7500",
+                "locked": false
+            }])
+        );
+    }
+
+    #[test]
+    fn test_write_accounts_json_lines() {
+        let (first, second) = (account("1"), account("2.5"));
+        let mut output = Vec::new();
+        write_accounts(
+            &mut output,
+            [(1, &first), (2, &second)],
+            OutputFormat::JsonLines,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "{\"client\":1,\"available\":\"1.0000\",\"held\":\"0.0000\",\"total\":\"1.0000\",\"locked\":false}\n\
+             {\"client\":2,\"available\":\"2.5000\",\"held\":\"0.0000\",\"total\":\"2.5000\",\"locked\":false}\n"
+        );
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_write_accounts_parquet() {
+        use std::fs::{self, File};
+        use std::{env, process};
+
+        use arrow_array::cast::AsArray;
+        use arrow_array::types::{Decimal128Type, UInt64Type};
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let (first, second) = (account("1.5"), account("2"));
+        let path = env::temp_dir().join(format!("tte-accounts-{}.parquet", process::id()));
+        write_accounts(
+            File::create(&path).unwrap(),
+            [(7, &first), (9, &second)],
+            OutputFormat::Parquet,
+        )
+        .unwrap();
+        let mut batches = ParquetRecordBatchReaderBuilder::try_new(File::open(&path).unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        let batch = batches.next().unwrap().unwrap();
+        assert_eq!(batch.num_rows(), 2);
+        let clients = batch.column(0).as_primitive::<UInt64Type>();
+        assert_eq!(clients.values(), &[7, 9]);
+        let available = batch.column(1).as_primitive::<Decimal128Type>();
+        assert_eq!(available.value_as_string(0), "1.5000");
+        assert!(!batch.column(4).as_boolean().value(1));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,163 @@
+//! Processing of CSV input on two threads: a reader thread parses the rows into batches while
+//! the calling thread applies them to the engine.
+//!
+//! Batches are passed through a bounded channel, so the reader blocks once it is the given
+//! number of batches ahead of the engine instead of buffering the whole input in memory. If
+//! processing stops early, e.g. at the first bad row in strict mode, the channel is closed and
+//! the reader stops as well.
+
+use std::error::Error;
+use std::io::Read;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::control_totals::ControlTotalsPolicy;
+use crate::dialect::CsvDialect;
+use crate::ordering::{OrderingConfig, Reorderer};
+use crate::rejects::Rejections;
+use crate::{
+    process_held_rows, process_row_in_order, read_csv_with_dialect, CsvRow, TransactionEngine,
+};
+
+/// The number of rows in a batch by default
+pub const DEFAULT_BATCH_SIZE: usize = 1024;
+
+/// How many batches the reader may be ahead of the engine by default
+pub const DEFAULT_PIPELINE_DEPTH: usize = 8;
+
+/// The sizes of the batches and of the channel between the threads
+#[derive(Debug, Clone, Copy)]
+pub struct PipelineConfig {
+    /// The number of rows in a batch
+    pub batch_size: usize,
+    /// How many batches the reader may be ahead of the engine before it blocks
+    pub depth: usize,
+}
+
+impl Default for PipelineConfig {
+    fn default() -> Self {
+        PipelineConfig {
+            batch_size: DEFAULT_BATCH_SIZE,
+            depth: DEFAULT_PIPELINE_DEPTH,
+        }
+    }
+}
+
+/// The error of the reader thread. Errors are sent across threads as their message.
+type ReaderError = Box<dyn Error + Send + Sync>;
+
+/// Reads and processes CSV input like `process_csv_in_order`, with the reading done on another
+/// thread.
+pub(crate) fn process_csv_pipelined<R: Read + Send + 'static>(
+    transaction_engine: &mut TransactionEngine,
+    input: R,
+    dialect: CsvDialect,
+    control_totals_policy: ControlTotalsPolicy,
+    rejections: &mut Rejections,
+    pipeline_config: PipelineConfig,
+    ordering_config: OrderingConfig,
+) -> Result<(), Box<dyn Error>> {
+    let batch_size = pipeline_config.batch_size.max(1);
+    let (sender, receiver) = mpsc::sync_channel::<Vec<CsvRow>>(pipeline_config.depth);
+    let reader = thread::spawn(move || -> Result<(), ReaderError> {
+        let mut batch = Vec::with_capacity(batch_size);
+        read_csv_with_dialect(input, &dialect, control_totals_policy, |row| {
+            batch.push(row);
+            if batch.len() == batch_size {
+                let full_batch = std::mem::replace(&mut batch, Vec::with_capacity(batch_size));
+                // the engine stopped early, there is nobody left to read for
+                sender.send(full_batch).map_err(|_| "processing stopped")?;
+            }
+            Ok(())
+        })
+        .map_err(|e| e.to_string())?;
+        if !batch.is_empty() {
+            let _ = sender.send(batch);
+        }
+        Ok(())
+    });
+
+    let mut reorderer = Reorderer::new(ordering_config);
+    let processed = receiver
+        .iter()
+        .try_for_each(|batch| {
+            batch.into_iter().try_for_each(|row| {
+                process_row_in_order(transaction_engine, &mut reorderer, row, rejections)
+            })
+        })
+        .and_then(|()| process_held_rows(transaction_engine, &mut reorderer, rejections));
+    // closes the channel so that a reader blocked on a full channel sees that processing stopped
+    drop(receiver);
+    let read = reader.join().map_err(|_| "the reader thread panicked")?;
+    processed?;
+    read.map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{process_csv_pipelined, PipelineConfig};
+    use crate::control_totals::ControlTotalsPolicy;
+    use crate::dialect::CsvDialect;
+    use crate::ordering::OrderingConfig;
+    use crate::rejects::{ProcessingPolicy, Rejections};
+    use crate::{process_reader_with_rejections, TransactionEngine};
+
+    const INPUT: &str = "type, client, tx, amount\n\
+                         deposit, 1, 1, 5\n\
+                         deposit, 2, 2, 3\n\
+                         withdrawal, 1, 3, 7\n\
+                         withdrawal, 1, 4, 2\n\
+                         dispute, 2, 2,\n\
+                         deposit, x, 5, 1\n\
+                         deposit, 3, 6, 1\n";
+
+    #[test]
+    fn test_pipeline_matches_sequential_processing() {
+        let (expected, expected_rejections) =
+            process_reader_with_rejections(INPUT.as_bytes()).unwrap();
+
+        let mut transaction_engine = TransactionEngine::new();
+        let mut rejections = Rejections::new(ProcessingPolicy::Skip, false);
+        process_csv_pipelined(
+            &mut transaction_engine,
+            INPUT.as_bytes(),
+            CsvDialect::default(),
+            ControlTotalsPolicy::Fail,
+            &mut rejections,
+            PipelineConfig {
+                batch_size: 2,
+                depth: 1,
+            },
+            OrderingConfig::default(),
+        )
+        .unwrap();
+        assert_eq!(
+            transaction_engine.sorted_accounts(),
+            expected.sorted_accounts()
+        );
+        let lines = |rejections: &[crate::rejects::Rejection]| -> Vec<u64> {
+            rejections.iter().map(|rejection| rejection.line).collect()
+        };
+        assert_eq!(lines(&rejections.into_vec()), lines(&expected_rejections));
+    }
+
+    #[test]
+    fn test_pipeline_stops_at_first_rejection_in_strict_mode() {
+        let mut transaction_engine = TransactionEngine::new();
+        let result = process_csv_pipelined(
+            &mut transaction_engine,
+            INPUT.as_bytes(),
+            CsvDialect::default(),
+            ControlTotalsPolicy::Fail,
+            &mut Rejections::new(ProcessingPolicy::Strict, false),
+            PipelineConfig {
+                batch_size: 1,
+                depth: 1,
+            },
+            OrderingConfig::default(),
+        );
+        assert!(result.unwrap_err().to_string().starts_with("line 4:"));
+        assert!(transaction_engine.get_account(3).is_none());
+    }
+}
@@ -0,0 +1,203 @@
+//! The policies of the long running modes which can be changed without restarting them.
+//!
+//! The daemon and the HTTP server read them from a TOML file passed with `--config`, e.g.
+//!
+//! ```toml
+//! control_totals = "fail"
+//! locked_accounts = "deposits"
+//! fees = "fees.toml"
+//! rules = "rules.toml"
+//! ```
+//!
+//! and read it again when they are asked to reload. `fees` and `rules` are the paths of a fee
+//! schedule and of a rules file in the formats of `--fees` and `--rules`, which are read again
+//! as well. If any of the files can't be read or parsed, the policies in effect stay as they
+//! are. Everything else, like the zero amount policy or the dispute window, is fixed for the
+//! lifetime of the process.
+
+use std::error::Error;
+use std::fs;
+
+use serde::Deserialize;
+
+use crate::control_totals::ControlTotalsPolicy;
+use crate::fees::FeeSchedule;
+use crate::rules::RulesConfig;
+use crate::transaction_engine::LockedAccountPolicy;
+use crate::TransactionEngine;
+
+/// The policies as written in the config file
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PolicyConfig {
+    pub control_totals: Option<ControlTotalsPolicy>,
+    pub locked_accounts: Option<LockedAccountPolicy>,
+    /// The path of the fee schedule, no fees are charged without one
+    pub fees: Option<String>,
+    /// The path of the rules file, no limits are checked without one
+    pub rules: Option<String>,
+}
+
+impl PolicyConfig {
+    /// Reads the policies from a TOML file.
+    pub fn from_path(path: &str) -> Result<PolicyConfig, Box<dyn Error>> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+}
+
+/// The policies in effect
+#[derive(Debug, Clone, PartialEq)]
+pub struct Policies {
+    pub control_totals_policy: ControlTotalsPolicy,
+    pub locked_account_policy: LockedAccountPolicy,
+    pub fee_schedule: Option<FeeSchedule>,
+    pub rules: RulesConfig,
+}
+
+impl Policies {
+    /// Applies the policies to the engine. The rules are only replaced if they differ from the
+    /// ones of the `replaced` policies, so that unchanged daily and rate limits keep what
+    /// counted towards them.
+    pub fn apply_to(
+        &self,
+        transaction_engine: &mut TransactionEngine,
+        replaced: Option<&Policies>,
+    ) {
+        transaction_engine.set_locked_account_policy(self.locked_account_policy);
+        transaction_engine.set_fee_schedule(self.fee_schedule);
+        if replaced.is_none_or(|replaced| replaced.rules != self.rules) {
+            transaction_engine.clear_rules();
+            for rule in self.rules.rules() {
+                transaction_engine.add_rule(rule);
+            }
+        }
+    }
+}
+
+/// The policies of a long running mode together with the file they are reloaded from
+#[derive(Debug)]
+pub struct ReloadablePolicies {
+    config_path: Option<String>,
+    /// The control totals policy of the command line, used if the file doesn't set one
+    control_totals_policy: ControlTotalsPolicy,
+    current: Policies,
+}
+
+impl ReloadablePolicies {
+    /// Policies without a config file, which stay as they are on a reload.
+    pub fn new(control_totals_policy: ControlTotalsPolicy) -> ReloadablePolicies {
+        ReloadablePolicies {
+            config_path: None,
+            control_totals_policy,
+            current: Policies {
+                control_totals_policy,
+                locked_account_policy: LockedAccountPolicy::default(),
+                fee_schedule: None,
+                rules: RulesConfig::default(),
+            },
+        }
+    }
+
+    /// Reads the policies from the config file, if there is one.
+    pub fn from_path(
+        config_path: Option<String>,
+        control_totals_policy: ControlTotalsPolicy,
+    ) -> Result<ReloadablePolicies, Box<dyn Error>> {
+        let mut policies = ReloadablePolicies::new(control_totals_policy);
+        policies.config_path = config_path;
+        policies.reload()?;
+        Ok(policies)
+    }
+
+    /// The policies in effect
+    pub fn current(&self) -> &Policies {
+        &self.current
+    }
+
+    /// Reads the config file and the files it names again, keeping the current policies if
+    /// that fails.
+    pub fn reload(&mut self) -> Result<(), Box<dyn Error>> {
+        let Some(config_path) = &self.config_path else {
+            return Ok(());
+        };
+        let policy_config = PolicyConfig::from_path(config_path)?;
+        self.current = Policies {
+            control_totals_policy: policy_config
+                .control_totals
+                .unwrap_or(self.control_totals_policy),
+            locked_account_policy: policy_config.locked_accounts.unwrap_or_default(),
+            fee_schedule: match &policy_config.fees {
+                Some(fees_path) => Some(FeeSchedule::from_path(fees_path)?),
+                None => None,
+            },
+            rules: match &policy_config.rules {
+                Some(rules_path) => RulesConfig::from_path(rules_path)?,
+                None => RulesConfig::default(),
+            },
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use super::ReloadablePolicies;
+    use crate::control_totals::ControlTotalsPolicy;
+    use crate::transaction_engine::LockedAccountPolicy;
+    use crate::{TransactionEngine, TransactionInput};
+
+    #[test]
+    fn test_reload_replaces_policies_only_if_all_files_are_valid() {
+        let directory = env::temp_dir().join(format!("tte-policy-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = |name: &str| directory.join(name).to_string_lossy().to_string();
+        fs::write(path("rules.toml"), "max_amount = \"10\"\n").unwrap();
+        fs::write(
+            path("policies.toml"),
+            format!(
+                "locked_accounts = \"deposits\"\nrules = \"{}\"\n",
+                path("rules.toml")
+            ),
+        )
+        .unwrap();
+
+        let mut policies =
+            ReloadablePolicies::from_path(Some(path("policies.toml")), ControlTotalsPolicy::Fail)
+                .unwrap();
+        assert_eq!(
+            policies.current().control_totals_policy,
+            ControlTotalsPolicy::Fail
+        );
+        assert_eq!(
+            policies.current().locked_account_policy,
+            LockedAccountPolicy::DepositsOnly
+        );
+        let mut transaction_engine = TransactionEngine::new();
+        policies.current().apply_to(&mut transaction_engine, None);
+        assert!(transaction_engine
+            .process_transaction(TransactionInput::deposit(1, 1, "11".parse().unwrap()))
+            .is_err());
+
+        // a broken rules file keeps the limit in place
+        fs::write(path("rules.toml"), "max_amount = \"ten\"\n").unwrap();
+        assert!(policies.reload().is_err());
+        assert_eq!(
+            policies.current().locked_account_policy,
+            LockedAccountPolicy::DepositsOnly
+        );
+
+        // without the rules file the limit is gone
+        fs::write(path("policies.toml"), "").unwrap();
+        let previous = policies.current().clone();
+        policies.reload().unwrap();
+        policies
+            .current()
+            .apply_to(&mut transaction_engine, Some(&previous));
+        transaction_engine
+            .process_transaction(TransactionInput::deposit(1, 1, "11".parse().unwrap()))
+            .unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
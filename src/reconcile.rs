@@ -0,0 +1,303 @@
+//! Reconciling the state of the engine against an accounts CSV produced earlier, or against
+//! the balances of MT940/MT942 bank statements, and the `reconcile` subcommand doing so for an
+//! input.
+//!
+//! Every account is compared field by field. An account which is only expected, or only in the
+//! engine, is a difference of its own. Statements only give the booked balance of the accounts
+//! they cover, so only the total of those accounts is compared.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read};
+
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+
+use crate::control_totals::ControlTotalsPolicy;
+use crate::mt940::{latest_balances, parse_statements, Mt940Error};
+use crate::rejects::{ProcessingPolicy, Rejections};
+use crate::{
+    csv_reader, parse_zero_amount_policy, process_csv, AccountDetails, Amount, ClientId,
+    TransactionEngine, ZeroAmountPolicy, STDIN_PATH,
+};
+
+/// Processes a CSV file of transactions and compares the resulting accounts with the expected
+/// ones, exiting with a nonzero code if they differ.
+#[derive(Parser, Debug)]
+#[command(name = "reconcile", bin_name = "toy-transaction-engine reconcile")]
+pub struct ReconcileConfig {
+    /// The input file, or - to read from stdin
+    #[arg(default_value = STDIN_PATH)]
+    pub input_path: String,
+
+    /// The accounts CSV, or the MT940/MT942 statements, the state is compared with
+    #[arg(long, value_name = "PATH")]
+    pub expected: String,
+
+    /// The format of the expected state: csv for an accounts CSV or mt940 for bank statements
+    #[arg(long, default_value = "csv", value_parser = parse_expected_format)]
+    pub expected_format: ExpectedFormat,
+
+    /// Start from the state saved in this snapshot instead of empty accounts
+    #[arg(long)]
+    pub load_snapshot: Option<String>,
+
+    /// What to do with deposits and withdrawals of a zero amount: skip or reject
+    #[arg(long = "zero-amounts", default_value = "reject", value_parser = parse_zero_amount_policy)]
+    pub zero_amount_policy: ZeroAmountPolicy,
+}
+
+impl ReconcileConfig {
+    /// Parses the arguments starting with the `reconcile` subcommand.
+    pub fn new(args: &[String]) -> Result<ReconcileConfig, clap::Error> {
+        ReconcileConfig::try_parse_from(args)
+    }
+}
+
+/// The format of the expected state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpectedFormat {
+    /// An accounts CSV as written by the engine
+    Csv,
+    /// MT940 statements and MT942 interim reports
+    Mt940,
+}
+
+impl ExpectedFormat {
+    /// The format for its command line name
+    pub fn from_name(name: &str) -> Option<ExpectedFormat> {
+        match name {
+            "csv" => Some(ExpectedFormat::Csv),
+            "mt940" => Some(ExpectedFormat::Mt940),
+            _ => None,
+        }
+    }
+}
+
+fn parse_expected_format(name: &str) -> Result<ExpectedFormat, &'static str> {
+    ExpectedFormat::from_name(name).ok_or("must be one of csv or mt940")
+}
+
+/// A row of an accounts CSV
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+pub(crate) struct AccountRecord {
+    pub client: ClientId,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub locked: bool,
+}
+
+/// Reads the accounts of an accounts CSV by client.
+pub(crate) fn read_accounts<R: Read>(
+    reader: R,
+) -> Result<BTreeMap<ClientId, AccountRecord>, csv::Error> {
+    let mut accounts = BTreeMap::new();
+    for account in csv_reader(reader).deserialize() {
+        let account: AccountRecord = account?;
+        accounts.insert(account.client, account);
+    }
+    Ok(accounts)
+}
+
+/// A way an account differs from what was expected
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Difference {
+    pub client: ClientId,
+    /// The field which differs, or `account` for an account which is missing or unexpected
+    pub field: &'static str,
+    /// The expected value, empty for an unexpected account
+    pub expected: String,
+    /// The value in the engine, empty for a missing account
+    pub actual: String,
+}
+
+/// Compares the accounts of the engine with the accounts CSV, returning the differences sorted
+/// by client.
+pub fn reconcile<R: Read>(
+    expected: R,
+    transaction_engine: &TransactionEngine,
+) -> Result<Vec<Difference>, csv::Error> {
+    let expected_accounts = read_accounts(expected)?;
+    let mut differences = Vec::new();
+    for (client, expected) in &expected_accounts {
+        match transaction_engine.get_account(*client) {
+            Some(account) => compare(expected, account, &mut differences),
+            None => differences.push(Difference {
+                client: *client,
+                field: "account",
+                expected: "present".to_string(),
+                actual: String::new(),
+            }),
+        }
+    }
+    for (client, _) in transaction_engine.sorted_accounts() {
+        if !expected_accounts.contains_key(&client) {
+            differences.push(Difference {
+                client,
+                field: "account",
+                expected: String::new(),
+                actual: "present".to_string(),
+            });
+        }
+    }
+    differences.sort_by_key(|difference| difference.client);
+    Ok(differences)
+}
+
+/// Compares the totals of the accounts of the engine with the latest booked balances of the
+/// MT940/MT942 statements, returning the differences sorted by client. Accounts without a
+/// statement aren't compared.
+pub fn reconcile_statements(
+    statements: &str,
+    transaction_engine: &TransactionEngine,
+) -> Result<Vec<Difference>, Mt940Error> {
+    let balances = latest_balances(&parse_statements(statements)?)?;
+    let mut differences = Vec::new();
+    for (client, balance) in balances {
+        match transaction_engine.get_account(client) {
+            Some(account) if account.total != balance => differences.push(Difference {
+                client,
+                field: "total",
+                expected: balance.to_string(),
+                actual: account.total.to_string(),
+            }),
+            Some(_) => (),
+            None => differences.push(Difference {
+                client,
+                field: "account",
+                expected: "present".to_string(),
+                actual: String::new(),
+            }),
+        }
+    }
+    differences.sort_by_key(|difference| difference.client);
+    Ok(differences)
+}
+
+fn compare(expected: &AccountRecord, account: &AccountDetails, differences: &mut Vec<Difference>) {
+    let fields = [
+        ("available", expected.available, account.available),
+        ("held", expected.held, account.held),
+        ("total", expected.total, account.total),
+    ];
+    for (field, expected_value, actual_value) in fields {
+        if expected_value != actual_value {
+            differences.push(Difference {
+                client: expected.client,
+                field,
+                expected: expected_value.to_string(),
+                actual: actual_value.to_string(),
+            });
+        }
+    }
+    if expected.locked != account.is_locked() {
+        differences.push(Difference {
+            client: expected.client,
+            field: "locked",
+            expected: expected.locked.to_string(),
+            actual: account.is_locked().to_string(),
+        });
+    }
+}
+
+/// Writes the differences as CSV.
+pub fn write_differences<W: io::Write>(writer: W, differences: &[Difference]) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    writer.write_record(["client", "field", "expected", "actual"])?;
+    for difference in differences {
+        writer.serialize(difference)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Processes the input and writes the differences to stdout, returning how many there are.
+/// Rows which can't be applied are skipped and logged.
+pub fn run(config: ReconcileConfig) -> Result<usize, Box<dyn Error>> {
+    let mut transaction_engine = match &config.load_snapshot {
+        Some(snapshot_path) => {
+            TransactionEngine::restore(BufReader::new(File::open(snapshot_path)?))?
+        }
+        None => TransactionEngine::new(),
+    };
+    transaction_engine.set_zero_amount_policy(config.zero_amount_policy);
+    let input: Box<dyn Read> = if config.input_path == STDIN_PATH {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(&config.input_path)?))
+    };
+    process_csv(
+        &mut transaction_engine,
+        input,
+        ControlTotalsPolicy::Warn,
+        &mut Rejections::new(ProcessingPolicy::Skip, true),
+    )?;
+    let differences = match config.expected_format {
+        ExpectedFormat::Csv => reconcile(File::open(&config.expected)?, &transaction_engine)?,
+        ExpectedFormat::Mt940 => {
+            reconcile_statements(&fs::read_to_string(&config.expected)?, &transaction_engine)?
+        }
+    };
+    write_differences(BufWriter::new(io::stdout().lock()), &differences)?;
+    Ok(differences.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reconcile, reconcile_statements, write_differences};
+    use crate::{TransactionEngine, TransactionInput};
+
+    #[test]
+    fn test_differences_are_reported_per_client() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "10".parse().unwrap()),
+            TransactionInput::deposit(2, 2, "5".parse().unwrap()),
+            TransactionInput::deposit(4, 3, "1".parse().unwrap()),
+        ]);
+        let expected = "client,available,held,total,locked\n\
+                        1,10.0000,0.0000,10.0000,false\n\
+                        2,4.0000,0.0000,4.0000,true\n\
+                        3,1.0000,0.0000,1.0000,false\n";
+        let differences = reconcile(expected.as_bytes(), &transaction_engine).unwrap();
+
+        let mut output = Vec::new();
+        write_differences(&mut output, &differences).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,field,expected,actual\n\
+             2,available,4.0000,5.0000\n\
+             2,total,4.0000,5.0000\n\
+             2,locked,true,false\n\
+             3,account,present,\n\
+             4,account,,present\n"
+        );
+    }
+
+    #[test]
+    fn test_totals_are_reconciled_against_statements() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "10".parse().unwrap()),
+            TransactionInput::deposit(2, 2, "5".parse().unwrap()),
+            TransactionInput::deposit(4, 3, "1".parse().unwrap()),
+        ]);
+        let statements = ":20:STMT1\n:25:10020030/1\n:62F:C220902EUR10,00\n\
+                          :20:STMT2\n:25:10020030/2\n:62F:C220902EUR4,00\n\
+                          :20:STMT3\n:25:10020030/3\n:62F:C220902EUR1,00\n";
+        let differences = reconcile_statements(statements, &transaction_engine).unwrap();
+
+        let mut output = Vec::new();
+        write_differences(&mut output, &differences).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,field,expected,actual\n\
+             2,total,4.0000,5.0000\n\
+             3,account,present,\n"
+        );
+    }
+}
@@ -0,0 +1,384 @@
+//! Collects the rows which were skipped while processing an input, so that they can be
+//! inspected through the library or written to a rejections file for correction and replay.
+
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::{fmt, io};
+
+use csv::StringRecord;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::dialect::CsvDialect;
+use crate::events::CsvResultsObserver;
+use crate::transaction_engine::TransactionProcessingError;
+use crate::{Timestamp, TransactionInput};
+
+/// What to do with rows which can't be read or processed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProcessingPolicy {
+    /// Skip the row, record it as a rejection and continue with the next one
+    #[default]
+    Skip,
+    /// Stop processing with the rejection as the error
+    Strict,
+}
+
+/// Why a row was skipped
+#[derive(Error, Debug)]
+#[non_exhaustive]
+pub enum RejectionError {
+    #[error("the row couldn't be read: {0}")]
+    Parse(String),
+
+    #[error("the row at {timestamp} arrived after a row at {latest}")]
+    OutOfOrder {
+        timestamp: Timestamp,
+        latest: Timestamp,
+    },
+
+    #[error(transparent)]
+    Processing(#[from] TransactionProcessingError),
+}
+
+impl RejectionError {
+    /// A short, machine-readable name of the error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RejectionError::Parse(_) => "parse_error",
+            RejectionError::OutOfOrder { .. } => "out_of_order",
+            RejectionError::Processing(e) => e.kind(),
+        }
+    }
+}
+
+/// A row which was skipped
+#[derive(Debug)]
+pub struct Rejection {
+    /// The line the row starts on, or the position of the transaction for inputs which aren't
+    /// line based
+    pub line: u64,
+    /// The row as CSV, or as it was read for JSON Lines input
+    pub record: String,
+    pub error: RejectionError,
+}
+
+impl Rejection {
+    /// Creates a rejection of a CSV row.
+    pub fn from_record(line: u64, record: &StringRecord, error: RejectionError) -> Rejection {
+        Rejection {
+            line,
+            record: to_csv_line(|writer| writer.write_record(record)),
+            error,
+        }
+    }
+
+    /// Creates a rejection of a line of JSON Lines input.
+    pub fn from_line(line: u64, text: &str, error: RejectionError) -> Rejection {
+        Rejection {
+            line,
+            record: text.trim().to_string(),
+            error,
+        }
+    }
+
+    /// Creates a rejection of a transaction read from an input which isn't CSV.
+    pub fn from_transaction(
+        position: u64,
+        transaction: &TransactionInput,
+        error: RejectionError,
+    ) -> Rejection {
+        Rejection {
+            line: position,
+            record: to_csv_line(|writer| writer.serialize(transaction)),
+            error,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {} ({})", self.line, self.error, self.record)
+    }
+}
+
+impl std::error::Error for Rejection {}
+
+/// The share of the rows of a run which may be rejected before the run counts as failed
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct RejectThreshold {
+    /// Between 0, failing the run on any rejected row, and 1
+    pub max_rate: f64,
+}
+
+/// A run rejected more rows than its threshold allows
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("{rejected} of {rows} rows were rejected, more than the allowed rate of {max_rate}")]
+pub struct TooManyRejections {
+    pub rejected: u64,
+    pub rows: u64,
+    pub max_rate: f64,
+}
+
+impl RejectThreshold {
+    /// Checks the number of rejected rows out of all rows read.
+    pub fn check(&self, rejected: u64, rows: u64) -> Result<(), TooManyRejections> {
+        if rejected > 0 && rejected as f64 > self.max_rate * rows as f64 {
+            return Err(TooManyRejections {
+                rejected,
+                rows,
+                max_rate: self.max_rate,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Collects the rejected rows of a run, deciding with the processing policy whether the run
+/// continues after each of them
+pub(crate) struct Rejections {
+    processing_policy: ProcessingPolicy,
+    log: bool,
+    rejected: Vec<Rejection>,
+    results: Option<CsvResultsObserver<File>>,
+}
+
+impl Rejections {
+    /// Creates an empty collection. With `log`, every skipped row is also logged to stderr.
+    pub(crate) fn new(processing_policy: ProcessingPolicy, log: bool) -> Rejections {
+        Rejections {
+            processing_policy,
+            log,
+            rejected: Vec::new(),
+            results: None,
+        }
+    }
+
+    /// Also writes every row which never reached the engine to the results file of the run.
+    pub(crate) fn set_results(&mut self, results: CsvResultsObserver<File>) {
+        self.results = Some(results);
+    }
+
+    /// Records a rejected row, or returns it as the error in strict mode. Rows which failed on
+    /// the write-ahead log or the transaction store always stop processing, as every row after
+    /// them would most likely fail the same way, and so do rows which broke an invariant.
+    pub(crate) fn reject(&mut self, rejection: Rejection) -> Result<(), Rejection> {
+        self.reject_read(rejection, None)
+    }
+
+    /// Records a rejected row like `reject`, writing it to the results file as the transaction
+    /// it was read as, e.g. for a row which came out of order.
+    pub(crate) fn reject_read(
+        &mut self,
+        rejection: Rejection,
+        transaction: Option<&TransactionInput>,
+    ) -> Result<(), Rejection> {
+        if let Some(results) = &mut self.results {
+            results.on_unread(&rejection, transaction);
+        }
+        if let RejectionError::Processing(
+            TransactionProcessingError::WalWriteFailed(_)
+            | TransactionProcessingError::StorageFailed(_)
+            | TransactionProcessingError::InvariantViolated { .. },
+        ) = rejection.error
+        {
+            return Err(rejection);
+        }
+        match self.processing_policy {
+            ProcessingPolicy::Skip => {
+                if self.log {
+                    warn!("An error occurred when processing a transaction and it was skipped. We'll continue with next transactions. Error: {}", rejection);
+                }
+                self.rejected.push(rejection);
+                Ok(())
+            }
+            ProcessingPolicy::Strict => Err(rejection),
+        }
+    }
+
+    /// The rows rejected so far
+    pub(crate) fn rejected(&self) -> &[Rejection] {
+        &self.rejected
+    }
+
+    pub(crate) fn into_vec(self) -> Vec<Rejection> {
+        self.rejected
+    }
+}
+
+/// A row of the rejections file
+#[derive(Serialize)]
+pub(crate) struct RejectionRow<'a> {
+    line: u64,
+    kind: &'static str,
+    error: String,
+    record: &'a str,
+}
+
+impl<'a> From<&'a Rejection> for RejectionRow<'a> {
+    fn from(rejection: &'a Rejection) -> RejectionRow<'a> {
+        RejectionRow {
+            line: rejection.line,
+            kind: rejection.error.kind(),
+            error: rejection.error.to_string(),
+            record: &rejection.record,
+        }
+    }
+}
+
+/// Writes the rejections as CSV with a `line,kind,error,record` header.
+pub fn write_rejections<W: io::Write>(writer: W, rejections: &[Rejection]) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    writer.write_record(["line", "kind", "error", "record"])?;
+    for rejection in rejections {
+        writer.serialize(RejectionRow::from(rejection))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Copies the rejected rows of a CSV input verbatim to the writer, after the header row of the
+/// input. Every row gets the line it starts on in a first `line` column, which the engine
+/// ignores, so the rows can be fixed and submitted again as they are.
+pub fn write_raw_rejections<R: Read + Seek, W: Write>(
+    input: R,
+    dialect: &CsvDialect,
+    mut writer: W,
+    rejections: &[Rejection],
+) -> io::Result<()> {
+    let lines: BTreeSet<u64> = rejections.iter().map(|rejection| rejection.line).collect();
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .delimiter(dialect.delimiter)
+        .quote(dialect.quote)
+        .comment(dialect.comment)
+        .from_reader(input);
+    // the byte ranges of the header and of the rejected rows, each ending where the next row
+    // starts
+    let mut ranges = Vec::new();
+    let mut open: Option<(u64, u64)> = None;
+    let mut record = csv::ByteRecord::new();
+    while reader.read_byte_record(&mut record)? {
+        let Some(position) = record.position() else {
+            continue;
+        };
+        if let Some((line, start)) = open.take() {
+            ranges.push((line, start, position.byte()));
+        }
+        if position.line() == 1 || lines.contains(&position.line()) {
+            open = Some((position.line(), position.byte()));
+        }
+    }
+    let mut input = reader.into_inner();
+    if let Some((line, start)) = open {
+        ranges.push((line, start, input.seek(SeekFrom::End(0))?));
+    }
+    for (line, start, end) in ranges {
+        let mut raw = vec![0; (end - start) as usize];
+        input.seek(SeekFrom::Start(start))?;
+        input.read_exact(&mut raw)?;
+        // a row after a CRLF terminator starts at its LF
+        let start = raw.iter().take_while(|byte| **byte == b'\n').count();
+        let raw = raw[start..].trim_ascii_end();
+        if line == 1 {
+            writer.write_all(b"line")?;
+        } else {
+            write!(writer, "{}", line)?;
+        }
+        writer.write_all(&[dialect.delimiter])?;
+        writer.write_all(raw)?;
+        writer.write_all(b"\n")?;
+    }
+    writer.flush()
+}
+
+/// Renders a single row as a CSV line without the line terminator.
+fn to_csv_line(write: impl FnOnce(&mut csv::Writer<Vec<u8>>) -> csv::Result<()>) -> String {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .terminator(csv::Terminator::Any(b'\n'))
+        .from_writer(Vec::new());
+    // writing to memory can't fail
+    let _ = write(&mut writer);
+    let line = writer.into_inner().unwrap_or_default();
+    String::from_utf8_lossy(&line).trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use csv::StringRecord;
+
+    use std::io::Cursor;
+
+    use super::{
+        write_raw_rejections, write_rejections, RejectThreshold, Rejection, RejectionError,
+    };
+    use crate::dialect::CsvDialect;
+    use crate::process_reader_with_rejections;
+    use crate::transaction_engine::TransactionProcessingError;
+
+    #[test]
+    fn test_write_rejections() {
+        let rejections = vec![
+            Rejection::from_record(
+                3,
+                &StringRecord::from(vec!["withdrawal", "1", "2", "5.0"]),
+                TransactionProcessingError::InsufficientFunds.into(),
+            ),
+            Rejection::from_record(
+                4,
+                &StringRecord::from(vec!["deposit", "x", "3", "1,5"]),
+                RejectionError::Parse("invalid client".to_string()),
+            ),
+        ];
+        let mut output = Vec::new();
+        write_rejections(&mut output, &rejections).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "line,kind,error,record\n\
+             3,insufficient_funds,transaction cannot be completed due to insufficient funds,\"withdrawal,1,2,5.0\"\n\
+             4,parse_error,the row couldn't be read: invalid client,\"deposit,x,3,\"\"1,5\"\"\"\n"
+        );
+    }
+
+    #[test]
+    fn test_rejected_rows_are_copied_verbatim() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 2.5\n\
+                     deposit,\"x\n\",3,1.0\n\
+                     deposit, 1, 4, 1.0\n\
+                     withdrawal,1,2,  5.0\r\n";
+        let (_, rejections) = process_reader_with_rejections(input.as_bytes()).unwrap();
+        let mut output = Vec::new();
+        write_raw_rejections(
+            Cursor::new(input),
+            &CsvDialect::default(),
+            &mut output,
+            &rejections,
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "line,type, client, tx, amount\n\
+             3,deposit,\"x\n\",3,1.0\n\
+             6,withdrawal,1,2,  5.0\n"
+        );
+    }
+
+    #[test]
+    fn test_reject_threshold() {
+        let threshold = RejectThreshold { max_rate: 0.01 };
+        assert!(threshold.check(1, 100).is_ok());
+        assert_eq!(
+            threshold.check(2, 100).unwrap_err().to_string(),
+            "2 of 100 rows were rejected, more than the allowed rate of 0.01"
+        );
+        assert!(RejectThreshold::default().check(0, 100).is_ok());
+        assert!(RejectThreshold::default().check(1, 100).is_err());
+    }
+}
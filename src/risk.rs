@@ -0,0 +1,241 @@
+//! Scoring the risk of transactions before they are applied.
+//!
+//! A [`RiskAssessor`] looks at every transaction and allows it, denies it, or holds it for
+//! review. Transactions held for review aren't applied but put into the quarantine of the
+//! engine, from where they can be exported with [`write_quarantine`] and released or dropped once
+//! somebody decided about them. The export is CSV input with an `id` and `reason` column on top,
+//! so the approved rows can be submitted again as they are.
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+
+use serde::Serialize;
+
+use crate::{Amount, ClientId, Timestamp, TransactionId, TransactionInput, TransactionType};
+
+/// The disputes a client may file before further ones are held for review, by default
+pub const DEFAULT_MAX_DISPUTES: u32 = 3;
+
+/// The withdrawals a client may make within the window before further ones are held for review,
+/// by default
+pub const DEFAULT_MAX_WITHDRAWALS: u32 = 3;
+
+/// The seconds withdrawals are counted over, by default
+pub const DEFAULT_WITHDRAWAL_WINDOW: u64 = 60;
+
+/// What to do with a transaction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RiskDecision {
+    Allow,
+    /// Hold the transaction until somebody decided about it, for the reason given
+    Review(String),
+    /// Reject the transaction for the reason given
+    Deny(String),
+}
+
+/// What was decided about a transaction held for review
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReviewDecision {
+    /// Apply the transaction without assessing it again
+    Approve,
+    /// Drop the transaction
+    Reject,
+}
+
+/// Decides about every transaction before it is applied
+pub trait RiskAssessor {
+    /// Assesses the transaction, happening at the time given.
+    fn assess(&self, transaction: &TransactionInput, now: Timestamp) -> RiskDecision;
+
+    /// Called once the transaction was applied, e.g. to keep track of the history of clients.
+    fn applied(&mut self, _transaction: &TransactionInput, _now: Timestamp) {}
+}
+
+/// A transaction held for review
+#[derive(Debug, Clone)]
+pub struct QuarantinedTransaction {
+    /// The id given out by the engine in the order transactions were quarantined
+    pub id: u64,
+    pub transaction: TransactionInput,
+    pub reason: String,
+}
+
+/// Holds the disputes of clients who filed many of them and the withdrawals of clients who made
+/// many in a short time for review
+#[derive(Debug, Clone)]
+pub struct HeuristicRiskAssessor {
+    max_disputes: u32,
+    max_withdrawals: u32,
+    withdrawal_window: u64,
+    /// The disputes every client filed
+    disputes: HashMap<ClientId, u32>,
+    /// The times of every client's withdrawals within the window, oldest first
+    withdrawals: HashMap<ClientId, VecDeque<Timestamp>>,
+}
+
+impl HeuristicRiskAssessor {
+    pub fn new(max_disputes: u32, max_withdrawals: u32, withdrawal_window: u64) -> Self {
+        HeuristicRiskAssessor {
+            max_disputes,
+            max_withdrawals,
+            withdrawal_window,
+            disputes: HashMap::new(),
+            withdrawals: HashMap::new(),
+        }
+    }
+
+    fn recent_withdrawals(&self, client: ClientId, now: Timestamp) -> usize {
+        self.withdrawals.get(&client).map_or(0, |withdrawals| {
+            withdrawals
+                .iter()
+                .filter(|timestamp| timestamp.saturating_add(self.withdrawal_window) > now)
+                .count()
+        })
+    }
+}
+
+impl Default for HeuristicRiskAssessor {
+    fn default() -> Self {
+        HeuristicRiskAssessor::new(
+            DEFAULT_MAX_DISPUTES,
+            DEFAULT_MAX_WITHDRAWALS,
+            DEFAULT_WITHDRAWAL_WINDOW,
+        )
+    }
+}
+
+impl RiskAssessor for HeuristicRiskAssessor {
+    fn assess(&self, transaction: &TransactionInput, now: Timestamp) -> RiskDecision {
+        match transaction.kind {
+            TransactionType::Dispute
+                if self.disputes.get(&transaction.client).copied().unwrap_or(0)
+                    >= self.max_disputes =>
+            {
+                RiskDecision::Review(format!(
+                    "the client filed {} disputes already",
+                    self.max_disputes
+                ))
+            }
+            TransactionType::Withdrawal
+                if self.recent_withdrawals(transaction.client, now)
+                    >= self.max_withdrawals as usize =>
+            {
+                RiskDecision::Review(format!(
+                    "the client made {} withdrawals within {} seconds already",
+                    self.max_withdrawals, self.withdrawal_window
+                ))
+            }
+            _ => RiskDecision::Allow,
+        }
+    }
+
+    fn applied(&mut self, transaction: &TransactionInput, now: Timestamp) {
+        match transaction.kind {
+            TransactionType::Dispute => *self.disputes.entry(transaction.client).or_default() += 1,
+            TransactionType::Withdrawal => {
+                let withdrawal_window = self.withdrawal_window;
+                let withdrawals = self.withdrawals.entry(transaction.client).or_default();
+                while withdrawals
+                    .front()
+                    .is_some_and(|timestamp| timestamp.saturating_add(withdrawal_window) <= now)
+                {
+                    withdrawals.pop_front();
+                }
+                withdrawals.push_back(now);
+            }
+            _ => (),
+        }
+    }
+}
+
+/// A line of the quarantine export
+#[derive(Serialize)]
+struct QuarantineRow<'a> {
+    id: u64,
+    #[serde(rename = "type")]
+    kind: TransactionType,
+    client: ClientId,
+    tx: TransactionId,
+    amount: Option<Amount>,
+    timestamp: Option<Timestamp>,
+    reason: &'a str,
+}
+
+/// Writes the transactions held for review as CSV input with their id and the reason they are
+/// held for.
+pub fn write_quarantine<'a, W: io::Write>(
+    writer: W,
+    quarantine: impl IntoIterator<Item = &'a QuarantinedTransaction>,
+) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    writer.write_record([
+        "id",
+        "type",
+        "client",
+        "tx",
+        "amount",
+        "timestamp",
+        "reason",
+    ])?;
+    for quarantined in quarantine {
+        let transaction = &quarantined.transaction;
+        writer.serialize(QuarantineRow {
+            id: quarantined.id,
+            kind: transaction.kind,
+            client: transaction.client,
+            tx: transaction.tx,
+            amount: transaction.amount,
+            timestamp: transaction.timestamp,
+            reason: &quarantined.reason,
+        })?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{write_quarantine, HeuristicRiskAssessor, ReviewDecision};
+    use crate::{Amount, TransactionEngine, TransactionInput, TransactionProcessingError};
+
+    fn money(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_rapid_withdrawals_are_held_for_review() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_risk_assessor(Some(Box::new(HeuristicRiskAssessor::new(3, 2, 60))));
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("100")).with_timestamp(0),
+            TransactionInput::withdrawal(1, 2, money("10")).with_timestamp(10),
+            TransactionInput::withdrawal(1, 3, money("10")).with_timestamp(20),
+            TransactionInput::withdrawal(1, 4, money("10")).with_timestamp(30),
+            TransactionInput::withdrawal(1, 5, money("10")).with_timestamp(70),
+        ]);
+        assert!(matches!(
+            results[3],
+            Err(TransactionProcessingError::Quarantined(_))
+        ));
+        assert!(results[4].is_ok());
+
+        let mut export = Vec::new();
+        write_quarantine(&mut export, transaction_engine.quarantine()).unwrap();
+        assert_eq!(
+            String::from_utf8(export).unwrap(),
+            "id,type,client,tx,amount,timestamp,reason\n\
+             1,withdrawal,1,4,10.0000,30,the client made 2 withdrawals within 60 seconds already\n"
+        );
+
+        transaction_engine
+            .release_quarantined(1, ReviewDecision::Approve)
+            .unwrap();
+        assert_eq!(transaction_engine.quarantine().count(), 0);
+        assert_eq!(
+            transaction_engine.get_account(1).unwrap().available,
+            money("60")
+        );
+    }
+}
@@ -0,0 +1,318 @@
+//! Limits checked before a transaction is applied, like the largest amount of a single
+//! transaction or how much a client may withdraw in a day.
+//!
+//! Rules are read from a TOML or JSON file such as
+//!
+//! ```toml
+//! max_amount = "10000"
+//! max_daily_withdrawal = "2500"
+//!
+//! [rate]
+//! max_transactions = 10
+//! window = 60
+//! ```
+//!
+//! Every limit is optional. Time follows the timestamps of the input: days are calendar days
+//! in UTC and a row without a timestamp is taken to happen at the latest timestamp seen, so
+//! without timestamps the whole input counts as one day. Only transactions which were applied
+//! count towards the limits. Other rules can be added by implementing [`Rule`].
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fs;
+
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::{Amount, ClientId, MoneyOps, Timestamp, TransactionInput, TransactionType};
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Why a rule rejected a transaction
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum RuleViolation {
+    #[error("the amount is more than the limit of {limit} per transaction")]
+    AmountAboveLimit { limit: Amount },
+
+    #[error("the withdrawal takes the client past the limit of {limit} withdrawn per day")]
+    DailyWithdrawalLimitExceeded { limit: Amount },
+
+    #[error("the client made {max_transactions} transactions within {window} seconds already")]
+    TransactionRateExceeded { max_transactions: u32, window: u64 },
+}
+
+impl RuleViolation {
+    /// A short, machine-readable name of the violation
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RuleViolation::AmountAboveLimit { .. } => "amount_above_limit",
+            RuleViolation::DailyWithdrawalLimitExceeded { .. } => "daily_withdrawal_limit_exceeded",
+            RuleViolation::TransactionRateExceeded { .. } => "transaction_rate_exceeded",
+        }
+    }
+}
+
+/// A limit checked before every transaction is applied
+pub trait Rule {
+    /// Checks the transaction, happening at the time given, before it is applied.
+    fn check(&self, transaction: &TransactionInput, now: Timestamp) -> Result<(), RuleViolation>;
+
+    /// Called once the transaction was applied, to count it towards the limit.
+    fn applied(&mut self, _transaction: &TransactionInput, _now: Timestamp) {}
+}
+
+/// Rejects deposits and withdrawals of more than the limit
+#[derive(Debug, Clone, Copy)]
+pub struct MaxAmount {
+    pub limit: Amount,
+}
+
+impl Rule for MaxAmount {
+    fn check(&self, transaction: &TransactionInput, _now: Timestamp) -> Result<(), RuleViolation> {
+        match (transaction.kind, transaction.amount) {
+            (TransactionType::Deposit | TransactionType::Withdrawal, Some(amount))
+                if amount > self.limit =>
+            {
+                Err(RuleViolation::AmountAboveLimit { limit: self.limit })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Rejects withdrawals taking what a client withdrew in a day past the limit
+#[derive(Debug, Clone)]
+pub struct DailyWithdrawalLimit {
+    limit: Amount,
+    /// The day of every client's last withdrawal and how much was withdrawn on it
+    withdrawn: HashMap<ClientId, (u64, Amount)>,
+}
+
+impl DailyWithdrawalLimit {
+    pub fn new(limit: Amount) -> DailyWithdrawalLimit {
+        DailyWithdrawalLimit {
+            limit,
+            withdrawn: HashMap::new(),
+        }
+    }
+
+    /// What the client withdrew on the day of the time given
+    fn withdrawn_on(&self, client: ClientId, now: Timestamp) -> Amount {
+        match self.withdrawn.get(&client) {
+            Some((day, withdrawn)) if *day == now / SECONDS_PER_DAY => *withdrawn,
+            _ => Amount::ZERO,
+        }
+    }
+}
+
+impl Rule for DailyWithdrawalLimit {
+    fn check(&self, transaction: &TransactionInput, now: Timestamp) -> Result<(), RuleViolation> {
+        match (transaction.kind, transaction.amount) {
+            // a sum beyond the largest amount is beyond any limit too
+            (TransactionType::Withdrawal, Some(amount))
+                if self
+                    .withdrawn_on(transaction.client, now)
+                    .checked_add(amount)
+                    .is_none_or(|withdrawn| withdrawn > self.limit) =>
+            {
+                Err(RuleViolation::DailyWithdrawalLimitExceeded { limit: self.limit })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn applied(&mut self, transaction: &TransactionInput, now: Timestamp) {
+        if let (TransactionType::Withdrawal, Some(amount)) = (transaction.kind, transaction.amount)
+        {
+            let withdrawn = self
+                .withdrawn_on(transaction.client, now)
+                .checked_add(amount)
+                .unwrap_or(Amount::MAX);
+            self.withdrawn
+                .insert(transaction.client, (now / SECONDS_PER_DAY, withdrawn));
+        }
+    }
+}
+
+/// Rejects transactions of a client which made the most transactions allowed within the window
+/// already
+#[derive(Debug, Clone)]
+pub struct TransactionRateLimit {
+    max_transactions: u32,
+    window: u64,
+    /// The times of every client's transactions within the window, oldest first
+    recent: HashMap<ClientId, VecDeque<Timestamp>>,
+}
+
+impl TransactionRateLimit {
+    pub fn new(max_transactions: u32, window: u64) -> TransactionRateLimit {
+        TransactionRateLimit {
+            max_transactions,
+            window,
+            recent: HashMap::new(),
+        }
+    }
+
+    /// The number of the client's transactions within the window ending at the time given
+    fn count(&self, client: ClientId, now: Timestamp) -> usize {
+        self.recent.get(&client).map_or(0, |recent| {
+            recent
+                .iter()
+                .filter(|timestamp| timestamp.saturating_add(self.window) > now)
+                .count()
+        })
+    }
+}
+
+impl Rule for TransactionRateLimit {
+    fn check(&self, transaction: &TransactionInput, now: Timestamp) -> Result<(), RuleViolation> {
+        if self.count(transaction.client, now) >= self.max_transactions as usize {
+            return Err(RuleViolation::TransactionRateExceeded {
+                max_transactions: self.max_transactions,
+                window: self.window,
+            });
+        }
+        Ok(())
+    }
+
+    fn applied(&mut self, transaction: &TransactionInput, now: Timestamp) {
+        let recent = self.recent.entry(transaction.client).or_default();
+        while recent
+            .front()
+            .is_some_and(|timestamp| timestamp.saturating_add(self.window) <= now)
+        {
+            recent.pop_front();
+        }
+        recent.push_back(now);
+    }
+}
+
+/// The limit on the number of transactions of a client within a window of seconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateConfig {
+    pub max_transactions: u32,
+    pub window: u64,
+}
+
+/// The rules of a rules file
+#[derive(Debug, Clone, Copy, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RulesConfig {
+    /// The largest amount of a single deposit or withdrawal
+    pub max_amount: Option<Amount>,
+    /// The most a client may withdraw in a day
+    pub max_daily_withdrawal: Option<Amount>,
+    pub rate: Option<RateConfig>,
+}
+
+impl RulesConfig {
+    /// Reads the rules from a JSON file if the path ends with `.json`, TOML otherwise.
+    pub fn from_path(path: &str) -> Result<RulesConfig, Box<dyn Error>> {
+        let contents = fs::read_to_string(path)?;
+        let rules_config: RulesConfig = if path.ends_with(".json") {
+            serde_json::from_str(&contents)?
+        } else {
+            toml::from_str(&contents)?
+        };
+        for limit in [rules_config.max_amount, rules_config.max_daily_withdrawal]
+            .into_iter()
+            .flatten()
+        {
+            if limit.is_negative() {
+                return Err("limits can't be negative".into());
+            }
+        }
+        if rules_config.rate.is_some_and(|rate| rate.window == 0) {
+            return Err("the window of the rate limit must be at least a second".into());
+        }
+        Ok(rules_config)
+    }
+
+    /// The rules checking the limits which are set
+    pub fn rules(&self) -> Vec<Box<dyn Rule + Send>> {
+        let mut rules: Vec<Box<dyn Rule + Send>> = Vec::new();
+        if let Some(limit) = self.max_amount {
+            rules.push(Box::new(MaxAmount { limit }));
+        }
+        if let Some(limit) = self.max_daily_withdrawal {
+            rules.push(Box::new(DailyWithdrawalLimit::new(limit)));
+        }
+        if let Some(rate) = self.rate {
+            rules.push(Box::new(TransactionRateLimit::new(
+                rate.max_transactions,
+                rate.window,
+            )));
+        }
+        rules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RateConfig, RuleViolation, RulesConfig};
+    use crate::{Amount, TransactionEngine, TransactionInput, TransactionProcessingError};
+
+    fn money(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    fn violation(result: &Result<(), TransactionProcessingError>) -> Option<&'static str> {
+        match result {
+            Err(TransactionProcessingError::RuleViolated(violation)) => Some(violation.kind()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_transactions_breaking_a_rule_are_rejected() {
+        let rules_config = RulesConfig {
+            max_amount: Some(money("1000")),
+            max_daily_withdrawal: Some(money("100")),
+            rate: Some(RateConfig {
+                max_transactions: 2,
+                window: 60,
+            }),
+        };
+        let mut transaction_engine = TransactionEngine::new();
+        for rule in rules_config.rules() {
+            transaction_engine.add_rule(rule);
+        }
+        let day = 86_400;
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("1000.0001")).with_timestamp(0),
+            TransactionInput::deposit(1, 2, money("1000")).with_timestamp(0),
+            TransactionInput::withdrawal(1, 3, money("60")).with_timestamp(10),
+            TransactionInput::withdrawal(1, 4, money("50")).with_timestamp(100),
+            TransactionInput::withdrawal(1, 5, money("40")).with_timestamp(110),
+            TransactionInput::deposit(1, 6, money("1")).with_timestamp(120),
+            TransactionInput::deposit(2, 7, money("1")).with_timestamp(120),
+            TransactionInput::deposit(1, 8, money("1")).with_timestamp(130),
+            TransactionInput::deposit(1, 9, money("1")).with_timestamp(171),
+            TransactionInput::withdrawal(1, 10, money("50")).with_timestamp(day),
+        ]);
+        let violations: Vec<_> = results.iter().map(violation).collect();
+        assert_eq!(
+            violations,
+            vec![
+                Some("amount_above_limit"),
+                None,
+                None,
+                Some("daily_withdrawal_limit_exceeded"),
+                None,
+                None,
+                None,
+                Some("transaction_rate_exceeded"),
+                None,
+                None,
+            ]
+        );
+        assert_eq!(
+            RuleViolation::AmountAboveLimit {
+                limit: money("1000")
+            }
+            .to_string(),
+            "the amount is more than the limit of 1000.0000 per transaction"
+        );
+    }
+}
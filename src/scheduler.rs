@@ -0,0 +1,139 @@
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// Interleaves queued items across keys, e.g. clients or connections, in round-robin order
+/// while keeping the order of the items of every single key, so that a key with a large burst
+/// of queued items can't delay the items of all other keys until its burst is through.
+///
+/// A scheduler can be bounded both in the number of items queued in total and in the number
+/// queued for a single key, so that a burst can't take up all the memory either.
+pub struct FairScheduler<K, T> {
+    queues: HashMap<K, VecDeque<T>>,
+    // the keys with queued items, in the order they will be served
+    ready: VecDeque<K>,
+    len: usize,
+    max_len: usize,
+    max_len_per_key: usize,
+}
+
+impl<K: Copy + Eq + Hash, T> FairScheduler<K, T> {
+    /// Create a new, empty scheduler without any bounds
+    pub fn new() -> FairScheduler<K, T> {
+        FairScheduler::bounded(usize::MAX, usize::MAX)
+    }
+
+    /// Create a new, empty scheduler holding at most `max_len` items, and at most
+    /// `max_len_per_key` of a single key
+    pub fn bounded(max_len: usize, max_len_per_key: usize) -> FairScheduler<K, T> {
+        FairScheduler {
+            queues: HashMap::new(),
+            ready: VecDeque::new(),
+            len: 0,
+            max_len,
+            max_len_per_key,
+        }
+    }
+
+    /// Whether `count` more items of the key can be queued without exceeding the bounds
+    pub fn has_room(&self, key: K, count: usize) -> bool {
+        let queued_for_key = self.queues.get(&key).map_or(0, VecDeque::len);
+        count <= self.max_len - self.len && count <= self.max_len_per_key - queued_for_key
+    }
+
+    /// Queues an item behind all other queued items of the same key, handing it back if the
+    /// scheduler is full.
+    pub fn push(&mut self, key: K, item: T) -> Result<(), T> {
+        if !self.has_room(key, 1) {
+            return Err(item);
+        }
+        let queue = self.queues.entry(key).or_default();
+        if queue.is_empty() {
+            self.ready.push_back(key);
+        }
+        queue.push_back(item);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Takes the next item of the key whose turn it is, moving that key to the back of the line
+    /// if it has more items queued.
+    pub fn pop(&mut self) -> Option<(K, T)> {
+        let key = self.ready.pop_front()?;
+        let queue = self.queues.get_mut(&key)?;
+        let item = queue.pop_front()?;
+        if queue.is_empty() {
+            self.queues.remove(&key);
+        } else {
+            self.ready.push_back(key);
+        }
+        self.len -= 1;
+        Some((key, item))
+    }
+
+    /// The number of queued items
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Whether there are no queued items
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<K: Copy + Eq + Hash, T> Default for FairScheduler<K, T> {
+    fn default() -> Self {
+        FairScheduler::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FairScheduler;
+
+    #[test]
+    fn test_clients_are_interleaved_in_order() {
+        let mut scheduler = FairScheduler::new();
+        for item in 1..=4 {
+            scheduler.push(1, item).unwrap();
+        }
+        scheduler.push(2, 10).unwrap();
+        scheduler.push(3, 20).unwrap();
+        scheduler.push(2, 11).unwrap();
+        assert_eq!(scheduler.len(), 7);
+
+        let order: Vec<_> = std::iter::from_fn(|| scheduler.pop()).collect();
+        assert_eq!(
+            order,
+            vec![(1, 1), (2, 10), (3, 20), (1, 2), (2, 11), (1, 3), (1, 4)]
+        );
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn test_client_rejoins_at_the_back() {
+        let mut scheduler = FairScheduler::new();
+        scheduler.push(1, 1).unwrap();
+        scheduler.push(2, 2).unwrap();
+        assert_eq!(scheduler.pop(), Some((1, 1)));
+        scheduler.push(1, 3).unwrap();
+        assert_eq!(scheduler.pop(), Some((2, 2)));
+        assert_eq!(scheduler.pop(), Some((1, 3)));
+        assert_eq!(scheduler.pop(), None);
+    }
+
+    #[test]
+    fn test_bounds_are_kept() {
+        let mut scheduler = FairScheduler::bounded(3, 2);
+        assert!(scheduler.has_room(1, 2));
+        assert!(!scheduler.has_room(1, 3));
+        scheduler.push(1, 1).unwrap();
+        scheduler.push(1, 2).unwrap();
+        assert_eq!(scheduler.push(1, 3), Err(3));
+        scheduler.push(2, 4).unwrap();
+        assert_eq!(scheduler.push(3, 5), Err(5));
+        assert_eq!(scheduler.pop(), Some((1, 1)));
+        scheduler.push(3, 5).unwrap();
+        assert_eq!(scheduler.len(), 3);
+    }
+}
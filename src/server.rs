@@ -0,0 +1,974 @@
+//! A long running HTTP server which applies transaction streams from many clients to one engine.
+//! It is built on the standard library's `TcpListener` rather than axum or actix-web, so that the
+//! opt-in `server` feature pulls in no dependencies and no async runtime; the gRPC server is
+//! the one built on tokio. The few parts of HTTP/1.1 producers rely on are handled here:
+//! `Content-Length` and chunked bodies, and `Expect: 100-continue`, which curl sends for bodies
+//! over 1 KiB and otherwise waits a second on before sending the body anyway.
+//!
+//! Every connection carries a single HTTP/1.1 request and is closed after the response:
+//!
+//! - `POST /transactions` applies the body: a transaction or an array of them for
+//!   `application/json`, JSON Lines for any other json `Content-Type`, and CSV in the format of
+//!   the input file otherwise. The body is framed by `Content-Length` or sent with
+//!   `Transfer-Encoding: chunked`, so a producer can keep a stream open until it reaches
+//!   `--max-body-bytes`. A longer body is refused with `413` if its length is known up front,
+//!   and otherwise ends the stream with an error once the limit is reached.
+//!   Rows are applied as they arrive, interleaved with the rows of other connections, and the
+//!   response lists the rows of this request which were rejected. If the stream can't be read
+//!   any further, the rows before the error stay applied and the response carries the error.
+//! - `GET /accounts` returns the state of all accounts sorted by client, as CSV or in the format
+//!   given by `?format=json` or `?format=jsonl`.
+//! - `GET /accounts/{client}` returns the state of a single account as JSON.
+//! - `GET /transactions/{tx}` returns a deposit or withdrawal kept for disputes as JSON, with its
+//!   dispute state.
+//! - `GET /metrics` returns the Prometheus metrics of the engine, with the `metrics` feature.
+//! - `POST /reload` reads the policies of `--config` again, see [`crate::policy`], as does
+//!   SIGHUP. The new policies apply from the next row on, also to the streams in flight, and if
+//!   the file can't be read or parsed the previous ones stay in place.
+//!
+//! At most `--max-connections` connections are served at once, each on its own thread, and
+//! further ones wait in the backlog of the listener until one of them is closed.
+//!
+//! On SIGINT or SIGTERM the server stops accepting connections, lets the requests in flight
+//! finish and saves a snapshot if `--save-snapshot` was given.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use clap::Parser;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::{error, info};
+
+use crate::control_totals::ControlTotalsPolicy;
+use crate::input::json_lines::{transaction_from_value, JsonLines};
+#[cfg(feature = "metrics")]
+use crate::metrics::{self, Metrics};
+use crate::output::{self, OutputFormat};
+use crate::policy::ReloadablePolicies;
+use crate::rejects::{ProcessingPolicy, Rejection, RejectionError, RejectionRow, Rejections};
+use crate::{
+    parse_control_totals_policy, parse_zero_amount_policy, process_row, read_csv,
+    TransactionEngine, ZeroAmountPolicy,
+};
+
+/// The address listened on if `--listen` isn't given
+pub const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:8080";
+
+/// How often the accept loop checks whether it should shut down
+const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How long a connection may stay silent before it is closed, so that a stalled client can't
+/// hold up a shutdown forever
+const IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The largest request line and headers accepted, and the largest trailers of a chunked body
+const MAX_HEADER_BYTES: u64 = 64 * 1024;
+
+/// The longest line accepted giving the size of a chunk, including any chunk extensions
+const MAX_CHUNK_LINE_BYTES: u64 = 1024;
+
+/// The number of connections served at once if not configured otherwise
+pub const DEFAULT_MAX_CONNECTIONS: usize = 64;
+
+/// The largest request body accepted if not configured otherwise
+pub const DEFAULT_MAX_BODY_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Serves transaction streams over HTTP until the process is stopped.
+#[derive(Parser, Debug)]
+#[command(name = "serve", bin_name = "toy-transaction-engine serve")]
+pub struct ServerConfig {
+    /// The address to listen on
+    #[arg(long, default_value = DEFAULT_LISTEN_ADDRESS)]
+    pub listen: String,
+
+    /// Read the control totals, locked accounts, fees and rules policies from this TOML file,
+    /// again on SIGHUP or `POST /reload`
+    #[arg(long, value_name = "PATH")]
+    pub config: Option<String>,
+
+    /// What to do when the control totals of a CSV stream don't match: ignore, warn or fail
+    #[arg(long = "control-totals", default_value = "warn", value_parser = parse_control_totals_policy)]
+    pub control_totals_policy: ControlTotalsPolicy,
+
+    /// What to do with deposits and withdrawals of a zero amount: skip or reject
+    #[arg(long = "zero-amounts", default_value = "reject", value_parser = parse_zero_amount_policy)]
+    pub zero_amount_policy: ZeroAmountPolicy,
+
+    /// Start from the state saved in this snapshot instead of empty accounts
+    #[arg(long)]
+    pub load_snapshot: Option<String>,
+
+    /// Save the state to this snapshot when shutting down
+    #[arg(long)]
+    pub save_snapshot: Option<String>,
+
+    /// The number of connections served at once
+    #[arg(long, default_value_t = DEFAULT_MAX_CONNECTIONS, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub max_connections: usize,
+
+    /// The largest request body accepted in bytes
+    #[arg(long, default_value_t = DEFAULT_MAX_BODY_BYTES)]
+    pub max_body_bytes: u64,
+}
+
+impl ServerConfig {
+    /// Parses the arguments starting with the `serve` subcommand.
+    pub fn new(args: &[String]) -> Result<ServerConfig, clap::Error> {
+        ServerConfig::try_parse_from(args)
+    }
+}
+
+/// How much a server takes on at once
+#[derive(Debug, Clone, Copy)]
+pub struct ServerLimits {
+    pub max_connections: usize,
+    pub max_body_bytes: u64,
+}
+
+impl Default for ServerLimits {
+    fn default() -> Self {
+        ServerLimits {
+            max_connections: DEFAULT_MAX_CONNECTIONS,
+            max_body_bytes: DEFAULT_MAX_BODY_BYTES,
+        }
+    }
+}
+
+/// Everything shared between the connection threads
+struct Shared {
+    transaction_engine: Mutex<TransactionEngine>,
+    policies: Mutex<ReloadablePolicies>,
+    max_body_bytes: u64,
+    #[cfg(feature = "metrics")]
+    metrics: Arc<Metrics>,
+}
+
+/// Runs the server until SIGINT or SIGTERM is received.
+pub fn run(config: ServerConfig) -> Result<(), Box<dyn Error>> {
+    let mut transaction_engine = match &config.load_snapshot {
+        Some(snapshot_path) => {
+            TransactionEngine::restore(BufReader::new(File::open(snapshot_path)?))?
+        }
+        None => TransactionEngine::new(),
+    };
+    transaction_engine.set_zero_amount_policy(config.zero_amount_policy);
+    let policies = ReloadablePolicies::from_path(config.config, config.control_totals_policy)?;
+    policies.current().apply_to(&mut transaction_engine, None);
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let reload_requested = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    {
+        for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+            signal_hook::flag::register(signal, Arc::clone(&shutdown))?;
+        }
+        signal_hook::flag::register(signal_hook::consts::SIGHUP, Arc::clone(&reload_requested))?;
+    }
+
+    let listener = TcpListener::bind(&config.listen)?;
+    info!("Listening on {}", listener.local_addr()?);
+    let limits = ServerLimits {
+        max_connections: config.max_connections,
+        max_body_bytes: config.max_body_bytes,
+    };
+    let transaction_engine = serve(
+        listener,
+        transaction_engine,
+        policies,
+        limits,
+        shutdown,
+        reload_requested,
+    )?;
+    info!("Shut down.");
+
+    if let Some(snapshot_path) = &config.save_snapshot {
+        transaction_engine.snapshot(BufWriter::new(File::create(snapshot_path)?))?;
+    }
+    Ok(())
+}
+
+/// Serves connections on the listener until `shutdown` is set, then waits for the requests in
+/// flight and returns the engine. The policies are reloaded whenever `reload_requested` is set.
+pub fn serve(
+    listener: TcpListener,
+    transaction_engine: TransactionEngine,
+    policies: ReloadablePolicies,
+    limits: ServerLimits,
+    shutdown: Arc<AtomicBool>,
+    reload_requested: Arc<AtomicBool>,
+) -> io::Result<TransactionEngine> {
+    listener.set_nonblocking(true)?;
+    #[cfg(feature = "metrics")]
+    let mut transaction_engine = transaction_engine;
+    #[cfg(feature = "metrics")]
+    let metrics = {
+        let metrics = Arc::new(Metrics::new());
+        metrics.observe_accounts(transaction_engine.accounts());
+        transaction_engine.add_observer(metrics.observer());
+        metrics
+    };
+    let shared = Arc::new(Shared {
+        transaction_engine: Mutex::new(transaction_engine),
+        policies: Mutex::new(policies),
+        max_body_bytes: limits.max_body_bytes,
+        #[cfg(feature = "metrics")]
+        metrics,
+    });
+    let mut connections: Vec<JoinHandle<()>> = Vec::new();
+    while !shutdown.load(Ordering::SeqCst) {
+        if reload_requested.swap(false, Ordering::SeqCst) {
+            if let Err(e) = reload(&shared) {
+                error!(
+                    "The server configuration couldn't be reloaded and the previous one is kept. Error: {}",
+                    e
+                );
+            }
+        }
+        connections.retain(|connection| !connection.is_finished());
+        if connections.len() >= limits.max_connections {
+            // further connections wait in the backlog until one of these is closed
+            thread::sleep(ACCEPT_POLL_INTERVAL);
+            continue;
+        }
+        match listener.accept() {
+            Ok((stream, _)) => {
+                let shared = Arc::clone(&shared);
+                connections.push(thread::spawn(move || {
+                    if let Err(e) = handle_connection(&shared, stream) {
+                        error!("An error occurred when serving a connection. Error: {}", e);
+                    }
+                }));
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL_INTERVAL),
+            Err(e) => error!(
+                "An error occurred when accepting a connection. Error: {}",
+                e
+            ),
+        }
+    }
+    for connection in connections {
+        let _ = connection.join();
+    }
+    let shared = Arc::into_inner(shared).expect("all connections were joined");
+    Ok(shared
+        .transaction_engine
+        .into_inner()
+        .unwrap_or_else(|e| e.into_inner()))
+}
+
+/// Locks the mutex, carrying on with the data of a connection which panicked while holding it.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Reloads the policies and applies them to the engine, between two rows of the streams in
+/// flight.
+fn reload(shared: &Shared) -> Result<(), Box<dyn Error>> {
+    let mut policies = lock(&shared.policies);
+    let previous = policies.current().clone();
+    policies.reload()?;
+    policies
+        .current()
+        .apply_to(&mut lock(&shared.transaction_engine), Some(&previous));
+    info!("The server configuration was reloaded.");
+    Ok(())
+}
+
+/// The parts of a request needed to route it
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    content_type: String,
+    content_length: Option<u64>,
+    chunked: bool,
+    /// Whether the client waits for `100 Continue` before sending the body
+    expect_continue: bool,
+}
+
+/// The answer to a `POST /transactions`
+#[derive(Serialize)]
+struct StreamSummary<'a> {
+    applied: u64,
+    rejected: Vec<RejectionRow<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+fn handle_connection(shared: &Shared, mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let request = match read_request(&mut reader) {
+        Ok(request) => request,
+        Err(e) => return respond(&mut stream, "400 Bad Request", "text/plain", e.as_bytes()),
+    };
+
+    let path = request.path.strip_prefix('/').unwrap_or(&request.path);
+    let (resource, id) = match path.split_once('/') {
+        Some((resource, id)) => (resource, Some(id)),
+        None => (path, None),
+    };
+    match (request.method.as_str(), resource, id) {
+        ("GET", "accounts", None) => {
+            let format = query_param(&request.query, "format")
+                .map_or(Some(OutputFormat::Csv), OutputFormat::from_name);
+            let Some(format) = format else {
+                return respond(
+                    &mut stream,
+                    "400 Bad Request",
+                    "text/plain",
+                    b"format must be one of csv, json or jsonl",
+                );
+            };
+            // rendered before writing so that a slow reader doesn't keep the engine locked
+            let mut body = Vec::new();
+            {
+                let transaction_engine = lock(&shared.transaction_engine);
+                output::write_accounts(&mut body, transaction_engine.sorted_accounts(), format)?;
+            }
+            respond(&mut stream, "200 OK", content_type(format), &body)
+        }
+        ("GET", "accounts", Some(client)) => {
+            let Ok(client) = client.parse() else {
+                return respond(
+                    &mut stream,
+                    "400 Bad Request",
+                    "text/plain",
+                    b"invalid client",
+                );
+            };
+            let mut body = Vec::new();
+            {
+                let transaction_engine = lock(&shared.transaction_engine);
+                let Some(account) = transaction_engine.get_account(client) else {
+                    return respond(&mut stream, "404 Not Found", "text/plain", b"not found");
+                };
+                output::write_accounts(
+                    &mut body,
+                    vec![(client, account)],
+                    OutputFormat::JsonLines,
+                )?;
+            }
+            respond(&mut stream, "200 OK", "application/json", &body)
+        }
+        ("GET", "transactions", Some(tx)) => {
+            let Ok(tx) = tx.parse() else {
+                return respond(&mut stream, "400 Bad Request", "text/plain", b"invalid tx");
+            };
+            let transaction = lock(&shared.transaction_engine).get_transaction(tx)?;
+            match transaction {
+                Some(transaction) => respond(
+                    &mut stream,
+                    "200 OK",
+                    "application/json",
+                    &serde_json::to_vec(&transaction)?,
+                ),
+                None => respond(&mut stream, "404 Not Found", "text/plain", b"not found"),
+            }
+        }
+        ("POST", "transactions", None) => {
+            let body: Box<dyn BufRead> = match (request.chunked, request.content_length) {
+                (true, _) => Box::new(BufReader::new(BodyLimit::new(
+                    ChunkedReader::new(reader),
+                    shared.max_body_bytes,
+                ))),
+                (false, Some(length)) if length > shared.max_body_bytes => {
+                    return respond(
+                        &mut stream,
+                        "413 Content Too Large",
+                        "text/plain",
+                        format!("the body is larger than {} bytes", shared.max_body_bytes)
+                            .as_bytes(),
+                    )
+                }
+                (false, Some(length)) => Box::new(reader.take(length)),
+                (false, None) => {
+                    return respond(
+                        &mut stream,
+                        "411 Length Required",
+                        "text/plain",
+                        b"the body must be framed by Content-Length or chunked encoding",
+                    )
+                }
+            };
+            // only once the body is accepted, a refused one is answered without reading it
+            if request.expect_continue {
+                stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            }
+            let mut rejections = Rejections::new(ProcessingPolicy::Skip, false);
+            let mut rows = 0;
+            let result = if request.content_type.starts_with("application/json") {
+                apply_json(shared, body, &mut rows, &mut rejections)
+            } else if request.content_type.contains("json") {
+                apply_json_lines(shared, body, &mut rows, &mut rejections)
+            } else {
+                apply_csv(shared, body, &mut rows, &mut rejections)
+            };
+            let rejections = rejections.into_vec();
+            let summary = StreamSummary {
+                applied: rows - rejections.len() as u64,
+                rejected: rejections.iter().map(RejectionRow::from).collect(),
+                error: result.as_ref().err().map(ToString::to_string),
+            };
+            let status = match result {
+                Ok(()) => "200 OK",
+                Err(_) => "400 Bad Request",
+            };
+            let body = serde_json::to_vec(&summary)?;
+            respond(&mut stream, status, "application/json", &body)
+        }
+        ("POST", "reload", None) => match reload(shared) {
+            Ok(()) => respond(&mut stream, "200 OK", "text/plain", b"ok"),
+            Err(e) => respond(
+                &mut stream,
+                "500 Internal Server Error",
+                "text/plain",
+                e.to_string().as_bytes(),
+            ),
+        },
+        #[cfg(feature = "metrics")]
+        ("GET", "metrics", None) => respond(
+            &mut stream,
+            "200 OK",
+            metrics::CONTENT_TYPE,
+            shared.metrics.render().as_bytes(),
+        ),
+        (_, "accounts" | "transactions" | "reload", _) => respond(
+            &mut stream,
+            "405 Method Not Allowed",
+            "text/plain",
+            b"method not allowed",
+        ),
+        _ => respond(&mut stream, "404 Not Found", "text/plain", b"not found"),
+    }
+}
+
+/// Applies a CSV stream row by row. A row failing on the write-ahead log or the transaction
+/// store ends the stream, and the rows after it aren't read.
+fn apply_csv(
+    shared: &Shared,
+    body: impl Read,
+    rows: &mut u64,
+    rejections: &mut Rejections,
+) -> Result<(), Box<dyn Error>> {
+    let control_totals_policy = lock(&shared.policies).current().control_totals_policy;
+    read_csv(body, control_totals_policy, |row| {
+        *rows += 1;
+        let mut transaction_engine = lock(&shared.transaction_engine);
+        Ok(process_row(&mut transaction_engine, row, rejections)?)
+    })
+}
+
+/// Applies a JSON body holding a single transaction or an array of them. Transactions are
+/// numbered by their position in the body, which is bounded by `--max-body-bytes` as it is
+/// read as a whole.
+fn apply_json(
+    shared: &Shared,
+    mut body: impl BufRead,
+    rows: &mut u64,
+    rejections: &mut Rejections,
+) -> Result<(), Box<dyn Error>> {
+    let mut text = Vec::new();
+    body.read_to_end(&mut text)?;
+    let values = match serde_json::from_slice(&text)? {
+        Value::Array(values) => values,
+        value => vec![value],
+    };
+    for value in values {
+        *rows += 1;
+        let text = value.to_string();
+        let result = match transaction_from_value(value) {
+            Ok(transaction) => lock(&shared.transaction_engine)
+                .process_transaction(transaction)
+                .map_err(RejectionError::from),
+            Err(e) => Err(RejectionError::Parse(e.to_string())),
+        };
+        if let Err(e) = result {
+            rejections.reject(Rejection::from_line(*rows, &text, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Applies a JSON Lines stream line by line.
+fn apply_json_lines(
+    shared: &Shared,
+    body: impl BufRead,
+    rows: &mut u64,
+    rejections: &mut Rejections,
+) -> Result<(), Box<dyn Error>> {
+    for line in JsonLines::new(body) {
+        let line = line?;
+        *rows += 1;
+        let result = match line.transaction {
+            Ok(transaction) => lock(&shared.transaction_engine)
+                .process_transaction(transaction)
+                .map_err(RejectionError::from),
+            Err(e) => Err(RejectionError::Parse(e.to_string())),
+        };
+        if let Err(e) = result {
+            rejections.reject(Rejection::from_line(line.line, &line.text, e))?;
+        }
+    }
+    Ok(())
+}
+
+/// Reads the request line and the headers.
+fn read_request(reader: &mut impl BufRead) -> Result<Request, String> {
+    let mut remaining = MAX_HEADER_BYTES;
+    let mut read_line = |reader: &mut dyn BufRead| -> Result<String, String> {
+        let mut line = String::new();
+        reader
+            .take(remaining)
+            .read_line(&mut line)
+            .map_err(|e| e.to_string())?;
+        if !line.ends_with('\n') {
+            return Err("the request headers are incomplete or too large".to_string());
+        }
+        remaining -= line.len() as u64;
+        Ok(line.trim_end().to_string())
+    };
+
+    let request_line = read_line(reader)?;
+    let mut parts = request_line.split(' ');
+    let (Some(method), Some(target), Some(version)) = (parts.next(), parts.next(), parts.next())
+    else {
+        return Err(format!("{} is not a valid request line", request_line));
+    };
+    if !version.starts_with("HTTP/1.") {
+        return Err(format!("{} is not supported", version));
+    }
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let mut request = Request {
+        method: method.to_string(),
+        path: path.to_string(),
+        query: query.to_string(),
+        content_type: String::new(),
+        content_length: None,
+        chunked: false,
+        expect_continue: false,
+    };
+
+    loop {
+        let header = read_line(reader)?;
+        if header.is_empty() {
+            return Ok(request);
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            return Err(format!("{} is not a valid header", header));
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "content-type" => request.content_type = value.to_ascii_lowercase(),
+            "content-length" => {
+                request.content_length = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("{} is not a valid Content-Length", value))?,
+                )
+            }
+            "transfer-encoding" => request.chunked = value.eq_ignore_ascii_case("chunked"),
+            "expect" => request.expect_continue = value.eq_ignore_ascii_case("100-continue"),
+            _ => (),
+        }
+    }
+}
+
+/// The value of a parameter in the query string
+fn query_param<'a>(query: &'a str, name: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+fn content_type(format: OutputFormat) -> &'static str {
+    match format {
+        OutputFormat::Csv => "text/csv",
+        OutputFormat::Json => "application/json",
+        OutputFormat::JsonLines => "application/x-ndjson",
+        #[cfg(feature = "parquet")]
+        OutputFormat::Parquet => "application/vnd.apache.parquet",
+    }
+}
+
+fn respond(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+    Ok(())
+}
+
+/// Fails a body once it grows beyond the limit, instead of cutting it off silently
+struct BodyLimit<R: Read> {
+    inner: R,
+    /// The bytes which may still be read
+    remaining: u64,
+    limit: u64,
+}
+
+impl<R: Read> BodyLimit<R> {
+    fn new(inner: R, limit: u64) -> BodyLimit<R> {
+        BodyLimit {
+            inner,
+            remaining: limit,
+            limit,
+        }
+    }
+}
+
+impl<R: Read> Read for BodyLimit<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.remaining = self.remaining.checked_sub(read as u64).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the body is larger than {} bytes", self.limit),
+            )
+        })?;
+        Ok(read)
+    }
+}
+
+/// Decodes a body sent with `Transfer-Encoding: chunked`
+struct ChunkedReader<R: BufRead> {
+    inner: R,
+    /// The bytes left in the current chunk
+    remaining: u64,
+    done: bool,
+}
+
+impl<R: BufRead> ChunkedReader<R> {
+    fn new(inner: R) -> ChunkedReader<R> {
+        ChunkedReader {
+            inner,
+            remaining: 0,
+            done: false,
+        }
+    }
+
+    /// Reads a chunk size, chunk end or trailer line of at most `MAX_CHUNK_LINE_BYTES`.
+    fn read_line(&mut self) -> io::Result<String> {
+        let mut line = String::new();
+        (&mut self.inner)
+            .take(MAX_CHUNK_LINE_BYTES)
+            .read_line(&mut line)?;
+        if !line.ends_with('\n') {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "a line of the chunked body is incomplete or too long",
+            ));
+        }
+        Ok(line)
+    }
+}
+
+impl<R: BufRead> Read for ChunkedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.done || buf.is_empty() {
+            return Ok(0);
+        }
+        if self.remaining == 0 {
+            let line = self.read_line()?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            self.remaining = u64::from_str_radix(size, 16).map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is not a valid chunk size", size),
+                )
+            })?;
+            if self.remaining == 0 {
+                // skip the trailers up to the empty line ending the body
+                let mut trailers = 0;
+                loop {
+                    let line = self.read_line()?;
+                    trailers += line.len() as u64;
+                    if trailers > MAX_HEADER_BYTES {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "the trailers of the chunked body are too large",
+                        ));
+                    }
+                    if line.trim().is_empty() {
+                        break;
+                    }
+                }
+                self.done = true;
+                return Ok(0);
+            }
+        }
+        let max = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..max])?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        if self.remaining == 0 {
+            self.read_line()?;
+        }
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::{env, fs, process, thread};
+
+    use super::{serve, ServerLimits};
+    use crate::control_totals::ControlTotalsPolicy;
+    use crate::policy::ReloadablePolicies;
+    use crate::TransactionEngine;
+
+    fn request(address: SocketAddr, request: &str) -> String {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn test_serve_streams_and_account_state() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                serve(
+                    listener,
+                    TransactionEngine::new(),
+                    ReloadablePolicies::new(ControlTotalsPolicy::Warn),
+                    ServerLimits::default(),
+                    shutdown,
+                    Arc::new(AtomicBool::new(false)),
+                )
+            })
+        };
+
+        let csv = "type,client,tx,amount\ndeposit,1,1,5\nwithdrawal,1,2,9\n";
+        let response = request(
+            address,
+            &format!(
+                "POST /transactions HTTP/1.1\r\nContent-Type: text/csv\r\nContent-Length: {}\r\n\r\n{}",
+                csv.len(),
+                csv
+            ),
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains(r#""applied":1"#), "{}", response);
+        assert!(response.contains(r#""line":3,"kind":"insufficient_funds""#));
+
+        let response = request(
+            address,
+            "POST /transactions HTTP/1.1\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\n\r\n\
+             37\r\n{\"type\": \"deposit\", \"client\": 2, \"tx\": 3, \"amount\": 2}\n\r\n0\r\n\r\n",
+        );
+        assert!(
+            response.contains(r#"{"applied":1,"rejected":[]}"#),
+            "{}",
+            response
+        );
+
+        // the body is only sent once the server asked for it
+        let csv = "type,client,tx,amount\ndeposit,2,4,1\n";
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "POST /transactions HTTP/1.1\r\nContent-Length: {}\r\nExpect: 100-continue\r\n\r\n",
+            csv.len()
+        )
+        .unwrap();
+        let mut interim = [0; 25];
+        stream.read_exact(&mut interim).unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        stream.write_all(csv.as_bytes()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+
+        let response = request(address, "GET /accounts HTTP/1.1\r\n\r\n");
+        assert!(response.ends_with(
+            "client,available,held,total,locked\n\
+             1,5.0000,0.0000,5.0000,false\n\
+             2,3.0000,0.0000,3.0000,false\n"
+        ));
+        assert!(request(address, "GET /nothing HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+
+        let body = r#"[{"type": "dispute", "client": 1, "tx": 1}, {"type": "deposit"}]"#;
+        let response = request(
+            address,
+            &format!(
+                "POST /transactions HTTP/1.1\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                body.len(),
+                body
+            ),
+        );
+        assert!(response.contains(r#""applied":1"#), "{}", response);
+        assert!(response.contains(r#""line":2,"kind":"parse_error""#));
+        assert!(request(address, "GET /accounts/1 HTTP/1.1\r\n\r\n")
+            .trim_end()
+            .ends_with(
+                r#"{"client":1,"available":"0.0000","held":"5.0000","total":"5.0000","locked":false}"#
+            ));
+        assert!(
+            request(address, "GET /transactions/1 HTTP/1.1\r\n\r\n").ends_with(
+                r#"{"tx":1,"client":1,"type":"deposit","amount":"5.0000","state":"disputed","disputed":"5.0000","charged_back":"0.0000"}"#
+            )
+        );
+        assert!(request(address, "GET /accounts/9 HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        #[cfg(feature = "metrics")]
+        assert!(
+            request(address, "GET /metrics HTTP/1.1\r\n\r\n").contains("tte_held_funds 5.0000\n")
+        );
+
+        shutdown.store(true, Ordering::SeqCst);
+        let transaction_engine = server.join().unwrap().unwrap();
+        assert_eq!(transaction_engine.transaction_count(), 3);
+    }
+
+    #[test]
+    fn test_reload_applies_the_new_policies() {
+        let directory = env::temp_dir().join(format!("tte-server-reload-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let config_path = directory
+            .join("policies.toml")
+            .to_string_lossy()
+            .to_string();
+        let rules_path = directory.join("rules.toml").to_string_lossy().to_string();
+        fs::write(&config_path, "").unwrap();
+        fs::write(&rules_path, "max_amount = \"10\"\n").unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = {
+            let policies =
+                ReloadablePolicies::from_path(Some(config_path.clone()), ControlTotalsPolicy::Warn)
+                    .unwrap();
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                serve(
+                    listener,
+                    TransactionEngine::new(),
+                    policies,
+                    ServerLimits::default(),
+                    shutdown,
+                    Arc::new(AtomicBool::new(false)),
+                )
+            })
+        };
+        let deposit = |tx: u32| {
+            let csv = format!("type,client,tx,amount\ndeposit,1,{},11\n", tx);
+            request(
+                address,
+                &format!(
+                    "POST /transactions HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}",
+                    csv.len(),
+                    csv
+                ),
+            )
+        };
+
+        assert!(deposit(1).contains(r#""applied":1"#));
+        fs::write(&config_path, format!("rules = \"{}\"\n", rules_path)).unwrap();
+        let response = request(
+            address,
+            "POST /reload HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(deposit(2).contains("amount_above_limit"));
+
+        // a broken file keeps the limit in place
+        fs::write(&config_path, "rules = 1\n").unwrap();
+        let response = request(
+            address,
+            "POST /reload HTTP/1.1\r\nContent-Length: 0\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 500"), "{}", response);
+        assert!(deposit(3).contains("amount_above_limit"));
+
+        shutdown.store(true, Ordering::SeqCst);
+        server.join().unwrap().unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_oversized_bodies_and_chunk_lines_are_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let server = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                serve(
+                    listener,
+                    TransactionEngine::new(),
+                    ReloadablePolicies::new(ControlTotalsPolicy::Warn),
+                    ServerLimits {
+                        max_connections: 1,
+                        max_body_bytes: 64,
+                    },
+                    shutdown,
+                    Arc::new(AtomicBool::new(false)),
+                )
+            })
+        };
+
+        let response = request(
+            address,
+            "POST /transactions HTTP/1.1\r\nContent-Type: text/csv\r\nContent-Length: 65\r\n\r\n",
+        );
+        assert!(response.starts_with("HTTP/1.1 413"), "{}", response);
+
+        let response = request(
+            address,
+            &format!(
+                "POST /transactions HTTP/1.1\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\n\r\n1;{}\r\n",
+                "x".repeat(2000)
+            ),
+        );
+        assert!(response.contains("too long"), "{}", response);
+
+        let line = "{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 2}\n";
+        let response = request(
+            address,
+            &format!(
+                "POST /transactions HTTP/1.1\r\nContent-Type: application/x-ndjson\r\nTransfer-Encoding: chunked\r\n\r\n\
+                 {len:x}\r\n{line}\r\n{len:x}\r\n{line}\r\n0\r\n\r\n",
+                len = line.len()
+            ),
+        );
+        assert!(response.contains(r#""applied":1"#), "{}", response);
+        assert!(response.contains("larger than 64 bytes"), "{}", response);
+
+        shutdown.store(true, Ordering::SeqCst);
+        server.join().unwrap().unwrap();
+    }
+}
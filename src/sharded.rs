@@ -0,0 +1,367 @@
+//! An engine which spreads clients over several worker threads.
+//!
+//! Transactions of different clients are independent, as disputes, resolves and chargebacks
+//! only ever reference transactions of their own client. Every client is therefore assigned to
+//! one of N shards by its id, and each shard is a `TransactionEngine` running on its own
+//! thread. The transactions of a client always go to the same shard in the order they were
+//! submitted, so they are applied in that order.
+//!
+//! Two checks of the single engine span clients and can't be done across shards: a
+//! transaction id used by two clients in different shards isn't reported as a duplicate, and a
+//! dispute referencing a transaction of a client in another shard fails with
+//! `TransactionNotFound` instead of `ClientMismatch`.
+//!
+//! With the strict policy, the first rejected transaction stops every shard, and transactions
+//! submitted after that are dropped.
+
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use csv::StringRecord;
+use thiserror::Error;
+
+use crate::rejects::{ProcessingPolicy, Rejection};
+use crate::{AccountDetails, ClientId, TransactionEngine, TransactionInput};
+
+/// The number of transactions sent to a shard at once
+const BATCH_SIZE: usize = 256;
+
+/// How many batches may be waiting for a shard before submitting blocks
+const QUEUE_DEPTH: usize = 16;
+
+/// All errors which can happen when running a sharded engine
+#[derive(Error, Debug)]
+pub enum ShardedEngineError {
+    #[error("a shard stopped unexpectedly")]
+    ShardStopped,
+    #[error("the shards were stopped at a rejected transaction")]
+    Stopped,
+    #[error("client {client} appears in both {first} and {second}, but the inputs must be partitioned by client")]
+    ClientInSeveralPartitions {
+        client: ClientId,
+        first: String,
+        second: String,
+    },
+}
+
+/// A transaction together with its position in the input and the row it was read from, if
+/// there was one
+struct Submission {
+    position: u64,
+    transaction: TransactionInput,
+    record: Option<StringRecord>,
+}
+
+/// What a shard hands back when it is finished
+type ShardResult = (TransactionEngine, Vec<Rejection>);
+
+struct Shard {
+    sender: SyncSender<Vec<Submission>>,
+    pending: Vec<Submission>,
+    worker: JoinHandle<ShardResult>,
+}
+
+/// A transaction engine spreading clients over several worker threads
+pub struct ShardedTransactionEngine {
+    shards: Vec<Shard>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl ShardedTransactionEngine {
+    /// Creates an engine with the given number of shards, each with a default engine.
+    pub fn new(shards: usize) -> ShardedTransactionEngine {
+        ShardedTransactionEngine::with_engines((0..shards.max(1)).map(|_| TransactionEngine::new()))
+    }
+
+    /// Creates an engine with a shard for every given engine, e.g. to set policies on them.
+    /// Gets a single shard with a default engine if no engines are given.
+    pub fn with_engines(
+        engines: impl IntoIterator<Item = TransactionEngine>,
+    ) -> ShardedTransactionEngine {
+        ShardedTransactionEngine::with_engines_and_policy(engines, ProcessingPolicy::Skip)
+    }
+
+    /// Creates an engine with a shard for every given engine, which stops all shards at the
+    /// first rejected transaction with the strict policy.
+    pub fn with_engines_and_policy(
+        engines: impl IntoIterator<Item = TransactionEngine>,
+        processing_policy: ProcessingPolicy,
+    ) -> ShardedTransactionEngine {
+        let stopped = Arc::new(AtomicBool::new(false));
+        let mut engines: Vec<TransactionEngine> = engines.into_iter().collect();
+        if engines.is_empty() {
+            engines.push(TransactionEngine::new());
+        }
+        let shards = engines
+            .into_iter()
+            .map(|engine| {
+                let (sender, receiver) = mpsc::sync_channel(QUEUE_DEPTH);
+                let stopped = Arc::clone(&stopped);
+                Shard {
+                    sender,
+                    pending: Vec::with_capacity(BATCH_SIZE),
+                    worker: thread::spawn(move || {
+                        run_shard(engine, receiver, processing_policy, &stopped)
+                    }),
+                }
+            })
+            .collect();
+        ShardedTransactionEngine { shards, stopped }
+    }
+
+    /// The number of shards
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// The shard the client is assigned to
+    // the conversion is a no-op with 64-bit client ids
+    #[allow(clippy::useless_conversion)]
+    pub fn shard_of(&self, client: ClientId) -> usize {
+        (u64::from(client) % self.shards.len() as u64) as usize
+    }
+
+    /// Whether the shards were stopped, by a rejected transaction with the strict policy or
+    /// with `stop`. Transactions submitted from then on are dropped.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// Stops all shards, e.g. at a row which couldn't be read with the strict policy.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    /// Queues a transaction on the shard of its client. `position` is where the transaction
+    /// was found in the input and is reported with it if it is rejected. Blocks while the
+    /// shard is too far behind.
+    pub fn submit(
+        &mut self,
+        position: u64,
+        transaction: TransactionInput,
+    ) -> Result<(), ShardedEngineError> {
+        self.queue(Submission {
+            position,
+            transaction,
+            record: None,
+        })
+    }
+
+    /// Queues a transaction read from a CSV row, like `submit`, reporting the row as it was
+    /// read if the transaction is rejected.
+    pub fn submit_record(
+        &mut self,
+        position: u64,
+        transaction: TransactionInput,
+        record: &StringRecord,
+    ) -> Result<(), ShardedEngineError> {
+        self.queue(Submission {
+            position,
+            transaction,
+            record: Some(record.clone()),
+        })
+    }
+
+    fn queue(&mut self, submission: Submission) -> Result<(), ShardedEngineError> {
+        if self.is_stopped() {
+            return Ok(());
+        }
+        let index = self.shard_of(submission.transaction.client());
+        let shard = &mut self.shards[index];
+        shard.pending.push(submission);
+        if shard.pending.len() >= BATCH_SIZE {
+            let batch = mem::replace(&mut shard.pending, Vec::with_capacity(BATCH_SIZE));
+            shard
+                .sender
+                .send(batch)
+                .map_err(|_| ShardedEngineError::ShardStopped)?;
+        }
+        Ok(())
+    }
+
+    /// Waits for all shards to apply the queued transactions and returns their state.
+    pub fn finish(self) -> Result<ShardedOutcome, ShardedEngineError> {
+        let mut engines = Vec::with_capacity(self.shards.len());
+        let mut rejections = Vec::new();
+        for shard in self.shards {
+            if !shard.pending.is_empty() {
+                shard
+                    .sender
+                    .send(shard.pending)
+                    .map_err(|_| ShardedEngineError::ShardStopped)?;
+            }
+            drop(shard.sender);
+            let (engine, shard_rejections) = shard
+                .worker
+                .join()
+                .map_err(|_| ShardedEngineError::ShardStopped)?;
+            engines.push(engine);
+            rejections.extend(shard_rejections);
+        }
+        rejections.sort_by_key(|rejection| rejection.line);
+        Ok(ShardedOutcome {
+            engines,
+            rejections,
+        })
+    }
+}
+
+/// Applies the batches of a shard until the channel is closed. Once the shards are stopped,
+/// the remaining batches are only drained.
+fn run_shard(
+    mut engine: TransactionEngine,
+    receiver: Receiver<Vec<Submission>>,
+    processing_policy: ProcessingPolicy,
+    stopped: &AtomicBool,
+) -> ShardResult {
+    let mut rejections = Vec::new();
+    for batch in receiver {
+        for submission in batch {
+            if stopped.load(Ordering::Relaxed) {
+                break;
+            }
+            if let Err(e) = engine.process_transaction(submission.transaction) {
+                rejections.push(match &submission.record {
+                    Some(record) => Rejection::from_record(submission.position, record, e.into()),
+                    None => Rejection::from_transaction(
+                        submission.position,
+                        &submission.transaction,
+                        e.into(),
+                    ),
+                });
+                if processing_policy == ProcessingPolicy::Strict {
+                    stopped.store(true, Ordering::Relaxed);
+                }
+            }
+        }
+    }
+    (engine, rejections)
+}
+
+/// The state of all shards once they are finished
+pub struct ShardedOutcome {
+    engines: Vec<TransactionEngine>,
+    /// The rejected transactions of all shards, in the order of the input
+    pub rejections: Vec<Rejection>,
+}
+
+impl ShardedOutcome {
+    pub(crate) fn new(
+        engines: Vec<TransactionEngine>,
+        rejections: Vec<Rejection>,
+    ) -> ShardedOutcome {
+        ShardedOutcome {
+            engines,
+            rejections,
+        }
+    }
+
+    /// The engines of the shards
+    pub fn engines(&self) -> &[TransactionEngine] {
+        &self.engines
+    }
+
+    /// The accounts of all shards, sorted by client id
+    pub fn sorted_accounts(&self) -> Vec<(ClientId, &AccountDetails)> {
+        let mut accounts: Vec<(ClientId, &AccountDetails)> = self
+            .engines
+            .iter()
+            .flat_map(|engine| engine.accounts())
+            .collect();
+        accounts.sort_by_key(|(client_id, _)| *client_id);
+        accounts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use csv::StringRecord;
+
+    use super::ShardedTransactionEngine;
+    use crate::rejects::ProcessingPolicy;
+    use crate::{ClientId, TransactionEngine, TransactionId, TransactionInput};
+
+    #[test]
+    fn test_sharded_engine_matches_single_engine() {
+        let transactions: Vec<TransactionInput> = (1..=1000)
+            .flat_map(|tx| {
+                let client = (tx % 7) as ClientId;
+                [
+                    TransactionInput::deposit(client, tx * 3, "2".parse().unwrap()),
+                    TransactionInput::withdrawal(client, tx * 3 + 1, "3".parse().unwrap()),
+                    TransactionInput::dispute(client, tx * 3),
+                ]
+            })
+            .collect();
+
+        let mut single = TransactionEngine::new();
+        let single_results = single.process_transactions(transactions.iter().copied());
+
+        let mut sharded = ShardedTransactionEngine::new(3);
+        for (position, transaction) in transactions.iter().enumerate() {
+            sharded.submit(position as u64 + 1, *transaction).unwrap();
+        }
+        let outcome = sharded.finish().unwrap();
+        assert_eq!(outcome.engines().len(), 3);
+        assert_eq!(outcome.sorted_accounts(), single.sorted_accounts());
+        let rejected: Vec<u64> = single_results
+            .iter()
+            .enumerate()
+            .filter(|(_, result)| result.is_err())
+            .map(|(position, _)| position as u64 + 1)
+            .collect();
+        assert_eq!(
+            outcome
+                .rejections
+                .iter()
+                .map(|rejection| rejection.line)
+                .collect::<Vec<_>>(),
+            rejected
+        );
+    }
+
+    #[test]
+    fn test_strict_policy_stops_all_shards_at_the_first_rejection() {
+        let mut sharded = ShardedTransactionEngine::with_engines_and_policy(
+            (0..3).map(|_| TransactionEngine::new()),
+            ProcessingPolicy::Strict,
+        );
+        sharded
+            .submit(1, TransactionInput::deposit(1, 1, "2".parse().unwrap()))
+            .unwrap();
+        sharded
+            .submit_record(
+                2,
+                TransactionInput::withdrawal(1, 2, "5".parse().unwrap()),
+                &StringRecord::from(vec!["withdrawal", "1", "2", "5.0"]),
+            )
+            .unwrap();
+        for tx in 3..=10_000 {
+            let client = (tx % 3) as ClientId;
+            sharded
+                .submit(
+                    tx,
+                    TransactionInput::deposit(client, tx as TransactionId, "1".parse().unwrap()),
+                )
+                .unwrap();
+        }
+        let outcome = sharded.finish().unwrap();
+        assert_eq!(outcome.rejections.len(), 1);
+        assert_eq!(outcome.rejections[0].line, 2);
+        assert_eq!(outcome.rejections[0].record, "withdrawal,1,2,5.0");
+        // the shard of client 1 stopped right at the rejected withdrawal
+        assert_eq!(
+            outcome
+                .sorted_accounts()
+                .into_iter()
+                .find(|(client, _)| *client == 1)
+                .unwrap()
+                .1
+                .total,
+            "2".parse().unwrap()
+        );
+    }
+}
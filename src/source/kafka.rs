@@ -0,0 +1,160 @@
+//! A source consuming transactions from a Kafka topic as a member of a consumer group.
+//!
+//! Offsets are committed to Kafka for the group, so a restarted consumer, or another member
+//! taking over a partition, continues after the last commit. The position of a message is its
+//! offset within its partition, and the offsets saved in the snapshot skip messages which are
+//! delivered again.
+
+use std::error::Error;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
+
+use clap::Parser;
+use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
+
+use super::{
+    consume, resume, SourceError, SourceMessage, StreamConfig, TransactionSource,
+    DEFAULT_COMMIT_EVERY,
+};
+#[cfg(feature = "metrics")]
+use crate::metrics::{self, Metrics};
+
+/// Consumes transactions from a Kafka topic until SIGINT or SIGTERM is received.
+#[derive(Parser, Debug)]
+#[command(name = "consume", bin_name = "toy-transaction-engine consume")]
+pub struct ConsumerConfig {
+    /// The brokers to connect to, separated by commas
+    #[arg(long, value_delimiter = ',', required = true)]
+    pub brokers: Vec<String>,
+
+    /// The topic the transactions are published to
+    #[arg(long)]
+    pub topic: String,
+
+    /// The consumer group to commit offsets for
+    #[arg(long)]
+    pub group: String,
+
+    /// The snapshot the state is saved to before every commit and started from if it exists
+    #[arg(long)]
+    pub snapshot: String,
+
+    /// The number of messages between two commits
+    #[arg(long, default_value_t = DEFAULT_COMMIT_EVERY, value_parser = clap::value_parser!(u64).range(1..))]
+    pub commit_every: u64,
+
+    /// The longest time between two commits in seconds
+    #[arg(long, default_value_t = 5)]
+    pub commit_interval_secs: u64,
+
+    /// Serve Prometheus metrics on `/metrics` at this address
+    #[cfg(feature = "metrics")]
+    #[arg(long)]
+    pub metrics_listen: Option<String>,
+}
+
+impl ConsumerConfig {
+    /// Parses the arguments starting with the `consume` subcommand.
+    pub fn new(args: &[String]) -> Result<ConsumerConfig, clap::Error> {
+        ConsumerConfig::try_parse_from(args)
+    }
+}
+
+/// A source reading the messages of a topic
+pub struct KafkaSource {
+    consumer: Consumer,
+}
+
+impl KafkaSource {
+    /// Joins the consumer group on the topic. Without committed offsets for the group,
+    /// consuming starts at the earliest message.
+    pub fn connect(
+        brokers: Vec<String>,
+        topic: String,
+        group: String,
+    ) -> Result<KafkaSource, SourceError> {
+        let consumer = Consumer::from_hosts(brokers)
+            .with_topic(topic)
+            .with_group(group)
+            .with_fallback_offset(FetchOffset::Earliest)
+            .with_offset_storage(Some(GroupOffsetStorage::Kafka))
+            .create()
+            .map_err(failed)?;
+        Ok(KafkaSource { consumer })
+    }
+}
+
+impl TransactionSource for KafkaSource {
+    fn poll(&mut self) -> Result<Option<Vec<SourceMessage>>, SourceError> {
+        let message_sets = self.consumer.poll().map_err(failed)?;
+        let mut messages = Vec::new();
+        for message_set in message_sets.iter() {
+            let partition = message_set.partition();
+            messages.extend(message_set.messages().iter().map(|message| SourceMessage {
+                partition,
+                position: message.offset.unsigned_abs(),
+                payload: message.value.to_vec(),
+            }));
+            // only marked locally, the offsets are committed with `commit`
+            self.consumer
+                .consume_messageset(message_set)
+                .map_err(failed)?;
+        }
+        Ok(Some(messages))
+    }
+
+    fn commit(&mut self) -> Result<(), SourceError> {
+        self.consumer.commit_consumed().map_err(failed)
+    }
+}
+
+fn failed(error: kafka::Error) -> SourceError {
+    SourceError::Failed(error.to_string())
+}
+
+/// Consumes the topic until the process is stopped.
+pub fn run(config: ConsumerConfig) -> Result<(), Box<dyn Error>> {
+    let (mut transaction_engine, mut offsets) = resume(&config.snapshot)?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    for signal in [signal_hook::consts::SIGINT, signal_hook::consts::SIGTERM] {
+        signal_hook::flag::register(signal, Arc::clone(&stop))?;
+    }
+
+    let mut source = KafkaSource::connect(config.brokers, config.topic, config.group)?;
+    #[cfg(feature = "metrics")]
+    let exporter = match &config.metrics_listen {
+        Some(address) => {
+            let listener = std::net::TcpListener::bind(address)?;
+            let metrics = Arc::new(Metrics::new());
+            metrics.observe_accounts(transaction_engine.accounts());
+            transaction_engine.add_observer(metrics.observer());
+            let stop = Arc::clone(&stop);
+            Some(std::thread::spawn(move || {
+                metrics::serve(listener, metrics, stop)
+            }))
+        }
+        None => None,
+    };
+
+    let stream_config = StreamConfig {
+        snapshot_path: config.snapshot,
+        commit_every: config.commit_every,
+        commit_interval: Duration::from_secs(config.commit_interval_secs),
+    };
+    let result = consume(
+        &mut transaction_engine,
+        &mut offsets,
+        &mut source,
+        &stream_config,
+        &stop,
+    );
+    #[cfg(feature = "metrics")]
+    if let Some(exporter) = exporter {
+        stop.store(true, std::sync::atomic::Ordering::SeqCst);
+        let _ = exporter.join();
+    }
+    result
+}
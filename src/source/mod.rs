@@ -0,0 +1,374 @@
+//! Continuous sources of transactions, such as a Kafka topic, and the stream processor which
+//! applies them to an engine.
+//!
+//! Every `commit_every` messages, and whenever no message arrived for a while, the state of the
+//! engine is saved to a snapshot together with the position of the last message applied from
+//! every partition, and only then the source is told that the messages up to there are
+//! processed. After a crash the consumer starts from the last snapshot and the source delivers
+//! again everything after the last commit, which may include messages already contained in the
+//! snapshot. Those are at or below the saved positions and are skipped, so every message is
+//! applied exactly once.
+
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use thiserror::Error;
+
+use crate::input::json_lines::parse_transaction;
+use crate::rejects::{ProcessingPolicy, Rejection, RejectionError, Rejections};
+use crate::TransactionEngine;
+
+#[cfg(feature = "kafka")]
+pub mod kafka;
+
+/// The number of messages between two commits if not configured otherwise
+pub const DEFAULT_COMMIT_EVERY: u64 = 10_000;
+
+/// The longest time between two commits while messages keep arriving, if not configured
+/// otherwise
+pub const DEFAULT_COMMIT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// All errors which can happen when reading from a source
+#[derive(Error, Debug)]
+pub enum SourceError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("the source failed: {0}")]
+    Failed(String),
+}
+
+/// The position of the last message applied from every partition of a source
+pub type SourceOffsets = BTreeMap<i32, u64>;
+
+/// A message holding a single transaction as a JSON object, in the same format as a line of
+/// JSON Lines input
+pub struct SourceMessage {
+    /// The partition of the source the message belongs to, 0 for sources without partitions
+    pub partition: i32,
+    /// Where the message was found in its partition, reported with it if it is rejected. It
+    /// grows with every message and stays the same when a message is delivered again.
+    pub position: u64,
+    pub payload: Vec<u8>,
+}
+
+/// A source which can be consumed continuously
+pub trait TransactionSource {
+    /// Waits for the next messages. An empty batch means that nothing arrived in time, None
+    /// that the source has ended.
+    fn poll(&mut self) -> Result<Option<Vec<SourceMessage>>, SourceError>;
+
+    /// Acknowledges every message returned by `poll` so far, so that they aren't delivered
+    /// again when consuming starts over.
+    fn commit(&mut self) -> Result<(), SourceError>;
+}
+
+/// A source reading a message from every line of a reader, e.g. a pipe. It ends with the
+/// reader, and committing does nothing as a reader can't be read again.
+pub struct LineSource<R: BufRead> {
+    reader: R,
+    line: u64,
+    batch_size: usize,
+}
+
+impl<R: BufRead> LineSource<R> {
+    /// Creates a source returning up to `batch_size` lines per poll.
+    pub fn new(reader: R, batch_size: usize) -> LineSource<R> {
+        LineSource {
+            reader,
+            line: 0,
+            batch_size: batch_size.max(1),
+        }
+    }
+}
+
+impl<R: BufRead> TransactionSource for LineSource<R> {
+    fn poll(&mut self) -> Result<Option<Vec<SourceMessage>>, SourceError> {
+        let mut messages = Vec::new();
+        while messages.len() < self.batch_size {
+            let mut payload = Vec::new();
+            if self.reader.read_until(b'\n', &mut payload)? == 0 {
+                break;
+            }
+            self.line += 1;
+            messages.push(SourceMessage {
+                partition: 0,
+                position: self.line,
+                payload,
+            });
+        }
+        Ok((!messages.is_empty()).then_some(messages))
+    }
+
+    fn commit(&mut self) -> Result<(), SourceError> {
+        Ok(())
+    }
+}
+
+/// Where the stream processor saves its state and how often
+#[derive(Debug, Clone)]
+pub struct StreamConfig {
+    pub snapshot_path: String,
+    pub commit_every: u64,
+    pub commit_interval: Duration,
+}
+
+impl StreamConfig {
+    pub fn new(snapshot_path: String) -> StreamConfig {
+        StreamConfig {
+            snapshot_path,
+            commit_every: DEFAULT_COMMIT_EVERY,
+            commit_interval: DEFAULT_COMMIT_INTERVAL,
+        }
+    }
+}
+
+/// Restores the engine and the source offsets from the snapshot of the stream processor, or
+/// starts without any accounts and offsets if there is no snapshot yet.
+pub fn resume(snapshot_path: &str) -> Result<(TransactionEngine, SourceOffsets), Box<dyn Error>> {
+    if !Path::new(snapshot_path).exists() {
+        return Ok((TransactionEngine::new(), SourceOffsets::new()));
+    }
+    Ok(TransactionEngine::restore_with_source_offsets(
+        BufReader::new(File::open(snapshot_path)?),
+    )?)
+}
+
+/// Applies the messages of the source to the engine until the source ends or `stop` is set,
+/// committing after saving a snapshot as described in the module documentation. Messages at or
+/// below the offset of their partition were applied before and are skipped, and the offsets
+/// are moved past every message applied. Messages which can't be read or applied are logged
+/// and skipped.
+pub fn consume(
+    transaction_engine: &mut TransactionEngine,
+    offsets: &mut SourceOffsets,
+    source: &mut impl TransactionSource,
+    config: &StreamConfig,
+    stop: &AtomicBool,
+) -> Result<(), Box<dyn Error>> {
+    let mut rejections = Rejections::new(ProcessingPolicy::Skip, true);
+    let mut uncommitted = 0;
+    let mut last_commit = Instant::now();
+    while !stop.load(Ordering::SeqCst) {
+        let Some(messages) = source.poll()? else {
+            break;
+        };
+        for message in messages {
+            uncommitted += 1;
+            if offsets
+                .get(&message.partition)
+                .is_some_and(|&offset| message.position <= offset)
+            {
+                continue;
+            }
+            offsets.insert(message.partition, message.position);
+            apply_message(transaction_engine, message, &mut rejections)?;
+        }
+        if uncommitted >= config.commit_every
+            || uncommitted > 0 && last_commit.elapsed() >= config.commit_interval
+        {
+            commit(transaction_engine, offsets, source, config)?;
+            uncommitted = 0;
+            last_commit = Instant::now();
+        }
+    }
+    if uncommitted > 0 {
+        commit(transaction_engine, offsets, source, config)?;
+    }
+    Ok(())
+}
+
+fn apply_message(
+    transaction_engine: &mut TransactionEngine,
+    message: SourceMessage,
+    rejections: &mut Rejections,
+) -> Result<(), Rejection> {
+    let text = String::from_utf8_lossy(&message.payload);
+    if text.trim().is_empty() {
+        return Ok(());
+    }
+    let result = match parse_transaction(&text) {
+        Ok(transaction) => transaction_engine
+            .process_transaction(transaction)
+            .map_err(RejectionError::from),
+        Err(e) => Err(RejectionError::Parse(e.to_string())),
+    };
+    if let Err(e) = result {
+        rejections.reject(Rejection::from_line(message.position, &text, e))?;
+    }
+    Ok(())
+}
+
+/// Saves the state and the offsets to the snapshot, replacing the previous one at once so that
+/// a crash leaves either of them intact, and then commits the source.
+fn commit(
+    transaction_engine: &TransactionEngine,
+    offsets: &SourceOffsets,
+    source: &mut impl TransactionSource,
+    config: &StreamConfig,
+) -> Result<(), Box<dyn Error>> {
+    let mut snapshot = transaction_engine.to_snapshot()?;
+    snapshot.source_offsets = Some(offsets.clone());
+    let temporary_path = format!("{}.tmp", config.snapshot_path);
+    let mut writer = BufWriter::new(File::create(&temporary_path)?);
+    serde_json::to_writer(&mut writer, &snapshot)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&temporary_path, &config.snapshot_path)?;
+    source.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::sync::atomic::AtomicBool;
+
+    use super::{
+        consume, resume, LineSource, SourceError, SourceMessage, SourceOffsets, StreamConfig,
+        TransactionSource,
+    };
+    use crate::{RetainPolicy, TransactionEngine};
+
+    /// Counts the commits of a line source
+    struct CountingSource {
+        inner: LineSource<&'static [u8]>,
+        commits: usize,
+    }
+
+    impl TransactionSource for CountingSource {
+        fn poll(&mut self) -> Result<Option<Vec<SourceMessage>>, SourceError> {
+            self.inner.poll()
+        }
+
+        fn commit(&mut self) -> Result<(), SourceError> {
+            self.commits += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_consume_commits_after_snapshots() {
+        let snapshot_path = env::temp_dir()
+            .join(format!("tte-stream-{}.json", std::process::id()))
+            .to_string_lossy()
+            .into_owned();
+        let mut source = CountingSource {
+            inner: LineSource::new(
+                b"{\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 2}\n\
+                  not json\n\
+                  {\"type\": \"deposit\", \"client\": 1, \"tx\": 1, \"amount\": 2}\n\
+                  {\"type\": \"withdrawal\", \"client\": 1, \"tx\": 2, \"amount\": 0.5}\n",
+                2,
+            ),
+            commits: 0,
+        };
+        let mut config = StreamConfig::new(snapshot_path.clone());
+        config.commit_every = 2;
+        let mut transaction_engine = TransactionEngine::new();
+        consume(
+            &mut transaction_engine,
+            &mut SourceOffsets::new(),
+            &mut source,
+            &config,
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        assert_eq!(source.commits, 2);
+        assert_eq!(
+            transaction_engine
+                .get_account(1)
+                .unwrap()
+                .available
+                .to_string(),
+            "1.5000"
+        );
+
+        let restored = TransactionEngine::restore(File::open(&snapshot_path).unwrap()).unwrap();
+        assert_eq!(restored.transaction_count(), 2);
+        fs::remove_file(snapshot_path).unwrap();
+    }
+
+    /// Delivers the messages given as (partition, position, payload) in a single batch
+    struct RedeliveringSource(Option<Vec<SourceMessage>>);
+
+    impl RedeliveringSource {
+        fn new(messages: &[(i32, u64, &str)]) -> RedeliveringSource {
+            RedeliveringSource(Some(
+                messages
+                    .iter()
+                    .map(|&(partition, position, payload)| SourceMessage {
+                        partition,
+                        position,
+                        payload: payload.as_bytes().to_vec(),
+                    })
+                    .collect(),
+            ))
+        }
+    }
+
+    impl TransactionSource for RedeliveringSource {
+        fn poll(&mut self) -> Result<Option<Vec<SourceMessage>>, SourceError> {
+            Ok(self.0.take())
+        }
+
+        fn commit(&mut self) -> Result<(), SourceError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_redelivered_messages_are_skipped_after_a_restart() {
+        let snapshot_path = env::temp_dir()
+            .join(format!(
+                "tte-stream-redelivered-{}.json",
+                std::process::id()
+            ))
+            .to_string_lossy()
+            .into_owned();
+        let deposit = r#"{"type": "deposit", "client": 1, "tx": 1, "amount": 2}"#;
+        let dispute = r#"{"type": "dispute", "client": 1, "tx": 1}"#;
+        let resolve = r#"{"type": "resolve", "client": 1, "tx": 1}"#;
+        let withdrawal = r#"{"type": "withdrawal", "client": 1, "tx": 2, "amount": 0.5}"#;
+        let config = StreamConfig::new(snapshot_path.clone());
+        let (mut transaction_engine, mut offsets) = resume(&snapshot_path).unwrap();
+        // withdrawals aren't kept, so a redelivered one can't be told apart from a new one
+        transaction_engine.set_retain_policy(RetainPolicy::DepositsOnly);
+        consume(
+            &mut transaction_engine,
+            &mut offsets,
+            &mut RedeliveringSource::new(&[
+                (0, 10, deposit),
+                (0, 11, dispute),
+                (0, 12, resolve),
+                (1, 5, withdrawal),
+            ]),
+            &config,
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+
+        // the commit to the source was lost, so everything is delivered again after a restart
+        let (mut transaction_engine, mut offsets) = resume(&snapshot_path).unwrap();
+        transaction_engine.set_retain_policy(RetainPolicy::DepositsOnly);
+        assert_eq!(offsets, SourceOffsets::from([(0, 12), (1, 5)]));
+        consume(
+            &mut transaction_engine,
+            &mut offsets,
+            &mut RedeliveringSource::new(&[(0, 11, dispute), (0, 12, resolve), (1, 5, withdrawal)]),
+            &config,
+            &AtomicBool::new(false),
+        )
+        .unwrap();
+        let account = transaction_engine.get_account(1).unwrap();
+        assert_eq!(account.available.to_string(), "1.5000");
+        assert_eq!(account.held.to_string(), "0.0000");
+        fs::remove_file(snapshot_path).unwrap();
+    }
+}
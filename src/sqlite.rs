@@ -0,0 +1,187 @@
+//! Exports the state of the engine to a SQLite database at the end of a run, with the `sqlite`
+//! feature, so that it can be queried with SQL.
+//!
+//! The database gets three tables, which are replaced if they are already there:
+//!
+//! - `accounts` with `client`, `available`, `held`, `total`, `locked` and `status`
+//! - `transactions` with the deposits and withdrawals kept for disputes: `tx`, `client`,
+//!   `type`, `amount`, `state`, `disputed`, `charged_back` and `timestamp`
+//! - `disputes` with `dispute`, `tx`, `client`, `amount`, `remaining`, `charged_back`, `state`
+//!   and `timestamp`
+//!
+//! Amounts are stored as text with four decimal places, so that no precision is lost. Cast
+//! them to `REAL` to sum them up roughly, e.g. `SELECT SUM(CAST(total AS REAL)) FROM accounts`.
+//! Everything is written in a single transaction, so a failed export leaves the database as
+//! it was.
+
+use std::io;
+
+use rusqlite::{params, Connection};
+use thiserror::Error;
+
+use crate::{TransactionEngine, TransactionFilter};
+
+/// All errors which can happen when exporting to SQLite
+#[derive(Error, Debug)]
+pub enum SqliteExportError {
+    #[error("couldn't export to SQLite: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error("couldn't read the stored transactions: {0}")]
+    Io(#[from] io::Error),
+}
+
+/// Creates the tables, dropping the ones of an earlier export.
+const SCHEMA: &str = "
+DROP TABLE IF EXISTS accounts;
+DROP TABLE IF EXISTS transactions;
+DROP TABLE IF EXISTS disputes;
+CREATE TABLE accounts (
+    client INTEGER PRIMARY KEY,
+    available TEXT NOT NULL,
+    held TEXT NOT NULL,
+    total TEXT NOT NULL,
+    locked INTEGER NOT NULL,
+    status TEXT NOT NULL
+);
+CREATE TABLE transactions (
+    tx INTEGER PRIMARY KEY,
+    client INTEGER NOT NULL,
+    type TEXT NOT NULL,
+    amount TEXT,
+    state TEXT NOT NULL,
+    disputed TEXT NOT NULL,
+    charged_back TEXT NOT NULL,
+    timestamp INTEGER
+);
+CREATE INDEX transactions_client ON transactions (client);
+CREATE TABLE disputes (
+    dispute INTEGER PRIMARY KEY,
+    tx INTEGER NOT NULL,
+    client INTEGER NOT NULL,
+    amount TEXT NOT NULL,
+    remaining TEXT NOT NULL,
+    charged_back TEXT NOT NULL,
+    state TEXT NOT NULL,
+    timestamp INTEGER
+);
+";
+
+/// Writes the accounts, stored transactions and disputes of the engine to the database at the
+/// path, creating it if needed.
+pub fn export(
+    transaction_engine: &TransactionEngine,
+    database_path: &str,
+) -> Result<(), SqliteExportError> {
+    let mut connection = Connection::open(database_path)?;
+    let transaction = connection.transaction()?;
+    transaction.execute_batch(SCHEMA)?;
+    {
+        let mut insert = transaction.prepare(
+            "INSERT INTO accounts (client, available, held, total, locked, status)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+        )?;
+        for (client, account) in transaction_engine.sorted_accounts() {
+            insert.execute(params![
+                client,
+                account.available.to_string(),
+                account.held.to_string(),
+                account.total.to_string(),
+                account.is_locked(),
+                account.status.name(),
+            ])?;
+        }
+
+        let mut insert = transaction.prepare(
+            "INSERT INTO transactions
+             (tx, client, type, amount, state, disputed, charged_back, timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for stored in transaction_engine.transactions(&TransactionFilter::default())? {
+            insert.execute(params![
+                stored.tx,
+                stored.client,
+                stored.kind.name(),
+                stored.amount.map(|amount| amount.to_string()),
+                stored.state.name(),
+                stored.disputed.to_string(),
+                stored.charged_back.to_string(),
+                stored.timestamp,
+            ])?;
+        }
+
+        let mut insert = transaction.prepare(
+            "INSERT INTO disputes
+             (dispute, tx, client, amount, remaining, charged_back, state, timestamp)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+        )?;
+        for dispute in transaction_engine.disputes() {
+            insert.execute(params![
+                dispute.id,
+                dispute.tx,
+                dispute.client,
+                dispute.amount.to_string(),
+                dispute.remaining.to_string(),
+                dispute.charged_back.to_string(),
+                dispute.state.name(),
+                dispute.timestamp,
+            ])?;
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use rusqlite::Connection;
+
+    use super::export;
+    use crate::process_reader;
+
+    #[test]
+    fn test_state_can_be_queried_with_sql() {
+        let path = env::temp_dir().join(format!("tte-export-{}.sqlite", process::id()));
+        let path = path.to_str().unwrap();
+        let transaction_engine = process_reader(
+            "type,client,tx,amount\n\
+             deposit,1,1,10\n\
+             deposit,1,2,2.5\n\
+             deposit,2,3,1\n\
+             dispute,1,1,\n\
+             chargeback,1,1,\n"
+                .as_bytes(),
+        )
+        .unwrap();
+        // a second export replaces the first
+        export(&transaction_engine, path).unwrap();
+        export(&transaction_engine, path).unwrap();
+
+        let connection = Connection::open(path).unwrap();
+        let (total, locked): (String, bool) = connection
+            .query_row(
+                "SELECT total, locked FROM accounts WHERE client = 1",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((total.as_str(), locked), ("2.5000", true));
+        let deposits: u64 = connection
+            .query_row(
+                "SELECT COUNT(*) FROM transactions WHERE type = 'deposit'",
+                [],
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(deposits, 3);
+        let state: String = connection
+            .query_row("SELECT state FROM disputes WHERE tx = 1", [], |row| {
+                row.get(0)
+            })
+            .unwrap();
+        assert_eq!(state, "charged_back");
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+//! The `statement` subcommand, processing an input and writing the statement of one client's
+//! account instead of the state of all accounts.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read};
+
+use clap::Parser;
+
+use crate::control_totals::ControlTotalsPolicy;
+use crate::output::{self, OutputFormat};
+use crate::rejects::{ProcessingPolicy, Rejections};
+use crate::{
+    parse_output_format, parse_zero_amount_policy, process_csv, AsOf, ClientId, HistoryRetention,
+    TransactionEngine, TransactionId, ZeroAmountPolicy, STDIN_PATH,
+};
+
+/// Processes a CSV file of transactions and writes the statement of a client's account.
+#[derive(Parser, Debug)]
+#[command(name = "statement", bin_name = "toy-transaction-engine statement")]
+pub struct StatementConfig {
+    /// The input file, or - to read from stdin
+    #[arg(default_value = STDIN_PATH)]
+    pub input_path: String,
+
+    /// The client whose statement is written
+    #[arg(long)]
+    pub client: ClientId,
+
+    /// The format of the statement: csv, json or jsonl
+    #[arg(long, default_value = "csv", value_parser = parse_output_format)]
+    pub format: OutputFormat,
+
+    /// Only list the latest transactions of the client, at most this many
+    #[arg(long, value_name = "COUNT")]
+    pub limit: Option<usize>,
+
+    /// Only list the transactions up to the one with this sequence number
+    #[arg(long, value_name = "SEQ", conflicts_with = "as_of_tx")]
+    pub as_of: Option<u64>,
+
+    /// Only list the transactions up to the deposit or withdrawal with this id
+    #[arg(long, value_name = "TX")]
+    pub as_of_tx: Option<TransactionId>,
+
+    /// What to do with deposits and withdrawals of a zero amount: skip or reject
+    #[arg(long = "zero-amounts", default_value = "reject", value_parser = parse_zero_amount_policy)]
+    pub zero_amount_policy: ZeroAmountPolicy,
+}
+
+impl StatementConfig {
+    /// Parses the arguments starting with the `statement` subcommand.
+    pub fn new(args: &[String]) -> Result<StatementConfig, clap::Error> {
+        StatementConfig::try_parse_from(args)
+    }
+}
+
+/// Processes the input and writes the statement to stdout. Rows which can't be applied are
+/// skipped and logged.
+pub fn run(config: StatementConfig) -> Result<(), Box<dyn Error>> {
+    let mut transaction_engine =
+        TransactionEngine::with_zero_amount_policy(config.zero_amount_policy);
+    transaction_engine.set_history_retention(match config.limit {
+        Some(limit) => HistoryRetention::Last(limit),
+        None => HistoryRetention::All,
+    });
+    let input: Box<dyn Read> = if config.input_path == STDIN_PATH {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(&config.input_path)?))
+    };
+    process_csv(
+        &mut transaction_engine,
+        input,
+        ControlTotalsPolicy::Warn,
+        &mut Rejections::new(ProcessingPolicy::Skip, true),
+    )?;
+    let mut statement = transaction_engine.statement(config.client);
+    let as_of = match (config.as_of, config.as_of_tx) {
+        (Some(sequence), _) => Some(AsOf::Sequence(sequence)),
+        (None, Some(tx)) => Some(AsOf::Tx(tx)),
+        (None, None) => None,
+    };
+    if let Some(as_of) = as_of {
+        let sequence = transaction_engine
+            .sequence_of(as_of)
+            .ok_or("the transaction isn't a deposit or withdrawal of the input")?;
+        statement.retain(|line| line.seq <= sequence);
+    }
+    output::write_statement(
+        BufWriter::new(io::stdout().lock()),
+        &statement,
+        config.format,
+    )?;
+    Ok(())
+}
@@ -0,0 +1,233 @@
+//! Summary statistics of a run, to sanity check large batches at a glance.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{AccountDetails, Amount, MoneyOps, TransactionType};
+
+/// How the statistics are printed with `--stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum StatsFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl StatsFormat {
+    /// Parses the format from its command line name.
+    pub fn from_name(name: &str) -> Option<StatsFormat> {
+        match name {
+            "text" => Some(StatsFormat::Text),
+            "json" => Some(StatsFormat::Json),
+            _ => None,
+        }
+    }
+}
+
+/// What an engine processed since it was created and the state of its accounts
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct ProcessingStats {
+    /// The transactions processed by type, whether they were applied or not
+    pub transactions: BTreeMap<Cow<'static, str>, u64>,
+    /// The rejected transactions by error kind
+    pub rejections: BTreeMap<Cow<'static, str>, u64>,
+    pub accounts: usize,
+    pub locked_accounts: usize,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    /// Whether the funds of the accounts add up to more than the largest amount, in which case
+    /// `available`, `held` and `total` are capped at it
+    pub funds_overflowed: bool,
+}
+
+impl ProcessingStats {
+    /// Counts a processed transaction, with the kind of error it was rejected with, if it was.
+    pub(crate) fn count_transaction(&mut self, kind: TransactionType, error: Option<&'static str>) {
+        *self.transactions.entry(kind.name().into()).or_default() += 1;
+        if let Some(error) = error {
+            self.count_rejection(error);
+        }
+    }
+
+    /// Counts a rejected row, e.g. one which couldn't be read and so never reached the engine.
+    pub fn count_rejection(&mut self, kind: &'static str) {
+        *self.rejections.entry(kind.into()).or_default() += 1;
+    }
+
+    /// Replaces the account figures with the ones of these accounts.
+    pub(crate) fn set_accounts<'a>(&mut self, accounts: impl Iterator<Item = &'a AccountDetails>) {
+        self.accounts = 0;
+        self.locked_accounts = 0;
+        // summed wider, as the funds of many accounts can add up to more than any amount
+        let mut sums = [0_i128; 3];
+        for account in accounts {
+            self.accounts += 1;
+            self.locked_accounts += usize::from(account.is_locked());
+            sums[0] += account.available.to_minor_units();
+            sums[1] += account.held.to_minor_units();
+            sums[2] += account.total.to_minor_units();
+        }
+        self.funds_overflowed = false;
+        self.set_funds(sums);
+    }
+
+    /// Sets the funds from their sums in minor units, capped at the largest amount.
+    fn set_funds(&mut self, [available, held, total]: [i128; 3]) {
+        let mut capped = |sum: i128| {
+            Amount::try_from_minor_units(sum).unwrap_or_else(|| {
+                self.funds_overflowed = true;
+                if sum > 0 {
+                    Amount::MAX
+                } else {
+                    -Amount::MAX
+                }
+            })
+        };
+        self.available = capped(available);
+        self.held = capped(held);
+        self.total = capped(total);
+    }
+
+    /// Adds the statistics of another engine, e.g. of another shard.
+    pub fn merge(&mut self, other: &ProcessingStats) {
+        for (kind, count) in &other.transactions {
+            *self.transactions.entry(kind.clone()).or_default() += count;
+        }
+        for (kind, count) in &other.rejections {
+            *self.rejections.entry(kind.clone()).or_default() += count;
+        }
+        self.accounts += other.accounts;
+        self.locked_accounts += other.locked_accounts;
+        self.funds_overflowed |= other.funds_overflowed;
+        let sum = |a: Amount, b: Amount| a.to_minor_units() + b.to_minor_units();
+        self.set_funds([
+            sum(self.available, other.available),
+            sum(self.held, other.held),
+            sum(self.total, other.total),
+        ]);
+    }
+
+    /// The number of transactions processed
+    pub fn transaction_count(&self) -> u64 {
+        self.transactions.values().sum()
+    }
+
+    /// The number of rejected rows
+    pub fn rejection_count(&self) -> u64 {
+        self.rejections.values().sum()
+    }
+
+    /// The number of rows read from the input: the transactions processed, but not the
+    /// interest paid by the engine, and the rows which couldn't be read or came out of order and
+    /// so never reached it
+    pub fn row_count(&self) -> u64 {
+        let interest = self
+            .transactions
+            .get(TransactionType::Interest.name())
+            .copied()
+            .unwrap_or(0);
+        let unread: u64 = self
+            .rejections
+            .iter()
+            .filter(|(kind, _)| matches!(kind.as_ref(), "parse_error" | "out_of_order"))
+            .map(|(_, count)| count)
+            .sum();
+        self.transaction_count() - interest + unread
+    }
+}
+
+impl fmt::Display for ProcessingStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let counts = |counts: &BTreeMap<Cow<str>, u64>| {
+            counts
+                .iter()
+                .map(|(kind, count)| format!("{} {}", kind, count))
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        writeln!(
+            f,
+            "transactions: {} ({})",
+            self.transaction_count(),
+            counts(&self.transactions)
+        )?;
+        writeln!(
+            f,
+            "rejected: {} ({})",
+            self.rejection_count(),
+            counts(&self.rejections)
+        )?;
+        writeln!(
+            f,
+            "accounts: {} ({} locked)",
+            self.accounts, self.locked_accounts
+        )?;
+        write!(
+            f,
+            "available: {}, held: {}, total: {}",
+            self.available, self.held, self.total
+        )?;
+        if self.funds_overflowed {
+            write!(
+                f,
+                " (capped, the funds add up to more than the largest amount)"
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::transaction_engine::InterestPolicy;
+    use crate::{TransactionEngine, TransactionInput};
+
+    #[test]
+    fn test_stats_count_transactions_and_accounts() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "2".parse().unwrap()),
+            TransactionInput::deposit(2, 2, "3".parse().unwrap()),
+            TransactionInput::withdrawal(1, 3, "5".parse().unwrap()),
+            TransactionInput::dispute(2, 2),
+            TransactionInput::chargeback(2, 2),
+        ]);
+        let mut stats = transaction_engine.stats();
+        stats.count_rejection("parse_error");
+
+        assert_eq!(stats.transaction_count(), 5);
+        assert_eq!(stats.transactions["deposit"], 2);
+        assert_eq!(stats.rejections["insufficient_funds"], 1);
+        assert_eq!(stats.rejection_count(), 2);
+        assert_eq!(stats.row_count(), 6);
+        assert_eq!((stats.accounts, stats.locked_accounts), (2, 1));
+        assert_eq!(
+            stats.to_string(),
+            "transactions: 5 (chargeback 1, deposit 2, dispute 1, withdrawal 1)\n\
+             rejected: 2 (insufficient_funds 1, parse_error 1)\n\
+             accounts: 2 (1 locked)\n\
+             available: 2.0000, held: 0.0000, total: 2.0000"
+        );
+    }
+
+    #[test]
+    fn test_interest_payments_are_not_counted_as_rows() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_interest_policy(Some(InterestPolicy {
+            rate_bps: 365 * 100,
+            period: 86_400,
+        }));
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "100".parse().unwrap()).with_timestamp(0),
+            TransactionInput::deposit(2, 2, "100".parse().unwrap()).with_timestamp(86_400),
+            TransactionInput::withdrawal(1, 3, "500".parse().unwrap()).with_timestamp(172_800),
+        ]);
+        let stats = transaction_engine.stats();
+        assert!(stats.transactions["interest"] > 0);
+        assert_eq!((stats.rejection_count(), stats.row_count()), (1, 3));
+    }
+}
@@ -0,0 +1,204 @@
+//! Generated transactions and a reference model of the engine for property-based tests, with
+//! the `test-util` feature.
+//!
+//! `transactions` generates sequences of deposits, withdrawals, disputes, resolves and
+//! chargebacks over a few clients and transaction ids, so that most disputes reference a
+//! transaction which exists and ids are reused now and then. `ModelEngine` applies them the
+//! slow and obvious way, with the default settings of `TransactionEngine`, so the two can be
+//! compared.
+
+use std::collections::{BTreeMap, HashMap};
+
+use proptest::prelude::*;
+
+use crate::{Amount, ClientId, TransactionId, TransactionInput, TransactionType};
+
+/// A transaction of one of the clients `1..=clients` with an id in `1..=ids`. Amounts are up
+/// to 1000 with four decimal places, often whole numbers up to 10 so that withdrawals of
+/// exactly the available funds happen, and now and then zero or negative.
+pub fn transaction(
+    clients: ClientId,
+    ids: TransactionId,
+) -> impl Strategy<Value = TransactionInput> {
+    let amount = prop_oneof![
+        4 => 1..10_000_000i64,
+        4 => (1..=10i64).prop_map(|whole| whole * 10_000),
+        1 => -10_000..=0i64,
+    ]
+    .prop_map(Amount::from_minor_units);
+    (0..5u8, 1..=clients, 1..=ids, amount).prop_map(|(kind, client, tx, amount)| match kind {
+        0 => TransactionInput::deposit(client, tx, amount),
+        1 => TransactionInput::withdrawal(client, tx, amount),
+        2 => TransactionInput::dispute(client, tx),
+        3 => TransactionInput::resolve(client, tx),
+        _ => TransactionInput::chargeback(client, tx),
+    })
+}
+
+/// Up to `max_len` transactions over 3 clients and 20 transaction ids
+pub fn transactions(max_len: usize) -> impl Strategy<Value = Vec<TransactionInput>> {
+    proptest::collection::vec(transaction(3, 20), 0..max_len)
+}
+
+/// An account of the model
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ModelAccount {
+    pub available: Amount,
+    pub held: Amount,
+    pub locked: bool,
+}
+
+impl ModelAccount {
+    pub fn total(&self) -> Amount {
+        self.available + self.held
+    }
+}
+
+/// Where a deposit or withdrawal of the model is in its disputes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ModelState {
+    Normal,
+    Disputed,
+    ChargedBack,
+}
+
+#[derive(Debug, Clone)]
+struct ModelTransaction {
+    kind: TransactionType,
+    client: ClientId,
+    amount: Amount,
+    state: ModelState,
+}
+
+/// A reference implementation of deposits, withdrawals and whole disputes. It knows nothing
+/// of fees, timestamps, partial disputes or lifecycle transactions.
+#[derive(Debug, Default)]
+pub struct ModelEngine {
+    accounts: BTreeMap<ClientId, ModelAccount>,
+    transactions: HashMap<TransactionId, ModelTransaction>,
+}
+
+impl ModelEngine {
+    pub fn new() -> ModelEngine {
+        ModelEngine::default()
+    }
+
+    /// The accounts, sorted by client
+    pub fn accounts(&self) -> &BTreeMap<ClientId, ModelAccount> {
+        &self.accounts
+    }
+
+    /// Applies the transaction, returning whether it was accepted.
+    pub fn apply(&mut self, transaction: &TransactionInput) -> bool {
+        let client = transaction.client();
+        let tx = transaction.tx();
+        let locked = self.accounts.get(&client).is_some_and(|a| a.locked);
+        match transaction.kind {
+            TransactionType::Deposit | TransactionType::Withdrawal => {
+                let Some(amount) = transaction.amount() else {
+                    return false;
+                };
+                if locked || amount <= Amount::ZERO || self.transactions.contains_key(&tx) {
+                    return false;
+                }
+                if transaction.kind == TransactionType::Deposit {
+                    self.accounts.entry(client).or_default().available += amount;
+                } else {
+                    // the available funds must be more than the amount
+                    match self.accounts.get_mut(&client) {
+                        Some(account) if account.available > amount => account.available -= amount,
+                        _ => return false,
+                    }
+                }
+                self.transactions.insert(
+                    tx,
+                    ModelTransaction {
+                        kind: transaction.kind,
+                        client,
+                        amount,
+                        state: ModelState::Normal,
+                    },
+                );
+                true
+            }
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                let opening = transaction.kind == TransactionType::Dispute;
+                if locked && opening {
+                    return false;
+                }
+                let Some(t) = self.transactions.get_mut(&tx) else {
+                    return false;
+                };
+                let expected = if opening {
+                    ModelState::Normal
+                } else {
+                    ModelState::Disputed
+                };
+                if t.client != client || t.state != expected {
+                    return false;
+                }
+                let account = self.accounts.get_mut(&client).expect("the account exists");
+                let deposit = t.kind == TransactionType::Deposit;
+                match transaction.kind {
+                    TransactionType::Dispute => {
+                        // the funds of a deposit are held, a withdrawal's are held on top
+                        if deposit {
+                            account.available -= t.amount;
+                        }
+                        account.held += t.amount;
+                        t.state = ModelState::Disputed;
+                    }
+                    TransactionType::Resolve => {
+                        // a resolved transaction can be disputed again
+                        account.held -= t.amount;
+                        if deposit {
+                            account.available += t.amount;
+                        }
+                        t.state = ModelState::Normal;
+                    }
+                    _ => {
+                        account.held -= t.amount;
+                        if !deposit {
+                            account.available += t.amount;
+                        }
+                        account.locked = true;
+                        t.state = ModelState::ChargedBack;
+                    }
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{transactions, ModelEngine};
+    use crate::TransactionEngine;
+
+    proptest! {
+        #[test]
+        fn test_engine_matches_the_model(transactions in transactions(200)) {
+            let mut transaction_engine = TransactionEngine::new();
+            let mut model = ModelEngine::new();
+            for transaction in &transactions {
+                let applied = transaction_engine.process_transaction(*transaction).is_ok();
+                prop_assert_eq!(applied, model.apply(transaction), "{:?}", transaction);
+            }
+            let accounts = transaction_engine.sorted_accounts();
+            prop_assert_eq!(accounts.len(), model.accounts().len());
+            for ((client, account), (model_client, model_account)) in
+                accounts.iter().zip(model.accounts())
+            {
+                prop_assert_eq!(client, model_client);
+                prop_assert_eq!(account.available, model_account.available);
+                prop_assert_eq!(account.held, model_account.held);
+                prop_assert_eq!(account.total, model_account.total());
+                prop_assert_eq!(account.is_locked(), model_account.locked);
+            }
+        }
+    }
+}
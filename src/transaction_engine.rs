@@ -1,12 +1,66 @@
 use std::collections::HashMap;
+use std::io;
+use std::ops::RangeInclusive;
 
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use thiserror::Error;
+use tracing::{debug, error};
 
-pub use crate::{Amount, ClientId, TransactionId};
+use crate::account_config::AccountConfig;
+use crate::audit::{AuditRecord, AuditSink};
+use crate::events::EngineObserver;
+use crate::fees::FeeSchedule;
+use crate::idempotency::IdempotencyStore;
+use crate::output::{self, OutputError, OutputFormat};
+use crate::risk::{QuarantinedTransaction, ReviewDecision, RiskAssessor, RiskDecision};
+use crate::rules::{Rule, RuleViolation};
+use crate::stats::ProcessingStats;
+use crate::wal::Wal;
+
+pub use crate::{Amount, ClientId, Timestamp, TransactionId};
 pub use crate::{TransactionInput, TransactionType};
 
+/// The hasher of the maps looked up for every transaction: FxHash with the `fast-hash` feature,
+/// which is much faster for integer keys than SipHash but not resistant to keys crafted to
+/// collide, and SipHash otherwise.
+#[cfg(feature = "fast-hash")]
+type FastHasher = rustc_hash::FxBuildHasher;
+#[cfg(not(feature = "fast-hash"))]
+type FastHasher = std::collections::hash_map::RandomState;
+
+/// A map looked up for every transaction
+type FastMap<K, V> = HashMap<K, V, FastHasher>;
+
+mod config;
+mod disputes;
+mod event_log;
+mod history;
+mod interest;
+mod ledger;
+mod snapshot;
+mod store;
+mod undo;
+
+pub use config::{DuplicateIdPolicy, EngineConfig};
+use disputes::Disputes;
+pub use disputes::{Dispute, DisputeId, DisputeState};
+pub use event_log::{EventSink, JsonLinesEventSink, StateEvent};
+use history::History;
+pub use history::{AsOf, HistoryRetention, StatementLine};
+use interest::InterestAccrual;
+pub use interest::{InterestPolicy, DEFAULT_INTEREST_PERIOD};
+use ledger::LedgerEntry;
+pub use ledger::{Ledger, LedgerAccount, LedgerError};
+pub(crate) use snapshot::Snapshot;
+pub use snapshot::{SnapshotError, SNAPSHOT_VERSION};
+use store::TransactionStore;
+pub use undo::RollbackError;
+use undo::UndoLog;
+
 /// All errors which can happen when processing a transaction
 #[derive(Error, Debug, Clone)]
+#[non_exhaustive]
 pub enum TransactionProcessingError {
     #[error("transaction can't be processed as account is locked")]
     AccountLocked,
@@ -31,22 +85,432 @@ pub enum TransactionProcessingError {
 
     #[error("cannot dispute an already disputed transaction")]
     CannotDisputeAnAlreadyDisputedTransaction,
+
+    #[error("cannot dispute a transaction which was already charged back")]
+    CannotDisputeAChargedBackTransaction,
+
+    #[error("the dispute was filed after the dispute window of the transaction closed")]
+    DisputeWindowExpired,
+
+    #[error("{0} is more than the amount of the transaction which can be disputed or settled")]
+    DisputeAmountTooLarge(Amount),
+
+    #[error("the referenced transaction belongs to a different client")]
+    ClientMismatch,
+
+    #[error("a transaction with the same id was already processed")]
+    DuplicateTransactionId,
+
+    #[error("a transaction with the same id was processed by an earlier run")]
+    AlreadyProcessed,
+
+    #[error("admin operations aren't allowed")]
+    AdminOperationsNotAllowed,
+
+    #[error("the account isn't locked")]
+    AccountNotLocked,
+
+    #[error("transaction can't be processed as account is frozen")]
+    AccountFrozen,
+
+    #[error("the account isn't frozen")]
+    AccountNotFrozen,
+
+    #[error("transaction can't be processed as account is closed")]
+    AccountClosed,
+
+    #[error("the account is open already")]
+    AccountAlreadyOpen,
+
+    #[error("{0}")]
+    RuleViolated(RuleViolation),
+
+    #[error("the transaction is held for review: {0}")]
+    Quarantined(String),
+
+    #[error("the transaction was denied: {0}")]
+    RiskDenied(String),
+
+    #[error("the account can't be closed while it holds funds")]
+    AccountNotEmpty,
+
+    #[error("interest transactions are paid by the engine and can't be submitted")]
+    InterestNotAllowed,
+
+    #[error("timestamp {0} is too far past the latest one")]
+    TimestampTooFarAhead(Timestamp),
+
+    #[error("the fee of {0} is more than the amount deposited")]
+    FeeExceedsAmount(Amount),
+
+    #[error("the fee can't be collected as the collection account is locked or closed")]
+    CollectionAccountUnavailable,
+
+    #[error("{0} is not a valid amount, amounts must be positive")]
+    InvalidAmount(Amount),
+
+    #[error("the transaction would take a balance beyond the largest amount which can be kept")]
+    BalanceOverflow,
+
+    #[error("the transaction couldn't be written to the write-ahead log and was undone: {0}")]
+    WalWriteFailed(String),
+
+    #[error("the stored transactions couldn't be read or written: {0}")]
+    StorageFailed(String),
+
+    #[error("transaction {tx} left the account of client {client} inconsistent: {reason}")]
+    InvariantViolated {
+        tx: TransactionId,
+        client: ClientId,
+        reason: &'static str,
+    },
+}
+
+impl TransactionProcessingError {
+    /// A short, machine-readable name of the error
+    pub fn kind(&self) -> &'static str {
+        match self {
+            TransactionProcessingError::AccountLocked => "account_locked",
+            TransactionProcessingError::AccountNotFound => "account_not_found",
+            TransactionProcessingError::InsufficientFunds => "insufficient_funds",
+            TransactionProcessingError::AmountValueNotFound => "missing_amount",
+            TransactionProcessingError::TransactionNotFound => "transaction_not_found",
+            TransactionProcessingError::AmountNotFoundOnTransactionToDispute => {
+                "missing_disputed_amount"
+            }
+            TransactionProcessingError::CannotResolveNonDisputedTransaction => {
+                "transaction_not_disputed"
+            }
+            TransactionProcessingError::CannotDisputeAnAlreadyDisputedTransaction => {
+                "transaction_already_disputed"
+            }
+            TransactionProcessingError::CannotDisputeAChargedBackTransaction => {
+                "transaction_charged_back"
+            }
+            TransactionProcessingError::DisputeWindowExpired => "dispute_window_expired",
+            TransactionProcessingError::DisputeAmountTooLarge(_) => "dispute_amount_too_large",
+            TransactionProcessingError::ClientMismatch => "client_mismatch",
+            TransactionProcessingError::DuplicateTransactionId => "duplicate_transaction_id",
+            TransactionProcessingError::AlreadyProcessed => "already_processed",
+            TransactionProcessingError::AdminOperationsNotAllowed => "admin_ops_not_allowed",
+            TransactionProcessingError::AccountNotLocked => "account_not_locked",
+            TransactionProcessingError::AccountFrozen => "account_frozen",
+            TransactionProcessingError::AccountNotFrozen => "account_not_frozen",
+            TransactionProcessingError::AccountClosed => "account_closed",
+            TransactionProcessingError::AccountAlreadyOpen => "account_already_open",
+            TransactionProcessingError::AccountNotEmpty => "account_not_empty",
+            TransactionProcessingError::RuleViolated(violation) => violation.kind(),
+            TransactionProcessingError::Quarantined(_) => "quarantined",
+            TransactionProcessingError::RiskDenied(_) => "risk_denied",
+            TransactionProcessingError::InterestNotAllowed => "interest_not_allowed",
+            TransactionProcessingError::TimestampTooFarAhead(_) => "timestamp_too_far_ahead",
+            TransactionProcessingError::FeeExceedsAmount(_) => "fee_exceeds_amount",
+            TransactionProcessingError::CollectionAccountUnavailable => {
+                "collection_account_unavailable"
+            }
+            TransactionProcessingError::InvalidAmount(_) => "invalid_amount",
+            TransactionProcessingError::BalanceOverflow => "balance_overflow",
+            TransactionProcessingError::WalWriteFailed(_) => "wal_write_failed",
+            TransactionProcessingError::StorageFailed(_) => "storage_failed",
+            TransactionProcessingError::InvariantViolated { .. } => "invariant_violated",
+        }
+    }
+}
+
+impl From<io::Error> for TransactionProcessingError {
+    fn from(e: io::Error) -> TransactionProcessingError {
+        TransactionProcessingError::StorageFailed(e.to_string())
+    }
+}
+
+/// What to do with deposits and withdrawals of a zero amount
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ZeroAmountPolicy {
+    /// Skip the transaction without an error, it has no effect on any account
+    Skip,
+    /// Fail the transaction with an `InvalidAmount` error
+    #[default]
+    Reject,
 }
 
-/// The details stored for every account
+impl ZeroAmountPolicy {
+    /// Parses the policy from its command line name.
+    pub fn from_name(name: &str) -> Option<ZeroAmountPolicy> {
+        match name {
+            "skip" => Some(ZeroAmountPolicy::Skip),
+            "reject" => Some(ZeroAmountPolicy::Reject),
+            _ => None,
+        }
+    }
+}
+
+/// Which transactions are kept for disputes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RetainPolicy {
+    /// Keep deposits and withdrawals
+    #[default]
+    All,
+    /// Keep deposits only. Withdrawals can't be disputed then, and the id of a withdrawal may
+    /// be used again by a later transaction without a `DuplicateTransactionId` error.
+    #[serde(rename = "deposits")]
+    DepositsOnly,
+}
+
+impl RetainPolicy {
+    /// Parses the policy from its command line name.
+    pub fn from_name(name: &str) -> Option<RetainPolicy> {
+        match name {
+            "all" => Some(RetainPolicy::All),
+            "deposits" => Some(RetainPolicy::DepositsOnly),
+            _ => None,
+        }
+    }
+}
+
+/// Which transactions are still applied to a locked account, besides an unlock
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum LockedAccountPolicy {
+    /// Reject every transaction with `AccountLocked`
+    #[serde(rename = "block")]
+    BlockAll,
+    /// Apply deposits, rejecting everything else
+    #[serde(rename = "deposits")]
+    DepositsOnly,
+    /// Apply resolves and chargebacks of the disputes which are still open, rejecting
+    /// everything else. Disputes can't be opened on a locked account, so these are the disputes
+    /// which were opened before it was locked.
+    #[default]
+    #[serde(rename = "settle")]
+    SettleDisputes,
+}
+
+impl LockedAccountPolicy {
+    /// Parses the policy from its command line name.
+    pub fn from_name(name: &str) -> Option<LockedAccountPolicy> {
+        match name {
+            "block" => Some(LockedAccountPolicy::BlockAll),
+            "deposits" => Some(LockedAccountPolicy::DepositsOnly),
+            "settle" => Some(LockedAccountPolicy::SettleDisputes),
+            _ => None,
+        }
+    }
+
+    /// Whether a transaction of the kind is applied to a locked account
+    fn allows(self, kind: TransactionType) -> bool {
+        matches!(
+            (self, kind),
+            (_, TransactionType::Unlock)
+                | (LockedAccountPolicy::DepositsOnly, TransactionType::Deposit)
+                | (
+                    LockedAccountPolicy::SettleDisputes,
+                    TransactionType::Resolve | TransactionType::Chargeback
+                )
+        )
+    }
+}
+
+/// Where an account is in its lifecycle, deciding which transactions are allowed on it
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum AccountStatus {
+    /// Every transaction is allowed
+    #[default]
+    Active,
+    /// Funds can't leave the account, withdrawals are rejected until it is unfrozen
+    Frozen,
+    /// Nothing is allowed until the account is opened again
+    Closed,
+    /// Locked by a chargeback, nothing is allowed until an admin unlocks it
+    Locked,
+}
+
+impl AccountStatus {
+    /// The name of the status as it is written to snapshots and the audit log
+    pub fn name(&self) -> &'static str {
+        match self {
+            AccountStatus::Active => "active",
+            AccountStatus::Frozen => "frozen",
+            AccountStatus::Closed => "closed",
+            AccountStatus::Locked => "locked",
+        }
+    }
+
+    /// Parses the status from its name.
+    pub fn from_name(name: &str) -> Option<AccountStatus> {
+        match name {
+            "active" => Some(AccountStatus::Active),
+            "frozen" => Some(AccountStatus::Frozen),
+            "closed" => Some(AccountStatus::Closed),
+            "locked" => Some(AccountStatus::Locked),
+            _ => None,
+        }
+    }
+
+    /// Checks whether a transaction of the kind is allowed on an account with this status, with
+    /// the policy deciding what is allowed on a locked account.
+    fn check_allowed(
+        self,
+        kind: TransactionType,
+        locked_account_policy: LockedAccountPolicy,
+    ) -> Result<(), TransactionProcessingError> {
+        match (self, kind) {
+            (AccountStatus::Locked, kind) if locked_account_policy.allows(kind) => Ok(()),
+            (AccountStatus::Closed, TransactionType::Open)
+            | (AccountStatus::Frozen, TransactionType::Unfreeze) => Ok(()),
+            (AccountStatus::Locked, _) => Err(TransactionProcessingError::AccountLocked),
+            (AccountStatus::Closed, _) => Err(TransactionProcessingError::AccountClosed),
+            (
+                AccountStatus::Frozen,
+                TransactionType::Withdrawal | TransactionType::Freeze | TransactionType::Close,
+            ) => Err(TransactionProcessingError::AccountFrozen),
+            (_, TransactionType::Open) => Err(TransactionProcessingError::AccountAlreadyOpen),
+            (_, TransactionType::Unlock) => Err(TransactionProcessingError::AccountNotLocked),
+            (_, TransactionType::Unfreeze) => Err(TransactionProcessingError::AccountNotFrozen),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The details stored for every account. The `locked` field of earlier versions is part of
+/// the `status` now, `is_locked` tells whether a chargeback locked the account.
+#[derive(Debug, Clone, Default, PartialEq)]
 pub struct AccountDetails {
     pub available: Amount,
     pub held: Amount,
     pub total: Amount,
-    pub locked: bool,
+    pub status: AccountStatus,
+}
+
+impl AccountDetails {
+    /// Whether a chargeback locked the account
+    pub fn is_locked(&self) -> bool {
+        self.status == AccountStatus::Locked
+    }
+
+    /// Checks what must hold for every account after a transaction of the kind: the total is
+    /// the sum of the available and held funds, held funds aren't negative, and a withdrawal
+    /// never leaves the available funds below minus the credit limit of the account. They can
+    /// still go lower when a deposit which was spent already is disputed.
+    pub fn check_invariants(
+        &self,
+        kind: TransactionType,
+        credit_limit: Amount,
+    ) -> Result<(), &'static str> {
+        if self.total != self.available + self.held {
+            Err("the total isn't the sum of the available and held funds")
+        } else if self.held.is_negative() {
+            Err("the held funds are negative")
+        } else if kind == TransactionType::Withdrawal
+            && self.available + credit_limit < Amount::ZERO
+        {
+            Err("the withdrawal took the available funds past the credit limit")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Where a deposit or withdrawal is in its dispute cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum TransactionState {
+    Normal,
+    /// All or part of the transaction is held by disputes
+    Disputed,
+    /// The disputes were resolved, the transaction may be disputed again
+    Resolved,
+    /// The disputed amount was reversed for good. Only what wasn't charged back can still be
+    /// disputed.
+    ChargedBack,
+}
+
+impl TransactionState {
+    /// The name of the state as it is written to the output
+    pub fn name(&self) -> &'static str {
+        match self {
+            TransactionState::Normal => "normal",
+            TransactionState::Disputed => "disputed",
+            TransactionState::Resolved => "resolved",
+            TransactionState::ChargedBack => "charged_back",
+        }
+    }
 }
 
 /// The details stored for every deposit or withdraw transaction
+#[derive(Clone, Copy)]
 struct TransactionDetails {
     kind: TransactionType,
     client: ClientId,
+    /// The amount of the transaction, for a deposit without its fee as only that was credited
     amount: Option<Amount>,
-    is_disputed: bool,
+    state: TransactionState,
+    /// The part of the amount which is held by disputes
+    disputed: Amount,
+    /// The part of the amount which was charged back
+    charged_back: Amount,
+    timestamp: Option<Timestamp>,
+}
+
+impl TransactionDetails {
+    fn stored(&self, tx: TransactionId) -> StoredTransaction {
+        StoredTransaction {
+            tx,
+            client: self.client,
+            kind: self.kind,
+            amount: self.amount,
+            state: self.state,
+            disputed: self.disputed,
+            charged_back: self.charged_back,
+            timestamp: self.timestamp,
+        }
+    }
+}
+
+/// A deposit or withdrawal kept for disputes, as returned by `get_transaction`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct StoredTransaction {
+    pub tx: TransactionId,
+    pub client: ClientId,
+    #[serde(rename = "type")]
+    pub kind: TransactionType,
+    pub amount: Option<Amount>,
+    pub state: TransactionState,
+    /// The part of the amount which is held by disputes
+    pub disputed: Amount,
+    /// The part of the amount which was charged back
+    pub charged_back: Amount,
+    /// When the transaction happened, if the input said so
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+}
+
+/// Which stored transactions `transactions` returns. Every condition which is set must hold.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransactionFilter {
+    pub client: Option<ClientId>,
+    pub kind: Option<TransactionType>,
+    /// Only transactions of which a part is held by disputes
+    pub disputed_only: bool,
+    /// Only transactions with an id within the range
+    pub tx_range: Option<RangeInclusive<TransactionId>>,
+}
+
+impl TransactionFilter {
+    /// Whether the transaction passes the filter
+    pub fn matches(&self, transaction: &StoredTransaction) -> bool {
+        self.client
+            .is_none_or(|client| transaction.client == client)
+            && self.kind.is_none_or(|kind| transaction.kind == kind)
+            && (!self.disputed_only || transaction.disputed > Amount::ZERO)
+            && self
+                .tx_range
+                .as_ref()
+                .is_none_or(|tx_range| tx_range.contains(&transaction.tx))
+    }
 }
 
 /// The transaction engine is the main struct providing a method to process a transaction
@@ -55,76 +519,847 @@ pub struct TransactionEngine {
     // not putting client inside a vec and using a hashmap as searching which would need to be
     // done when processing every tx, would be an O(1)
     // operation while in a simple vec, it would take longer
-    accounts: HashMap<ClientId, AccountDetails>,
-    transactions: HashMap<TransactionId, TransactionDetails>,
+    accounts: FastMap<ClientId, AccountDetails>,
+    transactions: TransactionStore,
+    /// The settings transactions are treated with
+    config: EngineConfig,
+    /// The log every applied transaction is written to, if any
+    wal: Option<Wal>,
+    /// The ids of the deposits and withdrawals applied by earlier runs, if they are kept
+    idempotency: Option<IdempotencyStore>,
+    /// Told about every transaction processed
+    observers: Vec<Box<dyn EngineObserver + Send>>,
+    /// The transactions processed so far, by type and rejection
+    stats: ProcessingStats,
+    /// The ledger the balances of the accounts are derived from
+    ledger: Ledger,
+    /// The entries of the last transaction posted, to take them back if it can't be logged
+    last_posting: Vec<LedgerEntry>,
+    /// Where every balance mutation is recorded, if anywhere
+    audit_sink: Option<Box<dyn AuditSink + Send>>,
+    /// The first error writing to the audit sink, after which nothing more is recorded
+    audit_error: Option<io::Error>,
+    /// Where every state change is recorded as an event, if anywhere
+    event_sink: Option<Box<dyn EventSink + Send>>,
+    /// The first error writing to the event sink, after which nothing more is recorded
+    event_error: Option<io::Error>,
+    /// The fees charged on deposits and withdrawals, if any
+    fee_schedule: Option<FeeSchedule>,
+    /// The fee charged by the last transaction applied
+    last_fee: Amount,
+    /// The interest accrued on the accounts, if interest is paid
+    interest: Option<InterestAccrual>,
+    /// Every dispute opened, open or settled
+    disputes: Disputes,
+    /// The settings of the accounts which don't have the default ones
+    account_configs: HashMap<ClientId, AccountConfig>,
+    /// The limits checked before every transaction is applied
+    rules: Vec<Box<dyn Rule + Send>>,
+    /// The latest timestamp of the input, the time of rows without one
+    latest_timestamp: Timestamp,
+    /// Decides about every transaction before it is applied, if anything does
+    risk_assessor: Option<Box<dyn RiskAssessor + Send>>,
+    /// The transactions held for review, oldest first
+    quarantine: Vec<QuarantinedTransaction>,
+    /// The id of the last transaction quarantined
+    last_quarantine_id: u64,
+    /// The transactions of every client for statements, if history is kept
+    history: History,
+    /// What is needed to roll back the latest transactions, if anything
+    undo: UndoLog,
 }
 
 impl TransactionEngine {
     /// Create a new transaction engine instance
     pub fn new() -> TransactionEngine {
+        TransactionEngine::with_config(EngineConfig::default())
+    }
+
+    /// Create a new transaction engine instance handling zero amounts with the given policy
+    pub fn with_zero_amount_policy(zero_amount_policy: ZeroAmountPolicy) -> TransactionEngine {
+        TransactionEngine::with_config(
+            EngineConfig::default().with_zero_amount_policy(zero_amount_policy),
+        )
+    }
+
+    /// Create a new transaction engine instance treating transactions with the given settings
+    pub fn with_config(config: EngineConfig) -> TransactionEngine {
         TransactionEngine {
-            accounts: HashMap::new(),
-            transactions: HashMap::new(),
-        }
-    }
-
-    /// prints the state of accounts at the time of calling the method.
-    pub fn print_accounts_state(self) -> () {
-        println!("client, available, held, total, locked");
-        for (client_id, client_details) in self.accounts {
-            println!(
-                "{:>6},{:>10.4},{:>5.4},{:>6.4},{:>7}",
-                client_id,
-                client_details.available,
-                client_details.held,
-                client_details.total,
-                client_details.locked
-            );
+            accounts: FastMap::default(),
+            transactions: TransactionStore::new(),
+            config,
+            wal: None,
+            idempotency: None,
+            observers: Vec::new(),
+            stats: ProcessingStats::default(),
+            ledger: Ledger::default(),
+            last_posting: Vec::new(),
+            audit_sink: None,
+            audit_error: None,
+            event_sink: None,
+            event_error: None,
+            fee_schedule: None,
+            last_fee: Amount::ZERO,
+            interest: None,
+            disputes: Disputes::default(),
+            account_configs: HashMap::new(),
+            rules: Vec::new(),
+            latest_timestamp: 0,
+            risk_assessor: None,
+            quarantine: Vec::new(),
+            last_quarantine_id: 0,
+            history: History::default(),
+            undo: UndoLog::default(),
         }
     }
 
+    /// The policy deposits and withdrawals of a zero amount are handled with
+    pub fn zero_amount_policy(&self) -> ZeroAmountPolicy {
+        self.config.zero_amount_policy
+    }
+
+    /// The number of deposits and withdrawals kept for disputes
+    pub fn transaction_count(&self) -> usize {
+        self.transactions.len()
+    }
+
+    /// Changes the policy deposits and withdrawals of a zero amount are handled with, e.g. for
+    /// an engine restored from a snapshot.
+    pub fn set_zero_amount_policy(&mut self, zero_amount_policy: ZeroAmountPolicy) {
+        self.config.zero_amount_policy = zero_amount_policy;
+    }
+
+    /// Which transactions are kept for disputes
+    pub fn retain_policy(&self) -> RetainPolicy {
+        self.config.retain_policy
+    }
+
+    /// Changes which transactions are kept for disputes from now on.
+    pub fn set_retain_policy(&mut self, retain_policy: RetainPolicy) {
+        self.config.retain_policy = retain_policy;
+    }
+
+    /// Keeps at most this many deposits and withdrawals in memory, spilling older ones to a file
+    /// in the temp directory.
+    pub fn set_transaction_cache_size(&mut self, cache_size: usize) -> io::Result<()> {
+        self.transactions.set_cache_size(cache_size)
+    }
+
+    /// Makes room for about this many more deposits and withdrawals, so that the maps holding
+    /// them don't grow step by step on large inputs. Call it after limiting the transactions
+    /// kept in memory, as no more than that are reserved.
+    pub fn reserve(&mut self, transactions: usize) {
+        self.transactions.reserve(transactions);
+    }
+
+    /// Writes every transaction applied from now on to the write-ahead log.
+    pub fn set_wal(&mut self, wal: Wal) {
+        self.wal = Some(wal);
+    }
+
+    /// Rejects deposits and withdrawals whose id is in the store with `AlreadyProcessed`, and
+    /// adds the ids of those applied from now on to it.
+    pub fn set_idempotency_store(&mut self, idempotency: IdempotencyStore) {
+        self.idempotency = Some(idempotency);
+    }
+
+    /// Writes the ids of the deposits and withdrawals applied since the last commit to the
+    /// idempotency store, once the state holding them was saved.
+    pub fn commit_idempotency(&mut self) -> io::Result<()> {
+        match &mut self.idempotency {
+            Some(idempotency) => idempotency.commit(),
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes the write-ahead log to disk according to its fsync policy.
+    pub fn sync_wal(&mut self) -> io::Result<()> {
+        match &mut self.wal {
+            Some(wal) => wal.sync(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the account of the client, if the client has one.
+    pub fn get_account(&self, client: ClientId) -> Option<&AccountDetails> {
+        self.accounts.get(&client)
+    }
+
+    /// Returns the deposit or withdrawal with the id if it is kept for disputes.
+    pub fn get_transaction(&self, tx: TransactionId) -> io::Result<Option<StoredTransaction>> {
+        Ok(self
+            .transactions
+            .get(&tx)?
+            .map(|transaction| transaction.stored(tx)))
+    }
+
+    /// Returns the deposits and withdrawals kept for disputes which pass the filter, sorted by
+    /// id. Transactions spilled to disk are read back, which is what can fail.
+    pub fn transactions(
+        &self,
+        filter: &TransactionFilter,
+    ) -> io::Result<impl Iterator<Item = StoredTransaction>> {
+        let mut transactions: Vec<StoredTransaction> = self
+            .transactions
+            .entries()?
+            .into_iter()
+            .map(|(tx, transaction)| transaction.stored(tx))
+            .filter(|transaction| filter.matches(transaction))
+            .collect();
+        transactions.sort_unstable_by_key(|transaction| transaction.tx);
+        Ok(transactions.into_iter())
+    }
+
+    /// Returns an iterator over all accounts, in no particular order.
+    pub fn accounts(&self) -> impl Iterator<Item = (ClientId, &AccountDetails)> {
+        self.accounts
+            .iter()
+            .map(|(client_id, account)| (*client_id, account))
+    }
+
+    /// Returns all accounts sorted by client id.
+    pub fn sorted_accounts(&self) -> Vec<(ClientId, &AccountDetails)> {
+        let mut accounts: Vec<_> = self.accounts().collect();
+        accounts.sort_unstable_by_key(|(client_id, _)| *client_id);
+        accounts
+    }
+
+    /// A SHA-256 hash of the state of all accounts as lowercase hex, to check that two runs
+    /// ended with the same accounts. See `state_hash`.
+    pub fn state_hash(&self) -> String {
+        state_hash(&self.sorted_accounts())
+    }
+
+    /// The number of accounts
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+    }
+
+    /// Whether there are no accounts
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty()
+    }
+
+    /// writes the state of accounts at the time of calling the method to the writer as CSV.
+    pub fn write_accounts_state<W: io::Write>(&self, writer: W) -> csv::Result<()> {
+        output::write_accounts_csv(writer, self.accounts())
+    }
+
+    /// writes the state of accounts like `write_accounts_state`, but sorted by client id so that
+    /// the output is the same from run to run.
+    pub fn write_sorted_accounts_state<W: io::Write>(&self, writer: W) -> csv::Result<()> {
+        output::write_accounts_csv(writer, self.sorted_accounts())
+    }
+
+    /// writes the state of accounts at the time of calling the method to the writer in the
+    /// format, e.g. as a JSON array with `OutputFormat::Json` or as a JSON object per line with
+    /// `OutputFormat::JsonLines`.
+    pub fn write_accounts_state_json<W: io::Write>(
+        &self,
+        writer: W,
+        format: OutputFormat,
+    ) -> Result<(), OutputError> {
+        output::write_accounts(writer, self.accounts(), format)
+    }
+
+    /// Processes every transaction of the iterator in order, returning the result of each. A
+    /// failing transaction doesn't stop the ones after it.
+    pub fn process_transactions<I: IntoIterator<Item = TransactionInput>>(
+        &mut self,
+        transactions: I,
+    ) -> Vec<Result<(), TransactionProcessingError>> {
+        transactions
+            .into_iter()
+            .map(|transaction| self.process_transaction(transaction))
+            .collect()
+    }
+
     /// Takes transaction of different kinds, processes it while updating the state of accounts or transactions
     /// if required.
     pub fn process_transaction(
         &mut self,
         transaction: TransactionInput,
     ) -> Result<(), TransactionProcessingError> {
-        let previous_account_data = self.accounts.get(&transaction.client);
-        // if the account is locked, no transaction is allowed on it
-        if let Some(a) = previous_account_data {
-            if a.locked {
-                return Err(TransactionProcessingError::AccountLocked.into());
+        for observer in &mut self.observers {
+            observer.on_transaction_start(&transaction);
+        }
+        if let Some(timestamp) = transaction.timestamp {
+            if let Err(e) = self.advance_clock(timestamp) {
+                self.stats
+                    .count_transaction(transaction.kind, Some(e.kind()));
+                for observer in &mut self.observers {
+                    observer.on_rejected(&transaction, &e);
+                }
+                return Err(e);
             }
+            self.latest_timestamp = self.latest_timestamp.max(timestamp);
         }
+        let was_locked = self
+            .accounts
+            .get(&transaction.client)
+            .is_some_and(AccountDetails::is_locked);
+        let before = match self.audit_sink {
+            Some(_) => Some(
+                self.accounts
+                    .get(&transaction.client)
+                    .cloned()
+                    .unwrap_or_default(),
+            ),
+            None => None,
+        };
+        // fees change the collection account too, which gets its own audit record
+        let collection_before = match (&self.audit_sink, &self.fee_schedule) {
+            (Some(_), Some(fee_schedule))
+                if fee_schedule.collection_account != transaction.client =>
+            {
+                let collection_account = fee_schedule.collection_account;
+                let account = self.accounts.get(&collection_account).cloned();
+                Some((collection_account, account.unwrap_or_default()))
+            }
+            _ => None,
+        };
+        self.last_posting.clear();
+        let undo_point = self.undo_point(&transaction);
+        let result = self.process_and_log_transaction(transaction);
+        if result.is_ok() {
+            let now = transaction.timestamp.unwrap_or(self.latest_timestamp);
+            for rule in &mut self.rules {
+                rule.applied(&transaction, now);
+            }
+            if let Some(risk_assessor) = &mut self.risk_assessor {
+                risk_assessor.applied(&transaction, now);
+            }
+        }
+        // with verify_invariants they were checked before the transaction was logged
+        if let (Ok(()), Some(account), false) = (
+            &result,
+            self.accounts.get(&transaction.client),
+            self.config.verify_invariants,
+        ) {
+            let invariants =
+                account.check_invariants(transaction.kind, self.credit_limit(transaction.client));
+            debug_assert!(
+                invariants.is_ok(),
+                "transaction {} broke an invariant: {:?}",
+                transaction.tx,
+                invariants
+            );
+        }
+        self.stats.count_transaction(
+            transaction.kind,
+            result.as_ref().err().map(TransactionProcessingError::kind),
+        );
+        match &result {
+            Ok(()) => {
+                self.notify_applied(&transaction, was_locked, before, collection_before);
+                self.record_events(&transaction, was_locked);
+                if let Some(idempotency) = &mut self.idempotency {
+                    let posted = !self.last_posting.is_empty();
+                    if posted && transaction.amount.is_some() {
+                        idempotency.insert(transaction.tx);
+                    }
+                }
+                self.record_undo(undo_point);
+            }
+            Err(e) => {
+                debug!(
+                    tx = transaction.tx,
+                    client = transaction.client,
+                    kind = transaction.kind.name(),
+                    error = %e,
+                    "transaction rejected"
+                );
+                for observer in &mut self.observers {
+                    observer.on_rejected(&transaction, e);
+                }
+            }
+        }
+        result
+    }
 
-        match transaction.kind {
-            TransactionType::Deposit => {
-                if let Some(amount) = transaction.amount {
-                    return self.process_deposit_transaction(
-                        transaction.tx,
-                        transaction.client,
-                        amount,
-                    );
-                } else {
-                    return Err(TransactionProcessingError::AmountValueNotFound.into());
+    /// Tells the audit sink and the observers about an applied transaction. `was_locked` is
+    /// whether the account of its client was locked before it, `before` is that account before
+    /// it, if there is an audit sink, and `collection_before` the collection account of fees if
+    /// it isn't the same account.
+    fn notify_applied(
+        &mut self,
+        transaction: &TransactionInput,
+        was_locked: bool,
+        before: Option<AccountDetails>,
+        collection_before: Option<(ClientId, AccountDetails)>,
+    ) {
+        if let Some(account) = self.accounts.get(&transaction.client) {
+            debug!(
+                tx = transaction.tx,
+                client = transaction.client,
+                kind = transaction.kind.name(),
+                available = %account.available,
+                held = %account.held,
+                total = %account.total,
+                status = account.status.name(),
+                "transaction applied"
+            );
+            // interest isn't a transaction of the input, so it takes no sequence number and its
+            // statement line has the one of the transaction before it
+            if transaction.kind != TransactionType::Interest {
+                self.history.next_sequence();
+            }
+            self.history
+                .record(transaction.client, transaction, self.last_fee, account);
+            // the fee shows up on the statement of the collection account too
+            if let Some(fee_schedule) = &self.fee_schedule {
+                let client = fee_schedule.collection_account;
+                match self.accounts.get(&client) {
+                    Some(collection_account)
+                        if client != transaction.client && self.last_fee != Amount::ZERO =>
+                    {
+                        self.history
+                            .record(client, transaction, self.last_fee, collection_account);
+                    }
+                    _ => (),
+                }
+            }
+            if let Some(before) = before.filter(|before| before != account) {
+                let record = AuditRecord {
+                    tx: transaction.tx,
+                    client: transaction.client,
+                    kind: transaction.kind,
+                    fee: self.last_fee,
+                    timestamp: transaction.timestamp,
+                    before: &before,
+                    after: account,
                 };
+                record_audit(&mut self.audit_sink, &mut self.audit_error, &record);
+            }
+            if let Some((client, before)) = collection_before {
+                if let Some(after) = self.accounts.get(&client).filter(|a| **a != before) {
+                    let record = AuditRecord {
+                        tx: transaction.tx,
+                        client,
+                        kind: transaction.kind,
+                        fee: self.last_fee,
+                        timestamp: transaction.timestamp,
+                        before: &before,
+                        after,
+                    };
+                    record_audit(&mut self.audit_sink, &mut self.audit_error, &record);
+                }
+            }
+            for observer in &mut self.observers {
+                observer.on_applied(transaction, account);
+                if account.is_locked() && !was_locked {
+                    observer.on_account_locked(transaction, account);
+                }
+            }
+        }
+    }
+
+    /// What the engine processed since it was created, with the current state of its accounts
+    pub fn stats(&self) -> ProcessingStats {
+        let mut stats = self.stats.clone();
+        stats.set_accounts(self.accounts.values());
+        stats
+    }
+
+    /// Counts what an earlier run processed before this engine took over its state, e.g. up to
+    /// the checkpoint a run is resumed from.
+    pub(crate) fn add_stats(&mut self, stats: &ProcessingStats) {
+        self.stats.merge(stats);
+    }
+
+    /// The ledger the balances of the accounts are derived from
+    pub fn ledger(&self) -> &Ledger {
+        &self.ledger
+    }
+
+    /// Checks that the ledger balances and that every account matches its ledger balances.
+    /// A failure means a bug in how transactions are posted.
+    pub fn check_ledger(&self) -> Result<(), LedgerError> {
+        self.ledger.verify()?;
+        for (client, account) in &self.accounts {
+            let available = self.ledger.balance(LedgerAccount::ClientAvailable(*client));
+            let held = self.ledger.balance(LedgerAccount::ClientHeld(*client));
+            if account.available != available
+                || account.held != held
+                || account.total != available + held
+            {
+                return Err(LedgerError::AccountMismatch(*client));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the invariants of the account after every applied transaction, returning
+    /// `InvariantViolated` for a transaction which broke them. The transaction is put back
+    /// before it is written to the write-ahead log, but as it points to a bug in the engine,
+    /// processing should stop there. Without this, debug builds assert the invariants.
+    pub fn set_verify_invariants(&mut self, verify_invariants: bool) {
+        self.config.verify_invariants = verify_invariants;
+    }
+
+    /// Whether administrative operations like `unlock` are applied
+    pub fn allow_admin_ops(&self) -> bool {
+        self.config.allow_admin_ops
+    }
+
+    /// Applies administrative operations like `unlock` from now on. Without this, they are
+    /// rejected with `AdminOperationsNotAllowed`.
+    pub fn set_allow_admin_ops(&mut self, allow_admin_ops: bool) {
+        self.config.allow_admin_ops = allow_admin_ops;
+    }
+
+    /// Which transactions are still applied to locked accounts
+    pub fn locked_account_policy(&self) -> LockedAccountPolicy {
+        self.config.locked_account_policy
+    }
+
+    /// Changes which transactions are still applied to locked accounts from now on.
+    pub fn set_locked_account_policy(&mut self, locked_account_policy: LockedAccountPolicy) {
+        self.config.locked_account_policy = locked_account_policy;
+    }
+
+    /// The seconds after a transaction within which it can be disputed, if they are limited
+    pub fn dispute_window(&self) -> Option<u64> {
+        self.config.dispute_window
+    }
+
+    /// Rejects disputes filed more than this many seconds after their transaction with
+    /// `DisputeWindowExpired`, or allows them any time with None. Only disputes and
+    /// transactions with a timestamp are checked.
+    pub fn set_dispute_window(&mut self, dispute_window: Option<u64>) {
+        self.config.dispute_window = dispute_window;
+    }
+
+    /// The settings of the client's account
+    pub fn account_config(&self, client: ClientId) -> AccountConfig {
+        self.account_configs
+            .get(&client)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Changes the settings of the client's account from now on, whether or not it exists yet.
+    pub fn set_account_config(&mut self, client: ClientId, account_config: AccountConfig) {
+        self.account_configs.insert(client, account_config);
+    }
+
+    /// How far withdrawals may take the available funds of the client below zero
+    fn credit_limit(&self, client: ClientId) -> Amount {
+        self.account_config(client).credit_limit
+    }
+
+    /// The fees charged on deposits and withdrawals
+    pub fn fee_schedule(&self) -> Option<&FeeSchedule> {
+        self.fee_schedule.as_ref()
+    }
+
+    /// Charges the fees of the schedule on every deposit and withdrawal from now on, or no fees
+    /// with None.
+    pub fn set_fee_schedule(&mut self, fee_schedule: Option<FeeSchedule>) {
+        self.fee_schedule = fee_schedule;
+    }
+
+    /// Records every balance mutation from now on in the sink. If the sink fails, nothing more
+    /// is recorded and the error is returned by `flush_audit`.
+    pub fn set_audit_sink(&mut self, audit_sink: impl AuditSink + Send + 'static) {
+        self.audit_sink = Some(Box::new(audit_sink));
+    }
+
+    /// Flushes the audit sink, returning the error it failed with if it did.
+    pub fn flush_audit(&mut self) -> io::Result<()> {
+        if let Some(e) = self.audit_error.take() {
+            return Err(e);
+        }
+        match &mut self.audit_sink {
+            Some(audit_sink) => audit_sink.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Adds a rule which every transaction from now on is checked against before it is
+    /// applied. A transaction breaking it is rejected with `RuleViolated`.
+    pub fn add_rule(&mut self, rule: Box<dyn Rule + Send>) {
+        self.rules.push(rule);
+    }
+
+    /// Removes all rules, e.g. before adding the rules of a reloaded configuration.
+    pub fn clear_rules(&mut self) {
+        self.rules.clear();
+    }
+
+    /// Assesses the risk of every transaction from now on with the assessor, or of none with
+    /// None. Transactions it holds for review are rejected with `Quarantined` and kept in the
+    /// quarantine, those it denies are rejected with `RiskDenied`.
+    pub fn set_risk_assessor(&mut self, risk_assessor: Option<Box<dyn RiskAssessor + Send>>) {
+        self.risk_assessor = risk_assessor;
+    }
+
+    /// The transactions held for review, oldest first
+    pub fn quarantine(&self) -> impl Iterator<Item = &QuarantinedTransaction> {
+        self.quarantine.iter()
+    }
+
+    /// Takes the transaction with the id out of the quarantine, applying it if it was approved.
+    pub fn release_quarantined(
+        &mut self,
+        id: u64,
+        decision: ReviewDecision,
+    ) -> Result<(), TransactionProcessingError> {
+        let Some(index) = self
+            .quarantine
+            .iter()
+            .position(|quarantined| quarantined.id == id)
+        else {
+            return Err(TransactionProcessingError::TransactionNotFound);
+        };
+        let quarantined = self.quarantine.remove(index);
+        match decision {
+            ReviewDecision::Approve => self.process_approved_transaction(quarantined.transaction),
+            ReviewDecision::Reject => Ok(()),
+        }
+    }
+
+    /// Processes a transaction somebody approved without assessing its risk, e.g. one which
+    /// was held for review by an earlier run.
+    pub fn process_approved_transaction(
+        &mut self,
+        transaction: TransactionInput,
+    ) -> Result<(), TransactionProcessingError> {
+        let risk_assessor = self.risk_assessor.take();
+        let result = self.process_transaction(transaction);
+        self.risk_assessor = risk_assessor;
+        if let (Ok(()), Some(risk_assessor)) = (&result, &mut self.risk_assessor) {
+            risk_assessor.applied(
+                &transaction,
+                transaction.timestamp.unwrap_or(self.latest_timestamp),
+            );
+        }
+        result
+    }
+
+    /// Adds an observer which is told about every transaction processed from now on.
+    pub fn add_observer(&mut self, observer: impl EngineObserver + Send + 'static) {
+        self.observers.push(Box::new(observer));
+    }
+
+    /// Flushes whatever the observers buffered, e.g. at the end of the input.
+    pub fn flush_observers(&mut self) -> io::Result<()> {
+        self.observers
+            .iter_mut()
+            .try_for_each(|observer| observer.flush())
+    }
+
+    /// Applies the transaction and writes it to the write-ahead log, if there is one. With
+    /// `verify_invariants` the account is checked first, and a transaction which broke an
+    /// invariant is put back like one which couldn't be logged.
+    fn process_and_log_transaction(
+        &mut self,
+        transaction: TransactionInput,
+    ) -> Result<(), TransactionProcessingError> {
+        if self.wal.is_none() && !self.config.verify_invariants {
+            return self.apply_transaction(transaction);
+        }
+        // a transaction only touches the accounts of its client and of fees with their interest
+        // accrual, the transaction it references with its disputes and its ledger entries, so
+        // these are all that needs to be put back if it breaks an invariant or the log can't be
+        // written
+        let account = self.accounts.get(&transaction.client).cloned();
+        let collection_account = self.fee_schedule.map(|fee_schedule| {
+            let client = fee_schedule.collection_account;
+            (client, self.accounts.get(&client).cloned())
+        });
+        let accruals: Vec<_> = match &self.interest {
+            Some(interest) => collection_account
+                .iter()
+                .map(|(client, _)| *client)
+                .chain([transaction.client])
+                .map(|client| (client, interest.accrual(client)))
+                .collect(),
+            None => Vec::new(),
+        };
+        let stored_transaction = self.transactions.get(&transaction.tx)?;
+        let disputes = match transaction.kind {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                Some(self.disputes.undo_point(transaction.tx))
             }
-            TransactionType::Withdrawal => {
-                if let Some(amount) = transaction.amount {
-                    return self.process_withdrawal_transaction(
-                        transaction.tx,
-                        transaction.client,
-                        amount,
-                    );
-                } else {
-                    return Err(TransactionProcessingError::AmountValueNotFound.into());
+            _ => None,
+        };
+        self.last_posting.clear();
+        self.apply_transaction(transaction)?;
+        let mut failure = None;
+        if self.config.verify_invariants {
+            if let Some(account) = self.accounts.get(&transaction.client) {
+                let credit_limit = self.credit_limit(transaction.client);
+                if let Err(reason) = account.check_invariants(transaction.kind, credit_limit) {
+                    failure = Some(TransactionProcessingError::InvariantViolated {
+                        tx: transaction.tx,
+                        client: transaction.client,
+                        reason,
+                    });
+                }
+            }
+        }
+        if let (None, Some(wal)) = (&failure, &mut self.wal) {
+            if let Err(e) = wal.append(&transaction) {
+                failure = Some(TransactionProcessingError::WalWriteFailed(e.to_string()));
+            }
+        }
+        if let Some(failure) = failure {
+            let posting = std::mem::take(&mut self.last_posting);
+            if !posting.is_empty() {
+                self.ledger.unpost(&posting);
+            }
+            for (client, account) in collection_account
+                .into_iter()
+                .chain([(transaction.client, account)])
+            {
+                match account {
+                    Some(account) => self.accounts.insert(client, account),
+                    None => self.accounts.remove(&client),
                 };
             }
-            TransactionType::Dispute => return self.process_dispute_transaction(transaction.tx),
-            TransactionType::Resolve => return self.process_resolve_transaction(transaction.tx),
-            TransactionType::Chargeback => {
-                return self.process_chargeback_transaction(transaction.tx)
+            if let Some(interest) = &mut self.interest {
+                for (client, accrual) in accruals {
+                    interest.restore_accrual(client, accrual);
+                }
+            }
+            if let Some(disputes) = disputes {
+                self.disputes.undo(disputes);
             }
+            match stored_transaction {
+                Some(stored_transaction) => self
+                    .transactions
+                    .insert(transaction.tx, stored_transaction)?,
+                None => self.transactions.remove(&transaction.tx)?,
+            };
+            return Err(failure);
+        }
+        Ok(())
+    }
+
+    fn apply_transaction(
+        &mut self,
+        transaction: TransactionInput,
+    ) -> Result<(), TransactionProcessingError> {
+        self.last_fee = Amount::ZERO;
+        // the status of the account decides which transactions are allowed on it, e.g. nothing
+        // but an unlock once it is locked
+        if let Some(account) = self.accounts.get(&transaction.client) {
+            account
+                .status
+                .check_allowed(transaction.kind, self.config.locked_account_policy)?;
+        }
+        let now = transaction.timestamp.unwrap_or(self.latest_timestamp);
+        for rule in &self.rules {
+            rule.check(&transaction, now)
+                .map_err(TransactionProcessingError::RuleViolated)?;
+        }
+        if let Some(risk_assessor) = &self.risk_assessor {
+            match risk_assessor.assess(&transaction, now) {
+                RiskDecision::Allow => (),
+                RiskDecision::Review(reason) => {
+                    self.last_quarantine_id += 1;
+                    self.quarantine.push(QuarantinedTransaction {
+                        id: self.last_quarantine_id,
+                        transaction,
+                        reason: reason.clone(),
+                    });
+                    return Err(TransactionProcessingError::Quarantined(reason));
+                }
+                RiskDecision::Deny(reason) => {
+                    return Err(TransactionProcessingError::RiskDenied(reason))
+                }
+            }
+        }
+
+        match transaction.kind {
+            TransactionType::Deposit => match transaction.amount {
+                Some(amount) if self.is_skipped_amount(amount)? => Ok(()),
+                Some(_) if self.is_skipped_duplicate(transaction.tx)? => Ok(()),
+                Some(amount) => self.process_deposit_transaction(
+                    transaction.tx,
+                    transaction.client,
+                    amount,
+                    transaction.timestamp,
+                ),
+                None => Err(TransactionProcessingError::AmountValueNotFound),
+            },
+            TransactionType::Withdrawal => match transaction.amount {
+                Some(amount) if self.is_skipped_amount(amount)? => Ok(()),
+                Some(_) if self.is_skipped_duplicate(transaction.tx)? => Ok(()),
+                Some(amount) => self.process_withdrawal_transaction(
+                    transaction.tx,
+                    transaction.client,
+                    amount,
+                    transaction.timestamp,
+                ),
+                None => Err(TransactionProcessingError::AmountValueNotFound),
+            },
+            TransactionType::Dispute => self.process_dispute_transaction(
+                transaction.tx,
+                transaction.client,
+                transaction.amount,
+                transaction.timestamp,
+            ),
+            TransactionType::Resolve => self.process_resolve_transaction(
+                transaction.tx,
+                transaction.client,
+                transaction.amount,
+            ),
+            TransactionType::Chargeback => self.process_chargeback_transaction(
+                transaction.tx,
+                transaction.client,
+                transaction.amount,
+            ),
+            TransactionType::Interest => Err(TransactionProcessingError::InterestNotAllowed),
+            TransactionType::Open
+            | TransactionType::Close
+            | TransactionType::Freeze
+            | TransactionType::Unfreeze
+            | TransactionType::Unlock => self.process_status_change(
+                transaction.tx,
+                transaction.client,
+                transaction.kind,
+                transaction.timestamp,
+            ),
+        }
+    }
+
+    /// Validates the amount of a deposit or withdrawal, returning whether the transaction must be
+    /// skipped because of its zero amount.
+    fn is_skipped_amount(&self, amount: Amount) -> Result<bool, TransactionProcessingError> {
+        if amount.is_negative() {
+            return Err(TransactionProcessingError::InvalidAmount(amount));
+        }
+        if amount == Amount::ZERO {
+            return match self.config.zero_amount_policy {
+                ZeroAmountPolicy::Skip => Ok(true),
+                ZeroAmountPolicy::Reject => Err(TransactionProcessingError::InvalidAmount(amount)),
+            };
+        }
+        Ok(false)
+    }
+
+    /// Checks the id of a deposit or withdrawal against the transactions kept, returning whether
+    /// the transaction must be skipped because its id is taken.
+    fn is_skipped_duplicate(
+        &self,
+        transaction_id: TransactionId,
+    ) -> Result<bool, TransactionProcessingError> {
+        let error = if self.transactions.contains_key(&transaction_id)? {
+            TransactionProcessingError::DuplicateTransactionId
+        } else if self
+            .idempotency
+            .as_ref()
+            .is_some_and(|idempotency| idempotency.contains(transaction_id))
+        {
+            TransactionProcessingError::AlreadyProcessed
+        } else {
+            return Ok(false);
+        };
+        match self.config.duplicate_id_policy {
+            DuplicateIdPolicy::Ignore => Ok(true),
+            DuplicateIdPolicy::Reject => Err(error),
         }
     }
 
@@ -132,32 +1367,36 @@ impl TransactionEngine {
     fn process_deposit_transaction(
         &mut self,
         transaction_id: TransactionId,
-        client_id: u16,
-        amount: f32,
+        client_id: ClientId,
+        amount: Amount,
+        timestamp: Option<Timestamp>,
     ) -> Result<(), TransactionProcessingError> {
-        let previous_account_data = self.accounts.entry(client_id).or_insert(AccountDetails {
-            available: 0.0,
-            total: 0.0,
-            held: 0.0,
-            locked: false,
-        });
-
-        *previous_account_data = AccountDetails {
-            available: previous_account_data.available + amount,
-            total: previous_account_data.total + amount,
-            held: previous_account_data.held,
-            locked: previous_account_data.locked,
-        };
+        let fee = self.fee(TransactionType::Deposit, amount);
+        if fee > amount {
+            return Err(TransactionProcessingError::FeeExceedsAmount(fee));
+        }
+        let credited = amount - fee;
+        let mut entries = vec![
+            LedgerEntry::new(LedgerAccount::ClientAvailable(client_id), credited),
+            LedgerEntry::new(LedgerAccount::BankClearing, -amount),
+        ];
+        self.collect_fee(&mut entries, fee)?;
+        check_posting(&self.ledger, &entries)?;
+        // a dispute holds what was credited, the fee isn't refunded
         self.transactions.insert(
             transaction_id,
             TransactionDetails {
                 kind: TransactionType::Deposit,
                 client: client_id,
-                amount: Some(amount),
-                is_disputed: false,
+                amount: Some(credited),
+                state: TransactionState::Normal,
+                disputed: Amount::ZERO,
+                charged_back: Amount::ZERO,
+                timestamp,
             },
-        );
-        return Ok(());
+        )?;
+        self.post(client_id, entries);
+        Ok(())
     }
 
     /// An internal function to process a withdrawal transaction.
@@ -165,203 +1404,464 @@ impl TransactionEngine {
         &mut self,
         transaction_id: TransactionId,
         client_id: ClientId,
-        amount: f32,
+        amount: Amount,
+        timestamp: Option<Timestamp>,
     ) -> Result<(), TransactionProcessingError> {
-        let previous_account_data = self.accounts.get_mut(&client_id);
-        match previous_account_data {
-            Some(account) => {
-                if account.available > amount {
-                    *account = AccountDetails {
-                        available: account.available - amount,
-                        total: account.total - amount,
-                        held: account.held,
-                        locked: account.locked,
-                    };
-                    self.transactions.insert(
-                        transaction_id,
-                        TransactionDetails {
-                            kind: TransactionType::Withdrawal,
-                            client: client_id,
-                            amount: Some(amount),
-                            is_disputed: false,
-                        },
-                    );
-                    return Ok(());
-                } else {
-                    return Err(TransactionProcessingError::InsufficientFunds);
-                }
-            }
-            None => return Err(TransactionProcessingError::AccountNotFound),
+        let Some(account) = self.accounts.get(&client_id) else {
+            return Err(TransactionProcessingError::AccountNotFound);
+        };
+        let fee = self.fee(TransactionType::Withdrawal, amount);
+        let Some(taken) = amount.checked_add(fee) else {
+            return Err(TransactionProcessingError::BalanceOverflow);
+        };
+        // without credit, the available funds must be more than what is taken
+        let credit_limit = self.credit_limit(client_id);
+        let insufficient = if credit_limit > Amount::ZERO {
+            account
+                .available
+                .checked_add(credit_limit)
+                .is_some_and(|funds| funds < taken)
+        } else {
+            account.available <= taken
+        };
+        if insufficient {
+            return Err(TransactionProcessingError::InsufficientFunds);
         }
+        let mut entries = vec![
+            LedgerEntry::new(LedgerAccount::ClientAvailable(client_id), -taken),
+            LedgerEntry::new(LedgerAccount::BankClearing, amount),
+        ];
+        self.collect_fee(&mut entries, fee)?;
+        check_posting(&self.ledger, &entries)?;
+        if self.config.retain_policy == RetainPolicy::All {
+            self.transactions.insert(
+                transaction_id,
+                TransactionDetails {
+                    kind: TransactionType::Withdrawal,
+                    client: client_id,
+                    amount: Some(amount),
+                    state: TransactionState::Normal,
+                    disputed: Amount::ZERO,
+                    charged_back: Amount::ZERO,
+                    timestamp,
+                },
+            )?;
+        }
+        self.post(client_id, entries);
+        Ok(())
     }
 
-    /// An internal function to process a dispute transaction.
+    /// An internal function to process a dispute transaction, holding the given part of the
+    /// transaction or all of it which isn't disputed or charged back yet. With a dispute window,
+    /// a dispute filed after it closed is rejected if both the dispute and the transaction have
+    /// a timestamp.
     fn process_dispute_transaction(
         &mut self,
         transaction_id: TransactionId,
+        client_id: ClientId,
+        requested: Option<Amount>,
+        timestamp: Option<Timestamp>,
     ) -> Result<(), TransactionProcessingError> {
-        let existing_transaction_details = self.transactions.get_mut(&transaction_id);
-        match existing_transaction_details {
-            Some(t) => {
-                if t.is_disputed {
-                    return Err(
-                        TransactionProcessingError::CannotDisputeAnAlreadyDisputedTransaction
-                            .into(),
-                    );
-                }
-
-                match t.amount {
-                    Some(amount) => {
-                        let account_details = self.accounts.get_mut(&t.client);
-                        match account_details {
-                            Some(a) => {
-                                *a = AccountDetails {
-                                    available: a.available - amount,
-                                    total: a.total,
-                                    held: a.held + amount,
-                                    locked: a.locked,
-                                };
-
-                                *t = TransactionDetails {
-                                    kind: t.kind,
-                                    client: t.client,
-                                    amount: t.amount,
-                                    is_disputed: true,
-                                };
-                                Ok(())
-                            }
-                            None => return Err(TransactionProcessingError::AccountNotFound),
-                        }
-                    }
-                    None => {
-                        return Err(
-                            TransactionProcessingError::AmountNotFoundOnTransactionToDispute.into(),
-                        );
-                    }
-                }
+        check_dispute_amount(requested)?;
+        let dispute_window = self.config.dispute_window;
+        let (t, amount) = disputed_transaction(
+            &mut self.transactions,
+            &self.accounts,
+            transaction_id,
+            client_id,
+        )?;
+        if let (Some(window), Some(filed), Some(happened)) =
+            (dispute_window, timestamp, t.timestamp)
+        {
+            if filed > happened.saturating_add(window) {
+                return Err(TransactionProcessingError::DisputeWindowExpired);
             }
-            None => {
-                return Err(TransactionProcessingError::TransactionNotFound.into());
+        }
+        let disputable = amount - t.disputed - t.charged_back;
+        if disputable == Amount::ZERO {
+            return Err(if t.disputed > Amount::ZERO {
+                TransactionProcessingError::CannotDisputeAnAlreadyDisputedTransaction
+            } else {
+                TransactionProcessingError::CannotDisputeAChargedBackTransaction
+            });
+        }
+        let held = requested.unwrap_or(disputable);
+        if held > disputable {
+            return Err(TransactionProcessingError::DisputeAmountTooLarge(held));
+        }
+        let entries = hold_entries(t.kind, client_id, held);
+        check_posting(&self.ledger, &entries)?;
+        t.disputed += held;
+        t.state = TransactionState::Disputed;
+        self.disputes
+            .open(transaction_id, client_id, held, timestamp);
+        self.post(client_id, entries);
+        Ok(())
+    }
+
+    /// An internal function to process a resolve transaction, releasing the given part of the
+    /// disputed amount or all of it.
+    fn process_resolve_transaction(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        requested: Option<Amount>,
+    ) -> Result<(), TransactionProcessingError> {
+        check_dispute_amount(requested)?;
+        let (t, _) = disputed_transaction(
+            &mut self.transactions,
+            &self.accounts,
+            transaction_id,
+            client_id,
+        )?;
+        let released = settled_amount(t, requested)?;
+        let entries = release_entries(t.kind, client_id, released);
+        check_posting(&self.ledger, &entries)?;
+        t.disputed -= released;
+        if t.disputed == Amount::ZERO {
+            t.state = TransactionState::Resolved;
+        }
+        self.disputes.settle(transaction_id, released, false);
+        self.post(client_id, entries);
+        Ok(())
+    }
+
+    /// An internal function to process a charge back transaction, reversing the given part of
+    /// the disputed amount or all of it.
+    fn process_chargeback_transaction(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        requested: Option<Amount>,
+    ) -> Result<(), TransactionProcessingError> {
+        check_dispute_amount(requested)?;
+        let (t, _) = disputed_transaction(
+            &mut self.transactions,
+            &self.accounts,
+            transaction_id,
+            client_id,
+        )?;
+        let reversed = settled_amount(t, requested)?;
+        let entries = reversal_entries(t.kind, client_id, reversed);
+        check_posting(&self.ledger, &entries)?;
+        t.disputed -= reversed;
+        t.charged_back += reversed;
+        if t.disputed == Amount::ZERO {
+            t.state = TransactionState::ChargedBack;
+        }
+        self.disputes.settle(transaction_id, reversed, true);
+        self.post(client_id, entries);
+        if let Some(account) = self.accounts.get_mut(&client_id) {
+            account.status = AccountStatus::Locked;
+        }
+        Ok(())
+    }
+
+    /// An internal function to process a lifecycle transaction, which moves the account to
+    /// another status. The status it comes from was checked by `check_allowed` already. It is
+    /// kept with the other transactions, so its id can't be reused and it shows up in the
+    /// history of the engine.
+    fn process_status_change(
+        &mut self,
+        transaction_id: TransactionId,
+        client_id: ClientId,
+        kind: TransactionType,
+        timestamp: Option<Timestamp>,
+    ) -> Result<(), TransactionProcessingError> {
+        // every change of the lifecycle status is an admin operation
+        if !self.config.allow_admin_ops {
+            return Err(TransactionProcessingError::AdminOperationsNotAllowed);
+        }
+        if self.transactions.contains_key(&transaction_id)? {
+            return Err(TransactionProcessingError::DuplicateTransactionId);
+        }
+        let status = match (kind, self.accounts.get(&client_id)) {
+            (TransactionType::Open, _) => AccountStatus::Active,
+            (_, None) => return Err(TransactionProcessingError::AccountNotFound),
+            (TransactionType::Close, Some(account))
+                if account.total != Amount::ZERO || account.held != Amount::ZERO =>
+            {
+                return Err(TransactionProcessingError::AccountNotEmpty)
             }
+            (TransactionType::Close, Some(_)) => AccountStatus::Closed,
+            (TransactionType::Freeze, Some(_)) => AccountStatus::Frozen,
+            (_, Some(_)) => AccountStatus::Active,
+        };
+        self.transactions.insert(
+            transaction_id,
+            TransactionDetails {
+                kind,
+                client: client_id,
+                amount: None,
+                state: TransactionState::Normal,
+                disputed: Amount::ZERO,
+                charged_back: Amount::ZERO,
+                timestamp,
+            },
+        )?;
+        self.accounts.entry(client_id).or_default().status = status;
+        Ok(())
+    }
+
+    /// The fee charged on a transaction of the kind and amount
+    fn fee(&self, kind: TransactionType, amount: Amount) -> Amount {
+        match &self.fee_schedule {
+            Some(fee_schedule) => fee_schedule.fee(kind, amount),
+            None => Amount::ZERO,
         }
     }
 
-    /// An internal function to process a resolve transaction.
-    fn process_resolve_transaction(
+    /// Adds the entry crediting the fee to the collection account, if there is a fee. Fails
+    /// if the collection account is locked or closed, as nothing may be credited to it then.
+    fn collect_fee(
         &mut self,
-        transaction_id: TransactionId,
+        entries: &mut Vec<LedgerEntry>,
+        fee: Amount,
     ) -> Result<(), TransactionProcessingError> {
-        let existing_transaction_details = self.transactions.get_mut(&transaction_id);
-        match existing_transaction_details {
-            Some(t) => {
-                if t.is_disputed {
-                    match t.amount {
-                        Some(amount) => {
-                            let account_details = self.accounts.get_mut(&t.client);
-                            match account_details {
-                                Some(a) => {
-                                    *a = AccountDetails {
-                                        available: a.available + amount,
-                                        total: a.total,
-                                        held: a.held - amount,
-                                        locked: a.locked,
-                                    };
-
-                                    *t = TransactionDetails {
-                                        kind: t.kind,
-                                        client: t.client,
-                                        amount: t.amount,
-                                        is_disputed: false,
-                                    };
-                                    Ok(())
-                                }
-                                None => return Err(TransactionProcessingError::AccountNotFound),
-                            }
-                        }
-                        None => {
-                            return Err(
-                                TransactionProcessingError::AmountNotFoundOnTransactionToDispute
-                                    .into(),
-                            );
-                        }
-                    }
-                } else {
-                    return Err(
-                        TransactionProcessingError::CannotResolveNonDisputedTransaction.into(),
-                    );
-                }
-            }
-            None => {
-                return Err(TransactionProcessingError::TransactionNotFound.into());
+        if let (Some(fee_schedule), true) = (&self.fee_schedule, fee != Amount::ZERO) {
+            let client = fee_schedule.collection_account;
+            if let Some(AccountStatus::Locked | AccountStatus::Closed) =
+                self.accounts.get(&client).map(|account| account.status)
+            {
+                return Err(TransactionProcessingError::CollectionAccountUnavailable);
             }
+            entries.push(LedgerEntry::new(
+                LedgerAccount::ClientAvailable(client),
+                fee,
+            ));
+            self.last_fee = fee;
         }
+        Ok(())
     }
 
-    /// An internal function to process a charge back transaction.
-    fn process_chargeback_transaction(
-        &mut self,
-        transaction_id: TransactionId,
-    ) -> Result<(), TransactionProcessingError> {
-        let existing_transaction_details = self.transactions.get_mut(&transaction_id);
-        match existing_transaction_details {
-            Some(t) => {
-                if t.is_disputed {
-                    match t.amount {
-                        Some(amount) => {
-                            let account_details = self.accounts.get_mut(&t.client);
-                            match account_details {
-                                Some(a) => {
-                                    *a = AccountDetails {
-                                        available: a.available,
-                                        total: a.total - amount,
-                                        held: a.held - amount,
-                                        locked: true,
-                                    };
-
-                                    *t = TransactionDetails {
-                                        kind: t.kind,
-                                        client: t.client,
-                                        amount: t.amount,
-                                        is_disputed: false,
-                                    };
-                                    Ok(())
-                                }
-                                None => return Err(TransactionProcessingError::AccountNotFound),
-                            }
-                        }
-                        None => {
-                            return Err(
-                                TransactionProcessingError::AmountNotFoundOnTransactionToDispute
-                                    .into(),
-                            );
-                        }
-                    }
-                } else {
-                    return Err(
-                        TransactionProcessingError::CannotResolveNonDisputedTransaction.into(),
-                    );
+    /// Posts the entries of a transaction of the client to the ledger and updates the accounts
+    /// of the client and of any other client in the entries, like the collection account of
+    /// fees, from their ledger balances, creating them if needed.
+    fn post(&mut self, client_id: ClientId, entries: Vec<LedgerEntry>) {
+        self.ledger.post(&entries);
+        self.refresh_account(client_id);
+        for entry in &entries {
+            match entry.account {
+                LedgerAccount::ClientAvailable(other) | LedgerAccount::ClientHeld(other)
+                    if other != client_id =>
+                {
+                    self.refresh_account(other)
                 }
+                _ => (),
             }
-            None => {
-                return Err(TransactionProcessingError::TransactionNotFound.into());
-            }
         }
+        self.last_posting = entries;
+    }
+
+    /// Updates the account of the client from its ledger balances, creating it if needed.
+    fn refresh_account(&mut self, client_id: ClientId) {
+        // the interest on the balance it had until now
+        if let Some(interest) = &mut self.interest {
+            interest.accrue(client_id, self.accounts.get(&client_id));
+        }
+        let available = self
+            .ledger
+            .balance(LedgerAccount::ClientAvailable(client_id));
+        let held = self.ledger.balance(LedgerAccount::ClientHeld(client_id));
+        let account = self.accounts.entry(client_id).or_default();
+        account.available = available;
+        account.held = held;
+        account.total = available + held;
+    }
+}
+
+/// Looks up the deposit or withdrawal a dispute, resolve or chargeback refers to, returning
+/// it with its amount once it is known to belong to the client's account.
+/// The ledger isn't borrowed with it, so the entries settling it can be checked first.
+fn disputed_transaction<'a>(
+    transactions: &'a mut TransactionStore,
+    accounts: &FastMap<ClientId, AccountDetails>,
+    transaction_id: TransactionId,
+    client_id: ClientId,
+) -> Result<(&'a mut TransactionDetails, Amount), TransactionProcessingError> {
+    let Some(t) = transactions.get_mut(&transaction_id)? else {
+        return Err(TransactionProcessingError::TransactionNotFound);
+    };
+    if t.client != client_id {
+        return Err(TransactionProcessingError::ClientMismatch);
+    }
+    let Some(amount) = t.amount else {
+        return Err(TransactionProcessingError::AmountNotFoundOnTransactionToDispute);
+    };
+    if !accounts.contains_key(&t.client) {
+        return Err(TransactionProcessingError::AccountNotFound);
+    }
+    Ok((t, amount))
+}
+
+/// Checks that the entries can be posted without a balance overflowing.
+fn check_posting(
+    ledger: &Ledger,
+    entries: &[LedgerEntry],
+) -> Result<(), TransactionProcessingError> {
+    if ledger.can_post(entries) {
+        Ok(())
+    } else {
+        Err(TransactionProcessingError::BalanceOverflow)
+    }
+}
+
+/// Checks the amount given with a dispute, resolve or chargeback, which must be positive.
+fn check_dispute_amount(requested: Option<Amount>) -> Result<(), TransactionProcessingError> {
+    match requested {
+        Some(amount) if amount <= Amount::ZERO => {
+            Err(TransactionProcessingError::InvalidAmount(amount))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// The part of the disputed amount of the transaction which a resolve or chargeback settles, all
+/// of it unless the amount given is less.
+fn settled_amount(
+    t: &TransactionDetails,
+    requested: Option<Amount>,
+) -> Result<Amount, TransactionProcessingError> {
+    if t.disputed == Amount::ZERO {
+        return Err(TransactionProcessingError::CannotResolveNonDisputedTransaction);
+    }
+    let settled = requested.unwrap_or(t.disputed);
+    if settled > t.disputed {
+        return Err(TransactionProcessingError::DisputeAmountTooLarge(settled));
+    }
+    Ok(settled)
+}
+
+/// Hashes the accounts, sorted by client, with SHA-256. Every account is hashed as a line of
+/// `client,available,held,total,status` with amounts written with four decimal places, so the
+/// hash only depends on the state of the accounts and not on how it was reached.
+pub(crate) fn state_hash(accounts: &[(ClientId, &AccountDetails)]) -> String {
+    let mut hasher = Sha256::new();
+    for (client, account) in accounts {
+        hasher.update(
+            format!(
+                "{},{},{},{},{}\n",
+                client,
+                account.available,
+                account.held,
+                account.total,
+                account.status.name()
+            )
+            .as_bytes(),
+        );
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// The entries holding part of a disputed transaction of the kind.
+fn hold_entries(kind: TransactionType, client_id: ClientId, held: Amount) -> Vec<LedgerEntry> {
+    if kind == TransactionType::Withdrawal {
+        // the withdrawn funds are held until the dispute is settled
+        vec![
+            LedgerEntry::new(LedgerAccount::ClientHeld(client_id), held),
+            LedgerEntry::new(LedgerAccount::DisputesPending, -held),
+        ]
+    } else {
+        vec![
+            LedgerEntry::new(LedgerAccount::ClientAvailable(client_id), -held),
+            LedgerEntry::new(LedgerAccount::ClientHeld(client_id), held),
+        ]
+    }
+}
+
+/// The entries releasing part of a disputed transaction of the kind as it is resolved.
+fn release_entries(
+    kind: TransactionType,
+    client_id: ClientId,
+    released: Amount,
+) -> Vec<LedgerEntry> {
+    if kind == TransactionType::Withdrawal {
+        // the withdrawal stands, so the held funds are gone again
+        vec![
+            LedgerEntry::new(LedgerAccount::ClientHeld(client_id), -released),
+            LedgerEntry::new(LedgerAccount::DisputesPending, released),
+        ]
+    } else {
+        vec![
+            LedgerEntry::new(LedgerAccount::ClientHeld(client_id), -released),
+            LedgerEntry::new(LedgerAccount::ClientAvailable(client_id), released),
+        ]
+    }
+}
+
+/// The entries reversing part of a disputed transaction of the kind as it is charged back.
+fn reversal_entries(
+    kind: TransactionType,
+    client_id: ClientId,
+    reversed: Amount,
+) -> Vec<LedgerEntry> {
+    if kind == TransactionType::Withdrawal {
+        // the withdrawal is reversed, so the client gets the held funds back
+        vec![
+            LedgerEntry::new(LedgerAccount::ClientHeld(client_id), -reversed),
+            LedgerEntry::new(LedgerAccount::ClientAvailable(client_id), reversed),
+            LedgerEntry::new(LedgerAccount::DisputesPending, reversed),
+            LedgerEntry::new(LedgerAccount::Chargebacks, -reversed),
+        ]
+    } else {
+        vec![
+            LedgerEntry::new(LedgerAccount::ClientHeld(client_id), -reversed),
+            LedgerEntry::new(LedgerAccount::Chargebacks, reversed),
+        ]
+    }
+}
+
+/// Appends the record to the sink, remembering the first error and dropping the sink with it.
+fn record_audit(
+    audit_sink: &mut Option<Box<dyn AuditSink + Send>>,
+    audit_error: &mut Option<io::Error>,
+    record: &AuditRecord,
+) {
+    if let Some(sink) = audit_sink {
+        if let Err(e) = sink.record(record) {
+            error!(
+                "The audit log couldn't be written and no further mutations will be recorded. Error: {}",
+                e
+            );
+            *audit_sink = None;
+            *audit_error = Some(e);
+        }
+    }
+}
+
+impl Default for TransactionEngine {
+    fn default() -> Self {
+        TransactionEngine::new()
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{TransactionEngine, TransactionProcessingError};
-    use crate::{TransactionInput, TransactionType};
+    use super::{
+        AccountStatus, DuplicateIdPolicy, EngineConfig, LedgerAccount, LedgerEntry, LedgerError,
+        LockedAccountPolicy, RetainPolicy, TransactionEngine, TransactionFilter,
+        TransactionProcessingError, TransactionState, ZeroAmountPolicy,
+    };
+    use crate::output::OutputFormat;
+    use crate::{Amount, ClientId, MoneyOps, TransactionId, TransactionInput, TransactionType};
+
+    fn money(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
 
     #[test]
     fn test_deposit_transaction() {
         let mut transaction_engine = TransactionEngine::new();
         let deposit_transaction_1 = TransactionInput {
-            amount: Some(5.0004),
+            amount: Some(money("5.0004")),
             client: 1,
             kind: TransactionType::Deposit,
             tx: 1,
+            timestamp: None,
         };
         let result = transaction_engine.process_transaction(deposit_transaction_1);
         match result {
@@ -371,10 +1871,10 @@ mod tests {
                     .accounts
                     .get(&1)
                     .expect("An account wasn't found for the client 1");
-                assert_eq!(created_account.available, 5.0004);
-                assert_eq!(created_account.held, 0.0);
-                assert_eq!(created_account.total, 5.0004);
-                assert_eq!(created_account.locked, false);
+                assert_eq!(created_account.available, money("5.0004"));
+                assert_eq!(created_account.held, Amount::ZERO);
+                assert_eq!(created_account.total, money("5.0004"));
+                assert!(!created_account.is_locked());
             }
             Err(e) => {
                 panic!(
@@ -389,10 +1889,11 @@ mod tests {
     fn test_withdraw_transaction() {
         let mut transaction_engine = TransactionEngine::new();
         let result = transaction_engine.process_transaction(TransactionInput {
-            amount: Some(1.0004),
+            amount: Some(money("1.0004")),
             client: 1,
             kind: TransactionType::Withdrawal,
             tx: 1,
+            timestamp: None,
         });
         match result {
             Ok(_) => {
@@ -406,10 +1907,11 @@ mod tests {
             },
         }
         let deposit_result = transaction_engine.process_transaction(TransactionInput {
-            amount: Some(5.0004),
+            amount: Some(money("5.0004")),
             client: 1,
             kind: TransactionType::Deposit,
             tx: 1,
+            timestamp: None,
         });
         match deposit_result {
             Ok(_) => {
@@ -417,15 +1919,16 @@ mod tests {
                     .accounts
                     .get(&1)
                     .expect("An account wasn't found for the client 1");
-                assert_eq!(created_account.available, 5.0004);
-                assert_eq!(created_account.held, 0.0);
-                assert_eq!(created_account.total, 5.0004);
-                assert_eq!(created_account.locked, false);
+                assert_eq!(created_account.available, money("5.0004"));
+                assert_eq!(created_account.held, Amount::ZERO);
+                assert_eq!(created_account.total, money("5.0004"));
+                assert!(!created_account.is_locked());
                 let withdraw_result = transaction_engine.process_transaction(TransactionInput {
-                    amount: Some(1.0004),
+                    amount: Some(money("1.0004")),
                     client: 1,
                     kind: TransactionType::Withdrawal,
-                    tx: 1,
+                    tx: 2,
+                    timestamp: None,
                 });
                 match withdraw_result {
                     Ok(_) => {
@@ -433,16 +1936,17 @@ mod tests {
                             .accounts
                             .get(&1)
                             .expect("An account wasn't found for the client 1");
-                        assert_eq!(updated_account.available, 4.0);
-                        assert_eq!(updated_account.held, 0.0);
-                        assert_eq!(updated_account.total, 4.0);
-                        assert_eq!(updated_account.locked, false);
+                        assert_eq!(updated_account.available, money("4.0"));
+                        assert_eq!(updated_account.held, Amount::ZERO);
+                        assert_eq!(updated_account.total, money("4.0"));
+                        assert!(!updated_account.is_locked());
                         let withdraw_result_2 =
                             transaction_engine.process_transaction(TransactionInput {
-                                amount: Some(6.0),
+                                amount: Some(money("6.0")),
                                 client: 1,
                                 kind: TransactionType::Withdrawal,
-                                tx: 1,
+                                tx: 3,
+                                timestamp: None,
                             });
                         match withdraw_result_2 {
                             Ok(_) => {
@@ -481,6 +1985,7 @@ mod tests {
             client: 1,
             kind: TransactionType::Dispute,
             tx: 1,
+            timestamp: None,
         });
         match result {
             Ok(_) => {
@@ -494,10 +1999,11 @@ mod tests {
             },
         }
         let result = transaction_engine.process_transaction(TransactionInput {
-            amount: Some(1.1),
+            amount: Some(money("1.1")),
             client: 1,
             kind: TransactionType::Deposit,
             tx: 1,
+            timestamp: None,
         });
         match result {
             Ok(_) => {
@@ -506,6 +2012,7 @@ mod tests {
                     client: 1,
                     kind: TransactionType::Dispute,
                     tx: 1,
+                    timestamp: None,
                 });
                 match dispute_result {
                     Ok(_) => {
@@ -513,16 +2020,17 @@ mod tests {
                             .accounts
                             .get(&1)
                             .expect("An account wasn't found for the client 1");
-                        assert_eq!(account_state.available, 0.0);
-                        assert_eq!(account_state.held, 1.1);
-                        assert_eq!(account_state.total, 1.1);
-                        assert_eq!(account_state.locked, false);
+                        assert_eq!(account_state.available, Amount::ZERO);
+                        assert_eq!(account_state.held, money("1.1"));
+                        assert_eq!(account_state.total, money("1.1"));
+                        assert!(!account_state.is_locked());
                         let dispute_result_2 =
                             transaction_engine.process_transaction(TransactionInput {
                                 amount: None,
                                 client: 1,
                                 kind: TransactionType::Dispute,
                                 tx: 1,
+                                timestamp: None,
                             });
                         match dispute_result_2 {
                             Ok(_) => {
@@ -557,6 +2065,7 @@ mod tests {
             client: 1,
             kind: TransactionType::Resolve,
             tx: 1,
+            timestamp: None,
         });
         match result {
             Ok(_) => {
@@ -570,10 +2079,11 @@ mod tests {
             },
         };
         let result = transaction_engine.process_transaction(TransactionInput {
-            amount: Some(1.1),
+            amount: Some(money("1.1")),
             client: 1,
             kind: TransactionType::Deposit,
             tx: 1,
+            timestamp: None,
         });
         match result {
             Ok(_) => {
@@ -582,6 +2092,7 @@ mod tests {
                     client: 1,
                     kind: TransactionType::Dispute,
                     tx: 1,
+                    timestamp: None,
                 });
                 match dispute_result {
                     Ok(_) => {
@@ -589,27 +2100,23 @@ mod tests {
                             .accounts
                             .get(&1)
                             .expect("An account wasn't found for the client 1");
-                        assert_eq!(account_state.available, 0.0);
-                        assert_eq!(account_state.held, 1.1);
-                        assert_eq!(account_state.total, 1.1);
-                        assert_eq!(account_state.locked, false);
-                        let resolve_result =
-                            transaction_engine.process_transaction(TransactionInput {
-                                kind: TransactionType::Resolve,
-                                client: 1,
-                                tx: 1,
-                                amount: None,
-                            });
+                        assert_eq!(account_state.available, Amount::ZERO);
+                        assert_eq!(account_state.held, money("1.1"));
+                        assert_eq!(account_state.total, money("1.1"));
+                        assert!(!account_state.is_locked());
+                        let resolve_result = transaction_engine.process_transaction(
+                            TransactionInput::new(TransactionType::Resolve, 1, 1, None),
+                        );
                         match resolve_result {
                             Ok(_) => {
                                 let account_state = transaction_engine
                                     .accounts
                                     .get(&1)
                                     .expect("An account wasn't found for the client 1");
-                                assert_eq!(account_state.available, 1.1);
-                                assert_eq!(account_state.held, 0.0);
-                                assert_eq!(account_state.total, 1.1);
-                                assert_eq!(account_state.locked, false);
+                                assert_eq!(account_state.available, money("1.1"));
+                                assert_eq!(account_state.held, Amount::ZERO);
+                                assert_eq!(account_state.total, money("1.1"));
+                                assert!(!account_state.is_locked());
                             }
                             Err(_) => {
                                 panic!("Expected resolve to succeed");
@@ -635,6 +2142,7 @@ mod tests {
             client: 1,
             kind: TransactionType::Resolve,
             tx: 1,
+            timestamp: None,
         });
         match result {
             Ok(_) => {
@@ -648,10 +2156,11 @@ mod tests {
             },
         };
         let result = transaction_engine.process_transaction(TransactionInput {
-            amount: Some(1.1),
+            amount: Some(money("1.1")),
             client: 1,
             kind: TransactionType::Deposit,
             tx: 1,
+            timestamp: None,
         });
         match result {
             Ok(_) => {
@@ -660,6 +2169,7 @@ mod tests {
                     client: 1,
                     kind: TransactionType::Dispute,
                     tx: 1,
+                    timestamp: None,
                 });
                 match dispute_result {
                     Ok(_) => {
@@ -667,28 +2177,24 @@ mod tests {
                             .accounts
                             .get(&1)
                             .expect("An account wasn't found for the client 1");
-                        assert_eq!(account_state.available, 0.0);
-                        assert_eq!(account_state.held, 1.1);
-                        assert_eq!(account_state.total, 1.1);
-                        assert_eq!(account_state.locked, false);
+                        assert_eq!(account_state.available, Amount::ZERO);
+                        assert_eq!(account_state.held, money("1.1"));
+                        assert_eq!(account_state.total, money("1.1"));
+                        assert!(!account_state.is_locked());
 
-                        let chargeback_result =
-                            transaction_engine.process_transaction(TransactionInput {
-                                kind: TransactionType::Chargeback,
-                                client: 1,
-                                tx: 1,
-                                amount: None,
-                            });
+                        let chargeback_result = transaction_engine.process_transaction(
+                            TransactionInput::new(TransactionType::Chargeback, 1, 1, None),
+                        );
                         match chargeback_result {
                             Ok(_) => {
                                 let account_state = transaction_engine
                                     .accounts
                                     .get(&1)
                                     .expect("An account wasn't found for the client 1");
-                                assert_eq!(account_state.available, 0.0);
-                                assert_eq!(account_state.held, 0.0);
-                                assert_eq!(account_state.total, 0.0);
-                                assert_eq!(account_state.locked, true);
+                                assert_eq!(account_state.available, Amount::ZERO);
+                                assert_eq!(account_state.held, Amount::ZERO);
+                                assert_eq!(account_state.total, Amount::ZERO);
+                                assert!(account_state.is_locked());
                             }
                             Err(_) => {
                                 panic!("Expected chargeback to succeed");
@@ -705,4 +2211,790 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_write_accounts_state() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine
+            .process_transaction(TransactionInput {
+                amount: Some(money("1.5")),
+                client: 1,
+                kind: TransactionType::Deposit,
+                tx: 1,
+                timestamp: None,
+            })
+            .expect("Expected deposit transaction to succeed");
+        let mut output = Vec::new();
+        transaction_engine
+            .write_accounts_state(&mut output)
+            .expect("Expected the accounts state to be written");
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+
+        let mut output = Vec::new();
+        transaction_engine
+            .write_accounts_state_json(&mut output, OutputFormat::Csv)
+            .expect("Expected the accounts state to be written");
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "client,available,held,total,locked\n1,1.5000,0.0000,1.5000,false\n"
+        );
+    }
+
+    #[test]
+    fn test_account_read_api() {
+        let mut transaction_engine = TransactionEngine::new();
+        assert!(transaction_engine.is_empty());
+        for (client, tx) in [(1, 1), (2, 2), (1, 3)] {
+            transaction_engine
+                .process_transaction(TransactionInput {
+                    amount: Some(money("2")),
+                    client,
+                    kind: TransactionType::Deposit,
+                    tx,
+                    timestamp: None,
+                })
+                .expect("Expected deposit transaction to succeed");
+        }
+        assert_eq!(transaction_engine.len(), 2);
+        let account = transaction_engine
+            .get_account(1)
+            .expect("An account wasn't found for the client 1");
+        assert_eq!(account.total, money("4"));
+        assert!(transaction_engine.get_account(3).is_none());
+        let mut clients: Vec<_> = transaction_engine.accounts().map(|(c, _)| c).collect();
+        clients.sort();
+        assert_eq!(clients, vec![1, 2]);
+        let sorted_clients: Vec<_> = transaction_engine
+            .sorted_accounts()
+            .into_iter()
+            .map(|(c, _)| c)
+            .collect();
+        assert_eq!(sorted_clients, clients);
+    }
+
+    #[test]
+    fn test_dispute_from_other_client_is_rejected() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine
+            .process_transaction(TransactionInput {
+                amount: Some(money("3")),
+                client: 1,
+                kind: TransactionType::Deposit,
+                tx: 1,
+                timestamp: None,
+            })
+            .expect("Expected deposit transaction to succeed");
+        for kind in [
+            TransactionType::Dispute,
+            TransactionType::Resolve,
+            TransactionType::Chargeback,
+        ] {
+            let result = transaction_engine.process_transaction(TransactionInput {
+                amount: None,
+                client: 2,
+                kind,
+                tx: 1,
+                timestamp: None,
+            });
+            assert!(matches!(
+                result,
+                Err(TransactionProcessingError::ClientMismatch)
+            ));
+        }
+        let account_state = transaction_engine
+            .get_account(1)
+            .expect("An account wasn't found for the client 1");
+        assert_eq!(account_state.available, money("3"));
+        assert_eq!(account_state.held, Amount::ZERO);
+        assert!(transaction_engine.get_account(2).is_none());
+    }
+
+    fn deposit(client: ClientId, tx: TransactionId, amount: &str) -> TransactionInput {
+        TransactionInput {
+            amount: Some(money(amount)),
+            client,
+            kind: TransactionType::Deposit,
+            tx,
+            timestamp: None,
+        }
+    }
+
+    fn withdrawal(client: ClientId, tx: TransactionId, amount: &str) -> TransactionInput {
+        TransactionInput {
+            amount: Some(money(amount)),
+            client,
+            kind: TransactionType::Withdrawal,
+            tx,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_replayed_deposit_is_rejected() {
+        let mut transaction_engine = TransactionEngine::new();
+        assert!(transaction_engine
+            .process_transaction(deposit(1, 1, "10"))
+            .is_ok());
+        assert!(matches!(
+            transaction_engine.process_transaction(deposit(1, 1, "10")),
+            Err(TransactionProcessingError::DuplicateTransactionId)
+        ));
+        let account_state = transaction_engine
+            .get_account(1)
+            .expect("An account wasn't found for the client 1");
+        assert_eq!(account_state.total, money("10"));
+    }
+
+    #[test]
+    fn test_replayed_id_from_other_client_does_not_create_account() {
+        let mut transaction_engine = TransactionEngine::new();
+        assert!(transaction_engine
+            .process_transaction(deposit(1, 1, "10"))
+            .is_ok());
+        assert!(matches!(
+            transaction_engine.process_transaction(deposit(2, 1, "5")),
+            Err(TransactionProcessingError::DuplicateTransactionId)
+        ));
+        assert!(transaction_engine.get_account(2).is_none());
+    }
+
+    #[test]
+    fn test_replayed_withdrawal_is_rejected() {
+        let mut transaction_engine = TransactionEngine::new();
+        assert!(transaction_engine
+            .process_transaction(deposit(1, 1, "10"))
+            .is_ok());
+        assert!(transaction_engine
+            .process_transaction(withdrawal(1, 2, "3"))
+            .is_ok());
+        assert!(matches!(
+            transaction_engine.process_transaction(withdrawal(1, 2, "3")),
+            Err(TransactionProcessingError::DuplicateTransactionId)
+        ));
+        // a withdrawal can't reuse the id of a deposit either
+        assert!(matches!(
+            transaction_engine.process_transaction(withdrawal(1, 1, "3")),
+            Err(TransactionProcessingError::DuplicateTransactionId)
+        ));
+        let account_state = transaction_engine
+            .get_account(1)
+            .expect("An account wasn't found for the client 1");
+        assert_eq!(account_state.total, money("7"));
+    }
+
+    #[test]
+    fn test_failed_transaction_id_can_be_retried() {
+        let mut transaction_engine = TransactionEngine::new();
+        assert!(transaction_engine
+            .process_transaction(deposit(1, 1, "1"))
+            .is_ok());
+        assert!(matches!(
+            transaction_engine.process_transaction(withdrawal(1, 2, "5")),
+            Err(TransactionProcessingError::InsufficientFunds)
+        ));
+        assert!(transaction_engine
+            .process_transaction(deposit(1, 3, "10"))
+            .is_ok());
+        assert!(transaction_engine
+            .process_transaction(withdrawal(1, 2, "5"))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_negative_amounts_are_rejected() {
+        let mut transaction_engine = TransactionEngine::new();
+        assert!(transaction_engine
+            .process_transaction(deposit(1, 1, "10"))
+            .is_ok());
+        assert!(matches!(
+            transaction_engine.process_transaction(deposit(1, 2, "-100")),
+            Err(TransactionProcessingError::InvalidAmount(_))
+        ));
+        assert!(matches!(
+            transaction_engine.process_transaction(withdrawal(1, 3, "-5")),
+            Err(TransactionProcessingError::InvalidAmount(_))
+        ));
+        let account_state = transaction_engine
+            .get_account(1)
+            .expect("An account wasn't found for the client 1");
+        assert_eq!(account_state.total, money("10"));
+    }
+
+    #[test]
+    fn test_zero_amount_policy() {
+        let mut transaction_engine = TransactionEngine::new();
+        assert!(matches!(
+            transaction_engine.process_transaction(deposit(1, 1, "0")),
+            Err(TransactionProcessingError::InvalidAmount(_))
+        ));
+
+        let mut transaction_engine =
+            TransactionEngine::with_zero_amount_policy(ZeroAmountPolicy::Skip);
+        assert!(transaction_engine
+            .process_transaction(deposit(1, 1, "0"))
+            .is_ok());
+        assert!(transaction_engine.is_empty());
+        // a skipped transaction isn't stored, so its id can't be disputed
+        assert!(matches!(
+            transaction_engine.process_transaction(TransactionInput {
+                amount: None,
+                client: 1,
+                kind: TransactionType::Dispute,
+                tx: 1,
+                timestamp: None,
+            }),
+            Err(TransactionProcessingError::TransactionNotFound)
+        ));
+    }
+
+    #[test]
+    fn test_state_hash_only_depends_on_the_accounts() {
+        let mut first = TransactionEngine::new();
+        first.process_transactions([
+            deposit(1, 1, "10"),
+            deposit(2, 2, "5"),
+            withdrawal(1, 3, "2.5"),
+        ]);
+        let mut second = TransactionEngine::new();
+        second.process_transactions([
+            deposit(2, 7, "5"),
+            deposit(1, 8, "7.5"),
+            withdrawal(1, 9, "100"),
+        ]);
+        assert_eq!(first.state_hash(), second.state_hash());
+        assert_eq!(first.state_hash().len(), 64);
+
+        second.process_transactions([deposit(2, 10, "0.0001")]);
+        assert_ne!(first.state_hash(), second.state_hash());
+        assert_eq!(
+            TransactionEngine::new().state_hash(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+
+    #[test]
+    fn test_engine_config_ignores_duplicate_ids() {
+        let config = EngineConfig::default()
+            .with_duplicate_id_policy(DuplicateIdPolicy::Ignore)
+            .with_zero_amount_policy(ZeroAmountPolicy::Skip);
+        let mut transaction_engine = TransactionEngine::with_config(config);
+        assert_eq!(transaction_engine.config(), &config);
+        assert_eq!(
+            transaction_engine.zero_amount_policy(),
+            ZeroAmountPolicy::Skip
+        );
+        transaction_engine.process_transactions([
+            deposit(1, 1, "10"),
+            deposit(1, 1, "10"),
+            withdrawal(1, 1, "4"),
+        ]);
+        assert_eq!(transaction_engine.accounts[&1].total, money("10"));
+
+        let mut transaction_engine = TransactionEngine::new();
+        assert_eq!(transaction_engine.config(), &EngineConfig::default());
+        transaction_engine.process_transactions([deposit(1, 1, "10")]);
+        assert!(matches!(
+            transaction_engine.process_transaction(deposit(1, 1, "10")),
+            Err(TransactionProcessingError::DuplicateTransactionId)
+        ));
+    }
+
+    fn dispute_row(kind: TransactionType, tx: TransactionId) -> TransactionInput {
+        TransactionInput {
+            amount: None,
+            client: 1,
+            kind,
+            tx,
+            timestamp: None,
+        }
+    }
+
+    fn assert_account(
+        transaction_engine: &TransactionEngine,
+        available: &str,
+        held: &str,
+        total: &str,
+        locked: bool,
+    ) {
+        let account_state = transaction_engine
+            .get_account(1)
+            .expect("An account wasn't found for the client 1");
+        assert_eq!(account_state.available, money(available));
+        assert_eq!(account_state.held, money(held));
+        assert_eq!(account_state.total, money(total));
+        assert_eq!(account_state.is_locked(), locked);
+    }
+
+    #[test]
+    fn test_disputed_withdrawal_resolve() {
+        let mut transaction_engine = TransactionEngine::new();
+        for transaction in [deposit(1, 1, "10"), withdrawal(1, 2, "4")] {
+            assert!(transaction_engine.process_transaction(transaction).is_ok());
+        }
+        assert!(transaction_engine
+            .process_transaction(dispute_row(TransactionType::Dispute, 2))
+            .is_ok());
+        assert_account(&transaction_engine, "6", "4", "10", false);
+        assert!(transaction_engine
+            .process_transaction(dispute_row(TransactionType::Resolve, 2))
+            .is_ok());
+        assert_account(&transaction_engine, "6", "0", "6", false);
+    }
+
+    #[test]
+    fn test_disputed_withdrawal_chargeback() {
+        let mut transaction_engine = TransactionEngine::new();
+        for transaction in [deposit(1, 1, "10"), withdrawal(1, 2, "4")] {
+            assert!(transaction_engine.process_transaction(transaction).is_ok());
+        }
+        assert!(transaction_engine
+            .process_transaction(dispute_row(TransactionType::Dispute, 2))
+            .is_ok());
+        assert!(transaction_engine
+            .process_transaction(dispute_row(TransactionType::Chargeback, 2))
+            .is_ok());
+        assert_account(&transaction_engine, "10", "0", "10", true);
+    }
+
+    #[test]
+    fn test_ledger_balances_every_posting() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.process_transactions([
+            deposit(1, 1, "10"),
+            withdrawal(1, 2, "4"),
+            dispute_row(TransactionType::Dispute, 2),
+            dispute_row(TransactionType::Chargeback, 2),
+            deposit(1, 3, "1"),
+        ]);
+        assert!(transaction_engine.check_ledger().is_ok());
+        let ledger = transaction_engine.ledger();
+        assert_eq!(ledger.postings(), 4);
+        assert_eq!(ledger.balance(LedgerAccount::BankClearing), money("-6"));
+        assert_eq!(ledger.balance(LedgerAccount::Chargebacks), money("-4"));
+        assert_eq!(ledger.balance(LedgerAccount::DisputesPending), money("0"));
+        assert_eq!(
+            ledger.balance(LedgerAccount::ClientAvailable(1)),
+            money("10")
+        );
+
+        transaction_engine.accounts.get_mut(&1).unwrap().held = money("1");
+        assert_eq!(
+            transaction_engine.check_ledger(),
+            Err(LedgerError::AccountMismatch(1))
+        );
+    }
+
+    #[test]
+    fn test_balance_overflow_leaves_the_state_unchanged() {
+        let mut transaction_engine = TransactionEngine::new();
+        assert!(transaction_engine
+            .process_transaction(TransactionInput::deposit(1, 1, Amount::MAX))
+            .is_ok());
+        let result = transaction_engine.process_transaction(deposit(1, 2, "1"));
+        assert!(matches!(
+            result,
+            Err(TransactionProcessingError::BalanceOverflow)
+        ));
+        let account = transaction_engine.get_account(1).unwrap();
+        assert_eq!(
+            (account.available, account.total),
+            (Amount::MAX, Amount::MAX)
+        );
+        assert!(transaction_engine.get_transaction(2).unwrap().is_none());
+        assert!(transaction_engine.check_ledger().is_ok());
+    }
+
+    #[test]
+    fn test_verify_invariants_reports_the_offending_transaction() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_verify_invariants(true);
+        for transaction in [
+            deposit(1, 1, "10"),
+            withdrawal(1, 2, "4"),
+            dispute_row(TransactionType::Dispute, 1),
+        ] {
+            assert!(transaction_engine.process_transaction(transaction).is_ok());
+        }
+        // a bug releasing more than was held leaves negative held funds behind
+        transaction_engine.ledger.post(&[
+            LedgerEntry::new(LedgerAccount::ClientHeld(1), money("-11")),
+            LedgerEntry::new(LedgerAccount::ClientAvailable(1), money("11")),
+        ]);
+        let total = transaction_engine.get_account(1).unwrap().total;
+        assert!(matches!(
+            transaction_engine.process_transaction(deposit(1, 5, "1")),
+            Err(TransactionProcessingError::InvariantViolated {
+                tx: 5,
+                client: 1,
+                ..
+            })
+        ));
+        // the offending transaction isn't left applied
+        assert_eq!(transaction_engine.get_account(1).unwrap().total, total);
+        assert!(transaction_engine.get_transaction(5).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_locked_account_policy() {
+        let transactions = [
+            TransactionInput::deposit(1, 1, money("5")),
+            TransactionInput::deposit(1, 2, money("3")),
+            TransactionInput::dispute(1, 1),
+            TransactionInput::dispute(1, 2),
+            TransactionInput::chargeback(1, 1),
+            TransactionInput::resolve(1, 2),
+            TransactionInput::deposit(1, 3, money("1")),
+        ];
+        let outcome = |locked_account_policy| {
+            let mut transaction_engine = TransactionEngine::new();
+            transaction_engine.set_locked_account_policy(locked_account_policy);
+            let results = transaction_engine.process_transactions(transactions);
+            let account = transaction_engine.get_account(1).unwrap();
+            assert!(account.is_locked());
+            (results[5].is_ok(), results[6].is_ok(), account.held)
+        };
+        assert_eq!(
+            outcome(LockedAccountPolicy::BlockAll),
+            (false, false, money("3"))
+        );
+        assert_eq!(
+            outcome(LockedAccountPolicy::DepositsOnly),
+            (false, true, money("3"))
+        );
+        assert_eq!(
+            outcome(LockedAccountPolicy::SettleDisputes),
+            (true, false, Amount::ZERO)
+        );
+    }
+
+    #[test]
+    fn test_open_disputes_are_settled_after_a_chargeback() {
+        let mut transaction_engine = TransactionEngine::new();
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("5")),
+            TransactionInput::deposit(1, 2, money("3")),
+            TransactionInput::deposit(1, 3, money("2")),
+            TransactionInput::dispute(1, 1),
+            TransactionInput::dispute(1, 2),
+            TransactionInput::dispute(1, 3),
+            TransactionInput::chargeback(1, 1),
+            TransactionInput::resolve(1, 2),
+            TransactionInput::chargeback(1, 3),
+            TransactionInput::dispute(1, 2),
+        ]);
+        assert!(results[..9].iter().all(Result::is_ok));
+        assert!(matches!(
+            results[9],
+            Err(TransactionProcessingError::AccountLocked)
+        ));
+        let account = transaction_engine.get_account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held),
+            (money("3"), Amount::ZERO)
+        );
+        assert!(account.is_locked());
+    }
+
+    #[test]
+    fn test_partial_disputes() {
+        let mut transaction_engine = TransactionEngine::new();
+        let dispute = |amount: &str| {
+            TransactionInput::new(TransactionType::Dispute, 1, 1, Some(money(amount)))
+        };
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("10")),
+            dispute("4"),
+            dispute("7"),
+            dispute("5"),
+            TransactionInput::new(TransactionType::Resolve, 1, 1, Some(money("3"))),
+            TransactionInput::new(TransactionType::Chargeback, 1, 1, Some(money("6"))),
+        ]);
+        assert!(results[0].is_ok() && results[1].is_ok() && results[3].is_ok());
+        assert!(matches!(
+            results[2],
+            Err(TransactionProcessingError::DisputeAmountTooLarge(_))
+        ));
+        assert!(results[4].is_ok() && results[5].is_ok());
+        let account = transaction_engine.get_account(1).unwrap();
+        assert_eq!(
+            (account.available, account.held, account.total),
+            (money("4"), Amount::ZERO, money("4"))
+        );
+        let transaction = transaction_engine.get_transaction(1).unwrap().unwrap();
+        assert_eq!(transaction.state, TransactionState::ChargedBack);
+        assert_eq!(transaction.charged_back, money("6"));
+
+        // what wasn't charged back can still be disputed once the account is unlocked
+        transaction_engine.set_allow_admin_ops(true);
+        let results = transaction_engine.process_transactions([
+            TransactionInput::unlock(1, 2),
+            TransactionInput::dispute(1, 1),
+            TransactionInput::dispute(1, 1),
+        ]);
+        assert!(results[0].is_ok() && results[1].is_ok());
+        assert!(matches!(
+            results[2],
+            Err(TransactionProcessingError::CannotDisputeAnAlreadyDisputedTransaction)
+        ));
+        assert_eq!(transaction_engine.get_account(1).unwrap().held, money("4"));
+    }
+
+    #[test]
+    fn test_disputes_after_the_window_are_rejected() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_dispute_window(Some(100));
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("5")).with_timestamp(1000),
+            TransactionInput::deposit(1, 2, money("5")).with_timestamp(1050),
+            TransactionInput::deposit(1, 3, money("5")),
+            TransactionInput::dispute(1, 1).with_timestamp(1101),
+            TransactionInput::dispute(1, 2).with_timestamp(1101),
+            TransactionInput::dispute(1, 3).with_timestamp(5000),
+        ]);
+        assert!(matches!(
+            results[3],
+            Err(TransactionProcessingError::DisputeWindowExpired)
+        ));
+        assert!(results[4].is_ok() && results[5].is_ok());
+        assert_eq!(
+            transaction_engine
+                .get_transaction(2)
+                .unwrap()
+                .unwrap()
+                .timestamp,
+            Some(1050)
+        );
+    }
+
+    #[test]
+    fn test_unlock_reinstates_a_charged_back_account() {
+        let mut transaction_engine = TransactionEngine::new();
+        for transaction in [
+            deposit(1, 1, "10"),
+            dispute_row(TransactionType::Dispute, 1),
+            dispute_row(TransactionType::Chargeback, 1),
+        ] {
+            assert!(transaction_engine.process_transaction(transaction).is_ok());
+        }
+        assert!(matches!(
+            transaction_engine.process_transaction(TransactionInput::unlock(1, 2)),
+            Err(TransactionProcessingError::AdminOperationsNotAllowed)
+        ));
+
+        transaction_engine.set_allow_admin_ops(true);
+        assert!(transaction_engine
+            .process_transaction(TransactionInput::unlock(1, 2))
+            .is_ok());
+        assert!(!transaction_engine.get_account(1).unwrap().is_locked());
+        assert_eq!(
+            transaction_engine.get_transaction(2).unwrap().unwrap().kind,
+            TransactionType::Unlock
+        );
+        assert!(matches!(
+            transaction_engine.process_transaction(TransactionInput::unlock(1, 3)),
+            Err(TransactionProcessingError::AccountNotLocked)
+        ));
+        assert!(transaction_engine
+            .process_transaction(deposit(1, 4, "1"))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_account_lifecycle() {
+        let mut transaction_engine = TransactionEngine::new();
+        let status_change = TransactionInput::status_change;
+        for kind in [TransactionType::Open, TransactionType::Close] {
+            assert!(matches!(
+                transaction_engine.process_transaction(status_change(kind, 1, 1)),
+                Err(TransactionProcessingError::AdminOperationsNotAllowed)
+            ));
+        }
+
+        transaction_engine.set_allow_admin_ops(true);
+        let results = transaction_engine.process_transactions([
+            status_change(TransactionType::Open, 1, 1),
+            status_change(TransactionType::Freeze, 1, 2),
+            deposit(1, 3, "5"),
+            withdrawal(1, 4, "1"),
+            status_change(TransactionType::Close, 1, 5),
+            status_change(TransactionType::Unfreeze, 1, 6),
+            status_change(TransactionType::Close, 1, 7),
+            status_change(TransactionType::Open, 2, 8),
+            status_change(TransactionType::Close, 2, 9),
+            deposit(2, 10, "1"),
+            status_change(TransactionType::Open, 2, 11),
+            status_change(TransactionType::Open, 2, 12),
+        ]);
+        let kinds: Vec<_> = results
+            .iter()
+            .map(|result| result.as_ref().err().map(TransactionProcessingError::kind))
+            .collect();
+        assert_eq!(
+            kinds,
+            [
+                None,
+                None,
+                None,
+                Some("account_frozen"),
+                Some("account_frozen"),
+                None,
+                Some("account_not_empty"),
+                None,
+                None,
+                Some("account_closed"),
+                None,
+                Some("account_already_open"),
+            ]
+        );
+        assert_eq!(
+            transaction_engine.get_account(1).unwrap().status,
+            AccountStatus::Active
+        );
+    }
+
+    #[test]
+    fn test_charged_back_transaction_cannot_be_disputed_again() {
+        let mut transaction_engine = TransactionEngine::new();
+        for transaction in [
+            deposit(1, 1, "10"),
+            dispute_row(TransactionType::Dispute, 1),
+            dispute_row(TransactionType::Chargeback, 1),
+        ] {
+            assert!(transaction_engine.process_transaction(transaction).is_ok());
+        }
+        // unlock the account by hand so that the dispute reaches the transaction state check
+        transaction_engine.accounts.get_mut(&1).unwrap().status = AccountStatus::Active;
+        assert!(matches!(
+            transaction_engine.process_transaction(dispute_row(TransactionType::Dispute, 1)),
+            Err(TransactionProcessingError::CannotDisputeAChargedBackTransaction)
+        ));
+        assert_account(&transaction_engine, "0", "0", "0", false);
+    }
+
+    #[test]
+    fn test_resolved_transaction_can_be_disputed_again() {
+        let mut transaction_engine = TransactionEngine::new();
+        for transaction in [
+            deposit(1, 1, "10"),
+            dispute_row(TransactionType::Dispute, 1),
+            dispute_row(TransactionType::Resolve, 1),
+            dispute_row(TransactionType::Dispute, 1),
+        ] {
+            assert!(transaction_engine.process_transaction(transaction).is_ok());
+        }
+        assert_account(&transaction_engine, "0", "10", "10", false);
+    }
+
+    #[test]
+    fn test_process_transactions() {
+        let mut transaction_engine = TransactionEngine::new();
+        let results = transaction_engine.process_transactions(
+            [
+                deposit(1, 1, "5"),
+                withdrawal(1, 2, "7"),
+                withdrawal(1, 3, "2"),
+            ]
+            .into_iter()
+            .chain((4..6).map(|tx| deposit(1, tx, "1"))),
+        );
+        assert!(matches!(
+            results.as_slice(),
+            [
+                Ok(()),
+                Err(TransactionProcessingError::InsufficientFunds),
+                Ok(()),
+                Ok(()),
+                Ok(())
+            ]
+        ));
+        assert_account(&transaction_engine, "5", "0", "5", false);
+    }
+
+    #[test]
+    fn test_spilled_transactions_can_be_disputed() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_transaction_cache_size(2).unwrap();
+        for tx in 1..=10 {
+            assert!(transaction_engine
+                .process_transaction(deposit(1, tx, "1"))
+                .is_ok());
+        }
+        for transaction in [
+            dispute_row(TransactionType::Dispute, 1),
+            dispute_row(TransactionType::Dispute, 2),
+            dispute_row(TransactionType::Resolve, 1),
+        ] {
+            assert!(transaction_engine.process_transaction(transaction).is_ok());
+        }
+        assert!(matches!(
+            transaction_engine.process_transaction(deposit(1, 3, "1")),
+            Err(TransactionProcessingError::DuplicateTransactionId)
+        ));
+        assert_eq!(transaction_engine.transaction_count(), 10);
+        assert_account(&transaction_engine, "9", "1", "10", false);
+    }
+
+    #[test]
+    fn test_deposits_only_retain_policy() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_retain_policy(RetainPolicy::DepositsOnly);
+        for transaction in [
+            deposit(1, 1, "10"),
+            withdrawal(1, 2, "4"),
+            dispute_row(TransactionType::Dispute, 1),
+        ] {
+            assert!(transaction_engine.process_transaction(transaction).is_ok());
+        }
+        assert!(matches!(
+            transaction_engine.process_transaction(dispute_row(TransactionType::Dispute, 2)),
+            Err(TransactionProcessingError::TransactionNotFound)
+        ));
+        assert_eq!(transaction_engine.transaction_count(), 1);
+        assert_account(&transaction_engine, "-4", "10", "6", false);
+    }
+
+    #[test]
+    fn test_transactions_are_filtered() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.process_transactions([
+            deposit(1, 1, "10"),
+            deposit(1, 2, "10"),
+            withdrawal(1, 3, "4"),
+            deposit(2, 4, "10"),
+            dispute_row(TransactionType::Dispute, 2),
+        ]);
+        let ids = |filter: TransactionFilter| -> Vec<TransactionId> {
+            transaction_engine
+                .transactions(&filter)
+                .unwrap()
+                .map(|transaction| transaction.tx)
+                .collect()
+        };
+        assert_eq!(ids(TransactionFilter::default()), vec![1, 2, 3, 4]);
+        assert_eq!(
+            ids(TransactionFilter {
+                client: Some(1),
+                kind: Some(TransactionType::Deposit),
+                ..Default::default()
+            }),
+            vec![1, 2]
+        );
+        assert_eq!(
+            ids(TransactionFilter {
+                disputed_only: true,
+                ..Default::default()
+            }),
+            vec![2]
+        );
+        assert_eq!(
+            ids(TransactionFilter {
+                tx_range: Some(3..=10),
+                ..Default::default()
+            }),
+            vec![3, 4]
+        );
+    }
 }
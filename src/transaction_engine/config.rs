@@ -0,0 +1,112 @@
+//! The settings deciding how the engine treats transactions, gathered so that an engine can be
+//! created with all of them at once.
+
+use serde::Deserialize;
+
+use super::{LockedAccountPolicy, RetainPolicy, TransactionEngine, ZeroAmountPolicy};
+use crate::PrecisionPolicy;
+
+/// What to do with a deposit or withdrawal reusing the id of a transaction kept for disputes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateIdPolicy {
+    /// Fail the transaction with a `DuplicateTransactionId` error
+    #[default]
+    Reject,
+    /// Skip the transaction without an error, e.g. for input which may be delivered twice
+    Ignore,
+}
+
+impl DuplicateIdPolicy {
+    /// Parses the policy from its command line name.
+    pub fn from_name(name: &str) -> Option<DuplicateIdPolicy> {
+        match name {
+            "reject" => Some(DuplicateIdPolicy::Reject),
+            "ignore" => Some(DuplicateIdPolicy::Ignore),
+            _ => None,
+        }
+    }
+}
+
+/// The behavioral settings of an engine. The default is what `TransactionEngine::new` uses.
+/// It is deserialized with the names of the command line flags, e.g. `zero-amounts = "skip"`,
+/// and settings which are left out keep their default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct EngineConfig {
+    #[serde(rename = "zero-amounts")]
+    pub zero_amount_policy: ZeroAmountPolicy,
+    #[serde(rename = "duplicate-ids")]
+    pub duplicate_id_policy: DuplicateIdPolicy,
+    /// What to do with amounts which have more than four decimal places, for input read with
+    /// the engine's settings
+    #[serde(rename = "precision")]
+    pub precision_policy: PrecisionPolicy,
+    #[serde(rename = "locked-accounts")]
+    pub locked_account_policy: LockedAccountPolicy,
+    /// The seconds after a transaction within which it can be disputed, if they are limited
+    pub dispute_window: Option<u64>,
+    #[serde(rename = "retain")]
+    pub retain_policy: RetainPolicy,
+    /// Whether administrative operations like unlocking an account are applied
+    pub allow_admin_ops: bool,
+    /// Whether a transaction breaking the invariants of its account is reported as an error
+    pub verify_invariants: bool,
+}
+
+impl EngineConfig {
+    pub fn with_zero_amount_policy(mut self, zero_amount_policy: ZeroAmountPolicy) -> Self {
+        self.zero_amount_policy = zero_amount_policy;
+        self
+    }
+
+    pub fn with_duplicate_id_policy(mut self, duplicate_id_policy: DuplicateIdPolicy) -> Self {
+        self.duplicate_id_policy = duplicate_id_policy;
+        self
+    }
+
+    pub fn with_precision_policy(mut self, precision_policy: PrecisionPolicy) -> Self {
+        self.precision_policy = precision_policy;
+        self
+    }
+
+    pub fn with_locked_account_policy(
+        mut self,
+        locked_account_policy: LockedAccountPolicy,
+    ) -> Self {
+        self.locked_account_policy = locked_account_policy;
+        self
+    }
+
+    pub fn with_dispute_window(mut self, dispute_window: Option<u64>) -> Self {
+        self.dispute_window = dispute_window;
+        self
+    }
+
+    pub fn with_retain_policy(mut self, retain_policy: RetainPolicy) -> Self {
+        self.retain_policy = retain_policy;
+        self
+    }
+
+    pub fn with_allow_admin_ops(mut self, allow_admin_ops: bool) -> Self {
+        self.allow_admin_ops = allow_admin_ops;
+        self
+    }
+
+    pub fn with_verify_invariants(mut self, verify_invariants: bool) -> Self {
+        self.verify_invariants = verify_invariants;
+        self
+    }
+}
+
+impl TransactionEngine {
+    /// The settings the engine treats transactions with
+    pub fn config(&self) -> &EngineConfig {
+        &self.config
+    }
+
+    /// Changes all settings at once, e.g. for an engine restored from a snapshot.
+    pub fn set_config(&mut self, config: EngineConfig) {
+        self.config = config;
+    }
+}
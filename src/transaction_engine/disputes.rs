@@ -0,0 +1,252 @@
+//! Disputes as entities of their own, so that every dispute of a transaction can be followed
+//! from being opened until it is resolved or charged back.
+//!
+//! Every dispute row opens a dispute with the next dispute id, holding the amount it disputes.
+//! A transaction may have several open disputes when parts of it are disputed one after the
+//! other. Resolves and chargebacks reference the transaction rather than a dispute, so they
+//! settle its open disputes oldest first. A dispute which is only settled in part stays open for
+//! the rest.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::{FastMap, TransactionEngine};
+use crate::{Amount, ClientId, Timestamp, TransactionId};
+
+/// The id of a dispute, given out by the engine in the order disputes are opened
+pub type DisputeId = u64;
+
+/// Where a dispute is in its life
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DisputeState {
+    /// Some of the disputed amount is still held
+    Open,
+    /// The whole amount was released to the client again
+    Resolved,
+    /// The amount was settled and at least part of it was charged back
+    ChargedBack,
+}
+
+impl DisputeState {
+    /// The name of the state as it is written to the output
+    pub fn name(&self) -> &'static str {
+        match self {
+            DisputeState::Open => "open",
+            DisputeState::Resolved => "resolved",
+            DisputeState::ChargedBack => "charged_back",
+        }
+    }
+}
+
+/// A dispute of a deposit or withdrawal
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Dispute {
+    #[serde(rename = "dispute")]
+    pub id: DisputeId,
+    pub tx: TransactionId,
+    pub client: ClientId,
+    /// The amount held when the dispute was opened
+    pub amount: Amount,
+    /// The part of the amount which is still held
+    pub remaining: Amount,
+    /// The part of the amount which was charged back
+    pub charged_back: Amount,
+    pub state: DisputeState,
+    /// When the dispute was opened, if the input said so
+    #[serde(default)]
+    pub timestamp: Option<Timestamp>,
+}
+
+/// The disputes of a transaction before a dispute, resolve or chargeback of it
+#[derive(Debug, Clone)]
+pub(super) struct DisputesUndo {
+    tx: TransactionId,
+    /// The ids of the open disputes of the transaction
+    open: Option<Vec<DisputeId>>,
+    /// The open disputes of the transaction
+    disputes: Vec<Dispute>,
+    /// The id of the latest dispute
+    last_id: DisputeId,
+}
+
+/// All disputes of an engine
+#[derive(Debug, Clone, Default)]
+pub(super) struct Disputes {
+    disputes: BTreeMap<DisputeId, Dispute>,
+    /// The ids of the open disputes of every transaction, oldest first
+    open: FastMap<TransactionId, Vec<DisputeId>>,
+}
+
+impl Disputes {
+    /// Creates the disputes from the ones saved before.
+    pub(super) fn from_disputes(disputes: impl IntoIterator<Item = Dispute>) -> Disputes {
+        let mut restored = Disputes::default();
+        for dispute in disputes {
+            restored.insert(dispute);
+        }
+        restored
+    }
+
+    /// Opens a dispute holding the amount of the transaction.
+    pub(super) fn open(
+        &mut self,
+        tx: TransactionId,
+        client: ClientId,
+        amount: Amount,
+        timestamp: Option<Timestamp>,
+    ) -> DisputeId {
+        let id = self.disputes.keys().next_back().map_or(1, |id| id + 1);
+        self.insert(Dispute {
+            id,
+            tx,
+            client,
+            amount,
+            remaining: amount,
+            charged_back: Amount::ZERO,
+            state: DisputeState::Open,
+            timestamp,
+        });
+        id
+    }
+
+    /// Settles the amount of the open disputes of the transaction, oldest first. The engine
+    /// never settles more than the disputes hold.
+    pub(super) fn settle(&mut self, tx: TransactionId, mut amount: Amount, charge_back: bool) {
+        let Some(open) = self.open.get_mut(&tx) else {
+            return;
+        };
+        while amount > Amount::ZERO {
+            let Some(dispute) = open.first().and_then(|id| self.disputes.get_mut(id)) else {
+                break;
+            };
+            let settled = dispute.remaining.min(amount);
+            amount -= settled;
+            dispute.remaining -= settled;
+            if charge_back {
+                dispute.charged_back += settled;
+            }
+            if dispute.remaining == Amount::ZERO {
+                dispute.state = if dispute.charged_back > Amount::ZERO {
+                    DisputeState::ChargedBack
+                } else {
+                    DisputeState::Resolved
+                };
+                open.remove(0);
+            }
+        }
+        if open.is_empty() {
+            self.open.remove(&tx);
+        }
+    }
+
+    /// Takes what is needed to put the disputes of the transaction back as they are.
+    pub(super) fn undo_point(&self, tx: TransactionId) -> DisputesUndo {
+        let open = self.open.get(&tx).cloned();
+        let disputes = open
+            .iter()
+            .flatten()
+            .filter_map(|id| self.disputes.get(id).copied())
+            .collect();
+        DisputesUndo {
+            tx,
+            open,
+            disputes,
+            last_id: self.disputes.keys().next_back().copied().unwrap_or(0),
+        }
+    }
+
+    /// Puts the disputes of a transaction back as they were at the undo point.
+    pub(super) fn undo(&mut self, undo: DisputesUndo) {
+        self.disputes.split_off(&(undo.last_id + 1));
+        for dispute in undo.disputes {
+            self.disputes.insert(dispute.id, dispute);
+        }
+        match undo.open {
+            Some(open) => self.open.insert(undo.tx, open),
+            None => self.open.remove(&undo.tx),
+        };
+    }
+
+    pub(super) fn get(&self, id: DisputeId) -> Option<&Dispute> {
+        self.disputes.get(&id)
+    }
+
+    /// All disputes, by id
+    pub(super) fn iter(&self) -> impl Iterator<Item = &Dispute> {
+        self.disputes.values()
+    }
+
+    fn insert(&mut self, dispute: Dispute) {
+        if dispute.state == DisputeState::Open {
+            self.open.entry(dispute.tx).or_default().push(dispute.id);
+        }
+        self.disputes.insert(dispute.id, dispute);
+    }
+}
+
+impl TransactionEngine {
+    /// The dispute with the id, if there is one
+    pub fn get_dispute(&self, id: DisputeId) -> Option<&Dispute> {
+        self.disputes.get(id)
+    }
+
+    /// All disputes ever opened, sorted by id.
+    pub fn disputes(&self) -> impl Iterator<Item = &Dispute> {
+        self.disputes.iter()
+    }
+
+    /// The disputes of the client which are still open, sorted by id.
+    pub fn open_disputes(&self, client: ClientId) -> Vec<&Dispute> {
+        self.disputes
+            .iter()
+            .filter(|dispute| dispute.client == client && dispute.state == DisputeState::Open)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DisputeState;
+    use crate::{Amount, TransactionEngine, TransactionInput, TransactionType};
+
+    fn money(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_disputes_are_settled_oldest_first() {
+        let mut transaction_engine = TransactionEngine::new();
+        let partial = |kind, tx, amount| TransactionInput::new(kind, 1, tx, Some(money(amount)));
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("10")),
+            TransactionInput::deposit(1, 2, money("10")),
+            partial(TransactionType::Dispute, 1, "3"),
+            partial(TransactionType::Dispute, 1, "4"),
+            TransactionInput::dispute(1, 2),
+            partial(TransactionType::Resolve, 1, "5"),
+        ]);
+        assert!(results.iter().all(Result::is_ok));
+
+        let open: Vec<_> = transaction_engine
+            .open_disputes(1)
+            .iter()
+            .map(|dispute| (dispute.id, dispute.tx, dispute.remaining))
+            .collect();
+        assert_eq!(open, vec![(2, 1, money("2")), (3, 2, money("10"))]);
+        assert_eq!(
+            transaction_engine.get_dispute(1).unwrap().state,
+            DisputeState::Resolved
+        );
+
+        transaction_engine
+            .process_transaction(TransactionInput::chargeback(1, 2))
+            .unwrap();
+        assert_eq!(
+            transaction_engine.get_dispute(3).unwrap().state,
+            DisputeState::ChargedBack
+        );
+        assert_eq!(transaction_engine.open_disputes(1).len(), 1);
+    }
+}
@@ -0,0 +1,367 @@
+//! An append-only stream of the state changes of an engine, for event sourcing.
+//!
+//! Every applied deposit, withdrawal, dispute, resolve and chargeback becomes a typed event
+//! carrying the amount it moved, and a transaction locking an account is followed by an
+//! `AccountLocked` event. `TransactionEngine::from_events` rebuilds the accounts, the
+//! transactions kept for disputes and the disputes from the events alone, posting what every
+//! event says without checking it against funds or policies again. Fees, interest and lifecycle
+//! transactions other than locking aren't events, so an engine using them can't be rebuilt
+//! from its events.
+
+use std::io::{self, Write};
+
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::{
+    check_posting, hold_entries, release_entries, reversal_entries, settled_amount, AccountDetails,
+    AccountStatus, LedgerAccount, LedgerEntry, TransactionDetails, TransactionEngine,
+    TransactionProcessingError, TransactionState, TransactionStore,
+};
+use crate::{Amount, ClientId, Timestamp, TransactionId, TransactionInput, TransactionType};
+
+/// A change of the state of an engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum StateEvent {
+    Deposited {
+        tx: TransactionId,
+        client: ClientId,
+        amount: Amount,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<Timestamp>,
+    },
+    Withdrew {
+        tx: TransactionId,
+        client: ClientId,
+        amount: Amount,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<Timestamp>,
+    },
+    /// The amount of the transaction which the dispute holds
+    DisputeOpened {
+        tx: TransactionId,
+        client: ClientId,
+        amount: Amount,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        timestamp: Option<Timestamp>,
+    },
+    /// The amount of the disputed transaction which was released
+    DisputeResolved {
+        tx: TransactionId,
+        client: ClientId,
+        amount: Amount,
+    },
+    /// The amount of the disputed transaction which was reversed
+    ChargedBack {
+        tx: TransactionId,
+        client: ClientId,
+        amount: Amount,
+    },
+    AccountLocked {
+        client: ClientId,
+    },
+}
+
+/// Receives every state change of an engine, in the order it happened
+pub trait EventSink {
+    /// Appends the event to the stream.
+    fn record(&mut self, event: &StateEvent) -> io::Result<()>;
+
+    /// Writes out whatever was buffered.
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// Writes every event as a JSON object on its own line
+pub struct JsonLinesEventSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> JsonLinesEventSink<W> {
+    pub fn new(writer: W) -> JsonLinesEventSink<W> {
+        JsonLinesEventSink { writer }
+    }
+}
+
+impl<W: Write> EventSink for JsonLinesEventSink<W> {
+    fn record(&mut self, event: &StateEvent) -> io::Result<()> {
+        serde_json::to_writer(&mut self.writer, event)?;
+        self.writer.write_all(b"\n")
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl TransactionEngine {
+    /// Rebuilds an engine from the events of another one, in the order they were recorded.
+    /// The engine has the default settings. An event which doesn't follow from the ones before
+    /// it, like a dispute of a transaction which was never deposited, is an error.
+    pub fn from_events<I: IntoIterator<Item = StateEvent>>(
+        events: I,
+    ) -> Result<TransactionEngine, TransactionProcessingError> {
+        let mut transaction_engine = TransactionEngine::new();
+        for event in events {
+            transaction_engine.apply_event(event)?;
+        }
+        Ok(transaction_engine)
+    }
+
+    /// Writes every state change from now on to the sink. If writing fails, the error is
+    /// logged, nothing more is written and the error is returned by `flush_events`.
+    pub fn set_event_sink(&mut self, event_sink: impl EventSink + Send + 'static) {
+        self.event_sink = Some(Box::new(event_sink));
+    }
+
+    /// Flushes the event sink, returning the error it failed with if it did.
+    pub fn flush_events(&mut self) -> io::Result<()> {
+        if let Some(e) = self.event_error.take() {
+            return Err(e);
+        }
+        match &mut self.event_sink {
+            Some(event_sink) => event_sink.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the events of an applied transaction, from the entries it posted. `was_locked`
+    /// is whether the account of its client was locked before it.
+    pub(super) fn record_events(&mut self, transaction: &TransactionInput, was_locked: bool) {
+        let Some(event_sink) = &mut self.event_sink else {
+            return;
+        };
+        let (tx, client, timestamp) = (transaction.tx, transaction.client, transaction.timestamp);
+        // a dispute, resolve or chargeback moves what it holds, releases or reverses in and out
+        // of the held funds of the client
+        let held = self
+            .last_posting
+            .iter()
+            .find(|entry| entry.account == LedgerAccount::ClientHeld(client))
+            .map(|entry| entry.amount.abs());
+        let posted = !self.last_posting.is_empty();
+        let event = match (transaction.kind, transaction.amount, held) {
+            (TransactionType::Deposit, Some(amount), _) if posted => Some(StateEvent::Deposited {
+                tx,
+                client,
+                amount,
+                timestamp,
+            }),
+            (TransactionType::Withdrawal, Some(amount), _) if posted => {
+                Some(StateEvent::Withdrew {
+                    tx,
+                    client,
+                    amount,
+                    timestamp,
+                })
+            }
+            (TransactionType::Dispute, _, Some(amount)) => Some(StateEvent::DisputeOpened {
+                tx,
+                client,
+                amount,
+                timestamp,
+            }),
+            (TransactionType::Resolve, _, Some(amount)) => {
+                Some(StateEvent::DisputeResolved { tx, client, amount })
+            }
+            (TransactionType::Chargeback, _, Some(amount)) => {
+                Some(StateEvent::ChargedBack { tx, client, amount })
+            }
+            _ => None,
+        };
+        let locked = !was_locked
+            && self
+                .accounts
+                .get(&client)
+                .is_some_and(AccountDetails::is_locked);
+        let locked = locked.then_some(StateEvent::AccountLocked { client });
+        for event in event.into_iter().chain(locked) {
+            if let Err(e) = event_sink.record(&event) {
+                error!(
+                    "The event log couldn't be written and no further events will be recorded. Error: {}",
+                    e
+                );
+                self.event_sink = None;
+                self.event_error = Some(e);
+                return;
+            }
+        }
+    }
+
+    /// Applies what the event says to the state, posting its entries.
+    fn apply_event(&mut self, event: StateEvent) -> Result<(), TransactionProcessingError> {
+        match event {
+            StateEvent::Deposited {
+                tx,
+                client,
+                amount,
+                timestamp,
+            }
+            | StateEvent::Withdrew {
+                tx,
+                client,
+                amount,
+                timestamp,
+            } => {
+                if self.transactions.contains_key(&tx)? {
+                    return Err(TransactionProcessingError::DuplicateTransactionId);
+                }
+                let (kind, amount) = match event {
+                    StateEvent::Deposited { .. } => (TransactionType::Deposit, amount),
+                    _ => (TransactionType::Withdrawal, -amount),
+                };
+                let entries = vec![
+                    LedgerEntry::new(LedgerAccount::ClientAvailable(client), amount),
+                    LedgerEntry::new(LedgerAccount::BankClearing, -amount),
+                ];
+                check_posting(&self.ledger, &entries)?;
+                self.transactions.insert(
+                    tx,
+                    TransactionDetails {
+                        kind,
+                        client,
+                        amount: Some(amount.abs()),
+                        state: TransactionState::Normal,
+                        disputed: Amount::ZERO,
+                        charged_back: Amount::ZERO,
+                        timestamp,
+                    },
+                )?;
+                self.post(client, entries);
+            }
+            StateEvent::DisputeOpened {
+                tx,
+                client,
+                amount,
+                timestamp,
+            } => {
+                let t = event_transaction(&mut self.transactions, tx, client)?;
+                let entries = hold_entries(t.kind, client, amount);
+                check_posting(&self.ledger, &entries)?;
+                t.disputed += amount;
+                t.state = TransactionState::Disputed;
+                self.disputes.open(tx, client, amount, timestamp);
+                self.post(client, entries);
+            }
+            StateEvent::DisputeResolved { tx, client, amount } => {
+                let t = event_transaction(&mut self.transactions, tx, client)?;
+                let entries = release_entries(t.kind, client, settled_amount(t, Some(amount))?);
+                check_posting(&self.ledger, &entries)?;
+                t.disputed -= amount;
+                if t.disputed == Amount::ZERO {
+                    t.state = TransactionState::Resolved;
+                }
+                self.disputes.settle(tx, amount, false);
+                self.post(client, entries);
+            }
+            StateEvent::ChargedBack { tx, client, amount } => {
+                let t = event_transaction(&mut self.transactions, tx, client)?;
+                let entries = reversal_entries(t.kind, client, settled_amount(t, Some(amount))?);
+                check_posting(&self.ledger, &entries)?;
+                t.disputed -= amount;
+                t.charged_back += amount;
+                if t.disputed == Amount::ZERO {
+                    t.state = TransactionState::ChargedBack;
+                }
+                self.disputes.settle(tx, amount, true);
+                self.post(client, entries);
+            }
+            StateEvent::AccountLocked { client } => match self.accounts.get_mut(&client) {
+                Some(account) => account.status = AccountStatus::Locked,
+                None => return Err(TransactionProcessingError::AccountNotFound),
+            },
+        }
+        Ok(())
+    }
+}
+
+/// The stored transaction a dispute event refers to, which must be the client's.
+fn event_transaction(
+    transactions: &mut TransactionStore,
+    tx: TransactionId,
+    client: ClientId,
+) -> Result<&mut TransactionDetails, TransactionProcessingError> {
+    match transactions.get_mut(&tx)? {
+        Some(t) if t.client == client => Ok(t),
+        Some(_) => Err(TransactionProcessingError::ClientMismatch),
+        None => Err(TransactionProcessingError::TransactionNotFound),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    use super::{EventSink, JsonLinesEventSink, StateEvent};
+    use crate::{Amount, TransactionEngine, TransactionInput};
+
+    fn money(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    /// Keeps the events where they can still be read once the sink was handed to the engine
+    #[derive(Clone, Default)]
+    struct SharedEvents(Arc<Mutex<Vec<StateEvent>>>);
+
+    impl EventSink for SharedEvents {
+        fn record(&mut self, event: &StateEvent) -> io::Result<()> {
+            self.0.lock().unwrap().push(*event);
+            Ok(())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_state_is_rebuilt_from_events() {
+        let events = SharedEvents::default();
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_event_sink(events.clone());
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("10")),
+            TransactionInput::withdrawal(1, 2, money("3")),
+            TransactionInput::withdrawal(1, 3, money("30")),
+            TransactionInput::deposit(2, 4, money("5")),
+            TransactionInput::dispute(1, 2),
+            TransactionInput::resolve(1, 2),
+            TransactionInput::dispute(2, 4),
+            TransactionInput::chargeback(2, 4),
+            TransactionInput::deposit(3, 5, money("2")),
+            TransactionInput::dispute(3, 5),
+        ]);
+        let events = events.0.lock().unwrap().clone();
+        assert_eq!(events.len(), 10);
+        assert_eq!(events[7], StateEvent::AccountLocked { client: 2 });
+
+        let mut output = Vec::new();
+        let mut sink = JsonLinesEventSink::new(&mut output);
+        events
+            .iter()
+            .try_for_each(|event| sink.record(event))
+            .unwrap();
+        let read: Vec<StateEvent> = serde_json::Deserializer::from_slice(&output)
+            .into_iter()
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(read, events);
+
+        let rebuilt = TransactionEngine::from_events(read).unwrap();
+        assert_eq!(
+            rebuilt.sorted_accounts(),
+            transaction_engine.sorted_accounts()
+        );
+        assert_eq!(rebuilt.open_disputes(3).len(), 1);
+        rebuilt.check_ledger().unwrap();
+
+        assert!(TransactionEngine::from_events([StateEvent::DisputeOpened {
+            tx: 9,
+            client: 1,
+            amount: money("1"),
+            timestamp: None,
+        }])
+        .is_err());
+    }
+}
@@ -0,0 +1,252 @@
+//! The history of every client's account, for statements listing its transactions with the
+//! balances after each of them.
+//!
+//! History isn't kept unless asked for, as it grows with every transaction applied. It can be
+//! bounded to the latest transactions of every client, dropping the oldest ones.
+//!
+//! Every transaction applied gets the next sequence number, so with the whole history kept the
+//! state of any account can be looked up as it was after any transaction.
+
+use std::collections::{HashMap, VecDeque};
+
+use serde::Serialize;
+
+use super::{AccountDetails, AccountStatus, TransactionEngine};
+use crate::{Amount, ClientId, Timestamp, TransactionId, TransactionInput, TransactionType};
+
+/// How much of the history of accounts is kept
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HistoryRetention {
+    /// No history is kept
+    #[default]
+    None,
+    /// Every transaction is kept
+    All,
+    /// The latest transactions of every client are kept
+    Last(usize),
+}
+
+/// A point in the history of the engine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AsOf {
+    /// After the transaction with the sequence number was applied
+    Sequence(u64),
+    /// After the deposit or withdrawal with the id was applied
+    Tx(TransactionId),
+}
+
+/// A transaction on a statement with the balances of the account after it
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct StatementLine {
+    /// The number of transactions applied by the engine, including this one
+    pub seq: u64,
+    pub tx: TransactionId,
+    #[serde(rename = "type")]
+    pub kind: TransactionType,
+    pub amount: Option<Amount>,
+    /// The fee charged by the transaction
+    pub fee: Amount,
+    pub timestamp: Option<Timestamp>,
+    pub available: Amount,
+    pub held: Amount,
+    pub total: Amount,
+    pub status: AccountStatus,
+}
+
+impl StatementLine {
+    /// The account as it was after the transaction
+    pub fn account(&self) -> AccountDetails {
+        AccountDetails {
+            available: self.available,
+            held: self.held,
+            total: self.total,
+            status: self.status,
+        }
+    }
+}
+
+/// The statement lines of every client, oldest first
+#[derive(Debug, Clone, Default)]
+pub(super) struct History {
+    retention: HistoryRetention,
+    clients: HashMap<ClientId, VecDeque<StatementLine>>,
+    /// The sequence number of the last transaction applied
+    sequence: u64,
+}
+
+impl History {
+    /// Counts a transaction as applied.
+    pub(super) fn next_sequence(&mut self) {
+        self.sequence += 1;
+    }
+
+    /// The sequence number of the last transaction applied
+    pub(super) fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Takes the lines of a transaction which was rolled back off the statements.
+    pub(super) fn forget(&mut self, seq: u64) {
+        for lines in self.clients.values_mut() {
+            lines.retain(|line| line.seq != seq);
+        }
+    }
+
+    /// Adds a line for the transaction to the statement of the client, if history is kept.
+    pub(super) fn record(
+        &mut self,
+        client: ClientId,
+        transaction: &TransactionInput,
+        fee: Amount,
+        account: &AccountDetails,
+    ) {
+        let limit = match self.retention {
+            HistoryRetention::None => return,
+            HistoryRetention::All => usize::MAX,
+            HistoryRetention::Last(limit) => limit,
+        };
+        let lines = self.clients.entry(client).or_default();
+        lines.push_back(StatementLine {
+            seq: self.sequence,
+            tx: transaction.tx,
+            kind: transaction.kind,
+            amount: transaction.amount,
+            fee,
+            timestamp: transaction.timestamp,
+            available: account.available,
+            held: account.held,
+            total: account.total,
+            status: account.status,
+        });
+        while lines.len() > limit {
+            lines.pop_front();
+        }
+    }
+}
+
+impl TransactionEngine {
+    /// How much of the history of accounts is kept
+    pub fn history_retention(&self) -> HistoryRetention {
+        self.history.retention
+    }
+
+    /// Keeps the history of accounts for statements from now on, as much as the retention says.
+    /// History kept so far is trimmed to fit, or dropped with `HistoryRetention::None`.
+    pub fn set_history_retention(&mut self, retention: HistoryRetention) {
+        self.history.retention = retention;
+        match retention {
+            HistoryRetention::None => self.history.clients.clear(),
+            HistoryRetention::All => (),
+            HistoryRetention::Last(limit) => {
+                for lines in self.history.clients.values_mut() {
+                    while lines.len() > limit {
+                        lines.pop_front();
+                    }
+                }
+            }
+        }
+    }
+
+    /// The transactions applied to the account of the client with the balances after each of
+    /// them, oldest first, as far as history is kept.
+    pub fn statement(&self, client: ClientId) -> Vec<StatementLine> {
+        self.history
+            .clients
+            .get(&client)
+            .map_or_else(Vec::new, |lines| lines.iter().copied().collect())
+    }
+
+    /// The account of the client as it was at the point in history, if history going back that
+    /// far is kept and the account existed by then.
+    pub fn account_as_of(&self, client: ClientId, as_of: AsOf) -> Option<AccountDetails> {
+        let sequence = self.sequence_of(as_of)?;
+        self.history
+            .clients
+            .get(&client)?
+            .iter()
+            .take_while(|line| line.seq <= sequence)
+            .last()
+            .map(StatementLine::account)
+    }
+
+    /// The sequence number of the point in history, None for a transaction which isn't part
+    /// of the history kept
+    pub fn sequence_of(&self, as_of: AsOf) -> Option<u64> {
+        match as_of {
+            AsOf::Sequence(sequence) => Some(sequence),
+            AsOf::Tx(tx) => self
+                .history
+                .clients
+                .values()
+                .flatten()
+                .find(|line| {
+                    line.tx == tx
+                        && matches!(
+                            line.kind,
+                            TransactionType::Deposit | TransactionType::Withdrawal
+                        )
+                })
+                .map(|line| line.seq),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AsOf, HistoryRetention};
+    use crate::{Amount, TransactionEngine, TransactionInput};
+
+    fn money(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_statement_has_the_running_balances() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_history_retention(HistoryRetention::Last(2));
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("10")),
+            TransactionInput::deposit(2, 2, money("5")),
+            TransactionInput::deposit(1, 3, money("20")).with_timestamp(100),
+            TransactionInput::withdrawal(1, 4, money("50")),
+            TransactionInput::dispute(1, 1),
+        ]);
+        assert!(results[3].is_err());
+
+        let statement: Vec<_> = transaction_engine
+            .statement(1)
+            .iter()
+            .map(|line| (line.tx, line.timestamp, line.available, line.held))
+            .collect();
+        assert_eq!(
+            statement,
+            vec![
+                (3, Some(100), money("30"), money("0")),
+                (1, None, money("20"), money("10")),
+            ]
+        );
+        assert!(transaction_engine.statement(3).is_empty());
+    }
+
+    #[test]
+    fn test_accounts_as_of_earlier_transactions() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_history_retention(HistoryRetention::All);
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(7, 1, money("10")),
+            TransactionInput::deposit(8, 2, money("5")),
+            TransactionInput::withdrawal(7, 3, money("4")),
+            TransactionInput::dispute(7, 1),
+        ]);
+        let available = |as_of| {
+            transaction_engine
+                .account_as_of(7, as_of)
+                .map(|account| account.available)
+        };
+        assert_eq!(available(AsOf::Tx(2)), Some(money("10")));
+        assert_eq!(available(AsOf::Tx(3)), Some(money("6")));
+        assert_eq!(available(AsOf::Sequence(4)), Some(money("-4")));
+        assert_eq!(available(AsOf::Sequence(0)), None);
+        assert_eq!(available(AsOf::Tx(9)), None);
+    }
+}
@@ -0,0 +1,358 @@
+//! Interest on the available funds of accounts, for inputs with timestamps.
+//!
+//! The timestamps of the input drive a clock which only moves forward. Interest accrues on the
+//! positive available funds of every active or frozen account for as long as they are held, at
+//! a yearly rate. An account's accrual is brought up to date whenever its balance changes, so
+//! no pass over all accounts is needed until the clock crosses the end of a period. Then the
+//! interest accrued by every account is paid as a synthetic `interest` transaction, posted
+//! against the `InterestExpense` ledger account. If the clock crosses the ends of several
+//! periods at once, the interest of all of them is paid together at the end of the last one.
+//! Fractions of the smallest unit are carried over to the next period.
+//!
+//! Interest transactions are numbered from 1 in an id space of their own. They aren't kept with
+//! the transactions of the input, so they don't take any of their ids and can't be disputed,
+//! and they don't take a sequence number either. Every credit of a payment is checked before
+//! any is posted, so a payment is made to all accounts or to none.
+
+use std::collections::HashMap;
+
+use super::{
+    AccountDetails, AccountStatus, LedgerAccount, LedgerEntry, TransactionEngine,
+    TransactionProcessingError,
+};
+use crate::{
+    Amount, ClientId, MoneyOps, Timestamp, TransactionId, TransactionInput, TransactionType,
+};
+
+/// The seconds between two payments of interest if not told otherwise, a day
+pub const DEFAULT_INTEREST_PERIOD: u64 = 24 * 60 * 60;
+
+/// The furthest a timestamp may be past the clock, ten years. Later ones are most likely in
+/// another unit, e.g. milliseconds, and are rejected.
+pub const MAX_CLOCK_STEP: u64 = 10 * 365 * 24 * 60 * 60;
+
+const SECONDS_PER_YEAR: i128 = 365 * 24 * 60 * 60;
+
+/// How much interest is paid and how often
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterestPolicy {
+    /// The yearly rate in hundredths of a percent
+    pub rate_bps: u32,
+    /// The seconds between two payments, at least one
+    pub period: u64,
+}
+
+/// The interest accrued by the accounts of an engine
+#[derive(Debug, Clone)]
+pub(super) struct InterestAccrual {
+    policy: InterestPolicy,
+    /// The latest timestamp of the input, None until one was seen
+    clock: Option<Timestamp>,
+    next_payment: Timestamp,
+    /// For every account, when its accrual was last brought up to date and the interest accrued
+    /// since the last payment, in minor units times basis points times seconds
+    accounts: HashMap<ClientId, (Timestamp, i128)>,
+    /// The id of the next interest transaction
+    next_tx: TransactionId,
+}
+
+impl InterestAccrual {
+    fn new(policy: InterestPolicy) -> InterestAccrual {
+        InterestAccrual {
+            policy,
+            clock: None,
+            next_payment: 0,
+            accounts: HashMap::new(),
+            next_tx: 1,
+        }
+    }
+
+    /// Brings the accrual of the client up to the clock, with the account as it was since the
+    /// last time. Does nothing before the clock was started.
+    pub(super) fn accrue(&mut self, client: ClientId, account: Option<&AccountDetails>) {
+        let Some(clock) = self.clock else {
+            return;
+        };
+        let (since, accrued) = self.accounts.entry(client).or_insert((clock, 0));
+        if let Some(account) = account.filter(|account| earns_interest(account)) {
+            // saturating, as the balances of wider amounts can overflow even an i128 here
+            let interest = account
+                .available
+                .to_minor_units()
+                .saturating_mul(i128::from(self.policy.rate_bps))
+                .saturating_mul(i128::from(clock.saturating_sub(*since)));
+            *accrued = accrued.saturating_add(interest);
+        }
+        *since = clock;
+    }
+
+    /// The accrual of the client, to put it back with `restore_accrual`
+    pub(super) fn accrual(&self, client: ClientId) -> Option<(Timestamp, i128)> {
+        self.accounts.get(&client).copied()
+    }
+
+    /// Puts the accrual of the client back as it was.
+    pub(super) fn restore_accrual(&mut self, client: ClientId, accrual: Option<(Timestamp, i128)>) {
+        match accrual {
+            Some(accrual) => self.accounts.insert(client, accrual),
+            None => self.accounts.remove(&client),
+        };
+    }
+
+    /// The interest of the client which can be paid, without the fraction. Fails if it is more
+    /// than an amount can hold.
+    fn payable(&self, client: ClientId) -> Result<Amount, TransactionProcessingError> {
+        let Some((_, accrued)) = self.accounts.get(&client) else {
+            return Ok(Amount::ZERO);
+        };
+        Amount::try_from_minor_units(*accrued / (10_000 * SECONDS_PER_YEAR))
+            .ok_or(TransactionProcessingError::BalanceOverflow)
+    }
+
+    /// Takes the interest which was paid to the client off its accrual, leaving the fraction
+    /// behind.
+    fn take_paid(&mut self, client: ClientId, amount: Amount) {
+        if let Some((_, accrued)) = self.accounts.get_mut(&client) {
+            *accrued -= amount.to_minor_units() * 10_000 * SECONDS_PER_YEAR;
+        }
+    }
+}
+
+/// The entries paying the client interest
+fn interest_entries(client: ClientId, amount: Amount) -> [LedgerEntry; 2] {
+    [
+        LedgerEntry::new(LedgerAccount::ClientAvailable(client), amount),
+        LedgerEntry::new(LedgerAccount::InterestExpense, -amount),
+    ]
+}
+
+/// Whether interest accrues on the account
+fn earns_interest(account: &AccountDetails) -> bool {
+    matches!(
+        account.status,
+        AccountStatus::Active | AccountStatus::Frozen
+    ) && account.available > Amount::ZERO
+}
+
+impl TransactionEngine {
+    /// The interest paid on available funds, if any
+    pub fn interest_policy(&self) -> Option<InterestPolicy> {
+        self.interest.as_ref().map(|interest| interest.policy)
+    }
+
+    /// Pays interest according to the policy from now on, or no interest with None. Interest
+    /// only accrues with the timestamps of the transactions, and what was accrued so far is
+    /// dropped. Accrued interest which wasn't paid yet isn't part of snapshots either.
+    pub fn set_interest_policy(&mut self, interest_policy: Option<InterestPolicy>) {
+        self.interest = interest_policy.map(InterestAccrual::new);
+    }
+
+    /// Moves the clock forward to the timestamp, paying the interest of the periods which ended
+    /// by then. Timestamps before the clock leave it where it is, and timestamps more than
+    /// `MAX_CLOCK_STEP` past it are rejected.
+    pub(super) fn advance_clock(
+        &mut self,
+        timestamp: Timestamp,
+    ) -> Result<(), TransactionProcessingError> {
+        let Some(interest) = &mut self.interest else {
+            return Ok(());
+        };
+        let Some(clock) = interest.clock else {
+            interest.clock = Some(timestamp);
+            interest.next_payment = timestamp.saturating_add(interest.policy.period);
+            for client in self.accounts.keys() {
+                interest.accrue(*client, None);
+            }
+            return Ok(());
+        };
+        if timestamp <= clock {
+            return Ok(());
+        }
+        if timestamp - clock > MAX_CLOCK_STEP {
+            return Err(TransactionProcessingError::TimestampTooFarAhead(timestamp));
+        }
+        if let Some(interest) = &self.interest {
+            let due = (interest.clock, interest.next_payment);
+            if let Some(payment) = self.due_payment(timestamp) {
+                if let Err(e) = self.pay_interest(payment) {
+                    // nothing was paid, so the payment is due again with the next timestamp
+                    if let Some(interest) = &mut self.interest {
+                        (interest.clock, interest.next_payment) = due;
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        if let Some(interest) = &mut self.interest {
+            interest.clock = Some(timestamp);
+        }
+        Ok(())
+    }
+
+    /// Moves the clock to the end of the last period which ended by the timestamp, if any.
+    fn due_payment(&mut self, timestamp: Timestamp) -> Option<Timestamp> {
+        let interest = self.interest.as_mut()?;
+        let first = interest.next_payment;
+        if first > timestamp {
+            return None;
+        }
+        let period = interest.policy.period.max(1);
+        let payment = first + (timestamp - first) / period * period;
+        interest.clock = Some(payment);
+        interest.next_payment = payment.saturating_add(period);
+        Some(payment)
+    }
+
+    /// Pays every account the interest it accrued, in the order of the client ids. Nothing is
+    /// paid if any of the credits can't be posted.
+    fn pay_interest(&mut self, payment: Timestamp) -> Result<(), TransactionProcessingError> {
+        let Some(interest) = &mut self.interest else {
+            return Ok(());
+        };
+        let mut clients: Vec<ClientId> = self.accounts.keys().copied().collect();
+        clients.sort_unstable();
+        let mut payments = Vec::new();
+        for client in clients {
+            interest.accrue(client, self.accounts.get(&client));
+            let amount = interest.payable(client)?;
+            if amount != Amount::ZERO {
+                payments.push((client, amount));
+            }
+        }
+        let all_entries: Vec<LedgerEntry> = payments
+            .iter()
+            .flat_map(|(client, amount)| interest_entries(*client, *amount))
+            .collect();
+        if !self.ledger.can_post(&all_entries) {
+            return Err(TransactionProcessingError::BalanceOverflow);
+        }
+        for (client, amount) in payments {
+            let Some(interest) = &mut self.interest else {
+                return Ok(());
+            };
+            interest.take_paid(client, amount);
+            let tx = interest.next_tx;
+            interest.next_tx = interest.next_tx.saturating_add(1);
+            let was_locked = self
+                .accounts
+                .get(&client)
+                .is_some_and(AccountDetails::is_locked);
+            let before = match self.audit_sink {
+                Some(_) => self.accounts.get(&client).cloned(),
+                None => None,
+            };
+            self.last_fee = Amount::ZERO;
+            self.post(client, interest_entries(client, amount).to_vec());
+            let transaction =
+                TransactionInput::new(TransactionType::Interest, client, tx, Some(amount))
+                    .with_timestamp(payment);
+            self.stats
+                .count_transaction(TransactionType::Interest, None);
+            self.notify_applied(&transaction, was_locked, before, None);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{InterestPolicy, MAX_CLOCK_STEP};
+    use crate::transaction_engine::HistoryRetention;
+    use crate::{
+        Amount, MoneyOps, TransactionEngine, TransactionId, TransactionInput,
+        TransactionProcessingError, TransactionType,
+    };
+
+    #[test]
+    fn test_interest_is_paid_at_the_end_of_every_period() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_interest_policy(Some(InterestPolicy {
+            rate_bps: 365 * 100,
+            period: 86_400,
+        }));
+        let day = 86_400;
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "100".parse().unwrap()).with_timestamp(0),
+            TransactionInput::deposit(1, 2, "100".parse().unwrap()).with_timestamp(day / 2),
+            TransactionInput::deposit(2, 3, "1".parse().unwrap()).with_timestamp(day),
+            TransactionInput::deposit(2, 4, "1".parse().unwrap()).with_timestamp(3 * day),
+        ]);
+        assert!(results.iter().all(Result::is_ok));
+        // 1% a day on 100 for half a day and on 200 for another half, then 1% of 201.5 for the
+        // two days until the next timestamp
+        assert_eq!(
+            transaction_engine.get_account(1).unwrap().available,
+            "205.5300".parse::<Amount>().unwrap()
+        );
+        assert!(transaction_engine.check_ledger().is_ok());
+
+        // interest doesn't take the ids of the input
+        assert!(transaction_engine
+            .process_transaction(
+                TransactionInput::deposit(1, TransactionId::MAX, "5".parse().unwrap())
+                    .with_timestamp(3 * day)
+            )
+            .is_ok());
+        // a timestamp in milliseconds is far beyond the clock
+        assert!(matches!(
+            transaction_engine.process_transaction(
+                TransactionInput::deposit(1, 5, "1".parse().unwrap())
+                    .with_timestamp(3 * day + MAX_CLOCK_STEP + 1)
+            ),
+            Err(TransactionProcessingError::TimestampTooFarAhead(_))
+        ));
+    }
+
+    #[test]
+    fn test_interest_takes_no_sequence_number() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_history_retention(HistoryRetention::All);
+        transaction_engine.set_interest_policy(Some(InterestPolicy {
+            rate_bps: 365 * 100,
+            period: 86_400,
+        }));
+        transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "100".parse().unwrap()).with_timestamp(0),
+            TransactionInput::deposit(1, 2, "1".parse().unwrap()).with_timestamp(86_400),
+        ]);
+        let lines: Vec<(TransactionType, u64)> = transaction_engine
+            .statement(1)
+            .iter()
+            .map(|line| (line.kind, line.seq))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                (TransactionType::Deposit, 1),
+                (TransactionType::Interest, 1),
+                (TransactionType::Deposit, 2)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_interest_is_paid_to_all_accounts_or_none() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_interest_policy(Some(InterestPolicy {
+            rate_bps: 365 * 100,
+            period: 86_400,
+        }));
+        let hundred: Amount = "100".parse().unwrap();
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, hundred).with_timestamp(0),
+            TransactionInput::deposit(2, 2, Amount::MAX - hundred).with_timestamp(0),
+            TransactionInput::deposit(1, 3, hundred).with_timestamp(86_400),
+        ]);
+        assert!(results[0].is_ok() && results[1].is_ok());
+        // the interest of client 2 can't be credited, so client 1 isn't paid either
+        assert!(matches!(
+            results[2],
+            Err(TransactionProcessingError::BalanceOverflow)
+        ));
+        assert_eq!(
+            transaction_engine.get_account(1).unwrap().available,
+            hundred
+        );
+        assert!(transaction_engine.check_ledger().is_ok());
+    }
+}
@@ -0,0 +1,289 @@
+//! The double-entry ledger underneath the balances of accounts.
+//!
+//! Every transaction is posted as entries which sum up to zero, e.g. a deposit adds its amount
+//! to the available funds of the client and takes it from the bank clearing account. The
+//! available and held funds of an account are the balances of the client's ledger accounts and
+//! the total is their sum, so a posting which doesn't balance shows up in [`Ledger::verify`].
+//! A posting which would take a balance of a client, or its total, beyond the range of an
+//! amount is refused by [`Ledger::can_post`] before anything is changed. The accounts of the
+//! engine, like the bank clearing account, are shared by all clients and kept wider, so that
+//! the funds of one client never make a transaction of another overflow.
+
+use std::collections::HashMap;
+
+use thiserror::Error;
+
+use super::FastMap;
+use crate::{Amount, ClientId, MoneyOps};
+
+/// An account of the ledger
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum LedgerAccount {
+    /// The funds a client can use
+    ClientAvailable(ClientId),
+    /// The funds of a client held by disputes
+    ClientHeld(ClientId),
+    /// Where deposits come from and withdrawals go to
+    BankClearing,
+    /// The counterpart of the funds held for disputed withdrawals until the dispute is settled
+    DisputesPending,
+    /// The counterpart of the funds reversed by chargebacks
+    Chargebacks,
+    /// The counterpart of balances restored from a snapshot
+    OpeningBalances,
+    /// The counterpart of the interest paid to clients
+    InterestExpense,
+}
+
+impl LedgerAccount {
+    /// Whether the account belongs to a client rather than the engine
+    pub fn is_client(&self) -> bool {
+        matches!(
+            self,
+            LedgerAccount::ClientAvailable(_) | LedgerAccount::ClientHeld(_)
+        )
+    }
+}
+
+/// An amount added to the balance of a ledger account, negative to take it away
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct LedgerEntry {
+    pub account: LedgerAccount,
+    pub amount: Amount,
+}
+
+impl LedgerEntry {
+    pub(crate) fn new(account: LedgerAccount, amount: Amount) -> LedgerEntry {
+        LedgerEntry { account, amount }
+    }
+}
+
+/// All errors which can be found by checking the ledger
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum LedgerError {
+    #[error("the entries of the ledger sum up to {0} instead of zero")]
+    Unbalanced(Amount),
+
+    #[error("the account of client {0} doesn't match its ledger balances")]
+    AccountMismatch(ClientId),
+}
+
+/// The balances of all ledger accounts
+#[derive(Debug, Clone, Default)]
+pub struct Ledger {
+    /// The balances of the accounts of clients
+    balances: FastMap<LedgerAccount, Amount>,
+    /// The balances of the accounts of the engine in minor units, wider than an amount
+    system_balances: FastMap<LedgerAccount, i128>,
+    postings: u64,
+}
+
+impl Ledger {
+    /// The balance of the ledger account, zero if nothing was posted to it. The balance of an
+    /// account of the engine is capped at the largest amount, `balance_minor_units` has it in
+    /// full.
+    pub fn balance(&self, account: LedgerAccount) -> Amount {
+        if account.is_client() {
+            return self.balances.get(&account).copied().unwrap_or_default();
+        }
+        let balance = self.balance_minor_units(account);
+        Amount::try_from_minor_units(balance).unwrap_or(if balance > 0 {
+            Amount::MAX
+        } else {
+            -Amount::MAX
+        })
+    }
+
+    /// The balance of the ledger account in minor units, ten-thousandths
+    pub fn balance_minor_units(&self, account: LedgerAccount) -> i128 {
+        if account.is_client() {
+            return self.balance(account).to_minor_units();
+        }
+        self.system_balances
+            .get(&account)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// The balances of all ledger accounts anything was posted to, sorted by account, capped
+    /// like `balance`
+    pub fn balances(&self) -> Vec<(LedgerAccount, Amount)> {
+        let mut balances: Vec<_> = self
+            .balances
+            .keys()
+            .chain(self.system_balances.keys())
+            .map(|account| (*account, self.balance(*account)))
+            .collect();
+        balances.sort_by_key(|(account, _)| *account);
+        balances
+    }
+
+    /// The number of postings made
+    pub fn postings(&self) -> u64 {
+        self.postings
+    }
+
+    /// Checks that all entries ever posted sum up to zero.
+    pub fn verify(&self) -> Result<(), LedgerError> {
+        // summed wider, as the balances can be large enough to overflow on the way to zero
+        let sum: i128 = self
+            .balances
+            .values()
+            .map(|balance| balance.to_minor_units())
+            .chain(self.system_balances.values().copied())
+            .sum();
+        if sum == 0 {
+            Ok(())
+        } else {
+            let sum = Amount::try_from_minor_units(sum).unwrap_or(if sum > 0 {
+                Amount::MAX
+            } else {
+                -Amount::MAX
+            });
+            Err(LedgerError::Unbalanced(sum))
+        }
+    }
+
+    /// Whether the entries can be posted without any balance, or the total of any client,
+    /// overflowing. The accounts of the engine are wide enough for any posting.
+    pub(crate) fn can_post(&self, entries: &[LedgerEntry]) -> bool {
+        let mut balances: HashMap<LedgerAccount, Amount> = HashMap::new();
+        for entry in entries.iter().filter(|entry| entry.account.is_client()) {
+            let balance = balances
+                .get(&entry.account)
+                .copied()
+                .unwrap_or_else(|| self.balance(entry.account));
+            let Some(balance) = balance.checked_add(entry.amount) else {
+                return false;
+            };
+            balances.insert(entry.account, balance);
+        }
+        let balance = |account| {
+            balances
+                .get(&account)
+                .copied()
+                .unwrap_or_else(|| self.balance(account))
+        };
+        balances.keys().all(|account| match *account {
+            LedgerAccount::ClientAvailable(client) | LedgerAccount::ClientHeld(client) => {
+                balance(LedgerAccount::ClientAvailable(client))
+                    .checked_add(balance(LedgerAccount::ClientHeld(client)))
+                    .is_some()
+            }
+            _ => true,
+        })
+    }
+
+    /// Adds the entries to the balances.
+    pub(crate) fn post(&mut self, entries: &[LedgerEntry]) {
+        for entry in entries {
+            if entry.account.is_client() {
+                *self.balances.entry(entry.account).or_default() += entry.amount;
+            } else {
+                *self.system_balances.entry(entry.account).or_default() +=
+                    entry.amount.to_minor_units();
+            }
+        }
+        self.postings += 1;
+    }
+
+    /// Takes back a posting, e.g. of a transaction which couldn't be logged.
+    pub(crate) fn unpost(&mut self, entries: &[LedgerEntry]) {
+        for entry in entries {
+            if entry.account.is_client() {
+                *self.balances.entry(entry.account).or_default() -= entry.amount;
+            } else {
+                *self.system_balances.entry(entry.account).or_default() -=
+                    entry.amount.to_minor_units();
+            }
+        }
+        self.postings -= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Ledger, LedgerAccount, LedgerEntry, LedgerError};
+    use crate::{Amount, ClientId, MoneyOps, TransactionEngine, TransactionInput};
+
+    fn money(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    fn deposit(client: ClientId, amount: Amount) -> [LedgerEntry; 2] {
+        [
+            LedgerEntry::new(LedgerAccount::ClientAvailable(client), amount),
+            LedgerEntry::new(LedgerAccount::BankClearing, -amount),
+        ]
+    }
+
+    #[test]
+    fn test_postings_change_the_balances() {
+        let mut ledger = Ledger::default();
+        ledger.post(&deposit(1, money("5")));
+        ledger.post(&[
+            LedgerEntry::new(LedgerAccount::ClientAvailable(1), money("-2")),
+            LedgerEntry::new(LedgerAccount::ClientHeld(1), money("2")),
+        ]);
+        assert_eq!(ledger.postings(), 2);
+        assert_eq!(
+            ledger.balances(),
+            [
+                (LedgerAccount::ClientAvailable(1), money("3")),
+                (LedgerAccount::ClientHeld(1), money("2")),
+                (LedgerAccount::BankClearing, money("-5")),
+            ]
+        );
+        assert_eq!(ledger.verify(), Ok(()));
+
+        ledger.unpost(&deposit(1, money("5")));
+        assert_eq!(ledger.postings(), 1);
+        assert_eq!(ledger.balance(LedgerAccount::BankClearing), Amount::ZERO);
+        assert_eq!(ledger.verify(), Ok(()));
+    }
+
+    #[test]
+    fn test_unbalanced_postings_are_found() {
+        let mut ledger = Ledger::default();
+        ledger.post(&[LedgerEntry::new(
+            LedgerAccount::ClientAvailable(1),
+            money("1"),
+        )]);
+        assert_eq!(ledger.verify(), Err(LedgerError::Unbalanced(money("1"))));
+    }
+
+    #[test]
+    fn test_client_balances_are_kept_within_an_amount() {
+        let mut ledger = Ledger::default();
+        ledger.post(&deposit(1, Amount::MAX));
+        assert!(!ledger.can_post(&deposit(1, money("1"))));
+        // the total of available and held funds must fit too
+        assert!(!ledger.can_post(&[
+            LedgerEntry::new(LedgerAccount::ClientHeld(1), money("1")),
+            LedgerEntry::new(LedgerAccount::DisputesPending, money("-1")),
+        ]));
+        assert!(ledger.can_post(&deposit(2, money("1"))));
+    }
+
+    #[test]
+    fn test_shared_accounts_dont_overflow_for_other_clients() {
+        let mut ledger = Ledger::default();
+        ledger.post(&deposit(1, Amount::MAX));
+        assert!(ledger.can_post(&deposit(2, Amount::MAX)));
+        ledger.post(&deposit(2, Amount::MAX));
+        assert_eq!(
+            ledger.balance_minor_units(LedgerAccount::BankClearing),
+            -2 * Amount::MAX.to_minor_units()
+        );
+        assert_eq!(ledger.balance(LedgerAccount::BankClearing), -Amount::MAX);
+        assert_eq!(ledger.verify(), Ok(()));
+
+        let mut transaction_engine = TransactionEngine::new();
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, money("900000000000000")),
+            TransactionInput::deposit(2, 2, money("100000000000000")),
+        ]);
+        assert!(results.iter().all(Result::is_ok));
+        assert!(transaction_engine.check_ledger().is_ok());
+    }
+}
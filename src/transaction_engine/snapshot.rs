@@ -0,0 +1,318 @@
+//! Saving the state of an engine to a file and restoring it, so that long running jobs can
+//! survive restarts.
+//!
+//! A snapshot is a JSON document holding a format version, every account, every deposit and
+//! withdrawal kept for disputes and every dispute. Amounts are strings so that they are restored
+//! exactly. The policies of the engine are configuration rather than state and aren't part of a
+//! snapshot.
+//!
+//! Version 1 only knew whether an account was locked. Its snapshots are still restored, with
+//! every account which wasn't locked being active. Versions 1 and 2 only disputed whole
+//! transactions, so their disputed and charged back transactions are restored as such. Before
+//! version 4 disputes had no ids, and every disputed transaction gets an open dispute for its
+//! whole amount.
+
+use std::collections::BTreeMap;
+use std::io;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::disputes::{Dispute, Disputes};
+use super::ledger::{Ledger, LedgerAccount, LedgerEntry};
+use super::store::TransactionStore;
+use super::{
+    AccountDetails, AccountStatus, FastMap, TransactionDetails, TransactionEngine, TransactionState,
+};
+use crate::{Amount, ClientId, Timestamp, TransactionId, TransactionType};
+
+/// The version of the snapshot format written by this version of the engine
+pub const SNAPSHOT_VERSION: u32 = 4;
+
+/// All errors which can happen when saving or restoring a snapshot
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    #[error("couldn't read or write the snapshot: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("the snapshot is malformed: {0}")]
+    Malformed(#[from] serde_json::Error),
+
+    #[error("snapshot version {0} is not supported, expected version {SNAPSHOT_VERSION} or older")]
+    UnsupportedVersion(u32),
+
+    #[error("the snapshot contains client {0} more than once")]
+    DuplicateClient(ClientId),
+
+    #[error("the snapshot contains transaction {0} more than once")]
+    DuplicateTransaction(TransactionId),
+
+    #[error(
+        "the total of client {0} in the snapshot isn't the sum of its available and held funds"
+    )]
+    InconsistentAccount(ClientId),
+
+    #[error("the balances of client {0} in the snapshot are too large to be kept")]
+    BalanceOverflow(ClientId),
+}
+
+/// The state of an engine as it is written to a snapshot
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    version: u32,
+    accounts: Vec<AccountSnapshot>,
+    transactions: Vec<TransactionSnapshot>,
+    /// Only written by version 4 and later
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    disputes: Option<Vec<Dispute>>,
+    /// The position of the last message applied from every partition, only written by the
+    /// stream processor
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) source_offsets: Option<BTreeMap<i32, u64>>,
+}
+
+/// Only the version, read first so that a snapshot of another version gets a proper error
+#[derive(Deserialize)]
+struct SnapshotVersion {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AccountSnapshot {
+    client: ClientId,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    /// Only written by version 2 and later
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status: Option<AccountStatus>,
+    /// Only written by version 1, which had no status
+    #[serde(default, skip_serializing)]
+    locked: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TransactionSnapshot {
+    tx: TransactionId,
+    #[serde(rename = "type")]
+    kind: TransactionType,
+    client: ClientId,
+    amount: Option<Amount>,
+    state: TransactionState,
+    /// Only written by version 3 and later
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    disputed: Option<Amount>,
+    /// Only written by version 3 and later
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    charged_back: Option<Amount>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    timestamp: Option<Timestamp>,
+}
+
+impl TransactionEngine {
+    /// Writes the accounts and transactions of the engine to the writer. Accounts and
+    /// transactions are sorted by id so that the same state always gives the same snapshot.
+    pub fn snapshot<W: io::Write>(&self, mut writer: W) -> Result<(), SnapshotError> {
+        serde_json::to_writer(&mut writer, &self.to_snapshot()?)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Creates an engine from a snapshot written by `snapshot`. The engine uses the default
+    /// policies.
+    pub fn restore<R: io::Read>(reader: R) -> Result<TransactionEngine, SnapshotError> {
+        Ok(TransactionEngine::restore_with_source_offsets(reader)?.0)
+    }
+
+    /// Like `restore`, also returning the source offsets saved with the snapshot, if any.
+    pub(crate) fn restore_with_source_offsets<R: io::Read>(
+        reader: R,
+    ) -> Result<(TransactionEngine, BTreeMap<i32, u64>), SnapshotError> {
+        let value: serde_json::Value = serde_json::from_reader(reader)?;
+        let SnapshotVersion { version } = serde_json::from_value(value.clone())?;
+        if !(1..=SNAPSHOT_VERSION).contains(&version) {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let mut snapshot: Snapshot = serde_json::from_value(value)?;
+        let source_offsets = snapshot.source_offsets.take().unwrap_or_default();
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.replace_state(snapshot)?;
+        Ok((transaction_engine, source_offsets))
+    }
+
+    /// The state of the engine as a snapshot, e.g. to embed it in a checkpoint
+    pub(crate) fn to_snapshot(&self) -> io::Result<Snapshot> {
+        let mut transactions: Vec<TransactionSnapshot> = self
+            .transactions
+            .entries()?
+            .into_iter()
+            .map(|(tx, details)| TransactionSnapshot {
+                tx,
+                kind: details.kind,
+                client: details.client,
+                amount: details.amount,
+                state: details.state,
+                disputed: Some(details.disputed),
+                charged_back: Some(details.charged_back),
+                timestamp: details.timestamp,
+            })
+            .collect();
+        transactions.sort_by_key(|transaction| transaction.tx);
+        Ok(Snapshot {
+            version: SNAPSHOT_VERSION,
+            accounts: self
+                .sorted_accounts()
+                .into_iter()
+                .map(|(client, account)| AccountSnapshot {
+                    client,
+                    available: account.available,
+                    held: account.held,
+                    total: account.total,
+                    status: Some(account.status),
+                    locked: false,
+                })
+                .collect(),
+            transactions,
+            disputes: Some(self.disputes.iter().copied().collect()),
+            source_offsets: None,
+        })
+    }
+
+    /// Replaces the accounts and transactions with the ones of the snapshot, keeping the
+    /// policies, the transaction cache size and the write-ahead log of the engine.
+    pub(crate) fn replace_state(&mut self, snapshot: Snapshot) -> Result<(), SnapshotError> {
+        let mut accounts =
+            FastMap::with_capacity_and_hasher(snapshot.accounts.len(), Default::default());
+        // the history of the balances isn't kept, so they are opened against a single account
+        let mut ledger = Ledger::default();
+        for account in snapshot.accounts {
+            let details = AccountDetails {
+                available: account.available,
+                held: account.held,
+                total: account.total,
+                status: match account.status {
+                    Some(status) => status,
+                    None if account.locked => AccountStatus::Locked,
+                    None => AccountStatus::Active,
+                },
+            };
+            if details.available.checked_add(details.held) != Some(details.total) {
+                return Err(SnapshotError::InconsistentAccount(account.client));
+            }
+            let entries = [
+                LedgerEntry::new(
+                    LedgerAccount::ClientAvailable(account.client),
+                    details.available,
+                ),
+                LedgerEntry::new(LedgerAccount::ClientHeld(account.client), details.held),
+                LedgerEntry::new(LedgerAccount::OpeningBalances, -details.total),
+            ];
+            if !ledger.can_post(&entries) {
+                return Err(SnapshotError::BalanceOverflow(account.client));
+            }
+            ledger.post(&entries);
+            if accounts.insert(account.client, details).is_some() {
+                return Err(SnapshotError::DuplicateClient(account.client));
+            }
+        }
+        let mut transactions = TransactionStore::new();
+        // only used for snapshots written before disputes had ids
+        let mut migrated = Disputes::default();
+        if let Some(cache_size) = self.transactions.cache_size() {
+            transactions.set_cache_size(cache_size)?;
+        }
+        for transaction in snapshot.transactions {
+            if transactions.contains_key(&transaction.tx)? {
+                return Err(SnapshotError::DuplicateTransaction(transaction.tx));
+            }
+            // before version 3, the state tells whether the whole amount was disputed
+            let whole_amount_if = |state: TransactionState| {
+                if transaction.state == state {
+                    transaction.amount.unwrap_or_default()
+                } else {
+                    Amount::ZERO
+                }
+            };
+            let details = TransactionDetails {
+                kind: transaction.kind,
+                client: transaction.client,
+                amount: transaction.amount,
+                state: transaction.state,
+                disputed: transaction
+                    .disputed
+                    .unwrap_or_else(|| whole_amount_if(TransactionState::Disputed)),
+                charged_back: transaction
+                    .charged_back
+                    .unwrap_or_else(|| whole_amount_if(TransactionState::ChargedBack)),
+                timestamp: transaction.timestamp,
+            };
+            if details.disputed > Amount::ZERO {
+                migrated.open(transaction.tx, details.client, details.disputed, None);
+            }
+            transactions.insert(transaction.tx, details)?;
+        }
+        self.accounts = accounts;
+        self.transactions = transactions;
+        self.disputes = match snapshot.disputes {
+            Some(disputes) => Disputes::from_disputes(disputes),
+            None => migrated,
+        };
+        self.ledger = ledger;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SnapshotError;
+    use crate::{AccountStatus, TransactionEngine, TransactionInput};
+
+    #[test]
+    fn test_snapshot_and_restore() {
+        let mut transaction_engine = TransactionEngine::new();
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "10".parse().unwrap()),
+            TransactionInput::deposit(2, 2, "1.2345".parse().unwrap()),
+            TransactionInput::dispute(1, 1),
+        ]);
+        assert!(results.iter().all(Result::is_ok));
+
+        let mut snapshot = Vec::new();
+        transaction_engine.snapshot(&mut snapshot).unwrap();
+        let mut restored = TransactionEngine::restore(snapshot.as_slice()).unwrap();
+        assert_eq!(
+            restored.sorted_accounts(),
+            transaction_engine.sorted_accounts()
+        );
+        // the dispute state and the transaction ids survive the restore
+        assert!(restored
+            .process_transaction(TransactionInput::chargeback(1, 1))
+            .is_ok());
+        assert!(restored
+            .process_transaction(TransactionInput::deposit(2, 2, "1".parse().unwrap()))
+            .is_err());
+    }
+
+    #[test]
+    fn test_restore_rejects_other_versions() {
+        let snapshot = r#"{"version": 5, "accounts": {}}"#;
+        assert!(matches!(
+            TransactionEngine::restore(snapshot.as_bytes()),
+            Err(SnapshotError::UnsupportedVersion(5))
+        ));
+
+        // version 1 had a locked flag instead of a status
+        let snapshot = r#"{"version": 1, "transactions": [], "accounts": [
+            {"client": 1, "available": "0", "held": "0", "total": "0", "locked": true},
+            {"client": 2, "available": "1", "held": "0", "total": "1", "locked": false}]}"#;
+        let restored = TransactionEngine::restore(snapshot.as_bytes()).unwrap();
+        assert_eq!(
+            restored.get_account(1).unwrap().status,
+            AccountStatus::Locked
+        );
+        assert_eq!(
+            restored.get_account(2).unwrap().status,
+            AccountStatus::Active
+        );
+    }
+}
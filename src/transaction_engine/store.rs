@@ -0,0 +1,538 @@
+//! The store of deposits and withdrawals kept for disputes.
+//!
+//! By default every transaction is kept in memory. With a cache size, only that many of the
+//! most recently used transactions are kept in memory and older ones are spilled to a file in
+//! the temp directory, so that inputs of any size can be processed in constant memory. The spill
+//! file is addressed directly by transaction id, with a fixed size record per id, so a spilled
+//! transaction is found with a single read and no index has to be kept in memory. Most
+//! filesystems store the gaps between ids as holes, which take no space on disk.
+
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use super::{FastMap, TransactionDetails, TransactionState};
+use crate::{Amount, ClientId, MoneyOps, Timestamp, TransactionId, TransactionType};
+
+/// The size of an amount in a transaction record
+const AMOUNT_SIZE: usize = std::mem::size_of::<<Amount as MoneyOps>::Bytes>();
+
+/// The size of a client id in a transaction record
+const CLIENT_SIZE: usize = std::mem::size_of::<ClientId>();
+
+/// Where the timestamp starts in a transaction record, after five bytes of flags and the client
+const TIMESTAMP_START: usize = 5 + CLIENT_SIZE;
+
+/// The size of a transaction record in the spill file
+const RECORD_SIZE: u64 = (TIMESTAMP_START + 8 + 3 * AMOUNT_SIZE) as u64;
+
+/// Makes the names of the spill files of all engines of the process unique
+static SPILL_FILE_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// An entry kept in memory
+struct CachedTransaction {
+    details: TransactionDetails,
+    /// Whether the id was already added to the list of spilled ids
+    spilled: bool,
+    /// When the transaction was last used, matching its latest entry in the eviction order
+    used: u64,
+}
+
+#[derive(Default)]
+pub(crate) struct TransactionStore {
+    cached: FastMap<TransactionId, CachedTransaction>,
+    /// The ids of the cached transactions with when they were used, least recently used first.
+    /// A transaction is added again whenever it is used, and entries of transactions which were
+    /// used again or removed in the meantime are skipped when evicting.
+    order: VecDeque<(TransactionId, u64)>,
+    /// Counts the uses of cached transactions
+    clock: u64,
+    /// The maximum number of transactions kept in memory, if they are limited
+    cache_size: Option<usize>,
+    spill: Option<SpillFile>,
+    len: usize,
+}
+
+impl TransactionStore {
+    pub(crate) fn new() -> TransactionStore {
+        TransactionStore::default()
+    }
+
+    /// The maximum number of transactions kept in memory, if they are limited
+    pub(crate) fn cache_size(&self) -> Option<usize> {
+        self.cache_size
+    }
+
+    /// Limits the number of transactions kept in memory, spilling all others to disk.
+    pub(crate) fn set_cache_size(&mut self, cache_size: usize) -> io::Result<()> {
+        if self.spill.is_none() {
+            self.spill = Some(SpillFile::create()?);
+        }
+        if self.cache_size.is_none() {
+            self.order = self
+                .cached
+                .iter()
+                .map(|(&tx, cached)| (tx, cached.used))
+                .collect();
+        }
+        self.cache_size = Some(cache_size.max(1));
+        self.evict()
+    }
+
+    /// Makes room for this many more transactions in memory, up to the cache size.
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        let additional = match self.cache_size {
+            Some(cache_size) => additional.min(cache_size.saturating_sub(self.cached.len())),
+            None => additional,
+        };
+        self.cached.reserve(additional);
+    }
+
+    /// The number of stored transactions
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn contains_key(&self, tx: &TransactionId) -> io::Result<bool> {
+        Ok(self.get(tx)?.is_some())
+    }
+
+    /// Returns a copy of the transaction without moving it into memory.
+    pub(crate) fn get(&self, tx: &TransactionId) -> io::Result<Option<TransactionDetails>> {
+        if let Some(cached) = self.cached.get(tx) {
+            return Ok(Some(cached.details));
+        }
+        match &self.spill {
+            Some(spill) => spill.read(*tx),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the transaction for changing it, moving it into memory if it was spilled. This
+    /// counts as a use, unlike `get`, so the transaction is the last one to be spilled again.
+    pub(crate) fn get_mut(
+        &mut self,
+        tx: &TransactionId,
+    ) -> io::Result<Option<&mut TransactionDetails>> {
+        if self.cached.contains_key(tx) {
+            self.touch(*tx);
+        } else {
+            let spilled = match &self.spill {
+                Some(spill) => spill.read(*tx)?,
+                None => None,
+            };
+            match spilled {
+                Some(details) => {
+                    self.cache(*tx, details, true);
+                    self.evict()?;
+                }
+                None => return Ok(None),
+            }
+        }
+        Ok(self.cached.get_mut(tx).map(|cached| &mut cached.details))
+    }
+
+    pub(crate) fn insert(
+        &mut self,
+        tx: TransactionId,
+        details: TransactionDetails,
+    ) -> io::Result<()> {
+        if !self.contains_key(&tx)? {
+            self.len += 1;
+        }
+        match self.cached.get_mut(&tx) {
+            Some(cached) => {
+                cached.details = details;
+                self.touch(tx);
+            }
+            None => {
+                self.cache(tx, details, false);
+                self.evict()?;
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn remove(&mut self, tx: &TransactionId) -> io::Result<()> {
+        if !self.contains_key(tx)? {
+            return Ok(());
+        }
+        self.len -= 1;
+        self.cached.remove(tx);
+        if let Some(spill) = &self.spill {
+            spill.clear(*tx)?;
+        }
+        Ok(())
+    }
+
+    /// All stored transactions, in no particular order.
+    pub(crate) fn entries(&self) -> io::Result<Vec<(TransactionId, TransactionDetails)>> {
+        let mut entries: Vec<(TransactionId, TransactionDetails)> = self
+            .cached
+            .iter()
+            .map(|(&tx, cached)| (tx, cached.details))
+            .collect();
+        if let Some(spill) = &self.spill {
+            let mut seen: HashSet<TransactionId> = self.cached.keys().copied().collect();
+            for tx in spill.spilled_ids()? {
+                if seen.insert(tx) {
+                    if let Some(details) = spill.read(tx)? {
+                        entries.push((tx, details));
+                    }
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    fn cache(&mut self, tx: TransactionId, details: TransactionDetails, spilled: bool) {
+        self.cached.insert(
+            tx,
+            CachedTransaction {
+                details,
+                spilled,
+                used: 0,
+            },
+        );
+        self.touch(tx);
+    }
+
+    /// Moves a cached transaction to the back of the eviction order.
+    fn touch(&mut self, tx: TransactionId) {
+        // the order is only needed for evicting
+        if self.cache_size.is_none() {
+            return;
+        }
+        let Some(cached) = self.cached.get_mut(&tx) else {
+            return;
+        };
+        self.clock += 1;
+        cached.used = self.clock;
+        self.order.push_back((tx, self.clock));
+        // drops the outdated entries once they make up most of the order, so that it stays
+        // proportional to the cache
+        if self.order.len() > 2 * self.cached.len() + 16 {
+            let cached = &self.cached;
+            self.order
+                .retain(|(tx, used)| cached.get(tx).is_some_and(|c| c.used == *used));
+        }
+    }
+
+    /// Spills the least recently used transactions until the cache fits its size again.
+    fn evict(&mut self) -> io::Result<()> {
+        let (Some(cache_size), Some(spill)) = (self.cache_size, &self.spill) else {
+            return Ok(());
+        };
+        while self.cached.len() > cache_size {
+            let Some((tx, used)) = self.order.pop_front() else {
+                break;
+            };
+            // an outdated entry of a transaction used again or removed since
+            if self
+                .cached
+                .get(&tx)
+                .is_none_or(|cached| cached.used != used)
+            {
+                continue;
+            }
+            if let Some(cached) = self.cached.remove(&tx) {
+                spill.write(tx, &cached.details, !cached.spilled)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The file transactions are spilled to, together with the list of spilled ids which is used
+/// to iterate over them. Both are deleted when the store is dropped.
+struct SpillFile {
+    records: File,
+    records_path: PathBuf,
+    ids: RefCell<BufWriter<File>>,
+    ids_path: PathBuf,
+}
+
+impl SpillFile {
+    fn create() -> io::Result<SpillFile> {
+        let name = format!(
+            "toy-transaction-engine-{}-{}",
+            process::id(),
+            SPILL_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let records_path = env::temp_dir().join(format!("{}.transactions", name));
+        let ids_path = env::temp_dir().join(format!("{}.ids", name));
+        let open = |path: &PathBuf| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(path)
+        };
+        Ok(SpillFile {
+            records: open(&records_path)?,
+            ids: RefCell::new(BufWriter::new(open(&ids_path)?)),
+            records_path,
+            ids_path,
+        })
+    }
+
+    fn read(&self, tx: TransactionId) -> io::Result<Option<TransactionDetails>> {
+        let mut record = [0; RECORD_SIZE as usize];
+        let mut records = &self.records;
+        records.seek(SeekFrom::Start(record_offset(tx)?))?;
+        match records.read_exact(&mut record) {
+            Ok(()) => Ok(decode(&record)),
+            // past the end of the file, no transaction with this or a higher id was spilled
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn write(
+        &self,
+        tx: TransactionId,
+        details: &TransactionDetails,
+        new_id: bool,
+    ) -> io::Result<()> {
+        self.write_record(tx, &encode(details))?;
+        if new_id {
+            self.ids.borrow_mut().write_all(&tx.to_le_bytes())?;
+        }
+        Ok(())
+    }
+
+    fn clear(&self, tx: TransactionId) -> io::Result<()> {
+        if self.read(tx)?.is_some() {
+            self.write_record(tx, &[0; RECORD_SIZE as usize])?;
+        }
+        Ok(())
+    }
+
+    fn write_record(&self, tx: TransactionId, record: &[u8]) -> io::Result<()> {
+        let mut records = &self.records;
+        records.seek(SeekFrom::Start(record_offset(tx)?))?;
+        records.write_all(record)
+    }
+
+    fn spilled_ids(&self) -> io::Result<Vec<TransactionId>> {
+        self.ids.borrow_mut().flush()?;
+        let mut reader = BufReader::new(File::open(&self.ids_path)?);
+        let mut ids = Vec::new();
+        let mut id = [0; std::mem::size_of::<TransactionId>()];
+        loop {
+            match reader.read_exact(&mut id) {
+                Ok(()) => ids.push(TransactionId::from_le_bytes(id)),
+                Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(ids),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Drop for SpillFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.records_path);
+        let _ = fs::remove_file(&self.ids_path);
+    }
+}
+
+/// Where the record of the transaction is in the spill file, which has a record for every id up
+/// to the largest one spilled
+// the conversion is a no-op with 64-bit transaction ids
+#[allow(clippy::useless_conversion)]
+fn record_offset(tx: TransactionId) -> io::Result<u64> {
+    u64::from(tx).checked_mul(RECORD_SIZE).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("transaction {tx} is beyond the ids which can be spilled"),
+        )
+    })
+}
+
+/// Encodes a transaction as a record: a presence marker, the kind, the state, whether there is
+/// an amount, whether there is a timestamp, the client, the timestamp, the amount, the disputed
+/// amount and the amount charged back.
+fn encode(details: &TransactionDetails) -> [u8; RECORD_SIZE as usize] {
+    let mut record = [0; RECORD_SIZE as usize];
+    record[0] = 1;
+    record[1] = match details.kind {
+        TransactionType::Deposit => 0,
+        TransactionType::Withdrawal => 1,
+        TransactionType::Dispute => 2,
+        TransactionType::Resolve => 3,
+        TransactionType::Chargeback => 4,
+        TransactionType::Unlock => 5,
+        TransactionType::Open => 6,
+        TransactionType::Close => 7,
+        TransactionType::Freeze => 8,
+        TransactionType::Unfreeze => 9,
+        TransactionType::Interest => 10,
+    };
+    record[2] = match details.state {
+        TransactionState::Normal => 0,
+        TransactionState::Disputed => 1,
+        TransactionState::Resolved => 2,
+        TransactionState::ChargedBack => 3,
+    };
+    record[3] = u8::from(details.amount.is_some());
+    record[4] = u8::from(details.timestamp.is_some());
+    record[5..TIMESTAMP_START].copy_from_slice(&details.client.to_le_bytes());
+    record[TIMESTAMP_START..TIMESTAMP_START + 8]
+        .copy_from_slice(&details.timestamp.unwrap_or_default().to_le_bytes());
+    let amounts = [
+        details.amount.unwrap_or_default(),
+        details.disputed,
+        details.charged_back,
+    ];
+    for (amount, bytes) in amounts
+        .iter()
+        .zip(record[TIMESTAMP_START + 8..].chunks_exact_mut(AMOUNT_SIZE))
+    {
+        bytes.copy_from_slice(amount.to_le_bytes().as_ref());
+    }
+    record
+}
+
+/// Decodes a record, returning None for a record which was never written or was cleared.
+fn decode(record: &[u8; RECORD_SIZE as usize]) -> Option<TransactionDetails> {
+    if record[0] == 0 {
+        return None;
+    }
+    let kind = match record[1] {
+        0 => TransactionType::Deposit,
+        1 => TransactionType::Withdrawal,
+        2 => TransactionType::Dispute,
+        3 => TransactionType::Resolve,
+        4 => TransactionType::Chargeback,
+        5 => TransactionType::Unlock,
+        6 => TransactionType::Open,
+        7 => TransactionType::Close,
+        8 => TransactionType::Freeze,
+        9 => TransactionType::Unfreeze,
+        _ => TransactionType::Interest,
+    };
+    let state = match record[2] {
+        0 => TransactionState::Normal,
+        1 => TransactionState::Disputed,
+        2 => TransactionState::Resolved,
+        _ => TransactionState::ChargedBack,
+    };
+    let mut client = [0; CLIENT_SIZE];
+    client.copy_from_slice(&record[5..TIMESTAMP_START]);
+    let client = ClientId::from_le_bytes(client);
+    let mut timestamp = [0; 8];
+    timestamp.copy_from_slice(&record[TIMESTAMP_START..TIMESTAMP_START + 8]);
+    let timestamp = (record[4] == 1).then(|| Timestamp::from_le_bytes(timestamp));
+    let amount = |index: usize| {
+        let start = TIMESTAMP_START + 8 + index * AMOUNT_SIZE;
+        let mut bytes = <Amount as MoneyOps>::Bytes::default();
+        bytes
+            .as_mut()
+            .copy_from_slice(&record[start..start + AMOUNT_SIZE]);
+        Amount::from_le_bytes(bytes)
+    };
+    Some(TransactionDetails {
+        kind,
+        client,
+        amount: (record[3] == 1).then(|| amount(0)),
+        state,
+        disputed: amount(1),
+        charged_back: amount(2),
+        timestamp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TransactionStore;
+    use crate::transaction_engine::{TransactionDetails, TransactionState};
+    use crate::{ClientId, TransactionType};
+
+    fn deposit(amount: i64) -> TransactionDetails {
+        TransactionDetails {
+            kind: TransactionType::Deposit,
+            client: 7,
+            amount: Some(crate::Amount::from_minor_units(amount)),
+            state: TransactionState::Normal,
+            disputed: crate::Amount::ZERO,
+            charged_back: crate::Amount::ZERO,
+            timestamp: None,
+        }
+    }
+
+    #[test]
+    fn test_spilled_transactions_are_found() {
+        let mut store = TransactionStore::new();
+        store.set_cache_size(2).unwrap();
+        for tx in 1..=5 {
+            store.insert(tx * 1000, deposit(tx as i64)).unwrap();
+        }
+        assert_eq!(store.len(), 5);
+        assert_eq!(store.cached.len(), 2);
+
+        // a spilled transaction is moved back into memory to be changed, and the change is
+        // kept when it is spilled again
+        store.get_mut(&1000).unwrap().unwrap().state = TransactionState::Disputed;
+        for tx in 6..=8 {
+            store.insert(tx * 1000, deposit(tx as i64)).unwrap();
+        }
+        assert!(!store.cached.contains_key(&1000));
+        assert_eq!(
+            store.get(&1000).unwrap().unwrap().state,
+            TransactionState::Disputed
+        );
+        assert!(store.get(&1001).unwrap().is_none());
+
+        store.remove(&2000).unwrap();
+        let mut entries = store.entries().unwrap();
+        entries.sort_by_key(|(tx, _)| *tx);
+        assert_eq!(
+            entries.iter().map(|(tx, _)| *tx).collect::<Vec<_>>(),
+            vec![1000, 3000, 4000, 5000, 6000, 7000, 8000]
+        );
+        assert_eq!(
+            entries[2].1.amount,
+            Some(crate::Amount::from_minor_units(4))
+        );
+    }
+
+    #[test]
+    fn test_least_recently_used_transaction_is_spilled() {
+        let mut store = TransactionStore::new();
+        store.set_cache_size(2).unwrap();
+        store.insert(1, deposit(1)).unwrap();
+        store.insert(2, deposit(2)).unwrap();
+        store.get_mut(&1).unwrap().unwrap().state = TransactionState::Disputed;
+        store.insert(3, deposit(3)).unwrap();
+        assert!(store.cached.contains_key(&1));
+        assert!(!store.cached.contains_key(&2));
+
+        for _ in 0..100 {
+            store.get_mut(&1).unwrap();
+        }
+        assert!(store.order.len() <= 2 * store.cached.len() + 16);
+    }
+
+    #[test]
+    fn test_spilled_records_keep_the_widest_client_id() {
+        let mut store = TransactionStore::new();
+        store.set_cache_size(1).unwrap();
+        store
+            .insert(
+                1,
+                TransactionDetails {
+                    client: ClientId::MAX,
+                    ..deposit(5)
+                },
+            )
+            .unwrap();
+        store.insert(2, deposit(6)).unwrap();
+        assert!(!store.cached.contains_key(&1));
+        assert_eq!(store.get(&1).unwrap().unwrap().client, ClientId::MAX);
+    }
+}
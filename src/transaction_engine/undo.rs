@@ -0,0 +1,254 @@
+//! Rolling back the latest transactions applied, e.g. once a bad batch is discovered.
+//!
+//! While undo is enabled, the engine keeps what every applied transaction changed: the ledger
+//! entries it posted, the status of the accounts it touched, the stored transaction it
+//! created or changed, and the disputes of that transaction. Rolling back posts the inverse of
+//! the entries and puts the rest back as it was, latest transaction first. Interest payments,
+//! statistics, rules and what observers and the audit sink were told aren't rolled back.
+
+use std::collections::VecDeque;
+
+use thiserror::Error;
+
+use super::disputes::DisputesUndo;
+use super::{AccountStatus, LedgerAccount, LedgerEntry, TransactionDetails, TransactionEngine};
+use crate::{Amount, ClientId, TransactionId, TransactionInput, TransactionType};
+
+/// All errors which can occur rolling back transactions
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum RollbackError {
+    #[error("transactions can't be rolled back while they are written to a write-ahead log")]
+    WalAttached,
+
+    #[error("only the last {retained} transactions can be rolled back, not {requested}")]
+    NotEnoughRetained { requested: usize, retained: usize },
+
+    #[error("the transactions after sequence number {0} aren't retained for undo")]
+    SequenceNotRetained(u64),
+
+    #[error("the stored transactions couldn't be read or written: {0}")]
+    StorageFailed(String),
+}
+
+/// What is needed to undo a transaction
+#[derive(Clone)]
+pub(super) struct UndoRecord {
+    /// The sequence number of the transaction
+    seq: u64,
+    tx: TransactionId,
+    /// The status of every account the transaction touched, None if it didn't exist yet
+    statuses: Vec<(ClientId, Option<AccountStatus>)>,
+    /// The stored transaction with the id of the transaction before it
+    stored: Option<TransactionDetails>,
+    /// The entries the transaction posted
+    posting: Vec<LedgerEntry>,
+    disputes: Option<DisputesUndo>,
+}
+
+/// The undo records of the latest transactions, oldest first
+#[derive(Clone, Default)]
+pub(super) struct UndoLog {
+    limit: usize,
+    records: VecDeque<UndoRecord>,
+    /// The sequence number of the latest transaction which can't be undone any more
+    horizon: u64,
+}
+
+impl TransactionEngine {
+    /// The number of the latest transactions which can be rolled back at most
+    pub fn undo_limit(&self) -> usize {
+        self.undo.limit
+    }
+
+    /// Keeps what is needed to roll back the latest `limit` transactions from now on, or
+    /// nothing with zero, which is the default.
+    pub fn set_undo_limit(&mut self, limit: usize) {
+        self.undo.limit = limit;
+        self.trim_undo();
+    }
+
+    /// Rolls back the last `n` transactions applied.
+    pub fn rollback(&mut self, n: usize) -> Result<(), RollbackError> {
+        if self.wal.is_some() {
+            return Err(RollbackError::WalAttached);
+        }
+        if n > self.undo.records.len() {
+            return Err(RollbackError::NotEnoughRetained {
+                requested: n,
+                retained: self.undo.records.len(),
+            });
+        }
+        for _ in 0..n {
+            if let Some(record) = self.undo.records.pop_back() {
+                self.undo_transaction(record)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Rolls back every transaction applied after the one with the sequence number, returning
+    /// how many there were.
+    pub fn rollback_to(&mut self, seq: u64) -> Result<usize, RollbackError> {
+        if seq < self.undo.horizon {
+            return Err(RollbackError::SequenceNotRetained(seq));
+        }
+        let n = self
+            .undo
+            .records
+            .iter()
+            .rev()
+            .take_while(|record| record.seq > seq)
+            .count();
+        self.rollback(n)?;
+        Ok(n)
+    }
+
+    /// Takes what is needed to undo the transaction before it is applied, if undo is enabled.
+    pub(super) fn undo_point(&mut self, transaction: &TransactionInput) -> Option<UndoRecord> {
+        if self.undo.limit == 0 {
+            return None;
+        }
+        let mut clients = vec![transaction.client];
+        if let Some(fee_schedule) = &self.fee_schedule {
+            if fee_schedule.collection_account != transaction.client {
+                clients.push(fee_schedule.collection_account);
+            }
+        }
+        let statuses = clients
+            .iter()
+            .map(|client| {
+                let status = self.accounts.get(client).map(|account| account.status);
+                (*client, status)
+            })
+            .collect();
+        // a failing read shows up again when the transaction is applied
+        let stored = self.transactions.get(&transaction.tx).ok().flatten();
+        let disputes = match transaction.kind {
+            TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+                Some(self.disputes.undo_point(transaction.tx))
+            }
+            _ => None,
+        };
+        self.last_posting.clear();
+        Some(UndoRecord {
+            seq: 0,
+            tx: transaction.tx,
+            statuses,
+            stored,
+            posting: Vec::new(),
+            disputes,
+        })
+    }
+
+    /// Keeps the undo record of a transaction which was applied. Without one, the transactions
+    /// up to it can't be undone any more.
+    pub(super) fn record_undo(&mut self, record: Option<UndoRecord>) {
+        let Some(mut record) = record else {
+            self.undo.records.clear();
+            self.undo.horizon = self.history.sequence();
+            return;
+        };
+        record.seq = self.history.sequence();
+        record.posting = std::mem::take(&mut self.last_posting);
+        self.undo.records.push_back(record);
+        self.trim_undo();
+    }
+
+    fn trim_undo(&mut self) {
+        while self.undo.records.len() > self.undo.limit {
+            if let Some(record) = self.undo.records.pop_front() {
+                self.undo.horizon = record.seq;
+            }
+        }
+    }
+
+    fn undo_transaction(&mut self, record: UndoRecord) -> Result<(), RollbackError> {
+        let storage_failed = |e: std::io::Error| RollbackError::StorageFailed(e.to_string());
+        if !record.posting.is_empty() {
+            self.ledger.unpost(&record.posting);
+            for entry in &record.posting {
+                if let LedgerAccount::ClientAvailable(client) | LedgerAccount::ClientHeld(client) =
+                    entry.account
+                {
+                    self.refresh_account(client);
+                }
+            }
+        }
+        for (client, status) in record.statuses {
+            match status {
+                Some(status) => {
+                    if let Some(account) = self.accounts.get_mut(&client) {
+                        account.status = status;
+                    }
+                }
+                None => {
+                    let empty = self.accounts.get(&client).is_some_and(|account| {
+                        account.available == Amount::ZERO && account.held == Amount::ZERO
+                    });
+                    if empty {
+                        self.accounts.remove(&client);
+                    }
+                }
+            }
+        }
+        match record.stored {
+            Some(stored) => self.transactions.insert(record.tx, stored),
+            None => self.transactions.remove(&record.tx),
+        }
+        .map_err(storage_failed)?;
+        if let Some(disputes) = record.disputes {
+            self.disputes.undo(disputes);
+        }
+        self.history.forget(record.seq);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RollbackError;
+    use crate::{Amount, TransactionEngine, TransactionInput, TransactionState};
+
+    fn money(value: &str) -> Amount {
+        value.parse().unwrap()
+    }
+
+    #[test]
+    fn test_latest_transactions_are_rolled_back() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine
+            .process_transaction(TransactionInput::deposit(1, 1, money("5")))
+            .unwrap();
+        transaction_engine.set_undo_limit(10);
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 2, money("10")),
+            TransactionInput::deposit(2, 3, money("3")),
+            TransactionInput::withdrawal(1, 4, money("4")),
+            TransactionInput::dispute(1, 2),
+            TransactionInput::chargeback(1, 2),
+        ]);
+        assert!(results.iter().all(Result::is_ok));
+        assert!(transaction_engine.get_account(1).unwrap().is_locked());
+
+        transaction_engine.rollback(2).unwrap();
+        let account = transaction_engine.get_account(1).unwrap();
+        assert!(!account.is_locked());
+        assert_eq!((account.available, account.held), (money("11"), money("0")));
+        let deposit = transaction_engine.get_transaction(2).unwrap().unwrap();
+        assert_eq!(deposit.state, TransactionState::Normal);
+        assert_eq!(transaction_engine.disputes().count(), 0);
+
+        assert_eq!(transaction_engine.rollback_to(2), Ok(2));
+        assert!(transaction_engine.get_account(2).is_none());
+        assert!(transaction_engine.get_transaction(4).unwrap().is_none());
+        assert_eq!(
+            transaction_engine.get_account(1).unwrap().available,
+            money("15")
+        );
+        assert!(transaction_engine.check_ledger().is_ok());
+        assert_eq!(
+            transaction_engine.rollback_to(0),
+            Err(RollbackError::SequenceNotRetained(0))
+        );
+    }
+}
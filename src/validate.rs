@@ -0,0 +1,272 @@
+//! Checking an input before it is processed, e.g. as a pre-flight check of a batch.
+//!
+//! The `validate` subcommand reads the whole input without applying it to any accounts and
+//! reports every problem found: missing columns, rows which can't be parsed (including amounts
+//! with too many decimal places), deposits and withdrawals without a positive amount, reused
+//! transaction ids, disputes, resolves and chargebacks which don't follow from the rows before
+//! them, and control totals which don't match.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read};
+
+use clap::Parser;
+use serde::Serialize;
+
+use crate::control_totals::ControlTotals;
+use crate::{
+    csv_reader, read_row, Amount, ClientId, PrecisionPolicy, TransactionId, TransactionInput,
+    TransactionType, STDIN_PATH,
+};
+
+/// The columns every input needs
+const REQUIRED_COLUMNS: [&str; 3] = ["type", "client", "tx"];
+
+/// Checks a CSV file of transactions without processing it, exiting with a nonzero code if
+/// there are problems.
+#[derive(Parser, Debug)]
+#[command(name = "validate", bin_name = "toy-transaction-engine validate")]
+pub struct ValidateConfig {
+    /// The input file, or - to read from stdin
+    #[arg(default_value = STDIN_PATH)]
+    pub input_path: String,
+}
+
+impl ValidateConfig {
+    /// Parses the arguments starting with the `validate` subcommand.
+    pub fn new(args: &[String]) -> Result<ValidateConfig, clap::Error> {
+        ValidateConfig::try_parse_from(args)
+    }
+}
+
+/// A problem found in the input
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    /// The line the problem is on, None for problems of the whole input
+    pub line: Option<u64>,
+    /// A short, machine-readable name of the problem, the same as the engine rejects the row
+    /// with where there is one
+    pub kind: &'static str,
+    pub message: String,
+}
+
+/// What is known about a deposit or withdrawal seen earlier in the input
+struct Referenced {
+    client: ClientId,
+    disputed: bool,
+    charged_back: bool,
+}
+
+/// Reads the whole input, returning every problem found in the order of the lines.
+pub fn validate<R: Read>(input: R) -> Result<Vec<Problem>, csv::Error> {
+    let mut reader = csv_reader(input);
+    let headers = reader.headers()?.clone();
+    let mut problems = Vec::new();
+    for column in REQUIRED_COLUMNS {
+        if !headers.iter().any(|header| header == column) {
+            problems.push(Problem {
+                line: Some(1),
+                kind: "missing_column",
+                message: format!("the {column} column is missing"),
+            });
+        }
+    }
+    if !problems.is_empty() {
+        return Ok(problems);
+    }
+
+    let mut control_totals = ControlTotals::new();
+    let mut seen: HashMap<TransactionId, Referenced> = HashMap::new();
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map(|position| position.line());
+        let row = match read_row(
+            record,
+            &headers,
+            PrecisionPolicy::Reject,
+            &mut control_totals,
+        ) {
+            Ok(Some(row)) => row,
+            Ok(None) => continue,
+            Err(e) => {
+                problems.push(Problem {
+                    line,
+                    kind: "control_totals",
+                    message: e.to_string(),
+                });
+                continue;
+            }
+        };
+        match row.transaction {
+            Ok(transaction) => {
+                if let Some((kind, message)) = check(&transaction, &mut seen) {
+                    problems.push(Problem {
+                        line,
+                        kind,
+                        message: message.to_string(),
+                    });
+                }
+            }
+            Err(e) => problems.push(Problem {
+                line,
+                kind: "parse_error",
+                message: e.to_string(),
+            }),
+        }
+    }
+    if let Err(e) = control_totals.verify() {
+        problems.push(Problem {
+            line: None,
+            kind: "control_totals",
+            message: e.to_string(),
+        });
+    }
+    Ok(problems)
+}
+
+/// Checks a transaction against the rows before it, keeping track of what it changes.
+fn check(
+    transaction: &TransactionInput,
+    seen: &mut HashMap<TransactionId, Referenced>,
+) -> Option<(&'static str, &'static str)> {
+    match transaction.kind() {
+        TransactionType::Deposit | TransactionType::Withdrawal => {
+            match transaction.amount() {
+                None => return Some(("missing_amount", "deposits and withdrawals need an amount")),
+                Some(amount) if amount <= Amount::ZERO => {
+                    return Some(("invalid_amount", "amounts must be positive"))
+                }
+                Some(_) => (),
+            }
+            if seen.contains_key(&transaction.tx()) {
+                return Some((
+                    "duplicate_transaction_id",
+                    "a transaction with the same id is in the input before this row",
+                ));
+            }
+            seen.insert(
+                transaction.tx(),
+                Referenced {
+                    client: transaction.client(),
+                    disputed: false,
+                    charged_back: false,
+                },
+            );
+        }
+        TransactionType::Dispute | TransactionType::Resolve | TransactionType::Chargeback => {
+            let Some(referenced) = seen.get_mut(&transaction.tx()) else {
+                return Some((
+                    "transaction_not_found",
+                    "the referenced transaction isn't in the input before this row",
+                ));
+            };
+            if referenced.client != transaction.client() {
+                return Some((
+                    "client_mismatch",
+                    "the referenced transaction belongs to a different client",
+                ));
+            }
+            match transaction.kind() {
+                TransactionType::Dispute if referenced.charged_back => {
+                    return Some((
+                        "transaction_charged_back",
+                        "the referenced transaction was charged back already",
+                    ))
+                }
+                TransactionType::Dispute if referenced.disputed => {
+                    return Some((
+                        "transaction_already_disputed",
+                        "the referenced transaction is disputed already",
+                    ))
+                }
+                TransactionType::Dispute => referenced.disputed = true,
+                _ if !referenced.disputed => {
+                    return Some((
+                        "transaction_not_disputed",
+                        "the referenced transaction isn't disputed",
+                    ))
+                }
+                TransactionType::Chargeback => {
+                    referenced.disputed = false;
+                    referenced.charged_back = true;
+                }
+                _ => referenced.disputed = false,
+            }
+        }
+        TransactionType::Interest => {
+            return Some((
+                "interest_not_allowed",
+                "interest transactions are paid by the engine and can't be submitted",
+            ))
+        }
+        _ => (),
+    }
+    None
+}
+
+/// Writes the problems as CSV.
+pub fn write_problems<W: io::Write>(writer: W, problems: &[Problem]) -> csv::Result<()> {
+    let mut writer = csv::WriterBuilder::new()
+        .has_headers(false)
+        .from_writer(writer);
+    writer.write_record(["line", "kind", "message"])?;
+    for problem in problems {
+        writer.serialize(problem)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Validates the input and writes the problems to stdout, returning how many there are.
+pub fn run(config: ValidateConfig) -> Result<usize, Box<dyn Error>> {
+    let input: Box<dyn Read> = if config.input_path == STDIN_PATH {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(&config.input_path)?))
+    };
+    let problems = validate(input)?;
+    write_problems(BufWriter::new(io::stdout().lock()), &problems)?;
+    Ok(problems.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::validate;
+
+    #[test]
+    fn test_every_problem_is_reported() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 2.5\n\
+                     deposit, 1, 2, 1.00001\n\
+                     withdrawal, 1, 3, -1\n\
+                     deposit, 2, 1, 1.0\n\
+                     dispute, 2, 1\n\
+                     resolve, 1, 1\n\
+                     dispute, 1, 1\n\
+                     chargeback, 1, 1\n\
+                     dispute, 1, 1\n\
+                     dispute, 1, 9\n";
+        let problems: Vec<_> = validate(input.as_bytes())
+            .unwrap()
+            .iter()
+            .map(|problem| (problem.line, problem.kind))
+            .collect();
+        assert_eq!(
+            problems,
+            vec![
+                (Some(3), "parse_error"),
+                (Some(4), "invalid_amount"),
+                (Some(5), "duplicate_transaction_id"),
+                (Some(6), "client_mismatch"),
+                (Some(7), "transaction_not_disputed"),
+                (Some(10), "transaction_charged_back"),
+                (Some(11), "transaction_not_found"),
+            ]
+        );
+
+        let problems = validate("type, tx, amount\n".as_bytes()).unwrap();
+        assert_eq!(problems.len(), 1);
+        assert_eq!(problems[0].message, "the client column is missing");
+    }
+}
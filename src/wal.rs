@@ -0,0 +1,274 @@
+//! A write-ahead log of the transactions applied by an engine, so that its state can be rebuilt
+//! after a crash.
+//!
+//! Every transaction which was applied successfully is appended to the log as a JSON line, in
+//! the same format as JSON Lines input. If the write fails, the transaction is undone, so the
+//! state of the engine never holds a transaction which isn't in the log.
+//!
+//! Once the log grows beyond its size limit it is rotated: the current file is renamed to
+//! `<path>.<n>` with the next free `n`, and a new file is started at `<path>`. Replaying reads
+//! the rotated files in order followed by the current one. Rotated files are needed for
+//! replaying until a snapshot covering them was taken.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use thiserror::Error;
+
+use crate::input::json_lines;
+use crate::{TransactionEngine, TransactionInput, TransactionProcessingError};
+
+/// When the log is flushed to disk with fsync
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FsyncPolicy {
+    /// After every transaction, so that nothing is lost even if the machine crashes
+    Always,
+    /// When the log is rotated and when `sync` is called, e.g. at the end of every input. A
+    /// crash of the process loses nothing, a crash of the machine may lose the last
+    /// transactions.
+    #[default]
+    Batch,
+    /// Never, leaving it to the operating system
+    Never,
+}
+
+impl FsyncPolicy {
+    /// Parses the policy from its command line name.
+    pub fn from_name(name: &str) -> Option<FsyncPolicy> {
+        match name {
+            "always" => Some(FsyncPolicy::Always),
+            "batch" => Some(FsyncPolicy::Batch),
+            "never" => Some(FsyncPolicy::Never),
+            _ => None,
+        }
+    }
+}
+
+/// All errors which can happen when replaying a log
+#[derive(Error, Debug)]
+pub enum WalError {
+    #[error("couldn't read the write-ahead log: {0}")]
+    Io(#[from] io::Error),
+
+    #[error("line {line} of {path} is not a transaction: {error}")]
+    Corrupt {
+        path: String,
+        line: u64,
+        error: serde_json::Error,
+    },
+
+    #[error("line {line} of {path} can't be replayed: {error}")]
+    Replay {
+        path: String,
+        line: u64,
+        error: TransactionProcessingError,
+    },
+}
+
+/// An open write-ahead log
+pub struct Wal {
+    path: String,
+    file: File,
+    size: u64,
+    /// Rotate once the log is larger than this many bytes
+    max_bytes: Option<u64>,
+    fsync_policy: FsyncPolicy,
+    /// The number the next rotated file gets
+    next_segment: u64,
+}
+
+impl Wal {
+    /// Opens the log at the path for appending, creating it if needed.
+    pub fn open(path: &str, fsync_policy: FsyncPolicy, max_bytes: Option<u64>) -> io::Result<Wal> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Wal {
+            path: path.to_string(),
+            size: file.metadata()?.len(),
+            file,
+            max_bytes,
+            fsync_policy,
+            next_segment: segments(path)?.last().map_or(1, |(number, _)| number + 1),
+        })
+    }
+
+    /// Appends a transaction to the log, rotating it first if it is full.
+    pub fn append(&mut self, transaction: &TransactionInput) -> io::Result<()> {
+        let mut line = serde_json::to_vec(transaction)?;
+        line.push(b'\n');
+        if let Some(max_bytes) = self.max_bytes {
+            if self.size > 0 && self.size + line.len() as u64 > max_bytes {
+                self.rotate()?;
+            }
+        }
+        // a single write, so that a crash leaves at most a partial last line
+        self.file.write_all(&line)?;
+        self.size += line.len() as u64;
+        if self.fsync_policy == FsyncPolicy::Always {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Flushes the log to disk, unless the policy is to never do so.
+    pub fn sync(&mut self) -> io::Result<()> {
+        match self.fsync_policy {
+            FsyncPolicy::Never => Ok(()),
+            FsyncPolicy::Always | FsyncPolicy::Batch => self.file.sync_data(),
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.sync()?;
+        fs::rename(&self.path, format!("{}.{}", self.path, self.next_segment))?;
+        self.next_segment += 1;
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl TransactionEngine {
+    /// Applies every transaction of the log at the path, including its rotated files, on top
+    /// of the current state. Returns the number of replayed transactions.
+    ///
+    /// A partial last line, as left by a crash while writing, is ignored. Any other line which
+    /// can't be read or applied is an error, as the log only holds transactions which were
+    /// applied successfully. For the same reason, logged admin operations are replayed even if
+    /// the engine doesn't allow them.
+    pub fn replay_wal(&mut self, path: &str) -> Result<u64, WalError> {
+        let mut files: Vec<String> = segments(path)?.into_iter().map(|(_, p)| p).collect();
+        if Path::new(path).exists() {
+            files.push(path.to_string());
+        }
+
+        let allow_admin_ops = self.allow_admin_ops();
+        self.set_allow_admin_ops(true);
+        let replayed = self.replay_files(files);
+        self.set_allow_admin_ops(allow_admin_ops);
+        replayed
+    }
+
+    fn replay_files(&mut self, files: Vec<String>) -> Result<u64, WalError> {
+        let mut replayed = 0;
+        for file_path in files {
+            let mut reader = BufReader::new(File::open(&file_path)?);
+            let mut text = String::new();
+            let mut line = 0;
+            loop {
+                text.clear();
+                if reader.read_line(&mut text)? == 0 {
+                    break;
+                }
+                line += 1;
+                if !text.ends_with('\n') {
+                    break;
+                }
+                let transaction =
+                    json_lines::parse_transaction(&text).map_err(|error| WalError::Corrupt {
+                        path: file_path.clone(),
+                        line,
+                        error,
+                    })?;
+                self.process_transaction(transaction)
+                    .map_err(|error| WalError::Replay {
+                        path: file_path.clone(),
+                        line,
+                        error,
+                    })?;
+                replayed += 1;
+            }
+        }
+        Ok(replayed)
+    }
+}
+
+/// The rotated files of the log at the path, sorted by their number.
+fn segments(path: &str) -> io::Result<Vec<(u64, String)>> {
+    let path = Path::new(path);
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let prefix = match path.file_name() {
+        Some(name) => format!("{}.", name.to_string_lossy()),
+        None => return Ok(Vec::new()),
+    };
+    let mut segments = Vec::new();
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if let Some(number) = name
+            .strip_prefix(&prefix)
+            .and_then(|number| number.parse::<u64>().ok())
+        {
+            segments.push((number, entry.path().to_string_lossy().to_string()));
+        }
+    }
+    segments.sort();
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+
+    use super::{FsyncPolicy, Wal};
+    use crate::{Amount, TransactionEngine, TransactionInput, TransactionProcessingError};
+
+    #[test]
+    fn test_replay_rotated_wal() {
+        let directory = env::temp_dir().join(format!("tte-wal-{}", std::process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        let path = directory.join("engine.wal").to_string_lossy().to_string();
+
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine.set_wal(Wal::open(&path, FsyncPolicy::Batch, Some(100)).unwrap());
+        let results = transaction_engine.process_transactions([
+            TransactionInput::deposit(1, 1, "10".parse().unwrap()),
+            TransactionInput::withdrawal(1, 2, "20".parse().unwrap()),
+            TransactionInput::withdrawal(1, 3, "2.5".parse().unwrap()),
+            TransactionInput::dispute(1, 1),
+        ]);
+        assert!(results[1].is_err());
+        transaction_engine.sync_wal().unwrap();
+        // no two lines fit into 100 bytes, so the log was rotated twice
+        assert!(directory.join("engine.wal.2").exists());
+
+        // a crash while writing leaves a partial line behind
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"{\"type\":\"dep").unwrap();
+
+        let mut replayed = TransactionEngine::new();
+        assert_eq!(replayed.replay_wal(&path).unwrap(), 3);
+        assert_eq!(
+            replayed.sorted_accounts(),
+            transaction_engine.sorted_accounts()
+        );
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    // writes to /dev/full always fail
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_failed_append_undoes_the_dispute() {
+        let mut transaction_engine = TransactionEngine::new();
+        transaction_engine
+            .process_transaction(TransactionInput::deposit(1, 1, "10".parse().unwrap()))
+            .unwrap();
+        transaction_engine.set_wal(Wal::open("/dev/full", FsyncPolicy::Never, None).unwrap());
+        assert!(matches!(
+            transaction_engine.process_transaction(TransactionInput::dispute(1, 1)),
+            Err(TransactionProcessingError::WalWriteFailed(_))
+        ));
+        assert_eq!(transaction_engine.disputes().count(), 0);
+        let account = transaction_engine.get_account(1).unwrap();
+        assert_eq!(account.held, Amount::ZERO);
+        assert!(transaction_engine.check_ledger().is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+//! A JavaScript API for the engine compiled to WebAssembly, with the `wasm-bindgen` feature, so
+//! that uploaded files can be checked in a browser or with Node before they are sent anywhere.
+//!
+//! `WasmEngine` is exported as a class: `processCsv` processes CSV input like the command line,
+//! `submit` applies one transaction object, e.g. `{type: "deposit", client: 1, tx: 1, amount:
+//! "1.5"}`, and `accounts` returns the state of all accounts. Results are JSON strings in the
+//! formats of the command line, with amounts as decimal strings so that none are rounded.
+
+use std::error::Error;
+
+use wasm_bindgen::prelude::*;
+
+use crate::control_totals::ControlTotalsPolicy;
+use crate::output::write_accounts_json;
+use crate::rejects::{ProcessingPolicy, RejectionRow, Rejections};
+use crate::{process_csv, TransactionEngine, TransactionInput};
+
+/// An engine with the default settings, driven from JavaScript
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct WasmEngine {
+    engine: TransactionEngine,
+}
+
+#[wasm_bindgen]
+impl WasmEngine {
+    /// Creates an engine without any accounts.
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> WasmEngine {
+        WasmEngine::default()
+    }
+
+    /// Processes CSV input with a header row, skipping the rows which can't be read or
+    /// processed. Returns the skipped rows as a JSON array of objects with `line`, `kind`,
+    /// `error` and `record`, like `--rejects`. Throws if the input isn't CSV at all.
+    #[wasm_bindgen(js_name = processCsv)]
+    pub fn process_csv(&mut self, csv: &str) -> Result<String, JsError> {
+        self.process_csv_to_json(csv)
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+
+    /// Applies a transaction object with `type`, `client`, `tx` and, for deposits and
+    /// withdrawals, `amount` as a decimal string. Throws if the object isn't a transaction or
+    /// the engine rejects it.
+    pub fn submit(&mut self, transaction: JsValue) -> Result<(), JsError> {
+        let transaction: TransactionInput = serde_wasm_bindgen::from_value(transaction)?;
+        Ok(self.engine.process_transaction(transaction)?)
+    }
+
+    /// The accounts sorted by client as a JSON array, like `--format json`.
+    pub fn accounts(&self) -> Result<String, JsError> {
+        self.accounts_to_json()
+            .map_err(|e| JsError::new(&e.to_string()))
+    }
+}
+
+impl WasmEngine {
+    fn process_csv_to_json(&mut self, csv: &str) -> Result<String, Box<dyn Error>> {
+        let mut rejections = Rejections::new(ProcessingPolicy::Skip, false);
+        process_csv(
+            &mut self.engine,
+            csv.as_bytes(),
+            ControlTotalsPolicy::Warn,
+            &mut rejections,
+        )?;
+        let rejections = rejections.into_vec();
+        let rows: Vec<RejectionRow> = rejections.iter().map(Into::into).collect();
+        Ok(serde_json::to_string(&rows)?)
+    }
+
+    fn accounts_to_json(&self) -> Result<String, Box<dyn Error>> {
+        let mut json = Vec::new();
+        write_accounts_json(&mut json, self.engine.sorted_accounts())?;
+        Ok(String::from_utf8(json)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WasmEngine;
+
+    #[test]
+    fn test_csv_is_processed_into_json() {
+        let mut engine = WasmEngine::new();
+        let rejections = engine
+            .process_csv_to_json(
+                "type,client,tx,amount\n\
+                 deposit,1,1,2.5\n\
+                 withdrawal,1,2,3\n\
+                 deposit,2,3,1\n",
+            )
+            .unwrap();
+        let rejections: serde_json::Value = serde_json::from_str(&rejections).unwrap();
+        assert_eq!(rejections.as_array().unwrap().len(), 1);
+        assert_eq!(rejections[0]["line"], 3);
+
+        let accounts: serde_json::Value =
+            serde_json::from_str(&engine.accounts_to_json().unwrap()).unwrap();
+        assert_eq!(accounts[0]["client"], 1);
+        assert_eq!(accounts[0]["total"], "2.5000");
+        assert_eq!(accounts[1]["client"], 2);
+    }
+}
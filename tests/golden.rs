@@ -0,0 +1,82 @@
+//! Runs the command line pipeline over the cases in `tests/fixtures` and compares what it writes
+//! with the expected files committed next to the input.
+//!
+//! Every case is a directory with an `input.csv`, and `accounts.csv` and `rejects.csv` holding
+//! the expected accounts, sorted by client, and the expected rejected rows. Run with
+//! `UPDATE_GOLDENS=1 cargo test --test golden` to write the expected files from the current
+//! output after a deliberate change, and review the diff before committing it.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use toy_transaction_engine::{run, Config};
+
+/// The files written by a run which are compared with the expected ones
+const OUTPUTS: [&str; 2] = ["accounts.csv", "rejects.csv"];
+
+/// Makes the output comparable across platforms.
+fn normalize(text: &str) -> String {
+    text.lines()
+        .map(str::trim_end)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Runs the case, writing its outputs to the directory.
+fn run_case(case: &Path, output_directory: &Path) {
+    let arg = |path: PathBuf| path.to_string_lossy().to_string();
+    let args = [
+        "toy-transaction-engine".to_string(),
+        arg(case.join("input.csv")),
+        "--sorted".to_string(),
+        "--quiet".to_string(),
+        "--output".to_string(),
+        arg(output_directory.join("accounts.csv")),
+        "--rejects-path".to_string(),
+        arg(output_directory.join("rejects.csv")),
+    ];
+    run(Config::new(&args).unwrap()).unwrap();
+}
+
+#[test]
+fn test_fixtures_match_their_golden_files() {
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let update = env::var_os("UPDATE_GOLDENS").is_some();
+    let mut cases: Vec<PathBuf> = fs::read_dir(&fixtures)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.is_dir())
+        .collect();
+    cases.sort();
+    assert!(!cases.is_empty());
+
+    let mut mismatches = Vec::new();
+    for case in &cases {
+        let name = case.file_name().unwrap().to_string_lossy().to_string();
+        let output_directory = env::temp_dir().join(format!("tte-golden-{name}-{}", process::id()));
+        fs::create_dir_all(&output_directory).unwrap();
+        run_case(case, &output_directory);
+        for output in OUTPUTS {
+            let actual = fs::read_to_string(output_directory.join(output)).unwrap();
+            let golden = case.join(output);
+            if update {
+                fs::write(&golden, &actual).unwrap();
+                continue;
+            }
+            let expected = fs::read_to_string(&golden).unwrap_or_default();
+            if normalize(&actual) != normalize(&expected) {
+                mismatches.push(format!(
+                    "{name}/{output} differs\n--- expected\n{expected}\n--- actual\n{actual}"
+                ));
+            }
+        }
+        fs::remove_dir_all(&output_directory).unwrap();
+    }
+    assert!(
+        mismatches.is_empty(),
+        "{}\nrun with UPDATE_GOLDENS=1 to accept the changes",
+        mismatches.join("\n")
+    );
+}